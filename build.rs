@@ -1,12 +1,21 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+const PROTOS: &[&str] = &[
+    "proto/runtime/v1alpha2/api.proto",
+    "proto/grpc/reflection/v1alpha/reflection.proto",
+];
+
+const INCLUDES: &[&str] = &[
+    "proto",
+    "proto/runtime/v1alpha2/",
+    //"proto/github.com/gogo/protobuf/gogoproto/",
+];
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::configure().compile(
-        &["proto/runtime/v1alpha2/api.proto"],
-        &[
-            "proto",
-            "proto/runtime/v1alpha2/",
-            //"proto/github.com/gogo/protobuf/gogoproto/",
-        ],
-    )?;
+    tonic_build::configure().compile(PROTOS, INCLUDES)?;
+    compile_descriptor_set()?;
 
     println!("cargo:rustc-link-search=native=./target");
     println!("cargo:rustc-link-lib=static=wasm2oci");
@@ -18,3 +27,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Write the descriptors of all served protos (and their imports) to `$OUT_DIR/descriptor.bin`,
+/// so the reflection service can hand them out at runtime.
+fn compile_descriptor_set() -> Result<(), Box<dyn std::error::Error>> {
+    let out = PathBuf::from(env::var("OUT_DIR")?).join("descriptor.bin");
+    let mut cmd = Command::new(prost_build::protoc());
+    cmd.arg("--include_imports")
+        .arg("--include_source_info")
+        .arg(format!("--descriptor_set_out={}", out.display()))
+        .arg(format!("-I{}", prost_build::protoc_include().display()));
+    for include in INCLUDES {
+        cmd.arg(format!("-I{}", include));
+    }
+    cmd.args(PROTOS);
+
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(format!("protoc failed to write the descriptor set: {}", status).into());
+    }
+    Ok(())
+}
@@ -1,8 +1,9 @@
 extern crate wok;
 
 use std::collections::HashMap;
-use std::io::Read;
-use wok::wasm::{Runtime, WasiRuntime};
+
+use futures::executor::block_on_stream;
+use wok::wasm::{OutputBroker, Runtime, Stream, WasiRuntime};
 
 fn main() {
     let mut dirs = HashMap::default();
@@ -16,16 +17,27 @@ fn main() {
         .map(|&s| s.to_owned())
         .collect();
 
-    let runtime = WasiRuntime::new("./examples/printer.wasm", env, args, dirs, Some("./")).unwrap();
+    let output = OutputBroker::new(None).unwrap();
+    let lines = output.subscribe();
+    let runtime =
+        WasiRuntime::new("./examples/printer.wasm", env, args, dirs, Some(output)).unwrap();
 
     runtime.run().unwrap();
-    let (mut stdout_buf, mut stderr_buf) = runtime.output().unwrap();
+    // the subscription ends once the runtime is dropped
+    drop(runtime);
 
     let mut stdout = String::default();
     let mut stderr = String::default();
-
-    stdout_buf.read_to_string(&mut stdout).unwrap();
-    stderr_buf.read_to_string(&mut stderr).unwrap();
+    for line in block_on_stream(lines) {
+        let out = match line.stream {
+            Stream::Stdout => &mut stdout,
+            Stream::Stderr => &mut stderr,
+        };
+        out.push_str(&String::from_utf8_lossy(&line.content));
+        if !line.partial {
+            out.push('\n');
+        }
+    }
 
     println!("STDOUT is:\n{}", stdout);
     println!("STDERR is:\n{}", stderr);
@@ -2,19 +2,347 @@ package main
 
 import (
 	"C"
+	"archive/tar"
+	"bytes"
+	"compress/gzip"
+	"context"
+	"crypto/sha256"
+	"encoding/hex"
+	"encoding/json"
+	"errors"
+	"fmt"
+	"io"
+	"io/ioutil"
+	"net"
+	"net/http"
+	"os"
+	"path"
+	"regexp"
+	"strconv"
+	"strings"
+	"time"
 
-	"github.com/engineerd/wasm-to-oci/pkg/oci"
+	"github.com/containerd/containerd/remotes"
+	auth "github.com/deislabs/oras/pkg/auth/docker"
+	"github.com/deislabs/oras/pkg/content"
+	"github.com/deislabs/oras/pkg/oras"
+	ocispec "github.com/opencontainers/image-spec/specs-go/v1"
 	log "github.com/sirupsen/logrus"
 )
 
+// The results of Pull and Fetch. Keep them in sync with pull_wasm in src/store/mod.rs.
+const (
+	pullSucceeded = 0
+	// the pull failed for good, e.g. because the module does not exist (404) or we may not pull it (401)
+	pullFailed = 1
+	// the pull failed for a reason that may go away, e.g. a network error or a 5xx from the registry
+	pullRetryable = 2
+	// the downloaded module does not have the expected digest
+	pullDigestMismatch = 3
+	// the reference points to something that is not a WebAssembly module, e.g. a container image
+	pullNotWasm = 4
+	// the registry answered with 429 Too Many Requests and no Retry-After header
+	pullRateLimited = 5
+	// the registry answered with 429 Too Many Requests, asking to wait the result minus pullRetryAfter seconds
+	pullRetryAfter = 1000
+)
+
+// the media type of the layer holding the module, as pushed by wasm-to-oci
+const wasmLayerMediaType = "application/vnd.wasm.content.layer.v1+wasm"
+
+// the media type of the manifests of images pushed by docker
+const dockerManifestMediaType = "application/vnd.docker.distribution.manifest.v2+json"
+
+var errNotWasm = errors.New("not a WebAssembly module")
+
+// the registry client only reports unexpected HTTP statuses in the error message
+var serverError = regexp.MustCompile(`status(?: code)?:? 5\d\d`)
+var tooManyRequests = regexp.MustCompile(`status(?: code)?:? 429`)
+
+// rateLimitedError is a 429 Too Many Requests response, with the wait the server asked for in its
+// Retry-After header, or 0 if it didn't.
+type rateLimitedError struct {
+	url        string
+	retryAfter time.Duration
+}
+
+func (e *rateLimitedError) Error() string {
+	return fmt.Sprintf("%s: unexpected status code: 429, retry after %v", e.url, e.retryAfter)
+}
+
+// rateLimitTransport turns 429 responses into rateLimitedErrors, so the Retry-After header is not lost
+// in the registry client, which only reports unexpected HTTP statuses in the error message.
+type rateLimitTransport struct {
+	http.RoundTripper
+}
+
+func (t rateLimitTransport) RoundTrip(req *http.Request) (*http.Response, error) {
+	resp, err := t.RoundTripper.RoundTrip(req)
+	if err != nil || resp.StatusCode != http.StatusTooManyRequests {
+		return resp, err
+	}
+	resp.Body.Close()
+	return nil, &rateLimitedError{url: req.URL.String(), retryAfter: retryAfter(resp.Header.Get("Retry-After"))}
+}
+
+// retryAfter parses a Retry-After header, which is either a number of seconds or a date.
+func retryAfter(header string) time.Duration {
+	if seconds, err := strconv.Atoi(header); err == nil && seconds > 0 {
+		return time.Duration(seconds) * time.Second
+	}
+	if date, err := http.ParseTime(header); err == nil && time.Until(date) > 0 {
+		return time.Until(date).Round(time.Second)
+	}
+	return 0
+}
+
+// the client of every download, reporting 429 responses as rateLimitedErrors
+var httpClient = &http.Client{Transport: rateLimitTransport{http.DefaultTransport}}
+
 //export Pull
-func Pull(ref, outFile string) int64 {
-	if err := oci.Pull(ref, outFile); err != nil {
+func Pull(ref, outFile, dockerConfig string) int64 {
+	if err := pull(ref, outFile, dockerConfig); err != nil {
 		log.Infof("cannot pull module: %v", err)
-		return 1
+		return classify(err)
 	}
 
-	return 0
+	return pullSucceeded
+}
+
+// pull writes the WebAssembly layer of ref to outFile. The registry credentials are read from the
+// docker config file dockerConfig, or from docker's default config file if it is empty.
+func pull(ref, outFile, dockerConfig string) error {
+	ctx := context.Background()
+	var configs []string
+	if dockerConfig != "" {
+		configs = append(configs, dockerConfig)
+	}
+	cli, err := auth.NewClient(configs...)
+	if err != nil {
+		return err
+	}
+	resolver, err := cli.Resolver(ctx, httpClient, false)
+	if err != nil {
+		return err
+	}
+	if err := checkMediaType(ctx, resolver, ref); err != nil {
+		return err
+	}
+
+	store := content.NewMemoryStore()
+	_, layers, err := oras.Pull(ctx, resolver, ref, store, oras.WithAllowedMediaTypes([]string{wasmLayerMediaType}))
+	if err != nil {
+		return err
+	}
+	if len(layers) != 1 {
+		return fmt.Errorf("%s has %d %s layers, expected 1", ref, len(layers), wasmLayerMediaType)
+	}
+	_, module, ok := store.Get(layers[0])
+	if !ok {
+		return fmt.Errorf("the %s layer of %s was not pulled", wasmLayerMediaType, ref)
+	}
+	return ioutil.WriteFile(outFile, module, 0644)
+}
+
+// checkMediaType fails with errNotWasm unless the manifest of ref has a WebAssembly layer. Without
+// this, pulling a container image fails with an obscure error, or worse, succeeds with a file that
+// only fails when it is instantiated.
+func checkMediaType(ctx context.Context, resolver remotes.Resolver, ref string) error {
+	_, desc, err := resolver.Resolve(ctx, ref)
+	if err != nil {
+		return err
+	}
+	if desc.MediaType != ocispec.MediaTypeImageManifest && desc.MediaType != dockerManifestMediaType {
+		return fmt.Errorf("%w: %s has a manifest of type %s", errNotWasm, ref, desc.MediaType)
+	}
+
+	fetcher, err := resolver.Fetcher(ctx, ref)
+	if err != nil {
+		return err
+	}
+	rc, err := fetcher.Fetch(ctx, desc)
+	if err != nil {
+		return err
+	}
+	defer rc.Close()
+	var manifest ocispec.Manifest
+	if err := json.NewDecoder(rc).Decode(&manifest); err != nil {
+		return err
+	}
+	var mediaTypes []string
+	for _, layer := range manifest.Layers {
+		if layer.MediaType == wasmLayerMediaType {
+			return nil
+		}
+		mediaTypes = append(mediaTypes, layer.MediaType)
+	}
+	return fmt.Errorf("%w: %s has no %s layer, only %v", errNotWasm, ref, wasmLayerMediaType, mediaTypes)
+}
+
+//export Fetch
+func Fetch(url, outFile, digest string) int64 {
+	if err := fetch(url, outFile, digest); err != nil {
+		log.Infof("cannot fetch module: %v", err)
+		return classify(err)
+	}
+
+	return pullSucceeded
+}
+
+var errDigestMismatch = errors.New("digest mismatch")
+
+// fetch downloads the module at url into outFile. If digest is not empty, it is the sha256 digest
+// the module must have, e.g. "sha256:6c3c...".
+func fetch(url, outFile, digest string) error {
+	resp, err := httpClient.Get(url)
+	if err != nil {
+		return err
+	}
+	defer resp.Body.Close()
+	if resp.StatusCode != http.StatusOK {
+		return fmt.Errorf("GET %s: unexpected status code: %d", url, resp.StatusCode)
+	}
+
+	f, err := os.Create(outFile)
+	if err != nil {
+		return err
+	}
+	defer f.Close()
+	h := sha256.New()
+	if _, err := io.Copy(io.MultiWriter(f, h), resp.Body); err != nil {
+		return err
+	}
+	if actual := "sha256:" + hex.EncodeToString(h.Sum(nil)); digest != "" && actual != digest {
+		return fmt.Errorf("%w: expected %s, got %s", errDigestMismatch, digest, actual)
+	}
+	return f.Close()
+}
+
+// the GraphQL endpoint of the WAPM registry
+const wapmRegistry = "https://registry.wapm.io/graphql"
+
+const wapmQuery = `query($name: String!, $version: String) {
+  getPackageVersion(name: $name, version: $version) {
+    distribution { downloadUrl }
+  }
+}`
+
+//export PullWapm
+func PullWapm(name, version, outFile string) int64 {
+	if err := pullWapm(name, version, outFile); err != nil {
+		log.Infof("cannot pull WAPM package: %v", err)
+		return classify(err)
+	}
+
+	return pullSucceeded
+}
+
+// pullWapm resolves the package version with the WAPM registry, downloads its archive, and writes
+// the package's module to outFile. The version "latest" stands for the newest version.
+func pullWapm(name, version, outFile string) error {
+	variables := map[string]interface{}{"name": name, "version": nil}
+	if version != "latest" {
+		variables["version"] = version
+	}
+	query, err := json.Marshal(map[string]interface{}{"query": wapmQuery, "variables": variables})
+	if err != nil {
+		return err
+	}
+	resp, err := httpClient.Post(wapmRegistry, "application/json", bytes.NewReader(query))
+	if err != nil {
+		return err
+	}
+	defer resp.Body.Close()
+	if resp.StatusCode != http.StatusOK {
+		return fmt.Errorf("POST %s: unexpected status code: %d", wapmRegistry, resp.StatusCode)
+	}
+	var result struct {
+		Data struct {
+			GetPackageVersion *struct {
+				Distribution struct {
+					DownloadURL string `json:"downloadUrl"`
+				}
+			}
+		}
+	}
+	if err := json.NewDecoder(resp.Body).Decode(&result); err != nil {
+		return err
+	}
+	if result.Data.GetPackageVersion == nil {
+		return fmt.Errorf("package %s@%s does not exist", name, version)
+	}
+
+	archive, err := httpClient.Get(result.Data.GetPackageVersion.Distribution.DownloadURL)
+	if err != nil {
+		return err
+	}
+	defer archive.Body.Close()
+	if archive.StatusCode != http.StatusOK {
+		return fmt.Errorf("GET %s: unexpected status code: %d", archive.Request.URL, archive.StatusCode)
+	}
+	return extractModule(archive.Body, path.Base(name), outFile)
+}
+
+// extractModule writes the module of a WAPM package archive to outFile. Packages with several
+// modules must have one named like the package.
+func extractModule(archive io.Reader, pkg, outFile string) error {
+	gz, err := gzip.NewReader(archive)
+	if err != nil {
+		return err
+	}
+	modules := map[string][]byte{}
+	r := tar.NewReader(gz)
+	for {
+		h, err := r.Next()
+		if err == io.EOF {
+			break
+		}
+		if err != nil {
+			return err
+		}
+		if h.Typeflag != tar.TypeReg || !strings.HasSuffix(h.Name, ".wasm") {
+			continue
+		}
+		var b bytes.Buffer
+		if _, err := io.Copy(&b, r); err != nil {
+			return err
+		}
+		modules[strings.TrimSuffix(path.Base(h.Name), ".wasm")] = b.Bytes()
+	}
+
+	module, ok := modules[pkg]
+	if !ok && len(modules) == 1 {
+		for _, m := range modules {
+			module = m
+		}
+	} else if !ok {
+		return fmt.Errorf("package has %d modules and none is named %s", len(modules), pkg)
+	}
+	return ioutil.WriteFile(outFile, module, 0644)
+}
+
+func classify(err error) int64 {
+	if errors.Is(err, errNotWasm) {
+		return pullNotWasm
+	}
+	if errors.Is(err, errDigestMismatch) {
+		return pullDigestMismatch
+	}
+	var rateLimited *rateLimitedError
+	if errors.As(err, &rateLimited) {
+		if rateLimited.retryAfter > 0 {
+			return pullRetryAfter + int64(rateLimited.retryAfter.Seconds())
+		}
+		return pullRateLimited
+	}
+	if tooManyRequests.MatchString(err.Error()) {
+		return pullRateLimited
+	}
+	var netErr net.Error
+	if errors.As(err, &netErr) || serverError.MatchString(err.Error()) {
+		return pullRetryable
+	}
+	return pullFailed
 }
 
 func main() {}
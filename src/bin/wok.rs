@@ -1,21 +1,51 @@
+use std::convert::TryFrom;
 use std::error;
 use std::fmt;
 #[cfg(unix)]
 use std::fs;
+use std::future::Future;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
 #[cfg(unix)]
 use std::path::Path;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-#[cfg(unix)]
-use futures::stream::TryStreamExt;
+use futures::future::FutureExt;
+use futures::stream::{Stream, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::net::UnixListener;
-use tonic::transport::Server;
+use tonic::transport::server::Connected;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tracing_subscriber::{fmt::Subscriber, reload, EnvFilter};
 
 use ipnet::IpNet;
-#[cfg(unix)]
-use wok::server::{CriImageService, CriRuntimeService, ImageServiceServer, RuntimeServiceServer};
+use wok::config::{Config, LogFormat, ServerOptions, TlsOptions};
+use wok::docker::Reference;
+use wok::doctor;
+use wok::server::conditions::CAPABILITIES_READY;
+use wok::server::policy::Policy;
+use wok::server::runtime::RuntimeHandler;
+use wok::server::{
+    AdminService, CriImageService, CriRuntimeService, Drain, Draining, Events, ImageServiceServer,
+    LogFilterHandle, RateLimited, RateLimiter, ReflectionService, RuntimeServiceServer,
+    ServerReflectionServer, TimeLimited, Timeouts, Traced,
+};
+use wok::store::ModuleStore;
+#[cfg(feature = "wapc")]
+use wok::wasm::wapc::serve_http;
+use wok::wasm::wascc::{self, EnvVars};
+#[cfg(feature = "wapc")]
+use wok::wasm::WapcRuntime;
+#[cfg(feature = "wasi")]
+use wok::wasm::{Runtime, WasiRuntime};
 
 #[derive(Debug, Clone)]
 struct BadAddr;
@@ -39,51 +69,636 @@ impl error::Error for BadAddr {
 
 #[derive(clap::Clap)]
 struct Opts {
-    #[clap(short = "a", long = "addr", default_value = "unix:///tmp/wok.sock")]
-    addr: String,
+    /// Path to a TOML configuration file. Flags take precedence over the values in the file.
+    #[clap(short = "c", long = "config")]
+    config: Option<PathBuf>,
 
-    #[clap(short = "d", long = "dir", default_value = "/tmp")]
-    dir: PathBuf,
+    /// Address to listen on, e.g. unix:///tmp/wok.sock or tcp://127.0.0.1:8080. Can be given more than once.
+    #[clap(short = "a", long = "addr")]
+    addr: Vec<String>,
+
+    #[clap(short = "d", long = "dir")]
+    dir: Option<PathBuf>,
 
     #[clap(long = "pod-cidr")]
     pod_cidr: Option<String>,
+
+    /// Local address to serve a JSON dump of the internal state on, e.g. 127.0.0.1:10350
+    #[clap(long = "admin-addr")]
+    admin_addr: Option<String>,
+
+    /// Seconds to wait for running containers to exit when shutting down
+    #[clap(long = "shutdown-timeout")]
+    shutdown_timeout: Option<u64>,
+
+    /// Seconds an image pull may take, including retries. 0 disables the deadline.
+    #[clap(long = "pull-timeout")]
+    pull_timeout: Option<u64>,
+
+    /// Format of the daemon's logs, text or json
+    #[clap(long = "log-format")]
+    log_format: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Clap)]
+enum Command {
+    /// Pull a module into the store and run it in the foreground, without a kubelet
+    #[clap(name = "run")]
+    Run(RunOpts),
+    /// Check the environment for problems that keep wok from working, and suggest fixes
+    #[clap(name = "doctor")]
+    Doctor(DoctorOpts),
+}
+
+#[derive(clap::Clap)]
+struct DoctorOpts {
+    /// A registry modules are pulled from, as HOST or HOST:PORT. Can be given more than once. Defaults to
+    /// webassembly.azurecr.io.
+    #[clap(long = "registry")]
+    registries: Vec<String>,
+}
+
+#[derive(clap::Clap)]
+struct RunOpts {
+    /// The module to run, e.g. webassembly.azurecr.io/hello-wasm:v1
+    image: String,
+
+    /// The runtime handler to run the module with, WASI, WAPC or WASCC. Defaults to the configured default handler.
+    #[clap(long = "handler")]
+    handler: Option<String>,
+
+    /// Environment variable for the module, as KEY=VALUE. Can be given more than once.
+    #[clap(short = "e", long = "env")]
+    env: Vec<String>,
+
+    /// Host directory to make available to a WASI module, as HOST_PATH[:GUEST_PATH]. Can be given more than once.
+    #[clap(short = "m", long = "mount")]
+    mounts: Vec<String>,
+
+    /// Port to serve a waSCC HTTP actor or a waPC guest on
+    #[clap(short = "p", long = "port")]
+    port: Option<u16>,
+
+    /// Arguments passed to a WASI module, or the operation to invoke on a waPC guest followed by its payload
+    #[clap(last = true)]
+    args: Vec<String>,
+}
+
+impl Opts {
+    /// Load the configuration file, if any, and apply the command line flags on top of it.
+    fn into_config(self) -> Result<Config, failure::Error> {
+        let mut config = match self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        if !self.addr.is_empty() {
+            config.server.addrs = self.addr;
+        }
+        if let Some(dir) = self.dir {
+            config.store.dir = dir;
+        }
+        if let Some(pod_cidr) = self.pod_cidr {
+            config.network.pod_cidr = Some(pod_cidr);
+        }
+        if let Some(addr) = self.admin_addr {
+            config.admin.addr = Some(addr);
+        }
+        if let Some(timeout) = self.shutdown_timeout {
+            config.runtime.shutdown_timeout = timeout;
+        }
+        if let Some(timeout) = self.pull_timeout {
+            config.store.pull.timeout_secs = timeout;
+        }
+        if let Some(format) = self.log_format {
+            config.log.format = format.parse()?;
+        }
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-    let opts: Opts = Opts::parse();
-    let pod_cidr = match opts.pod_cidr {
-        Some(s) => Some(IpNet::from_str(&s)?),
+    let mut opts: Opts = Opts::parse();
+    let command = opts.command.take();
+    let config = opts.into_config().map_err(|e| e.compat())?;
+    // RUST_LOG takes precedence over the configured level
+    let (level, filter) = match std::env::var("RUST_LOG").ok().and_then(|level| {
+        EnvFilter::try_new(&level)
+            .ok()
+            .map(|filter| (level, filter))
+    }) {
+        Some(env) => env,
+        None => (
+            config.log.level.clone(),
+            EnvFilter::try_new(&config.log.level)?,
+        ),
+    };
+    let log_filter = match config.log.format {
+        LogFormat::Text => {
+            let subscriber = Subscriber::builder()
+                .with_env_filter(filter)
+                .with_filter_reloading();
+            let reload = subscriber.reload_handle();
+            subscriber.init();
+            log_filter_handle(level, reload)
+        }
+        LogFormat::Json => {
+            let subscriber = Subscriber::builder()
+                .json()
+                .with_env_filter(filter)
+                .with_filter_reloading();
+            let reload = subscriber.reload_handle();
+            subscriber.init();
+            log_filter_handle(level, reload)
+        }
+    };
+
+    config
+        .store
+        .pull
+        .check_rate_limits()
+        .map_err(|e| e.compat())?;
+
+    match command {
+        Some(Command::Run(run)) => return run_module(&config, run).await,
+        Some(Command::Doctor(doctor)) => return run_doctor(&config, doctor).await,
+        None => {}
+    }
+
+    let pod_cidr = match &config.network.pod_cidr {
+        Some(s) => Some(IpNet::from_str(s)?),
         None => None,
     };
-    log::debug!("Using {:?} for pod CIDR", pod_cidr);
-    let runtime = CriRuntimeService::new(opts.dir.clone(), pod_cidr).await;
-    let image_service = CriImageService::new(opts.dir.clone()).await;
+    tracing::debug!("Using {:?} for pod CIDR", pod_cidr);
+    let runtime =
+        CriRuntimeService::with_options(config.store.dir.clone(), pod_cidr, config.runtime.clone())
+            .await
+            .with_log_filter(log_filter)
+            .with_port_isolation(config.network.isolate_ports);
+    let backends = runtime
+        .backends()
+        .clone()
+        .with_libraries(&config.runtime.backend_libraries)?
+        .with_aliases(&config.runtime.handler_aliases)?;
+    let runtime = runtime.with_backends(backends);
+    // the default handler must not be one of the disabled ones
+    runtime
+        .backends()
+        .get(&config.runtime.default_handler)
+        .map_err(|e| e.compat())?;
+    let runtime = match &config.policy.file {
+        Some(file) => runtime.with_policy(Policy::from_file(file)?),
+        None => runtime,
+    };
+    let runtime = runtime.with_events(Events::from_options(&config.events)?);
+    let conditions = runtime.conditions();
+    match wascc::register_native_capabilities(&config.capabilities.libraries) {
+        Ok(()) => {
+            conditions
+                .set(CAPABILITIES_READY, true, "CapabilitiesLoaded", "")
+                .await
+        }
+        Err(e) => {
+            tracing::warn!("waSCC capabilities are unavailable: {}", e);
+            conditions
+                .set(
+                    CAPABILITIES_READY,
+                    false,
+                    "CapabilitiesUnavailable",
+                    &e.to_string(),
+                )
+                .await
+        }
+    }
+    let image_service = CriImageService::with_options(config.store.clone())
+        .await
+        .with_conditions(conditions)
+        .with_events(runtime.events())
+        .with_warm_pool(runtime.warm_pool());
+    if config.store.verify_on_start {
+        image_service.verify_store().await;
+    }
+    let runtime = runtime.with_module_store(image_service.module_store().await);
+    // no container survives a restart, so the directories of the containers of a crashed wok are left over
+    runtime.remove_orphaned_dirs().await;
+    let prepull = config
+        .store
+        .prepull
+        .iter()
+        .map(|r| {
+            Reference::try_from(r.clone())
+                .map_err(|e| format!("invalid module {} in store.prepull: {}", r, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if !prepull.is_empty() {
+        tokio::spawn(image_service.module_store().await.prepull(prepull));
+    }
+    if config.store.sideload_interval_secs > 0 {
+        let interval = Duration::from_secs(config.store.sideload_interval_secs);
+        tokio::spawn(image_service.module_store().await.watch_sideload(interval));
+    }
+    if config.store.usage_interval_secs > 0 {
+        let interval = Duration::from_secs(config.store.usage_interval_secs);
+        tokio::spawn(image_service.module_store().await.watch_usage(interval));
+    }
+
+    let addrs = config
+        .server
+        .addrs
+        .iter()
+        .map(|addr| parse_addr(addr))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let handle = runtime.clone();
+    let admin = match &config.admin.addr {
+        Some(addr) => Some((
+            addr.parse::<std::net::SocketAddr>()?,
+            AdminService::new(runtime.clone(), image_service.module_store().await),
+        )),
+        None => None,
+    };
+    let limiter = RateLimiter::new(&config.server.rate_limits).map_err(|e| e.compat())?;
+    let timeouts = Timeouts::new(config.server.rpc_timeout_secs, &config.server.rpc_timeouts)
+        .map_err(|e| e.compat())?;
+    let drain = Drain::new();
+    let services = Services {
+        runtime: Traced::new(Draining::new(
+            RateLimited::new(
+                TimeLimited::new(RuntimeServiceServer::new(runtime), timeouts.clone()),
+                limiter.clone(),
+            ),
+            drain.clone(),
+        )),
+        image: Traced::new(Draining::new(
+            RateLimited::new(
+                TimeLimited::new(ImageServiceServer::new(image_service), timeouts),
+                limiter,
+            ),
+            drain.clone(),
+        )),
+        reflection: Traced::new(ServerReflectionServer::new(
+            ReflectionService::new().map_err(|e| e.compat())?,
+        )),
+    };
+    let tls = tls_config(&config.server.tls).await?;
+    if tls.is_some() && addrs.iter().any(|(proto, _)| *proto != "tcp") {
+        tracing::warn!("TLS only applies to TCP addresses, unix sockets are served without it");
+    }
+    let shutdown = shutdown_signal().shared();
+    let servers = futures::future::try_join_all(addrs.iter().map(|(proto, addr)| {
+        tracing::info!("listening on {}://{}", proto, addr);
+        serve(
+            proto,
+            addr,
+            services.clone(),
+            &config.server,
+            tls.as_ref(),
+            shutdown.clone(),
+        )
+    }));
+    let admin = async {
+        if let Some((addr, admin)) = admin {
+            tracing::info!("serving admin endpoint on http://{}", addr);
+            admin.serve(addr, shutdown.clone()).await?;
+        }
+        Ok::<(), Box<dyn std::error::Error>>(())
+    };
+    futures::future::try_join(servers, admin).await?;
+
+    // The listeners are closed at this point, so no new connections come in. The established ones are refused new
+    // RPCs, and the ones in flight are waited for, so none starts a container while the running ones are stopped.
+    // the timeout may have been changed through UpdateRuntimeConfig
+    let timeout = Duration::from_secs(handle.options().await.shutdown_timeout);
+    drain.close();
+    if !drain.wait(timeout).await {
+        tracing::warn!(
+            "{} RPCs were still in flight after {:?}, stopping the containers anyway",
+            drain.in_flight(),
+            timeout
+        );
+    }
+    handle.shutdown(timeout).await;
+    tracing::info!("shutdown complete");
+    Ok(())
+}
+
+/// The TLS configuration of the TCP addresses, or None when TLS is disabled.
+async fn tls_config(tls: &TlsOptions) -> Result<Option<ServerTlsConfig>, Box<dyn error::Error>> {
+    let (cert, key) = match tls.identity().map_err(|e| e.compat())? {
+        Some(identity) => identity,
+        None => return Ok(None),
+    };
+    let mut config = ServerTlsConfig::with_rustls();
+    config.identity(Identity::from_pem(
+        tokio::fs::read(cert).await?,
+        tokio::fs::read(key).await?,
+    ));
+    if let Some(ca) = &tls.client_ca {
+        // rustls rejects clients without a certificate signed by the CA once a client CA is set
+        config.client_ca_root(tonic::transport::Certificate::from_pem(
+            tokio::fs::read(ca).await?,
+        ));
+    }
+    Ok(Some(config))
+}
+
+/// Let the runtime service change the filter of the subscriber the reload handle belongs to.
+fn log_filter_handle<S>(level: String, reload: reload::Handle<EnvFilter, S>) -> LogFilterHandle
+where
+    S: tracing::Subscriber + Send + Sync + 'static,
+{
+    LogFilterHandle::new(level, move |level| {
+        let filter = EnvFilter::try_new(level)
+            .map_err(|e| failure::format_err!("invalid log filter {}: {}", level, e))?;
+        reload
+            .reload(filter)
+            .map_err(|e| failure::format_err!("cannot change the log filter: {}", e))
+    })
+}
+
+/// Pull a module into the store and run it until it exits or, for waSCC actors and waPC guests serving HTTP, until
+/// SIGINT or SIGTERM. WASI modules write straight to the terminal. waPC guests not serving HTTP are invoked once,
+/// and their reply is written to stdout.
+async fn run_module(config: &Config, opts: RunOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let handler = opts
+        .handler
+        .as_deref()
+        .unwrap_or(&config.runtime.default_handler);
+    let handler = RuntimeHandler::from_string(handler).map_err(|e| e.compat())?;
+    let env = opts
+        .env
+        .iter()
+        .map(
+            |pair| match pair.splitn(2, '=').collect::<Vec<_>>().as_slice() {
+                [key, value] => Ok((key.to_string(), value.to_string())),
+                _ => Err(format!(
+                    "invalid environment variable {}, expected KEY=VALUE",
+                    pair
+                )),
+            },
+        )
+        .collect::<Result<EnvVars, _>>()?;
+
+    let reference = Reference::try_from(opts.image.clone())
+        .map_err(|e| format!("invalid image reference {}: {}", opts.image, e))?;
+    let mut store = ModuleStore::with_options(config.store.dir.clone(), config.store.pull.clone())
+        .await
+        .with_compression(config.store.compress);
+    tracing::info!("pulling {}", opts.image);
+    store.pull(&reference).await?;
+    let module = store.read(&reference).await?;
+
+    match handler {
+        #[cfg(feature = "wasi")]
+        RuntimeHandler::WASI => {
+            let dirs = opts
+                .mounts
+                .iter()
+                .map(|mount| {
+                    let mut parts = mount.splitn(2, ':');
+                    let host = parts.next().unwrap_or_default().to_owned();
+                    (host, parts.next().map(ToOwned::to_owned))
+                })
+                .collect();
+            let runtime = WasiRuntime::from_module_data(module, env, opts.args, dirs, None)
+                .map_err(|e| e.compat())?
+                .inherit_stdio();
+            tokio::task::spawn_blocking(move || runtime.run())
+                .await?
+                .map_err(|e| e.compat())?;
+        }
+        RuntimeHandler::WASCC => {
+            wascc::register_native_capabilities(&config.capabilities.libraries)
+                .map_err(|e| e.compat())?;
+            let wasm = module;
+            let key = wascc::actor_key(&wasm, None).map_err(|e| e.compat())?;
+            wascc::wascc_run_http(wasm, env, &key, opts.port, vec![]).map_err(|e| e.compat())?;
+            tracing::info!("actor {} is running, press Ctrl-C to stop it", key);
+            shutdown_signal().await;
+            wascc::wascc_stop(&key).map_err(|e| format!("cannot stop actor {}: {}", key, e))?;
+        }
+        #[cfg(feature = "wapc")]
+        RuntimeHandler::WAPC => {
+            if opts.port.is_none() && opts.args.is_empty() {
+                return Err(
+                    "a waPC guest needs an operation to invoke, or --port to serve HTTP on".into(),
+                );
+            }
+            let (runtime, guest) = WapcRuntime::new(module, env, vec![], None);
+            let running = tokio::task::spawn_blocking(move || runtime.run());
+            match opts.port {
+                Some(port) => {
+                    let server = serve_http(guest.clone(), port, shutdown_signal())
+                        .map_err(|e| e.compat())?;
+                    tracing::info!(
+                        "guest is serving HTTP on port {}, press Ctrl-C to stop it",
+                        port
+                    );
+                    server.await;
+                }
+                None => {
+                    let payload = opts.args[1..].join(" ").into_bytes();
+                    let reply = guest
+                        .call(&opts.args[0], payload)
+                        .await
+                        .map_err(|e| e.compat())?;
+                    std::io::Write::write_all(&mut std::io::stdout(), &reply)?;
+                }
+            }
+            guest.stop();
+            running.await?.map_err(|e| e.compat())?;
+        }
+        #[cfg(not(feature = "wapc"))]
+        _ => unreachable!("RuntimeHandler::from_string rejects the handlers left out of the build"),
+    }
+    Ok(())
+}
 
-    let parts: Vec<&str> = opts.addr.split("://").collect();
+/// Print the findings of `wok doctor`, failing when any check did.
+async fn run_doctor(config: &Config, opts: DoctorOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let registries = if opts.registries.is_empty() {
+        vec![doctor::DEFAULT_REGISTRY.to_owned()]
+    } else {
+        opts.registries
+    };
+    let findings = doctor::examine(config, &registries).await;
+    for finding in &findings {
+        println!(
+            "[{}] {} {}",
+            finding.severity, finding.check, finding.message
+        );
+        if let Some(fix) = &finding.fix {
+            println!("       fix: {}", fix);
+        }
+    }
+    let failed = findings
+        .iter()
+        .filter(|f| f.severity == doctor::Severity::Error)
+        .count();
+    if failed > 0 {
+        return Err(format!("{} of {} checks failed", failed, findings.len()).into());
+    }
+    Ok(())
+}
 
-    if parts.len() != 2 {
-        return Err(BadAddr.into());
+/// Split an address like `unix:///tmp/wok.sock` into its protocol and the address itself.
+fn parse_addr(addr: &str) -> Result<(&str, &str), BadAddr> {
+    let parts: Vec<&str> = addr.split("://").collect();
+    match parts.as_slice() {
+        [proto, addr] => Ok((*proto, *addr)),
+        _ => Err(BadAddr),
     }
+}
 
-    log::info!("listening on {}", parts[1]);
+/// The gRPC services. They are shared by all listeners, so every listener sees the same state.
+#[derive(Clone)]
+struct Services {
+    runtime: Traced<Draining<RateLimited<TimeLimited<RuntimeServiceServer<CriRuntimeService>>>>>,
+    image: Traced<Draining<RateLimited<TimeLimited<ImageServiceServer<CriImageService>>>>>,
+    reflection: Traced<ServerReflectionServer<ReflectionService>>,
+}
 
-    // Temporary work-around for async/.await
-    serve(parts[0], parts[1], runtime, image_service)
+/// Wait for SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Error setting SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(windows)]
+    tokio::signal::ctrl_c()
         .await
-        .map(|_| ())
+        .expect("Error setting exit handler");
+
+    tracing::info!("shutting down");
+}
+
+/// Wrap a stream of incoming connections so that it ends once `shutdown` completes.
+///
+/// The server stops accepting new connections when the stream ends. Connections that are already
+/// established stay open until the process exits, but are refused new RPCs once the services are drained,
+/// see `Drain`.
+fn until_shutdown<S, F>(mut incoming: S, shutdown: F) -> impl Stream<Item = S::Item>
+where
+    S: Stream + Unpin,
+    F: Future<Output = ()>,
+{
+    let mut shutdown = Box::pin(shutdown);
+    futures::stream::poll_fn(move |cx| match shutdown.as_mut().poll(cx) {
+        Poll::Ready(()) => Poll::Ready(None),
+        Poll::Pending => Pin::new(&mut incoming).poll_next(cx),
+    })
+}
+
+/// A server builder with the configured limits on the calls of a connection.
+fn server_builder(options: &ServerOptions) -> Server {
+    let mut server = Server::builder();
+    if options.max_concurrent_streams > 0 {
+        server.max_concurrent_streams(options.max_concurrent_streams);
+    }
+    if options.concurrency_limit_per_connection > 0 {
+        server.concurrency_limit_per_connection(options.concurrency_limit_per_connection);
+    }
+    server
+}
+
+/// Serve at most `max` of the incoming connections at once, 0 for any number. Connections over the limit are
+/// closed right away, rather than left waiting, so the client sees the node is busy.
+fn limit_connections<S, IO, E>(
+    incoming: S,
+    max: usize,
+) -> impl Stream<Item = Result<LimitedConnection<IO>, E>>
+where
+    S: Stream<Item = Result<IO, E>>,
+{
+    let live = Arc::new(AtomicUsize::new(0));
+    incoming.try_filter_map(move |io| {
+        let connection = if max > 0 && live.load(Ordering::SeqCst) >= max {
+            tracing::warn!("closing a connection, {} are served already", max);
+            None
+        } else {
+            live.fetch_add(1, Ordering::SeqCst);
+            Some(LimitedConnection {
+                io,
+                live: live.clone(),
+            })
+        };
+        futures::future::ok(connection)
+    })
+}
+
+/// A connection counted against `server.max_connections` until it is closed.
+#[derive(Debug)]
+struct LimitedConnection<IO> {
+    io: IO,
+    live: Arc<AtomicUsize>,
+}
+
+impl<IO> Drop for LimitedConnection<IO> {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<IO: Connected> Connected for LimitedConnection<IO> {
+    fn remote_addr(&self) -> Option<std::net::SocketAddr> {
+        self.io.remote_addr()
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for LimitedConnection<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for LimitedConnection<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
 }
 
 #[cfg(unix)]
 mod unix {
     use std::{
+        error::Error,
+        ffi::CString,
+        fs, io,
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{FileTypeExt, PermissionsExt},
+        },
+        path::Path,
         pin::Pin,
         task::{Context, Poll},
     };
 
     use tokio::io::{AsyncRead, AsyncWrite};
     use tonic::transport::server::Connected;
+    use wok::config::SocketOptions;
 
     #[derive(Debug)]
     pub struct UnixStream(pub tokio::net::UnixStream);
@@ -120,44 +735,192 @@ mod unix {
             Pin::new(&mut self.0).poll_shutdown(cx)
         }
     }
+
+    /// The mode and ownership to give a unix socket after binding it.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct SocketPermissions {
+        mode: Option<u32>,
+        uid: Option<libc::uid_t>,
+        gid: Option<libc::gid_t>,
+    }
+
+    impl SocketPermissions {
+        /// Resolve the configured mode, user and group.
+        pub fn from_options(options: &SocketOptions) -> Result<Self, Box<dyn Error>> {
+            Ok(SocketPermissions {
+                mode: options.mode().map_err(|e| e.compat())?,
+                uid: options.owner.as_deref().map(lookup_user).transpose()?,
+                gid: options.group.as_deref().map(lookup_group).transpose()?,
+            })
+        }
+
+        pub fn apply(&self, path: &Path) -> io::Result<()> {
+            if self.uid.is_some() || self.gid.is_some() {
+                let c_path = CString::new(path.as_os_str().as_bytes())?;
+                // -1 leaves the owner or the group unchanged
+                let res = unsafe {
+                    libc::chown(
+                        c_path.as_ptr(),
+                        self.uid.unwrap_or(!0),
+                        self.gid.unwrap_or(!0),
+                    )
+                };
+                if res != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(mode) = self.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Remove the socket a wok that crashed left at `path`, which would fail binding it with `AddrInUse`. Nothing
+    /// accepts connections on a stale socket, while a live one belongs to another wok, which is an error. Anything
+    /// else at `path` is left to fail binding.
+    pub fn remove_stale_socket(path: &Path) -> Result<(), Box<dyn Error>> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {}
+            _ => return Ok(()),
+        }
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => Err(format!(
+                "another wok is running on {}, it must be stopped first",
+                path.display()
+            )
+            .into()),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                tracing::info!("removing stale socket {}", path.display());
+                fs::remove_file(path)?;
+                Ok(())
+            }
+            Err(_) => Ok(()),
+        }
+    }
+
+    // getpwnam and getgrnam are not thread safe, but they only run while starting up, before
+    // anything else could call them.
+
+    fn lookup_user(name: &str) -> Result<libc::uid_t, Box<dyn Error>> {
+        if let Ok(uid) = name.parse() {
+            return Ok(uid);
+        }
+        let c_name = CString::new(name)?;
+        let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+        if passwd.is_null() {
+            return Err(format!("unknown user {}", name).into());
+        }
+        Ok(unsafe { (*passwd).pw_uid })
+    }
+
+    fn lookup_group(name: &str) -> Result<libc::gid_t, Box<dyn Error>> {
+        if let Ok(gid) = name.parse() {
+            return Ok(gid);
+        }
+        let c_name = CString::new(name)?;
+        let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+        if group.is_null() {
+            return Err(format!("unknown group {}", name).into());
+        }
+        Ok(unsafe { (*group).gr_gid })
+    }
 }
 
-/// Create a server for handling CRI Runtime requests.
+/// The first file descriptor passed in by systemd socket activation (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take over the listening socket passed in by systemd socket activation, if there is one.
+///
+/// See sd_listen_fds(3) for the protocol.
+#[cfg(unix)]
+fn systemd_listener() -> Option<std::os::unix::net::UnixListener> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let fds: RawFd = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        tracing::warn!("systemd passed {} sockets, only the first one is used", fds);
+    }
+
+    // make sure the sockets aren't inherited by anything we spawn
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    Some(unsafe { std::os::unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+/// Create a server for handling CRI Runtime requests on `addr` until `shutdown` completes.
 #[cfg(unix)]
 async fn serve(
     proto: &str,
     addr: &str,
-    runtime: CriRuntimeService,
-    image_service: CriImageService,
+    services: Services,
+    options: &ServerOptions,
+    tls: Option<&ServerTlsConfig>,
+    shutdown: impl Future<Output = ()>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     match proto {
         "unix" => {
-            // attempt to create base directory if it doesn't already exist
-            tokio::fs::create_dir_all(Path::new(addr).parent().unwrap_or_else(|| Path::new(addr)))
-                .await?;
+            let permissions = unix::SocketPermissions::from_options(&options.socket)?;
+            let (mut uds, activated) = match systemd_listener() {
+                Some(listener) => {
+                    tracing::info!("using socket passed in by systemd");
+                    listener.set_nonblocking(true)?;
+                    (UnixListener::from_std(listener)?, true)
+                }
+                None => {
+                    // attempt to create base directory if it doesn't already exist
+                    tokio::fs::create_dir_all(
+                        Path::new(addr).parent().unwrap_or_else(|| Path::new(addr)),
+                    )
+                    .await?;
+                    unix::remove_stale_socket(Path::new(addr))?;
+                    let uds = UnixListener::bind(addr)?;
+                    permissions.apply(Path::new(addr))?;
+                    (uds, false)
+                }
+            };
 
-            let mut uds = UnixListener::bind(addr)?;
-
-            let path = addr.to_owned();
-            ctrlc::set_handler(move || {
-                // ignore the error if we fail to remove the file; there can be cases where the user exits before the UDS is bound
-                fs::remove_file(&path).unwrap_or(());
-                std::process::exit(0);
-            })
-            .expect("Error setting exit handler");
-
-            Server::builder()
-                .add_service(RuntimeServiceServer::new(runtime))
-                .add_service(ImageServiceServer::new(image_service))
-                .serve_with_incoming(uds.incoming().map_ok(unix::UnixStream))
+            server_builder(options)
+                .add_service(services.runtime)
+                .add_service(services.image)
+                .add_service(services.reflection)
+                .serve_with_incoming(until_shutdown(
+                    limit_connections(
+                        uds.incoming().map_ok(unix::UnixStream),
+                        options.max_connections,
+                    ),
+                    shutdown,
+                ))
                 .await?;
+
+            // the socket file belongs to systemd when we were socket activated, so leave it in place.
+            if !activated {
+                fs::remove_file(addr).unwrap_or(());
+            }
         }
         "tcp" => {
-            let listener = addr.parse::<std::net::SocketAddr>()?;
+            let mut listener = TcpListener::bind(addr.parse::<std::net::SocketAddr>()?).await?;
 
-            Server::builder()
-                .add_service(RuntimeServiceServer::new(runtime))
-                .serve(listener)
+            let mut server = server_builder(options);
+            if let Some(tls) = tls {
+                server.tls_config(tls);
+            }
+            server
+                .add_service(services.runtime)
+                .add_service(services.image)
+                .add_service(services.reflection)
+                .serve_with_incoming(until_shutdown(
+                    limit_connections(listener.incoming(), options.max_connections),
+                    shutdown,
+                ))
                 .await?;
         }
         _ => return Err(BadAddr.into()),
@@ -170,18 +933,29 @@ async fn serve(
 async fn serve(
     proto: &str,
     addr: &str,
-    runtime: CriRuntimeService,
-    _image_service: CriImageService,
+    services: Services,
+    options: &ServerOptions,
+    tls: Option<&ServerTlsConfig>,
+    shutdown: impl Future<Output = ()>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     match proto {
         "unix" => {
             panic!("unix domain sockets are not supported on Windows!");
         }
         "tcp" => {
-            let listener = addr.parse::<std::net::SocketAddr>()?;
-            Server::builder()
-                .add_service(RuntimeServiceServer::new(runtime))
-                .serve(listener)
+            let mut listener = TcpListener::bind(addr.parse::<std::net::SocketAddr>()?).await?;
+            let mut server = server_builder(options);
+            if let Some(tls) = tls {
+                server.tls_config(tls);
+            }
+            server
+                .add_service(services.runtime)
+                .add_service(services.image)
+                .add_service(services.reflection)
+                .serve_with_incoming(until_shutdown(
+                    limit_connections(listener.incoming(), options.max_connections),
+                    shutdown,
+                ))
                 .await?;
         }
         _ => return Err(BadAddr.into()),
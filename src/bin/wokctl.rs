@@ -0,0 +1,414 @@
+use std::error::Error;
+
+use chrono::{TimeZone, Utc};
+use clap::Clap;
+use futures::TryStreamExt;
+use tonic::transport::{Channel, Endpoint};
+
+use wok::server::grpc;
+use wok::server::grpc::image_service_client::ImageServiceClient;
+use wok::server::grpc::runtime_service_client::RuntimeServiceClient;
+
+/// A small CRI client tailored to wok.
+#[derive(Clap)]
+#[clap(name = "wokctl")]
+struct Opts {
+    /// Address of the wok daemon, e.g. unix:///tmp/wok.sock or tcp://127.0.0.1:8080
+    #[clap(short = "a", long = "addr", default_value = "unix:///tmp/wok.sock")]
+    addr: String,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Manage pod sandboxes
+    #[clap(name = "pods")]
+    Pods {
+        #[clap(subcommand)]
+        command: PodsCommand,
+    },
+    /// Manage containers
+    #[clap(name = "containers")]
+    Containers {
+        #[clap(subcommand)]
+        command: ContainersCommand,
+    },
+    /// Manage images
+    #[clap(name = "images")]
+    Images {
+        #[clap(subcommand)]
+        command: ImagesCommand,
+    },
+}
+
+#[derive(Clap)]
+enum PodsCommand {
+    /// List pod sandboxes
+    #[clap(name = "list")]
+    List,
+}
+
+#[derive(Clap)]
+enum ContainersCommand {
+    /// List containers
+    #[clap(name = "list")]
+    List {
+        /// Only list the containers of this pod sandbox
+        #[clap(long = "pod")]
+        pod: Option<String>,
+    },
+    /// Show the status of a container
+    #[clap(name = "inspect")]
+    Inspect { id: String },
+    /// Print the logs of a container, through wok's admin endpoint
+    #[clap(name = "logs")]
+    Logs {
+        id: String,
+        /// Keep printing the lines the container writes until it exits
+        #[clap(short = "f", long = "follow")]
+        follow: bool,
+        /// Address of wok's admin endpoint, see `admin.addr` in its configuration
+        #[clap(long = "admin-addr", default_value = "127.0.0.1:10350")]
+        admin_addr: String,
+    },
+}
+
+#[derive(Clap)]
+enum ImagesCommand {
+    /// List images
+    #[clap(name = "list")]
+    List,
+    /// Pull an image
+    #[clap(name = "pull")]
+    Pull { image: String },
+    /// Remove an image
+    #[clap(name = "rm")]
+    Rm { image: String },
+    /// Remove the images no container uses, through wok's admin endpoint
+    #[clap(name = "prune")]
+    Prune {
+        /// Address of wok's admin endpoint, see `admin.addr` in its configuration
+        #[clap(long = "admin-addr", default_value = "127.0.0.1:10350")]
+        admin_addr: String,
+    },
+    /// Make an image in wok's store available under another reference, e.g. a module built locally under the
+    /// reference a pod spec uses, through wok's admin endpoint
+    #[clap(name = "tag")]
+    Tag {
+        /// The reference the image is stored under
+        source: String,
+        /// The reference to make the image available under
+        target: String,
+        /// The namespace whose store holds the image, if wok keeps a store per namespace
+        #[clap(long = "namespace", default_value = "")]
+        namespace: String,
+        /// Address of wok's admin endpoint, see `admin.addr` in its configuration
+        #[clap(long = "admin-addr", default_value = "127.0.0.1:10350")]
+        admin_addr: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let opts: Opts = Opts::parse();
+    // pruning, tagging and following logs are not part of the CRI
+    match &opts.command {
+        Command::Images {
+            command: ImagesCommand::Prune { admin_addr },
+        } => return prune(admin_addr).await,
+        Command::Images {
+            command:
+                ImagesCommand::Tag {
+                    source,
+                    target,
+                    namespace,
+                    admin_addr,
+                },
+        } => return tag(admin_addr, source, target, namespace).await,
+        Command::Containers {
+            command:
+                ContainersCommand::Logs {
+                    id,
+                    follow,
+                    admin_addr,
+                },
+        } => return logs(admin_addr, id, *follow).await,
+        _ => {}
+    }
+    let channel = connect(&opts.addr).await?;
+
+    match opts.command {
+        Command::Pods { command } => pods(RuntimeServiceClient::new(channel), command).await,
+        Command::Containers { command } => {
+            containers(RuntimeServiceClient::new(channel), command).await
+        }
+        Command::Images { command } => images(ImageServiceClient::new(channel), command).await,
+    }
+}
+
+/// Connect to wok on a `unix://` or `tcp://` address.
+async fn connect(addr: &str) -> Result<Channel, Box<dyn Error>> {
+    let parts: Vec<&str> = addr.split("://").collect();
+    let channel = match parts.as_slice() {
+        #[cfg(unix)]
+        ["unix", path] => {
+            let path = path.to_string();
+            // the URI is ignored by the connector, but the endpoint needs one
+            Endpoint::from_static("http://[::]:50051")
+                .connect_with_connector(tower::service_fn(move |_: http::Uri| {
+                    tokio::net::UnixStream::connect(path.clone())
+                }))
+                .await?
+        }
+        ["tcp", addr] => {
+            Endpoint::from_shared(format!("http://{}", addr))?
+                .connect()
+                .await?
+        }
+        _ => return Err(format!("invalid address {}", addr).into()),
+    };
+    Ok(channel)
+}
+
+async fn pods(
+    mut client: RuntimeServiceClient<Channel>,
+    command: PodsCommand,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        PodsCommand::List => {
+            let pods = client
+                .list_pod_sandbox(grpc::ListPodSandboxRequest::default())
+                .await?
+                .into_inner()
+                .items;
+            println!(
+                "{:<36}  {:<30}  {:<20}  {:<16}  {:<8}  CREATED",
+                "POD ID", "NAME", "NAMESPACE", "STATE", "HANDLER"
+            );
+            for pod in pods {
+                let metadata = pod.metadata.unwrap_or_default();
+                println!(
+                    "{:<36}  {:<30}  {:<20}  {:<16}  {:<8}  {}",
+                    pod.id,
+                    metadata.name,
+                    metadata.namespace,
+                    pod_state(pod.state),
+                    pod.runtime_handler,
+                    timestamp(pod.created_at)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn containers(
+    mut client: RuntimeServiceClient<Channel>,
+    command: ContainersCommand,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        ContainersCommand::List { pod } => {
+            let filter = pod.map(|pod_sandbox_id| grpc::ContainerFilter {
+                pod_sandbox_id,
+                ..Default::default()
+            });
+            let containers = client
+                .list_containers(grpc::ListContainersRequest { filter })
+                .await?
+                .into_inner()
+                .containers;
+            println!(
+                "{:<36}  {:<30}  {:<40}  {:<18}  {:<36}  CREATED",
+                "CONTAINER ID", "NAME", "IMAGE", "STATE", "POD ID"
+            );
+            for container in containers {
+                println!(
+                    "{:<36}  {:<30}  {:<40}  {:<18}  {:<36}  {}",
+                    container.id,
+                    container.metadata.unwrap_or_default().name,
+                    container.image_ref,
+                    container_state(container.state),
+                    container.pod_sandbox_id,
+                    timestamp(container.created_at)
+                );
+            }
+        }
+        ContainersCommand::Inspect { id } => {
+            let status = client
+                .container_status(grpc::ContainerStatusRequest {
+                    container_id: id,
+                    verbose: true,
+                })
+                .await?
+                .into_inner();
+            println!("{:#?}", status);
+        }
+        ContainersCommand::Logs { .. } => {
+            unreachable!("logs are read through the admin endpoint")
+        }
+    }
+    Ok(())
+}
+
+async fn images(
+    mut client: ImageServiceClient<Channel>,
+    command: ImagesCommand,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        ImagesCommand::List => {
+            let images = client
+                .list_images(grpc::ListImagesRequest::default())
+                .await?
+                .into_inner()
+                .images;
+            println!("{:<60}  SIZE", "IMAGE");
+            for image in images {
+                println!("{:<60}  {}", image.id, image.size);
+            }
+        }
+        ImagesCommand::Pull { image } => {
+            let image_ref = client
+                .pull_image(grpc::PullImageRequest {
+                    image: Some(image_spec(image)),
+                    ..Default::default()
+                })
+                .await?
+                .into_inner()
+                .image_ref;
+            println!("pulled {}", image_ref);
+        }
+        ImagesCommand::Rm { image } => {
+            client
+                .remove_image(grpc::RemoveImageRequest {
+                    image: Some(image_spec(image.clone())),
+                })
+                .await?;
+            println!("removed {}", image);
+        }
+        ImagesCommand::Prune { .. } | ImagesCommand::Tag { .. } => {
+            unreachable!("pruning and tagging go through the admin endpoint")
+        }
+    }
+    Ok(())
+}
+
+async fn prune(admin_addr: &str) -> Result<(), Box<dyn Error>> {
+    let request = hyper::Request::post(format!("http://{}/images/prune", admin_addr))
+        .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    let status = response.status();
+    let body = response.into_body().try_concat().await?;
+    if !status.is_success() {
+        return Err(String::from_utf8_lossy(&body).into_owned().into());
+    }
+    let pruned: serde_json::Value = serde_json::from_slice(&body)?;
+    for image in pruned["removed"].as_array().into_iter().flatten() {
+        println!("removed {}", image.as_str().unwrap_or_default());
+    }
+    println!("reclaimed {} bytes", pruned["reclaimed_bytes"]);
+    Ok(())
+}
+
+async fn tag(
+    admin_addr: &str,
+    source: &str,
+    target: &str,
+    namespace: &str,
+) -> Result<(), Box<dyn Error>> {
+    let body = serde_json::json!({
+        "source": source,
+        "target": target,
+        "namespace": namespace,
+    });
+    let request = hyper::Request::post(format!("http://{}/images/tag", admin_addr))
+        .body(hyper::Body::from(serde_json::to_vec(&body)?))?;
+    let response = hyper::Client::new().request(request).await?;
+    let status = response.status();
+    let body = response.into_body().try_concat().await?;
+    if !status.is_success() {
+        return Err(String::from_utf8_lossy(&body).into_owned().into());
+    }
+    let tagged: serde_json::Value = serde_json::from_slice(&body)?;
+    println!(
+        "tagged {} as {}",
+        source,
+        tagged["id"].as_str().unwrap_or(target)
+    );
+    Ok(())
+}
+
+async fn logs(admin_addr: &str, id: &str, follow: bool) -> Result<(), Box<dyn Error>> {
+    let request = hyper::Request::get(format!(
+        "http://{}/containers/{}/logs?follow={}",
+        admin_addr, id, follow
+    ))
+    .body(hyper::Body::empty())?;
+    let response = hyper::Client::new().request(request).await?;
+    let status = response.status();
+    let mut body = response.into_body();
+    if !status.is_success() {
+        let body = body.try_concat().await?;
+        return Err(String::from_utf8_lossy(&body).into_owned().into());
+    }
+    // lines may be split across chunks
+    let mut pending = vec![];
+    while let Some(chunk) = body.try_next().await? {
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            print_log_line(&line[..end])?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the content of a line in the CRI logging format, `<timestamp> <stream> <P|F> <content>`, to the stream it
+/// was written to.
+fn print_log_line(line: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut parts = line.splitn(4, |&b| b == b' ');
+    let (stream, tag, content) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(_), Some(stream), Some(tag), content) => (stream, tag, content.unwrap_or_default()),
+        _ => return Ok(()),
+    };
+    let newline: &[u8] = if tag == b"P" { b"" } else { b"\n" };
+    if stream == b"stderr" {
+        let mut stderr = std::io::stderr();
+        stderr.write_all(content)?;
+        stderr.write_all(newline)
+    } else {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(content)?;
+        stdout.write_all(newline)?;
+        stdout.flush()
+    }
+}
+
+fn image_spec(image: String) -> grpc::ImageSpec {
+    grpc::ImageSpec { image }
+}
+
+fn pod_state(state: i32) -> String {
+    match grpc::PodSandboxState::from_i32(state) {
+        Some(grpc::PodSandboxState::SandboxReady) => "Ready".to_owned(),
+        Some(grpc::PodSandboxState::SandboxNotready) => "NotReady".to_owned(),
+        None => format!("Unknown({})", state),
+    }
+}
+
+fn container_state(state: i32) -> String {
+    match grpc::ContainerState::from_i32(state) {
+        Some(grpc::ContainerState::ContainerCreated) => "Created".to_owned(),
+        Some(grpc::ContainerState::ContainerRunning) => "Running".to_owned(),
+        Some(grpc::ContainerState::ContainerExited) => "Exited".to_owned(),
+        Some(grpc::ContainerState::ContainerUnknown) | None => "Unknown".to_owned(),
+    }
+}
+
+/// Format a timestamp in nanoseconds.
+fn timestamp(nanos: i64) -> String {
+    Utc.timestamp_nanos(nanos).to_rfc3339()
+}
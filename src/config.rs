@@ -0,0 +1,776 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::restrictions::Profile;
+use crate::wasm::wascc::HTTP_LIB;
+
+/// Config describes the contents of a wok configuration file.
+///
+/// Every section is optional and falls back to its defaults. Command line flags take precedence
+/// over the values given here.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerOptions,
+    pub store: StoreOptions,
+    pub network: NetworkOptions,
+    pub runtime: RuntimeOptions,
+    pub log: LogOptions,
+    pub capabilities: CapabilityOptions,
+    pub admin: AdminOptions,
+    pub policy: PolicyOptions,
+    pub events: EventOptions,
+}
+
+impl Config {
+    /// Read the configuration from a TOML file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("cannot read config file {}: {}", path.display(), e))?;
+        toml::from_str(&raw)
+            .map_err(|e| format_err!("invalid config file {}: {}", path.display(), e))
+    }
+}
+
+/// ServerOptions configures how the CRI services are exposed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerOptions {
+    /// the addresses to listen on, e.g. `unix:///tmp/wok.sock` or `tcp://127.0.0.1:8080`. All of them
+    /// serve the same services.
+    pub addrs: Vec<String>,
+    /// permissions applied to unix sockets after binding them
+    pub socket: SocketOptions,
+    /// TLS for the TCP addresses
+    pub tls: TlsOptions,
+    /// limits on how often expensive RPCs may be called
+    pub rate_limits: Vec<RateLimitOptions>,
+    /// the HTTP/2 streams, i.e. calls in flight, a client may open on a connection. 0 leaves the limit to hyper.
+    pub max_concurrent_streams: u32,
+    /// the calls served at once on a connection, the others wait for their turn. 0 disables the limit.
+    pub concurrency_limit_per_connection: usize,
+    /// the connections served at once on each address, further connections are closed right away. 0 disables the
+    /// limit.
+    pub max_connections: usize,
+    /// fail calls still running after this many seconds with DeadlineExceeded, unless their RPC has a timeout of its
+    /// own in `rpc_timeouts`. 0 disables the timeout.
+    pub rpc_timeout_secs: u64,
+    /// the timeouts of RPCs taking longer, or shorter, than the others, e.g. `PullImage`
+    pub rpc_timeouts: Vec<RpcTimeoutOptions>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            addrs: vec!["unix:///tmp/wok.sock".to_owned()],
+            socket: SocketOptions::default(),
+            tls: TlsOptions::default(),
+            rate_limits: vec![],
+            max_concurrent_streams: 0,
+            concurrency_limit_per_connection: 0,
+            max_connections: 0,
+            rpc_timeout_secs: 0,
+            rpc_timeouts: vec![],
+        }
+    }
+}
+
+/// SocketOptions configures the mode and ownership of the unix sockets wok binds.
+///
+/// Sockets passed in by systemd are left alone, as their permissions are configured in the socket
+/// unit.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketOptions {
+    /// the file mode in octal, e.g. `"0660"`
+    pub mode: Option<String>,
+    /// the name or ID of the user owning the socket
+    pub owner: Option<String>,
+    /// the name or ID of the group owning the socket
+    pub group: Option<String>,
+}
+
+impl SocketOptions {
+    /// Parse the configured file mode.
+    pub fn mode(&self) -> Result<Option<u32>, failure::Error> {
+        self.mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| format_err!("invalid socket mode {}", mode))
+            })
+            .transpose()
+    }
+}
+
+/// RpcTimeoutOptions bounds how long calls to an RPC may take. Calls taking longer fail with DeadlineExceeded.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcTimeoutOptions {
+    /// the name of the RPC, e.g. `PullImage`
+    pub rpc: String,
+    /// the seconds a call may take
+    pub secs: u64,
+}
+
+/// RateLimitOptions limits how often an RPC may be called. Calls over the limit fail with ResourceExhausted.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitOptions {
+    /// the name of the RPC, e.g. `PullImage`
+    pub rpc: String,
+    /// the calls allowed per second on average
+    pub per_second: f64,
+    /// the calls allowed at once after a quiet period
+    pub burst: u32,
+    /// give every client a budget of its own, telling them apart by their user agent
+    pub per_client: bool,
+}
+
+impl Default for RateLimitOptions {
+    fn default() -> Self {
+        RateLimitOptions {
+            rpc: String::new(),
+            per_second: 1.0,
+            burst: 1,
+            per_client: false,
+        }
+    }
+}
+
+/// TlsOptions configures TLS on the TCP addresses. Unix sockets are protected by their permissions instead.
+///
+/// TLS is enabled by giving a certificate and its key. With a client CA, clients must present a certificate
+/// signed by it, so only the kubelet and the tooling it was issued to can drive the runtime.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsOptions {
+    /// the PEM encoded certificate chain of the server
+    pub cert: Option<PathBuf>,
+    /// the PEM encoded private key of the certificate
+    pub key: Option<PathBuf>,
+    /// the PEM encoded CA certificate client certificates must be signed by
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    /// The certificate and key files, or None when TLS is disabled.
+    pub fn identity(&self) -> Result<Option<(&Path, &Path)>, failure::Error> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) if self.client_ca.is_none() => Ok(None),
+            (None, None) => failure::bail!("a client CA needs the server's cert and key"),
+            _ => failure::bail!("TLS needs both a cert and a key"),
+        }
+    }
+}
+
+/// StoreOptions configures where wok keeps its data.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreOptions {
+    /// the root directory for modules, containers and volumes
+    pub dir: PathBuf,
+    /// how modules are pulled from registries
+    pub pull: PullOptions,
+    /// keep modules compressed on disk. They are decompressed every time a container starts, so
+    /// turning this off trades disk space for faster starts.
+    pub compress: bool,
+    /// where the metadata of the modules is kept
+    pub metadata: MetadataBackend,
+    /// seconds between two looks for modules dropped into the `sideload` directory below `dir`. 0 disables
+    /// sideloading.
+    pub sideload_interval_secs: u64,
+    /// keep the modules of each namespace in a store of their own, so tenants sharing the node are isolated
+    pub namespaced: bool,
+    /// modules pulled into the store when wok starts, unless they are in it already, so the first pods using them
+    /// start without waiting for the pull
+    pub prepull: Vec<String>,
+    /// seconds between two measurements of what the store takes on disk. When set, the image filesystem usage
+    /// reported to the kubelet is measured rather than summed from the sizes of the modules. 0 disables it.
+    pub usage_interval_secs: u64,
+    /// check the size and digest of every module when wok starts, and drop the corrupt ones, e.g. the ones
+    /// truncated by an unclean shutdown
+    pub verify_on_start: bool,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            dir: PathBuf::from("/tmp"),
+            pull: PullOptions::default(),
+            compress: true,
+            metadata: MetadataBackend::Memory,
+            sideload_interval_secs: 0,
+            namespaced: false,
+            prepull: vec![],
+            usage_interval_secs: 0,
+            verify_on_start: true,
+        }
+    }
+}
+
+/// PullOptions configures how modules are pulled from registries.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PullOptions {
+    /// how often a pull is retried after a transient failure, i.e. a network error or a 5xx from the registry
+    pub retries: u32,
+    /// milliseconds to wait before the first retry. The wait doubles with every retry, give or take some jitter.
+    pub initial_backoff_ms: u64,
+    /// the upper bound of the wait between two retries, in milliseconds
+    pub max_backoff_ms: u64,
+    /// seconds a pull may take, including its retries. 0 disables the deadline.
+    pub timeout_secs: u64,
+    /// how many pulls may download at a time. Further pulls wait for their turn; pulls of a reference that is
+    /// already being pulled wait for that pull instead. 0 disables the limit.
+    pub max_concurrent: usize,
+    /// the docker config file with the registry credentials of each namespace, so tenants sharing the node don't
+    /// share a registry login. Pods in other namespaces use `default_credentials`.
+    pub credentials: BTreeMap<String, PathBuf>,
+    /// the docker config file with the registry credentials of the namespaces without credentials of their own.
+    /// Docker's default config file if unset.
+    pub default_credentials: Option<PathBuf>,
+    /// how often pulls may start from each registry, by registry, e.g. `docker.io`. Pulls over the limit wait their
+    /// turn. Registries answering with 429 Too Many Requests are left alone for as long as they ask either way.
+    pub rate_limits: BTreeMap<String, RegistryRateLimit>,
+}
+
+impl PullOptions {
+    /// Fail unless every rate limit allows some pulls.
+    pub fn check_rate_limits(&self) -> Result<(), failure::Error> {
+        for (registry, limit) in &self.rate_limits {
+            if limit.per_second.is_nan() || limit.per_second <= 0.0 || limit.burst == 0 {
+                failure::bail!(
+                    "invalid rate limit of {}: per_second and burst must be positive",
+                    registry
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The docker config file with the registry credentials for pulls of pods in the namespace, None for docker's
+    /// default.
+    pub fn credentials(&self, namespace: &str) -> Option<&Path> {
+        self.credentials
+            .get(namespace)
+            .or_else(|| self.default_credentials.as_ref())
+            .map(PathBuf::as_path)
+    }
+}
+
+impl Default for PullOptions {
+    fn default() -> Self {
+        PullOptions {
+            retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            timeout_secs: 300,
+            max_concurrent: 4,
+            credentials: BTreeMap::new(),
+            default_credentials: None,
+            rate_limits: BTreeMap::new(),
+        }
+    }
+}
+
+/// RegistryRateLimit limits how often pulls may start from a registry.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryRateLimit {
+    /// the pulls allowed per second on average
+    pub per_second: f64,
+    /// the pulls allowed at once after a quiet period
+    pub burst: u32,
+}
+
+impl Default for RegistryRateLimit {
+    fn default() -> Self {
+        RegistryRateLimit {
+            per_second: 1.0,
+            burst: 10,
+        }
+    }
+}
+
+/// MetadataBackend selects where the module store keeps the metadata of its modules.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataBackend {
+    /// in memory, lost when wok restarts
+    Memory,
+    /// in an SQLite database in the store's directory, surviving restarts. Needs wok to be built with the
+    /// `sqlite` feature.
+    Sqlite,
+}
+
+/// NetworkOptions configures pod networking.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkOptions {
+    /// the CIDR to use for pod IP addresses
+    pub pod_cidr: Option<String>,
+    /// let every container serving HTTP listen on a local port of its own, and forward the host ports of its pod
+    /// there, so pods serving on the same container port don't conflict. See `server::proxy`.
+    pub isolate_ports: bool,
+}
+
+/// RuntimeOptions configures the defaults of the runtime service. They can be changed at runtime through
+/// `UpdateRuntimeConfig`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeOptions {
+    /// the runtime handler used for sandboxes that don't request one
+    pub default_handler: String,
+    /// seconds to wait for running containers to exit when shutting down
+    pub shutdown_timeout: u64,
+    /// keep the logs of removed containers and the log directories of removed sandboxes
+    pub retain_logs: bool,
+    /// the number of instances of each WASI module compiled ahead of time, as soon as it is pulled, so containers
+    /// start without waiting for their module to compile. Each instance holds a thread. 0 disables the pool.
+    pub warm_pool_size: usize,
+    /// the number of modules the warm pool keeps instances of. Warming another module evicts the instances of the
+    /// least recently used one. 0 keeps every module.
+    pub warm_pool_max_modules: usize,
+    /// the number of containers started at the same time, as compiling and instantiating modules is CPU heavy.
+    /// Further starts wait for one of them to finish. 0 starts every container right away. Read when wok starts.
+    pub max_concurrent_starts: usize,
+    /// seconds a start waits for its turn before it fails with ResourceExhausted, for the kubelet to retry it
+    pub start_queue_timeout_secs: u64,
+    /// the number of sandboxes created at the same time, so a burst of new pods, e.g. when a node is drained,
+    /// doesn't swamp the node with directory creation and bookkeeping. 0 creates every sandbox right away. Read
+    /// when wok starts.
+    pub max_concurrent_sandbox_creations: usize,
+    /// the number of sandbox creations that may wait for their turn. Further ones fail right away with
+    /// ResourceExhausted, for the kubelet to retry them later.
+    pub sandbox_queue_size: usize,
+    /// seconds between two health checks of a waSCC actor that names its health check operation in the
+    /// `deislabs.io/health-check` annotation. 0 disables health checks. Read when wok starts.
+    pub health_check_interval_secs: u64,
+    /// the number of health checks in a row an actor has to fail to be reported unhealthy. Read when wok starts.
+    pub health_check_failure_threshold: u32,
+    /// the directory to link container logs into under the names node level log collectors expect, e.g.
+    /// `/var/log/containers`. No links are made when unset.
+    pub legacy_log_dir: Option<PathBuf>,
+    /// the runtime handlers sandboxes and containers may not use, e.g. `["WASCC"]` to keep waSCC actors off the
+    /// node. Read when wok starts.
+    pub disabled_handlers: Vec<String>,
+    /// runtime handlers backed by engines loaded from shared libraries, see `server::backend`. Read when wok
+    /// starts.
+    pub backend_libraries: Vec<BackendLibraryOptions>,
+    /// other names of runtime handlers, e.g. the handlers of the cluster's RuntimeClasses, see `server::backend`.
+    /// Read when wok starts.
+    pub handler_aliases: Vec<HandlerAliasOptions>,
+    /// the host directories containers may mount, see `server::mounts`. A mount below one of them is preopened for
+    /// the module as it is, the others get an empty directory of their own. Read when containers are created.
+    pub host_path_prefixes: Vec<PathBuf>,
+    /// the directory on a file system in memory, e.g. a tmpfs, the scratch directories containers ask for with the
+    /// `deislabs.io/memory-dirs` annotation are created in. Read when containers are created.
+    pub memory_dir: PathBuf,
+    /// the WASI profile of the containers that don't name one in the `deislabs.io/wasi-profile` annotation, see
+    /// `server::restrictions`. Read when containers are created.
+    pub default_wasi_profile: Profile,
+    /// the environment variables whose values are secret, by parts of their keys matched regardless of case, see
+    /// `server::redact`. Read when containers are started and their status is asked for.
+    pub secret_env_patterns: Vec<String>,
+    /// the size in bytes of the stack WASI modules run on, for the containers without a `deislabs.io/wasm-stack-size`
+    /// annotation. 0 keeps the default stack of the threads modules run on, 2 MiB. Read when wok starts.
+    pub wasm_stack_size: usize,
+    /// the largest stack containers may ask for with the `deislabs.io/wasm-stack-size` annotation, in bytes. 0 allows
+    /// any size. Read when containers are created.
+    pub max_wasm_stack_size: usize,
+    /// the largest number of directories preopened for a WASI container, counting its mounts, its scratch
+    /// directories in memory and its working directory, which is preopened twice. Containers with more are rejected
+    /// when they are created. 0 allows any number. Read when containers are created.
+    pub max_preopened_dirs: usize,
+    /// the largest number of files and directories a WASI module may have open at once, besides stdio and its
+    /// preopened directories. Opening more fails with `EMFILE`. 0 allows any number. Read when containers start.
+    pub max_open_handles: usize,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        RuntimeOptions {
+            default_handler: "WASI".to_owned(),
+            shutdown_timeout: 10,
+            retain_logs: false,
+            warm_pool_size: 0,
+            warm_pool_max_modules: 32,
+            max_concurrent_starts: 0,
+            start_queue_timeout_secs: 30,
+            max_concurrent_sandbox_creations: 0,
+            sandbox_queue_size: 64,
+            health_check_interval_secs: 10,
+            health_check_failure_threshold: 3,
+            legacy_log_dir: None,
+            disabled_handlers: vec![],
+            backend_libraries: vec![],
+            handler_aliases: vec![],
+            host_path_prefixes: vec![],
+            memory_dir: PathBuf::from("/dev/shm/wok"),
+            default_wasi_profile: Profile::Unconfined,
+            secret_env_patterns: ["PASSWORD", "TOKEN", "KEY", "SECRET"]
+                .iter()
+                .map(|p| (*p).to_owned())
+                .collect(),
+            wasm_stack_size: 0,
+            max_wasm_stack_size: 0,
+            max_preopened_dirs: 0,
+            max_open_handles: 0,
+        }
+    }
+}
+
+/// BackendLibraryOptions names the shared library backing a runtime handler.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendLibraryOptions {
+    /// the name of the runtime handler, e.g. `WASMER`. A built-in handler of the same name is replaced.
+    pub handler: String,
+    /// the shared library exporting the backend with `wok::declare_backend!`
+    pub path: PathBuf,
+}
+
+/// HandlerAliasOptions names a runtime handler after another one, with annotations of its own.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HandlerAliasOptions {
+    /// the name the handler is asked for by, e.g. `wasmtime`
+    pub name: String,
+    /// the handler it stands for, e.g. `WASI`
+    pub handler: String,
+    /// the annotations of the sandboxes and containers running with the alias, unless they set them themselves,
+    /// e.g. `{ "deislabs.io/wasi-profile" = "restricted" }`
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// LogOptions configures the daemon's own logging.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogOptions {
+    /// the log filter, in the same format as `RUST_LOG`. `RUST_LOG` takes precedence when it is set.
+    pub level: String,
+    /// how log lines are written
+    pub format: LogFormat,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            level: "wok=info".to_owned(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// LogFormat selects how the daemon writes its logs.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable lines
+    Text,
+    /// one JSON object per line, with the timestamp, level, target and fields of the event, for log pipelines
+    /// that index them without parsing the text
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format_err!(
+                "unknown log format {}, expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+/// CapabilityOptions configures the waSCC capability providers loaded into the host.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapabilityOptions {
+    /// paths to the native capability provider libraries
+    pub libraries: Vec<PathBuf>,
+}
+
+impl Default for CapabilityOptions {
+    fn default() -> Self {
+        CapabilityOptions {
+            // there is no host to load providers into without waSCC
+            libraries: if cfg!(feature = "wascc") {
+                vec![PathBuf::from(HTTP_LIB)]
+            } else {
+                vec![]
+            },
+        }
+    }
+}
+
+/// AdminOptions configures the HTTP endpoint dumping wok's internal state for debugging.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminOptions {
+    /// the local address to serve the endpoint on, e.g. `127.0.0.1:10350`. Disabled when unset.
+    pub addr: Option<String>,
+}
+
+/// PolicyOptions configures the policy restricting what the containers of each pod may use.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyOptions {
+    /// the policy file, see `server::policy`. Every pod is unrestricted when unset.
+    pub file: Option<PathBuf>,
+}
+
+/// EventOptions configures where lifecycle events, e.g. a container exiting, are sent.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventOptions {
+    /// whether to log every event
+    pub log: bool,
+    /// the URL to post every event to as JSON, e.g. `http://127.0.0.1:9000/events`. Only plain HTTP is supported.
+    pub webhook: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empty_config() {
+        let config: Config = toml::from_str("").expect("parsed empty config");
+        assert_eq!(Config::default(), config);
+    }
+
+    #[test]
+    fn test_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            addrs = ["unix:///run/wok/wok.sock", "tcp://127.0.0.1:8080"]
+            max_connections = 64
+            rpc_timeout_secs = 30
+
+            [[server.rpc_timeouts]]
+            rpc = "PullImage"
+            secs = 600
+
+            [[server.rate_limits]]
+            rpc = "PullImage"
+            per_second = 0.5
+            burst = 5
+
+            [store]
+            compress = false
+            metadata = "sqlite"
+            namespaced = true
+
+            [store.pull]
+            retries = 5
+            timeout_secs = 0
+            default_credentials = "/etc/wok/docker.json"
+
+            [store.pull.credentials]
+            team-a = "/etc/wok/team-a.json"
+
+            [store.pull.rate_limits."docker.io"]
+            per_second = 0.1
+
+            [network]
+            pod_cidr = "10.244.0.0/16"
+
+            [runtime]
+            default_handler = "WASCC"
+            host_path_prefixes = ["/srv/shared"]
+            default_wasi_profile = "baseline"
+            secret_env_patterns = ["PASSWORD", "CREDENTIALS"]
+            wasm_stack_size = 1048576
+            max_wasm_stack_size = 16777216
+            max_preopened_dirs = 16
+            max_open_handles = 256
+
+            [[runtime.backend_libraries]]
+            handler = "WASMER"
+            path = "/opt/wok/libwok_wasmer.so"
+
+            [[runtime.handler_aliases]]
+            name = "wasmtime"
+            handler = "WASI"
+            annotations = { "deislabs.io/wasi-profile" = "restricted" }
+
+            [log]
+            format = "json"
+
+            [capabilities]
+            libraries = ["/opt/wok/libwascc_httpsrv.so", "/opt/wok/libkeyvalue.so"]
+
+            [policy]
+            file = "/etc/wok/policy.toml"
+
+            [events]
+            webhook = "http://127.0.0.1:9000/events"
+            "#,
+        )
+        .expect("parsed config");
+
+        assert_eq!(
+            vec!["unix:///run/wok/wok.sock", "tcp://127.0.0.1:8080"],
+            config.server.addrs
+        );
+        assert_eq!(
+            vec![RateLimitOptions {
+                rpc: "PullImage".to_owned(),
+                per_second: 0.5,
+                burst: 5,
+                per_client: false,
+            }],
+            config.server.rate_limits
+        );
+        assert_eq!(64, config.server.max_connections);
+        assert_eq!(30, config.server.rpc_timeout_secs);
+        assert_eq!(
+            vec![RpcTimeoutOptions {
+                rpc: "PullImage".to_owned(),
+                secs: 600,
+            }],
+            config.server.rpc_timeouts
+        );
+        assert_eq!(0, config.server.max_concurrent_streams);
+        assert_eq!(Some("10.244.0.0/16".to_owned()), config.network.pod_cidr);
+        assert_eq!("WASCC", config.runtime.default_handler);
+        assert_eq!(
+            vec![BackendLibraryOptions {
+                handler: "WASMER".to_owned(),
+                path: PathBuf::from("/opt/wok/libwok_wasmer.so"),
+            }],
+            config.runtime.backend_libraries
+        );
+        assert_eq!(
+            vec![HandlerAliasOptions {
+                name: "wasmtime".to_owned(),
+                handler: "WASI".to_owned(),
+                annotations: vec![(
+                    "deislabs.io/wasi-profile".to_owned(),
+                    "restricted".to_owned()
+                )]
+                .into_iter()
+                .collect(),
+            }],
+            config.runtime.handler_aliases
+        );
+        assert_eq!(
+            vec![PathBuf::from("/srv/shared")],
+            config.runtime.host_path_prefixes
+        );
+        assert_eq!(Profile::Baseline, config.runtime.default_wasi_profile);
+        assert_eq!(
+            vec!["PASSWORD", "CREDENTIALS"],
+            config.runtime.secret_env_patterns
+        );
+        assert_eq!(1024 * 1024, config.runtime.wasm_stack_size);
+        assert_eq!(16 * 1024 * 1024, config.runtime.max_wasm_stack_size);
+        assert_eq!(16, config.runtime.max_preopened_dirs);
+        assert_eq!(256, config.runtime.max_open_handles);
+        assert_eq!(LogFormat::Json, config.log.format);
+        assert_eq!(LogOptions::default().level, config.log.level);
+        // unset values keep their defaults
+        assert_eq!(10, config.runtime.shutdown_timeout);
+        assert!(!config.runtime.retain_logs);
+        assert_eq!(PathBuf::from("/dev/shm/wok"), config.runtime.memory_dir);
+        assert_eq!(5, config.store.pull.retries);
+        assert_eq!(0, config.store.pull.timeout_secs);
+        assert!(!config.store.compress);
+        assert_eq!(MetadataBackend::Sqlite, config.store.metadata);
+        assert!(config.store.namespaced);
+        assert_eq!(
+            PullOptions::default().initial_backoff_ms,
+            config.store.pull.initial_backoff_ms
+        );
+        assert_eq!(4, config.store.pull.max_concurrent);
+        assert_eq!(
+            Some(Path::new("/etc/wok/team-a.json")),
+            config.store.pull.credentials("team-a")
+        );
+        assert_eq!(
+            Some(Path::new("/etc/wok/docker.json")),
+            config.store.pull.credentials("team-b")
+        );
+        assert_eq!(None, PullOptions::default().credentials("team-a"));
+        assert_eq!(
+            Some(&RegistryRateLimit {
+                per_second: 0.1,
+                burst: 10,
+            }),
+            config.store.pull.rate_limits.get("docker.io")
+        );
+        config.store.pull.check_rate_limits().unwrap();
+        let mut invalid = PullOptions::default();
+        invalid.rate_limits.insert(
+            "example.com".to_owned(),
+            RegistryRateLimit {
+                per_second: 0.0,
+                burst: 1,
+            },
+        );
+        invalid
+            .check_rate_limits()
+            .expect_err("no pulls per second");
+        assert_eq!(2, config.capabilities.libraries.len());
+        assert_eq!(
+            Some(PathBuf::from("/etc/wok/policy.toml")),
+            config.policy.file
+        );
+        assert!(!config.events.log);
+        assert_eq!(
+            Some("http://127.0.0.1:9000/events".to_owned()),
+            config.events.webhook
+        );
+    }
+
+    #[test]
+    fn test_tls_identity() {
+        let mut tls = TlsOptions::default();
+        assert_eq!(None, tls.identity().unwrap());
+        tls.client_ca = Some(PathBuf::from("ca.pem"));
+        tls.identity().expect_err("client CA without a cert");
+        tls.cert = Some(PathBuf::from("cert.pem"));
+        tls.identity().expect_err("cert without a key");
+        tls.key = Some(PathBuf::from("key.pem"));
+        assert_eq!(
+            Some((Path::new("cert.pem"), Path::new("key.pem"))),
+            tls.identity().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_socket_mode() {
+        let mut socket = SocketOptions::default();
+        assert_eq!(None, socket.mode().unwrap());
+        socket.mode = Some("0660".to_owned());
+        assert_eq!(Some(0o660), socket.mode().unwrap());
+        socket.mode = Some("660".to_owned());
+        assert_eq!(Some(0o660), socket.mode().unwrap());
+        socket.mode = Some("0690".to_owned());
+        socket.mode().expect_err("not an octal number");
+        socket.mode = Some("17777".to_owned());
+        socket.mode().expect_err("too many bits");
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        toml::from_str::<Config>("[server]\naddress = [\"unix:///tmp/wok.sock\"]")
+            .expect_err("typos are reported");
+    }
+}
@@ -1,3 +1,3 @@
 mod reference;
 
-pub use reference::Reference;
+pub use reference::{ParseError, Reference, Source};
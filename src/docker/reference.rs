@@ -1,42 +1,373 @@
 use std::convert::{Into, TryFrom};
+use std::error::Error;
+use std::fmt;
 
-// currently, the library only accepts modules tagged in the following structure:
-// <registry>/<repository>:<tag>
-// for example: webassembly.azurecr.io/hello:v1
-#[derive(Clone)]
+/// The registry used when a reference does not name one.
+const DEFAULT_REGISTRY: &str = "docker.io";
+/// The namespace of official images on the default registry.
+const OFFICIAL_REPOSITORY_PREFIX: &str = "library/";
+/// The tag used when a reference has neither a tag nor a digest.
+const DEFAULT_TAG: &str = "latest";
+/// The maximum length of a repository name, including the registry.
+const NAME_TOTAL_LENGTH_MAX: usize = 255;
+/// The maximum length of a tag.
+const TAG_LENGTH_MAX: usize = 128;
+/// The minimum number of hex characters in a digest.
+const DIGEST_HEX_LENGTH_MIN: usize = 32;
+/// The scheme of modules downloaded straight from a web server.
+const URL_SCHEME: &str = "https://";
+/// The extension a module's URL must end with.
+const URL_EXTENSION: &str = ".wasm";
+/// The fragment of a module's URL carrying the expected digest, e.g. `#sha256=6c3c...`.
+const URL_DIGEST_PREFIX: &str = "sha256=";
+/// The number of hex characters in a sha256 digest.
+const SHA256_HEX_LENGTH: usize = 64;
+/// The scheme of packages published to WAPM.
+const WAPM_SCHEME: &str = "wapm://";
+/// The registry WAPM packages are resolved with.
+const WAPM_REGISTRY: &str = "registry.wapm.io";
+
+/// Reference is a parsed image reference, following the grammar of the OCI distribution spec:
+///
+/// ```text
+/// reference := name [ ":" tag ] [ "@" digest ]
+/// name      := [ registry "/" ] path-component [ "/" path-component ]*
+/// registry  := domain-component [ "." domain-component ]* [ ":" port-number ]
+/// ```
+///
+/// References are normalized the way docker does it: a missing registry means `docker.io`, single
+/// component repositories on `docker.io` live under `library/`, and a reference without a tag or a
+/// digest refers to the `latest` tag. For example, `alpine` is short for
+/// `docker.io/library/alpine:latest`.
+///
+/// A module can also be given as an HTTPS URL to a `.wasm` file, optionally followed by the sha256
+/// digest it must have, e.g. `https://example.com/modules/hello.wasm#sha256=6c3c...`. Such a
+/// reference gets a synthetic name: the host is its registry, the path without the extension its
+/// repository, and the digest from the fragment its digest.
+///
+/// Packages published to WAPM are given as `wapm://namespace/package[@version]`, e.g.
+/// `wapm://syrusakbary/qr2text@0.1.0`. Their repository is `namespace/package` on
+/// `registry.wapm.io`, and the version is their tag.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Reference {
+    /// the reference as it was given
     whole: String,
-    slash: usize,
-    colon: usize,
+    registry: String,
+    repository: String,
+    tag: Option<String>,
+    digest: Option<String>,
+    source: Source,
+}
+
+/// Where a module is pulled from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    /// an OCI registry
+    Registry,
+    /// a web server, with the URL of the module without the digest fragment
+    Url(String),
+    /// the WAPM package registry
+    Wapm,
 }
 
 impl Reference {
+    /// The reference as it was given.
     pub fn whole(&self) -> &str {
         &self.whole
     }
 
+    /// The registry, including the port if there is one, e.g. `localhost:5000`.
     pub fn registry(&self) -> &str {
-        &self.whole[..self.slash]
+        &self.registry
     }
 
+    /// The repository, which may be nested, e.g. `org/team/app`.
     pub fn repository(&self) -> &str {
-        &self.whole[self.slash + 1..self.colon]
+        &self.repository
+    }
+
+    /// The tag. This is `latest` when the reference has neither a tag nor a digest.
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// The digest, e.g. `sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b`.
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    /// Where the module is pulled from.
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// The URL to download the module from, without the digest fragment. This is only set for
+    /// references given as an HTTPS URL.
+    pub fn url(&self) -> Option<&str> {
+        match &self.source {
+            Source::Url(url) => Some(url),
+            _ => None,
+        }
+    }
+
+    /// Parse `https://host/path/module.wasm[#sha256=hex]`.
+    fn from_url(string: String) -> Result<Self, ParseError> {
+        let (url, fragment) = match string.find('#') {
+            Some(hash) => (&string[..hash], Some(&string[hash + 1..])),
+            None => (&string[..], None),
+        };
+        let digest = match fragment {
+            Some(fragment) if fragment.starts_with(URL_DIGEST_PREFIX) => {
+                let hex = &fragment[URL_DIGEST_PREFIX.len()..];
+                if hex.len() != SHA256_HEX_LENGTH || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(ParseError::InvalidDigest(fragment.to_owned()));
+                }
+                Some(format!("sha256:{}", hex.to_ascii_lowercase()))
+            }
+            Some(fragment) => return Err(ParseError::InvalidDigest(fragment.to_owned())),
+            None => None,
+        };
+
+        let rest = &url[URL_SCHEME.len()..];
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash + 1..]),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(ParseError::EmptyRegistry);
+        }
+        check_characters(host, |c| c.is_ascii_alphanumeric() || ".-:".contains(c))?;
+        if !is_registry(host) {
+            return Err(ParseError::InvalidRegistry(host.to_owned()));
+        }
+        // the path ends up in the module store's directory structure, so only plain components
+        // are allowed
+        check_characters(path, |c| c.is_ascii_alphanumeric() || "._-/".contains(c))?;
+        if !path.ends_with(URL_EXTENSION) {
+            return Err(ParseError::InvalidUrl(url.to_owned()));
+        }
+        let repository = &path[..path.len() - URL_EXTENSION.len()];
+        if !repository
+            .split('/')
+            .all(|c| !c.is_empty() && c != "." && c != "..")
+        {
+            return Err(ParseError::InvalidUrl(url.to_owned()));
+        }
+
+        Ok(Reference {
+            registry: host.to_owned(),
+            repository: repository.to_owned(),
+            tag: if digest.is_none() {
+                Some(DEFAULT_TAG.to_owned())
+            } else {
+                None
+            },
+            digest,
+            source: Source::Url(url.to_owned()),
+            whole: string,
+        })
+    }
+
+    /// Parse `wapm://namespace/package[@version]`.
+    fn from_wapm(string: String) -> Result<Self, ParseError> {
+        let name = &string[WAPM_SCHEME.len()..];
+        let (name, version) = match name.find('@') {
+            Some(at) => (&name[..at], Some(&name[at + 1..])),
+            None => (name, None),
+        };
+        if name.is_empty() {
+            return Err(ParseError::MissingRepository);
+        }
+        check_characters(name, |c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || "._-/".contains(c)
+        })?;
+        let components: Vec<&str> = name.split('/').collect();
+        // `_` is the namespace of WAPM's global packages
+        let valid_namespace = |c: &str| c == "_" || is_path_component(c);
+        if components.len() != 2
+            || !valid_namespace(components[0])
+            || !is_path_component(components[1])
+        {
+            return Err(ParseError::InvalidRepository(name.to_owned()));
+        }
+        let version = match version {
+            Some("") => return Err(ParseError::MissingTag),
+            Some(version) => {
+                check_characters(version, |c| c.is_ascii_alphanumeric() || "_.-".contains(c))?;
+                if !is_tag(version) {
+                    return Err(ParseError::InvalidTag(version.to_owned()));
+                }
+                version
+            }
+            None => DEFAULT_TAG,
+        };
+
+        Ok(Reference {
+            registry: WAPM_REGISTRY.to_owned(),
+            repository: name.to_owned(),
+            tag: Some(version.to_owned()),
+            digest: None,
+            source: Source::Wapm,
+            whole: string,
+        })
     }
+}
 
-    pub fn tag(&self) -> &str {
-        &self.whole[self.colon + 1..]
+/// Formats the normalized reference, e.g. `docker.io/library/alpine:latest`. References given as a
+/// URL or a WAPM package are formatted as they were given.
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.source != Source::Registry {
+            return f.write_str(&self.whole);
+        }
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
     }
 }
 
+/// An error returned when a string is not a valid reference.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// the reference is empty
+    Empty,
+    /// the reference starts with a slash, so the registry before it is empty
+    EmptyRegistry,
+    /// the reference has a registry or a tag, but no repository
+    MissingRepository,
+    /// the reference ends with a colon that is not followed by a tag
+    MissingTag,
+    /// the reference ends with an `@` that is not followed by a digest
+    MissingDigest,
+    /// the reference contains a character that is not allowed where it appears
+    InvalidCharacter(char),
+    InvalidRegistry(String),
+    InvalidRepository(String),
+    InvalidTag(String),
+    InvalidDigest(String),
+    /// the URL does not point to a `.wasm` file or has empty or relative path components
+    InvalidUrl(String),
+    /// the registry and repository are longer than 255 characters
+    NameTooLong(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Empty => f.write_str("reference is empty"),
+            ParseError::EmptyRegistry => f.write_str("registry is empty"),
+            ParseError::MissingRepository => f.write_str("reference has no repository"),
+            ParseError::MissingTag => f.write_str("reference has a ':' but no tag"),
+            ParseError::MissingDigest => f.write_str("reference has an '@' but no digest"),
+            ParseError::InvalidCharacter(c) => write!(f, "invalid character {:?}", c),
+            ParseError::InvalidRegistry(r) => write!(f, "invalid registry {:?}", r),
+            ParseError::InvalidRepository(r) => write!(f, "invalid repository {:?}", r),
+            ParseError::InvalidTag(t) => write!(f, "invalid tag {:?}", t),
+            ParseError::InvalidDigest(d) => write!(f, "invalid digest {:?}", d),
+            ParseError::InvalidUrl(u) => write!(f, "invalid module URL {:?}", u),
+            ParseError::NameTooLong(len) => write!(
+                f,
+                "name is {} characters long, the maximum is {}",
+                len, NAME_TOTAL_LENGTH_MAX
+            ),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
 impl TryFrom<String> for Reference {
-    type Error = ();
+    type Error = ParseError;
     fn try_from(string: String) -> Result<Self, Self::Error> {
-        let slash = string.find('/').ok_or(())?;
-        let colon = string[slash + 1..].find(':').ok_or(())?;
+        if string.is_empty() {
+            return Err(ParseError::Empty);
+        }
+        if string.starts_with(URL_SCHEME) {
+            return Reference::from_url(string);
+        }
+        if string.starts_with(WAPM_SCHEME) {
+            return Reference::from_wapm(string);
+        }
+
+        let (name, digest) = match string.find('@') {
+            Some(at) => (&string[..at], Some(&string[at + 1..])),
+            None => (&string[..], None),
+        };
+        if let Some(digest) = digest {
+            if digest.is_empty() {
+                return Err(ParseError::MissingDigest);
+            }
+            check_characters(digest, |c| c.is_ascii_alphanumeric() || "+._-:".contains(c))?;
+            if !is_digest(digest) {
+                return Err(ParseError::InvalidDigest(digest.to_owned()));
+            }
+        }
+
+        // a colon after the last slash separates the tag, any other colon is the registry's port
+        let last_slash = name.rfind('/').map_or(0, |i| i + 1);
+        let (name, tag) = match name[last_slash..].find(':') {
+            Some(colon) => (
+                &name[..last_slash + colon],
+                Some(&name[last_slash + colon + 1..]),
+            ),
+            None => (name, None),
+        };
+        if let Some(tag) = tag {
+            if tag.is_empty() {
+                return Err(ParseError::MissingTag);
+            }
+            check_characters(tag, |c| c.is_ascii_alphanumeric() || "_.-".contains(c))?;
+            if !is_tag(tag) {
+                return Err(ParseError::InvalidTag(tag.to_owned()));
+            }
+        }
+
+        if name.is_empty() {
+            return Err(ParseError::MissingRepository);
+        }
+        if name.starts_with('/') {
+            return Err(ParseError::EmptyRegistry);
+        }
+        if name.len() > NAME_TOTAL_LENGTH_MAX {
+            return Err(ParseError::NameTooLong(name.len()));
+        }
+        let (registry, repository) = split_registry(name);
+        check_characters(registry, |c| c.is_ascii_alphanumeric() || ".-:".contains(c))?;
+        if !is_registry(registry) {
+            return Err(ParseError::InvalidRegistry(registry.to_owned()));
+        }
+        if repository.is_empty() {
+            return Err(ParseError::MissingRepository);
+        }
+        check_characters(repository, |c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || "._-/".contains(c)
+        })?;
+        if !repository.split('/').all(is_path_component) {
+            return Err(ParseError::InvalidRepository(repository.to_owned()));
+        }
+
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("{}{}", OFFICIAL_REPOSITORY_PREFIX, repository)
+        } else {
+            repository.to_owned()
+        };
+        let tag = match (tag, digest) {
+            (None, None) => Some(DEFAULT_TAG),
+            (tag, _) => tag,
+        };
+
         Ok(Reference {
+            registry: registry.to_owned(),
+            repository,
+            tag: tag.map(ToOwned::to_owned),
+            digest: digest.map(ToOwned::to_owned),
+            source: Source::Registry,
             whole: string,
-            slash,
-            colon: slash + 1 + colon,
         })
     }
 }
@@ -47,10 +378,135 @@ impl Into<String> for Reference {
     }
 }
 
+/// Fail on the first character of `part` that is not allowed.
+fn check_characters(part: &str, allowed: impl Fn(char) -> bool) -> Result<(), ParseError> {
+    match part.chars().find(|c| !allowed(*c)) {
+        Some(c) => Err(ParseError::InvalidCharacter(c)),
+        None => Ok(()),
+    }
+}
+
+/// Split a name into its registry and repository. The first component is only a registry if it
+/// looks like a host name, otherwise the whole name is a repository on the default registry.
+fn split_registry(name: &str) -> (&str, &str) {
+    match name.find('/') {
+        Some(slash) => {
+            let first = &name[..slash];
+            if first.contains('.') || first.contains(':') || first == "localhost" {
+                (first, &name[slash + 1..])
+            } else {
+                (DEFAULT_REGISTRY, name)
+            }
+        }
+        None => (DEFAULT_REGISTRY, name),
+    }
+}
+
+/// registry := domain-component [ "." domain-component ]* [ ":" port-number ]
+fn is_registry(registry: &str) -> bool {
+    let (host, port) = match registry.rfind(':') {
+        Some(colon) => (&registry[..colon], Some(&registry[colon + 1..])),
+        None => (registry, None),
+    };
+    let valid_port = port.map_or(true, |p| {
+        !p.is_empty() && p.len() <= 5 && p.bytes().all(|b| b.is_ascii_digit())
+    });
+    valid_port && host.split('.').all(is_domain_component)
+}
+
+/// domain-component := /([a-zA-Z0-9]|[a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9])/
+fn is_domain_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    match (bytes.first(), bytes.last()) {
+        (Some(first), Some(last)) => {
+            first.is_ascii_alphanumeric()
+                && last.is_ascii_alphanumeric()
+                && bytes
+                    .iter()
+                    .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+        }
+        _ => false,
+    }
+}
+
+/// path-component := alpha-numeric [ separator alpha-numeric ]*
+/// alpha-numeric  := /[a-z0-9]+/
+/// separator      := /[_.]|__|[-]*/
+fn is_path_component(component: &str) -> bool {
+    let is_alphanumeric = |b: u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+    let bytes = component.as_bytes();
+    if !bytes.first().map_or(false, |b| is_alphanumeric(*b))
+        || !bytes.last().map_or(false, |b| is_alphanumeric(*b))
+    {
+        return false;
+    }
+
+    let mut separator = String::new();
+    for b in bytes {
+        if is_alphanumeric(*b) {
+            let valid = separator.is_empty()
+                || separator == "."
+                || separator == "_"
+                || separator == "__"
+                || separator.bytes().all(|s| s == b'-');
+            if !valid {
+                return false;
+            }
+            separator.clear();
+        } else if *b == b'.' || *b == b'_' || *b == b'-' {
+            separator.push(*b as char);
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// tag := /[\w][\w.-]{0,127}/
+fn is_tag(tag: &str) -> bool {
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let bytes = tag.as_bytes();
+    !bytes.is_empty()
+        && bytes.len() <= TAG_LENGTH_MAX
+        && is_word(bytes[0])
+        && bytes
+            .iter()
+            .all(|b| is_word(*b) || *b == b'.' || *b == b'-')
+}
+
+/// digest    := algorithm ":" hex
+/// algorithm := component [ [+._-] component ]*
+/// component := /[A-Za-z][A-Za-z0-9]*/
+/// hex       := /[0-9a-fA-F]{32,}/
+fn is_digest(digest: &str) -> bool {
+    let colon = match digest.find(':') {
+        Some(colon) => colon,
+        None => return false,
+    };
+    let (algorithm, hex) = (&digest[..colon], &digest[colon + 1..]);
+    let valid_algorithm = algorithm
+        .split(|c| c == '+' || c == '.' || c == '_' || c == '-')
+        .all(|component| {
+            let bytes = component.as_bytes();
+            !bytes.is_empty()
+                && bytes[0].is_ascii_alphabetic()
+                && bytes.iter().all(u8::is_ascii_alphanumeric)
+        });
+    valid_algorithm
+        && hex.len() >= DIGEST_HEX_LENGTH_MIN
+        && hex.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const DIGEST: &str = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+
+    fn parse(s: &str) -> Reference {
+        Reference::try_from(s.to_owned()).unwrap_or_else(|_| panic!("could not parse {}", s))
+    }
+
     #[test]
     fn correctly_parses_string() {
         let reference = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
@@ -58,6 +514,216 @@ mod tests {
 
         assert_eq!(reference.registry(), "webassembly.azurecr.io");
         assert_eq!(reference.repository(), "hello");
-        assert_eq!(reference.tag(), "v1");
+        assert_eq!(reference.tag(), Some("v1"));
+    }
+
+    #[test]
+    fn parses_registry_with_port() {
+        let reference = parse("localhost:5000/foo:v1");
+        assert_eq!("localhost:5000", reference.registry());
+        assert_eq!("foo", reference.repository());
+        assert_eq!(Some("v1"), reference.tag());
+
+        // without a tag, the port must not be mistaken for one
+        let reference = parse("localhost:5000/foo");
+        assert_eq!("localhost:5000", reference.registry());
+        assert_eq!("foo", reference.repository());
+        assert_eq!(Some("latest"), reference.tag());
+    }
+
+    #[test]
+    fn parses_nested_repositories() {
+        let reference = parse("webassembly.azurecr.io/org/team/app:v1");
+        assert_eq!("webassembly.azurecr.io", reference.registry());
+        assert_eq!("org/team/app", reference.repository());
+        assert_eq!(Some("v1"), reference.tag());
+    }
+
+    #[test]
+    fn parses_digests() {
+        let reference = parse(&format!("webassembly.azurecr.io/hello@{}", DIGEST));
+        assert_eq!("hello", reference.repository());
+        assert_eq!(None, reference.tag());
+        assert_eq!(Some(DIGEST), reference.digest());
+
+        let reference = parse(&format!("webassembly.azurecr.io/hello:v1@{}", DIGEST));
+        assert_eq!(Some("v1"), reference.tag());
+        assert_eq!(Some(DIGEST), reference.digest());
+    }
+
+    #[test]
+    fn normalizes_references() {
+        assert_eq!(
+            "docker.io/library/alpine:latest",
+            parse("alpine").to_string()
+        );
+        assert_eq!(
+            "docker.io/library/alpine:3.11",
+            parse("alpine:3.11").to_string()
+        );
+        assert_eq!(
+            "docker.io/deislabs/wok:latest",
+            parse("deislabs/wok").to_string()
+        );
+        assert_eq!("localhost/foo:latest", parse("localhost/foo").to_string());
+        assert_eq!(
+            format!("docker.io/library/alpine@{}", DIGEST),
+            parse(&format!("alpine@{}", DIGEST)).to_string()
+        );
+        // the original string is kept around
+        assert_eq!("alpine", parse("alpine").whole());
+    }
+
+    #[test]
+    fn parses_urls() {
+        let hex = &DIGEST["sha256:".len()..];
+        let reference = parse("https://example.com:8443/modules/hello.wasm");
+        assert_eq!("example.com:8443", reference.registry());
+        assert_eq!("modules/hello", reference.repository());
+        assert_eq!(Some("latest"), reference.tag());
+        assert_eq!(
+            Some("https://example.com:8443/modules/hello.wasm"),
+            reference.url()
+        );
+        assert_eq!(None, parse("example.com/hello").url());
+
+        let url = format!("https://example.com/hello.wasm#sha256={}", hex);
+        let reference = parse(&url);
+        assert_eq!(None, reference.tag());
+        assert_eq!(Some(DIGEST), reference.digest());
+        assert_eq!(Some("https://example.com/hello.wasm"), reference.url());
+        assert_eq!(url, reference.to_string());
+
+        let invalid_url = |u: &str| ParseError::InvalidUrl(u.to_owned());
+        for (s, expected) in vec![
+            ("https:///hello.wasm", ParseError::EmptyRegistry),
+            (
+                "https://example.com/hello",
+                invalid_url("https://example.com/hello"),
+            ),
+            (
+                "https://example.com/.wasm",
+                invalid_url("https://example.com/.wasm"),
+            ),
+            (
+                "https://example.com/../hello.wasm",
+                invalid_url("https://example.com/../hello.wasm"),
+            ),
+            (
+                "https://example.com/hello.wasm?v=1",
+                ParseError::InvalidCharacter('?'),
+            ),
+            (
+                "https://example.com/hello.wasm#sha256=abc",
+                ParseError::InvalidDigest("sha256=abc".to_owned()),
+            ),
+            (
+                "https://example.com/hello.wasm#md5=abc",
+                ParseError::InvalidDigest("md5=abc".to_owned()),
+            ),
+        ] {
+            assert_eq!(
+                Err(expected),
+                Reference::try_from(s.to_string()),
+                "parsing {:?}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn parses_wapm_packages() {
+        let reference = parse("wapm://syrusakbary/qr2text@0.1.0");
+        assert_eq!(&Source::Wapm, reference.source());
+        assert_eq!("registry.wapm.io", reference.registry());
+        assert_eq!("syrusakbary/qr2text", reference.repository());
+        assert_eq!(Some("0.1.0"), reference.tag());
+        assert_eq!(None, reference.url());
+        assert_eq!("wapm://syrusakbary/qr2text@0.1.0", reference.to_string());
+        assert_eq!(Some("latest"), parse("wapm://_/cowsay").tag());
+
+        for (s, expected) in vec![
+            ("wapm://", ParseError::MissingRepository),
+            (
+                "wapm://cowsay",
+                ParseError::InvalidRepository("cowsay".to_owned()),
+            ),
+            (
+                "wapm://a/b/c",
+                ParseError::InvalidRepository("a/b/c".to_owned()),
+            ),
+            ("wapm://a/b@", ParseError::MissingTag),
+            ("wapm://a/B", ParseError::InvalidCharacter('B')),
+            ("wapm://a/b@1.0+1", ParseError::InvalidCharacter('+')),
+        ] {
+            assert_eq!(
+                Err(expected),
+                Reference::try_from(s.to_string()),
+                "parsing {:?}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_separators() {
+        for s in &[
+            "example.com/a.b/c_d/e__f/g-h/i---j:v1",
+            "example.com/foo:V1_2.3-rc",
+            "my-registry.example.com:443/foo",
+        ] {
+            parse(s);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        let too_long = format!("example.com/{}", "a".repeat(NAME_TOTAL_LENGTH_MAX));
+        let long_tag = format!("foo:{}", "a".repeat(TAG_LENGTH_MAX + 1));
+        let invalid_repository = |r: &str| ParseError::InvalidRepository(r.to_owned());
+        let invalid_registry = |r: &str| ParseError::InvalidRegistry(r.to_owned());
+        let invalid_digest = |d: &str| ParseError::InvalidDigest(d.to_owned());
+        for (s, expected) in vec![
+            ("", ParseError::Empty),
+            (":v1", ParseError::MissingRepository),
+            ("localhost:5000/", ParseError::MissingRepository),
+            ("/foo", ParseError::EmptyRegistry),
+            ("foo/", invalid_repository("foo/")),
+            ("foo//bar", invalid_repository("foo//bar")),
+            ("Foo", ParseError::InvalidCharacter('F')),
+            ("example.com/Foo", ParseError::InvalidCharacter('F')),
+            ("example.com/fo o", ParseError::InvalidCharacter(' ')),
+            ("example.com/foo:", ParseError::MissingTag),
+            (
+                "example.com/foo:-v1",
+                ParseError::InvalidTag("-v1".to_owned()),
+            ),
+            ("example.com/foo:v1:v2", ParseError::InvalidCharacter(':')),
+            ("example.com/foo@", ParseError::MissingDigest),
+            ("example.com/foo@sha256:abc", invalid_digest("sha256:abc")),
+            ("example.com/foo@sha256", invalid_digest("sha256")),
+            (
+                "example.com/foo@:6c3c624b58dbbcd3c0dd82b4c53f0419",
+                invalid_digest(":6c3c624b58dbbcd3c0dd82b4c53f0419"),
+            ),
+            ("example.com/.foo", invalid_repository(".foo")),
+            ("example.com/foo..bar", invalid_repository("foo..bar")),
+            ("example.com/foo_-bar", invalid_repository("foo_-bar")),
+            ("-example.com/foo", invalid_registry("-example.com")),
+            ("example.com:/foo", invalid_registry("example.com:")),
+            ("example.com:port/foo", invalid_registry("example.com:port")),
+            (too_long.as_str(), ParseError::NameTooLong(too_long.len())),
+            (
+                long_tag.as_str(),
+                ParseError::InvalidTag(long_tag[4..].to_owned()),
+            ),
+        ] {
+            assert_eq!(
+                Err(expected),
+                Reference::try_from(s.to_string()),
+                "parsing {:?}",
+                s
+            );
+        }
     }
 }
@@ -0,0 +1,406 @@
+//! The checks behind `wok doctor`, which look for the setup problems that keep wok from serving the kubelet or
+//! running modules, and suggest how to fix them.
+
+use std::fmt;
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(feature = "wasi")]
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+#[cfg(feature = "wasi")]
+use crate::wasm::wasi::CompiledModule;
+#[cfg(feature = "wasi")]
+use crate::wasm::EngineConfig;
+
+/// The registry checked when none is given, the one the examples pull from.
+pub const DEFAULT_REGISTRY: &str = "webassembly.azurecr.io";
+
+/// How long connecting to a registry may take before it counts as unreachable.
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The smallest valid module: the magic number and version, nothing else.
+#[cfg(feature = "wasi")]
+const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+/// Severity tells how bad a finding is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Ok,
+    /// wok starts, but something will not work
+    Warning,
+    /// wok won't start, or can't do its job
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warn",
+            Severity::Error => "fail",
+        })
+    }
+}
+
+/// Finding is the outcome of a single check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    /// what was checked, e.g. `socket /tmp/wok.sock`
+    pub check: String,
+    pub severity: Severity,
+    pub message: String,
+    /// what to do about a warning or an error
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: impl Into<String>, message: impl Into<String>) -> Self {
+        Finding {
+            check: check.into(),
+            severity: Severity::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(
+        check: impl Into<String>,
+        message: impl Into<String>,
+        fix: impl Into<String>,
+    ) -> Self {
+        Finding {
+            check: check.into(),
+            severity: Severity::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn error(check: impl Into<String>, message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Finding {
+            check: check.into(),
+            severity: Severity::Error,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Run every check against the given configuration. `registries` are the hosts modules are pulled from, e.g.
+/// `webassembly.azurecr.io` or `localhost:5000`.
+pub async fn examine(config: &Config, registries: &[String]) -> Vec<Finding> {
+    let mut findings = vec![];
+    for addr in &config.server.addrs {
+        findings.push(check_addr(addr));
+    }
+    findings.push(check_writable_dir("store", &config.store.dir));
+    findings.extend(check_capability_libraries(&config.capabilities.libraries));
+    for library in &config.runtime.backend_libraries {
+        findings.push(check_file(
+            &format!("backend library {}", library.handler),
+            &library.path,
+            "fix runtime.backend_libraries, or remove the handler to run without it",
+        ));
+    }
+    findings.extend(check_wasm_features());
+    for registry in registries {
+        findings.push(check_registry(registry).await);
+    }
+    findings
+}
+
+/// Check that wok can bind a server address.
+pub fn check_addr(addr: &str) -> Finding {
+    let check = format!("address {}", addr);
+    match addr.split("://").collect::<Vec<_>>().as_slice() {
+        ["unix", path] => check_socket(check, Path::new(path)),
+        ["tcp", addr] => match TcpListener::bind(addr) {
+            Ok(_) => Finding::ok(check, "can be bound"),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => Finding::error(
+                check,
+                "is in use by another process",
+                "stop the process listening on it, or pick another address with --addr",
+            ),
+            Err(e) => Finding::error(
+                check,
+                format!("cannot be bound: {}", e),
+                "pick an address of this host, e.g. tcp://127.0.0.1:8080",
+            ),
+        },
+        _ => Finding::error(
+            check,
+            "is not a valid address",
+            "use unix:///path/to/wok.sock or tcp://host:port",
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn check_socket(check: String, path: &Path) -> Finding {
+    use std::os::unix::net::UnixStream;
+
+    if path.exists() {
+        return match UnixStream::connect(path) {
+            Ok(_) => Finding::error(
+                check,
+                "is served by another process, probably another wok",
+                "stop the other wok, or pick another address with --addr",
+            ),
+            Err(_) => Finding::error(
+                check,
+                "is a stale socket left behind by a wok that didn't shut down cleanly",
+                format!("remove it with `rm {}`", path.display()),
+            ),
+        };
+    }
+    // wok creates the missing directories, so the closest existing one has to be writable
+    let dir = path
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| Path::new("."));
+    match tempfile::tempfile_in(dir) {
+        Ok(_) => Finding::ok(check, "can be created"),
+        Err(e) => Finding::error(
+            check,
+            format!(
+                "cannot be created, {} is not writable: {}",
+                dir.display(),
+                e
+            ),
+            format!(
+                "run wok as a user that may write to {}, or pick another address with --addr",
+                dir.display()
+            ),
+        ),
+    }
+}
+
+#[cfg(not(unix))]
+fn check_socket(check: String, _path: &Path) -> Finding {
+    Finding::error(
+        check,
+        "unix sockets are not supported on this platform",
+        "use a tcp:// address",
+    )
+}
+
+/// Check that a directory exists or can be created, and that wok can write to it.
+pub fn check_writable_dir(name: &str, dir: &Path) -> Finding {
+    let check = format!("{} {}", name, dir.display());
+    match std::fs::create_dir_all(dir).and_then(|_| tempfile::tempfile_in(dir)) {
+        Ok(_) => Finding::ok(check, "is writable"),
+        Err(e) => Finding::error(
+            check,
+            format!("is not writable: {}", e),
+            format!(
+                "run wok as a user that may write to {}, or pick another directory with --dir",
+                dir.display()
+            ),
+        ),
+    }
+}
+
+/// Check that the waSCC capability providers wok loads are there.
+pub fn check_capability_libraries(libraries: &[PathBuf]) -> Vec<Finding> {
+    if !cfg!(feature = "wascc") {
+        return vec![Finding::ok(
+            "capabilities",
+            "not needed, wok was built without the wascc feature",
+        )];
+    }
+    if libraries.is_empty() {
+        return vec![Finding::warning(
+            "capabilities",
+            "no capability providers are configured, so waSCC actors can't serve HTTP",
+            "list libwascc_httpsrv in capabilities.libraries",
+        )];
+    }
+    libraries
+        .iter()
+        .map(|library| {
+            check_file(
+                "capability library",
+                library,
+                "install the provider there, or fix capabilities.libraries. waSCC actors can't serve HTTP without it",
+            )
+        })
+        .collect()
+}
+
+fn check_file(name: &str, path: &Path, fix: &str) -> Finding {
+    let check = format!("{} {}", name, path.display());
+    if path.is_file() {
+        return Finding::ok(check, "is present");
+    }
+    let message = match std::env::current_dir() {
+        Ok(cwd) if path.is_relative() => format!("is missing (relative to {})", cwd.display()),
+        _ => "is missing".to_owned(),
+    };
+    Finding::error(check, message, fix)
+}
+
+/// Check which of the wasm features pods may ask for with `deislabs.io/wasm-features` this build of wasmtime
+/// supports.
+#[cfg(feature = "wasi")]
+pub fn check_wasm_features() -> Vec<Finding> {
+    let features: Vec<(&str, fn(&mut EngineConfig))> = vec![
+        ("threads", |c| c.threads = true),
+        ("simd", |c| c.simd = true),
+        ("reference-types", |c| c.reference_types = true),
+        ("bulk-memory", |c| c.bulk_memory = true),
+        ("multi-value", |c| c.multi_value = true),
+    ];
+    features
+        .into_iter()
+        .map(|(name, enable)| {
+            let check = format!("wasm feature {}", name);
+            let mut config = EngineConfig::default();
+            enable(&mut config);
+            // wasmtime panics on some combinations of features it doesn't support
+            match panic::catch_unwind(|| CompiledModule::compile(EMPTY_MODULE, &config)) {
+                Ok(Ok(_)) => Finding::ok(check, "is supported"),
+                Ok(Err(e)) => Finding::warning(
+                    check,
+                    format!("is not supported: {}", e),
+                    format!("don't schedule pods asking for {} on this node", name),
+                ),
+                Err(_) => Finding::warning(
+                    check,
+                    "is not supported, the engine panicked",
+                    format!("don't schedule pods asking for {} on this node", name),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// There is no wasmtime to check without the WASI and waPC handlers.
+#[cfg(not(feature = "wasi"))]
+pub fn check_wasm_features() -> Vec<Finding> {
+    vec![Finding::ok(
+        "wasm features",
+        "not checked, wok was built without the wasi feature",
+    )]
+}
+
+/// Check that the registry accepts connections. Registries are pulled from over HTTPS, so its port defaults to
+/// 443.
+pub async fn check_registry(registry: &str) -> Finding {
+    let check = format!("registry {}", registry);
+    let addr = if registry.contains(':') {
+        registry.to_owned()
+    } else {
+        format!("{}:443", registry)
+    };
+    let resolved = match addr.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next(),
+        Err(e) => {
+            return Finding::error(
+                check,
+                format!("cannot be resolved: {}", e),
+                "check the node's DNS settings",
+            )
+        }
+    };
+    let resolved = match resolved {
+        Some(addr) => addr,
+        None => return Finding::error(check, "has no addresses", "check the node's DNS settings"),
+    };
+    match tokio::time::timeout(REGISTRY_TIMEOUT, TcpStream::connect(resolved)).await {
+        Ok(Ok(_)) => Finding::ok(check, format!("is reachable at {}", resolved)),
+        Ok(Err(e)) => Finding::error(
+            check,
+            format!("is unreachable at {}: {}", resolved, e),
+            "check the firewall and proxy settings, pulls need to reach the registry over HTTPS",
+        ),
+        Err(_) => Finding::error(
+            check,
+            format!("did not answer within {}s", REGISTRY_TIMEOUT.as_secs()),
+            "check the firewall and proxy settings, pulls need to reach the registry over HTTPS",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_tcp_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("tcp://{}", listener.local_addr().unwrap());
+        assert_eq!(Severity::Error, check_addr(&addr).severity);
+        drop(listener);
+        assert_eq!(Severity::Ok, check_addr(&addr).severity);
+        assert_eq!(Severity::Error, check_addr("localhost:8080").severity);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/wok.sock");
+        let addr = format!("unix://{}", path.display());
+        assert_eq!(Severity::Ok, check_addr(&addr).severity);
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let listener = UnixListener::bind(&path).unwrap();
+        let finding = check_addr(&addr);
+        assert_eq!(Severity::Error, finding.severity);
+        assert!(finding.message.contains("another process"));
+
+        // the socket file outlives the listener, like it does when wok is killed
+        drop(listener);
+        let finding = check_addr(&addr);
+        assert_eq!(Severity::Error, finding.severity);
+        assert!(finding.message.contains("stale"));
+        assert_eq!(
+            Some(format!("remove it with `rm {}`", path.display())),
+            finding.fix
+        );
+    }
+
+    #[test]
+    fn test_check_writable_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let finding = check_writable_dir("store", &dir.path().join("store"));
+        assert_eq!(Severity::Ok, finding.severity);
+        assert!(dir.path().join("store").is_dir());
+    }
+
+    #[cfg(feature = "wascc")]
+    #[test]
+    fn test_check_capability_libraries() {
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("libwascc_httpsrv.so");
+        std::fs::write(&present, b"").unwrap();
+        let findings =
+            check_capability_libraries(&[present, dir.path().join("libwascc_missing.so")]);
+        assert_eq!(
+            vec![Severity::Ok, Severity::Error],
+            findings.iter().map(|f| f.severity).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Severity::Warning,
+            check_capability_libraries(&[])[0].severity
+        );
+    }
+
+    #[cfg(feature = "wasi")]
+    #[test]
+    fn test_check_wasm_features() {
+        let findings = check_wasm_features();
+        assert_eq!(5, findings.len());
+        assert!(findings
+            .iter()
+            .any(|f| f.check == "wasm feature multi-value" && f.severity == Severity::Ok));
+    }
+}
@@ -1,7 +1,9 @@
 #[macro_use]
 extern crate failure;
 
+pub mod config;
 pub mod docker;
+pub mod doctor;
 pub mod oci;
 pub mod server;
 pub mod store;
@@ -203,5 +203,11 @@ fn bindgen_test_layout_GoSlice() {
     );
 }
 extern "C" {
-    pub fn Pull(p0: GoString, p1: GoString) -> GoInt64;
+    pub fn Pull(p0: GoString, p1: GoString, p2: GoString) -> GoInt64;
+}
+extern "C" {
+    pub fn Fetch(p0: GoString, p1: GoString, p2: GoString) -> GoInt64;
+}
+extern "C" {
+    pub fn PullWapm(p0: GoString, p1: GoString, p2: GoString) -> GoInt64;
 }
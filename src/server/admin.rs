@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::convert::{Infallible, TryFrom};
+use std::future::Future;
+use std::net::SocketAddr;
+
+use futures::{future, stream, StreamExt, TryStreamExt};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::docker::Reference;
+use crate::server::{stats, CriRuntimeService};
+use crate::store::{ModuleStore, ModuleStoreError};
+
+/// The body of a request to tag a module.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TagRequest {
+    /// the reference the module is stored under
+    source: String,
+    /// the reference to make the module available under
+    target: String,
+    /// the namespace whose store holds the module, if the store is partitioned by namespace
+    #[serde(default)]
+    namespace: String,
+}
+
+/// AdminService serves a dump of wok's internal state over HTTP, and the few operations operators need besides
+/// the CRI, e.g. pruning unused modules, tagging modules that were built locally or following the logs of
+/// containers.
+///
+/// It is meant for debugging situations where the kubelet and wok disagree about what is running,
+/// so it should only ever listen on a local address.
+#[derive(Clone, Debug)]
+pub struct AdminService {
+    runtime: CriRuntimeService,
+    modules: ModuleStore,
+}
+
+impl AdminService {
+    pub fn new(runtime: CriRuntimeService, modules: ModuleStore) -> Self {
+        AdminService { runtime, modules }
+    }
+
+    /// Dump the state of the runtime and the module store.
+    pub async fn state(&self) -> serde_json::Value {
+        let modules: Vec<_> = self
+            .modules
+            .list()
+            .await
+            .into_iter()
+            .map(|m| {
+                json!({
+                    "id": m.id,
+                    "repo_tags": m.repo_tags,
+                    "repo_digests": m.repo_digests,
+                    "size": m.size,
+                })
+            })
+            .collect();
+
+        let pulls: BTreeMap<_, _> = self
+            .modules
+            .pulls()
+            .await
+            .into_iter()
+            .map(|(reference, started)| (reference, started.to_rfc3339()))
+            .collect();
+
+        json!({
+            "runtime": self.runtime.dump().await,
+            "modules": modules,
+            "pulls": pulls,
+            "pull_stats": self.modules.pull_stats().await,
+            "usage": self.modules.usage().await,
+        })
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/debug/state") => {
+                let body = serde_json::to_vec_pretty(&self.state().await)
+                    .expect("state serializes to JSON");
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .expect("valid response")
+            }
+            (&Method::GET, "/metrics") => {
+                let mut metrics = stats::to_prometheus(&self.runtime.sandbox_stats().await);
+                metrics.push_str(&stats::warm_pool_to_prometheus(
+                    &self.runtime.warm_pool_stats().await,
+                ));
+                metrics.push_str(&stats::orphans_to_prometheus(&self.runtime.orphans().await));
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(Body::from(metrics))
+                    .expect("valid response")
+            }
+            (&Method::POST, "/images/prune") => match self.runtime.prune_images().await {
+                Ok(pruned) => Response::builder()
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::to_vec_pretty(&pruned).expect("pruned serializes to JSON"),
+                    ))
+                    .expect("valid response"),
+                Err(e) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from(format!("cannot prune images: {}", e)))
+                    .expect("valid response"),
+            },
+            (&Method::POST, "/images/tag") => self.tag(req.into_body()).await,
+            (&Method::GET, path) if container_logs_id(path).is_some() => {
+                let id = container_logs_id(path).unwrap_or_default();
+                let follow = req
+                    .uri()
+                    .query()
+                    .map_or(false, |q| q.split('&').any(|p| p == "follow=true"));
+                self.logs(id, follow).await
+            }
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("valid response"),
+        }
+    }
+
+    /// Tag the module named in the JSON body, see `TagRequest`.
+    async fn tag(&self, body: Body) -> Response<Body> {
+        let error = |status, message: String| {
+            Response::builder()
+                .status(status)
+                .body(Body::from(message))
+                .expect("valid response")
+        };
+        let body = match body.try_concat().await {
+            Ok(body) => body,
+            Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        let request: TagRequest = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return error(StatusCode::BAD_REQUEST, format!("invalid request: {}", e)),
+        };
+        let parse = |reference: String| {
+            Reference::try_from(reference.clone())
+                .map_err(|e| format!("invalid image reference {}: {}", reference, e))
+        };
+        let (source, target) = match (parse(request.source), parse(request.target)) {
+            (Ok(source), Ok(target)) => (source, target),
+            (Err(e), _) | (_, Err(e)) => return error(StatusCode::BAD_REQUEST, e),
+        };
+        match self
+            .runtime
+            .tag_image(&source, &target, &request.namespace)
+            .await
+        {
+            Ok(module) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec_pretty(&json!({
+                        "id": module.id,
+                        "repo_digests": module.repo_digests,
+                        "size": module.size,
+                    }))
+                    .expect("module serializes to JSON"),
+                ))
+                .expect("valid response"),
+            Err(e @ ModuleStoreError::NotFound) => error(
+                StatusCode::NOT_FOUND,
+                format!("cannot tag {}: {}", source.whole(), e),
+            ),
+            Err(e @ ModuleStoreError::DigestMismatch)
+            | Err(e @ ModuleStoreError::InvalidNamespace(_)) => error(
+                StatusCode::BAD_REQUEST,
+                format!("cannot tag {}: {}", source.whole(), e),
+            ),
+            Err(e) => error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("cannot tag {}: {}", source.whole(), e),
+            ),
+        }
+    }
+
+    /// The CRI log of a started container. When following it, the lines the container writes are streamed until its
+    /// module returns.
+    async fn logs(&self, id: &str, follow: bool) -> Response<Body> {
+        let (log, lines) = match self.runtime.follow_logs(id).await {
+            Some(Ok(logs)) => logs,
+            Some(Err(e)) => {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from(format!("cannot read the log of {}: {}", id, e)))
+                    .expect("valid response")
+            }
+            None => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from(format!("container {} was not started", id)))
+                    .expect("valid response")
+            }
+        };
+        let body = if follow {
+            let lines = lines.map(|line| Ok::<_, Infallible>(line.to_cri()));
+            Body::wrap_stream(stream::once(future::ready(Ok(log))).chain(lines))
+        } else {
+            Body::from(log)
+        };
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(body)
+            .expect("valid response")
+    }
+
+    /// Serve the admin endpoint on `addr` until `shutdown` completes.
+    pub async fn serve(
+        self,
+        addr: SocketAddr,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), hyper::Error> {
+        let make_svc = make_service_fn(move |_| {
+            let admin = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let admin = admin.clone();
+                    async move { Ok::<_, Infallible>(admin.handle(req).await) }
+                }))
+            }
+        });
+
+        Server::bind(&addr)
+            .serve(make_svc)
+            .with_graceful_shutdown(shutdown)
+            .await
+    }
+}
+
+/// The ID of the container in a path like `/containers/<id>/logs`, None for any other path.
+fn container_logs_id(path: &str) -> Option<&str> {
+    const PREFIX: &str = "/containers/";
+    const SUFFIX: &str = "/logs";
+    if path.len() <= PREFIX.len() + SUFFIX.len()
+        || !path.starts_with(PREFIX)
+        || !path.ends_with(SUFFIX)
+    {
+        return None;
+    }
+    Some(&path[PREFIX.len()..path.len() - SUFFIX.len()])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handle() {
+        let admin = AdminService::new(CriRuntimeService::default(), ModuleStore::default());
+
+        let res = admin
+            .handle(
+                Request::get("/debug/state")
+                    .body(Body::empty())
+                    .expect("valid request"),
+            )
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        let body = res.into_body().try_concat().await.expect("read body");
+        let state: serde_json::Value = serde_json::from_slice(&body).expect("body is JSON");
+        assert_eq!(json!([]), state["runtime"]["sandboxes"]);
+        assert_eq!(json!([]), state["modules"]);
+        assert_eq!(json!({}), state["pulls"]);
+
+        let res = admin
+            .handle(
+                Request::post("/images/prune")
+                    .body(Body::empty())
+                    .expect("valid request"),
+            )
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        let body = res.into_body().try_concat().await.expect("read body");
+        let pruned: serde_json::Value = serde_json::from_slice(&body).expect("body is JSON");
+        assert_eq!(json!({"removed": [], "reclaimed_bytes": 0}), pruned);
+
+        let res = admin
+            .handle(
+                Request::post("/images/tag")
+                    .body(Body::from(
+                        r#"{"source": "localhost/app:dev", "target": "example.com/app:v1"}"#,
+                    ))
+                    .expect("valid request"),
+            )
+            .await;
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+        let res = admin
+            .handle(
+                Request::post("/images/tag")
+                    .body(Body::from(r#"{"source": "localhost/app:dev"}"#))
+                    .expect("valid request"),
+            )
+            .await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+        let res = admin
+            .handle(
+                Request::get("/metrics")
+                    .body(Body::empty())
+                    .expect("valid request"),
+            )
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        let body = res.into_body().try_concat().await.expect("read body");
+        let metrics = String::from_utf8_lossy(&body);
+        assert!(metrics.contains("# TYPE wok_pod_fs_used_bytes gauge"));
+        assert!(metrics.contains("wok_warm_pool_hits_total 0\n"));
+        assert!(metrics.contains("wok_orphaned_dirs_removed_total 0\n"));
+
+        let res = admin
+            .handle(
+                Request::get("/containers/app/logs?follow=true")
+                    .body(Body::empty())
+                    .expect("valid request"),
+            )
+            .await;
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        for path in &["/containers/logs", "/containers//logs"] {
+            let res = admin
+                .handle(
+                    Request::get(*path)
+                        .body(Body::empty())
+                        .expect("valid request"),
+                )
+                .await;
+            assert_eq!(StatusCode::NOT_FOUND, res.status());
+        }
+
+        let res = admin
+            .handle(
+                Request::get("/nope")
+                    .body(Body::empty())
+                    .expect("valid request"),
+            )
+            .await;
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+}
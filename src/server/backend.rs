@@ -0,0 +1,672 @@
+//! The engines containers run with, looked up by the name of their runtime handler.
+//!
+//! Besides the built-in `WASI`, `WAPC` and `WASCC` backends, engines can be loaded from shared libraries listed in
+//! `runtime.backend_libraries`, so specialized nodes can run them with a stock wok binary. A library exports its
+//! backend with `declare_backend!`:
+//!
+//! ```ignore
+//! #[derive(Debug, Default)]
+//! struct WasmerBackend;
+//!
+//! #[tonic::async_trait]
+//! impl wok::server::RuntimeBackend for WasmerBackend {
+//!     // ...
+//! }
+//!
+//! wok::declare_backend!(WasmerBackend, WasmerBackend::default);
+//! ```
+//!
+//! Trait objects have no stable ABI, so the library must be built with the same compiler and the same version of
+//! wok as the binary loading it.
+//!
+//! A handler can be given other names in `runtime.handler_aliases`, so the handlers of the cluster's RuntimeClasses
+//! need not match wok's, e.g. `wasmtime` for `WASI`. Sandboxes and containers asking for an alias run with the
+//! backend of its handler, with the alias' annotations filling in the ones they don't set.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "wasi")]
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use tonic::Status;
+use tracing::debug;
+
+#[cfg(any(feature = "wasi", feature = "wascc"))]
+use super::expansion;
+use super::grpc;
+#[cfg(feature = "wasi")]
+use super::mounts;
+use super::policy::Policy;
+#[cfg(feature = "wasi")]
+use super::redact;
+#[cfg(feature = "wasi")]
+use super::restrictions::{check_supported, Capability as WasiCapability, WasiRestrictions};
+#[cfg(feature = "wasi")]
+use super::runtime::{
+    container_deadline, container_stack_size, sandbox_engine_config, RuntimeContainer,
+};
+use super::runtime::{ContainerCancellationToken, CriRuntimeService, UserContainer};
+#[cfg(feature = "wascc")]
+use super::runtime::{ACTOR_KEY_ANNOTATION, CAPABILITIES_ANNOTATION};
+use crate::config::{BackendLibraryOptions, HandlerAliasOptions};
+#[cfg(feature = "wapc")]
+use crate::wasm::wapc::serve_http;
+use crate::wasm::wascc::*;
+#[cfg(feature = "wascc")]
+use crate::wasm::wascc_logging::{LOGGING_CAPABILITY, LOG_PATH_KEY};
+use crate::wasm::EngineConfig;
+#[cfg(feature = "wapc")]
+use crate::wasm::WapcRuntime;
+#[cfg(feature = "wasi")]
+use crate::wasm::WasiRuntime;
+
+/// The handler the CRI means by an empty one.
+const DEFAULT_HANDLER: &str = "WASI";
+
+/// The function a backend library exports to create its backend, see `declare_backend!`.
+const BACKEND_CREATE_SYMBOL: &[u8] = b"__wok_backend_create";
+
+/// Export a backend from a shared library, given its type and a function creating it.
+#[macro_export]
+macro_rules! declare_backend {
+    ($backend_type:ty, $constructor:path) => {
+        #[no_mangle]
+        pub extern "C" fn __wok_backend_create() -> *mut dyn $crate::server::RuntimeBackend {
+            let constructor: fn() -> $backend_type = $constructor;
+            let backend: Box<dyn $crate::server::RuntimeBackend> = Box::new(constructor());
+            Box::into_raw(backend)
+        }
+    };
+}
+
+/// RuntimeBackend runs the containers of a runtime handler.
+#[tonic::async_trait]
+pub trait RuntimeBackend: fmt::Debug + Send + Sync {
+    /// Check that the node's policy allows the container to run in a sandbox with the given config.
+    fn check_policy(
+        &self,
+        policy: &Policy,
+        sandbox_config: &grpc::PodSandboxConfig,
+        config: &grpc::ContainerConfig,
+    ) -> Result<(), Status>;
+
+    /// Start running the module of the container. The runtime service has already checked the container against
+    /// the policy and its resources, and records the returned token once the container runs.
+    async fn start(
+        &self,
+        runtime: &CriRuntimeService,
+        container: &UserContainer,
+        module: Vec<u8>,
+        engine_config: EngineConfig,
+    ) -> Result<ContainerCancellationToken, Status>;
+
+    /// Whether the backend starts containers from the warm pool, so their modules are worth compiling as soon as
+    /// the containers are created.
+    fn uses_warm_pool(&self) -> bool {
+        false
+    }
+}
+
+/// Backends holds the backend of each runtime handler.
+///
+/// The default registry has the built-in `WASI`, `WAPC` and `WASCC` handlers. Engines are added with `with_backend` and
+/// disabled with `without`, e.g. to keep waSCC actors off a node.
+#[derive(Clone, Debug)]
+pub struct Backends {
+    backends: BTreeMap<String, Arc<dyn RuntimeBackend>>,
+}
+
+impl Default for Backends {
+    fn default() -> Self {
+        let mut backends: BTreeMap<String, Arc<dyn RuntimeBackend>> = BTreeMap::new();
+        #[cfg(feature = "wasi")]
+        backends.insert("WASI".to_owned(), Arc::new(WasiBackend));
+        #[cfg(feature = "wapc")]
+        backends.insert("WAPC".to_owned(), Arc::new(WapcBackend));
+        #[cfg(feature = "wascc")]
+        backends.insert("WASCC".to_owned(), Arc::new(WasccBackend));
+        Backends { backends }
+    }
+}
+
+impl Backends {
+    /// A registry without any backend.
+    pub fn new() -> Self {
+        Backends {
+            backends: BTreeMap::new(),
+        }
+    }
+
+    /// Run the containers of the handler with the given name with the backend, replacing its current backend.
+    pub fn with_backend<B: RuntimeBackend + 'static>(mut self, name: &str, backend: B) -> Self {
+        self.backends.insert(name.to_owned(), Arc::new(backend));
+        self
+    }
+
+    /// Load the backends of the given libraries, replacing the backends of handlers of the same name.
+    pub fn with_libraries(
+        mut self,
+        libraries: &[BackendLibraryOptions],
+    ) -> Result<Self, failure::Error> {
+        for library in libraries {
+            let backend = load_backend(&library.path)?;
+            tracing::info!(
+                handler = %library.handler,
+                path = %library.path.display(),
+                "loaded runtime backend"
+            );
+            self.backends.insert(library.handler.clone(), backend);
+        }
+        Ok(self)
+    }
+
+    /// Register the aliases, each with the backend of the handler it stands for. Aliases must not hide a handler,
+    /// and must stand for a handler that is available, i.e. not disabled.
+    pub fn with_aliases(mut self, aliases: &[HandlerAliasOptions]) -> Result<Self, failure::Error> {
+        for alias in aliases {
+            if alias.name.is_empty() || self.backends.contains_key(&alias.name) {
+                failure::bail!(
+                    "invalid runtime handler alias {:?}: it must name no other handler",
+                    alias.name
+                );
+            }
+            let backend = self.backends.get(&alias.handler).cloned().ok_or_else(|| {
+                format_err!(
+                    "invalid runtime handler alias {}: handler {} is not available",
+                    alias.name,
+                    alias.handler
+                )
+            })?;
+            self.backends.insert(alias.name.clone(), backend);
+        }
+        Ok(self)
+    }
+
+    /// Disable the handler with the given name, so sandboxes and containers asking for it are rejected.
+    pub fn without(mut self, name: &str) -> Self {
+        self.backends.remove(name);
+        self
+    }
+
+    /// The backend of the handler with the given name. Per the CRI, the empty name stands for the default handler.
+    pub fn get(&self, name: &str) -> Result<Arc<dyn RuntimeBackend>, failure::Error> {
+        let name = if name.is_empty() {
+            DEFAULT_HANDLER
+        } else {
+            name
+        };
+        self.backends.get(name).cloned().ok_or_else(|| {
+            unavailable_handler(name)
+                .unwrap_or_else(|| format_err!("Invalid runtime handler {}", name))
+        })
+    }
+
+    /// The names of the registered handlers.
+    pub fn names(&self) -> Vec<&str> {
+        self.backends.keys().map(String::as_str).collect()
+    }
+}
+
+/// Why the built-in handler with the given name can't be used, if its cargo feature was left out of this build,
+/// e.g. to build wok where waSCC's native capability providers aren't available.
+pub(crate) fn unavailable_handler(name: &str) -> Option<failure::Error> {
+    let feature = match name {
+        "WASI" if !cfg!(feature = "wasi") => "wasi",
+        "WAPC" if !cfg!(feature = "wapc") => "wapc",
+        "WASCC" if !cfg!(feature = "wascc") => "wascc",
+        _ => return None,
+    };
+    Some(format_err!(
+        "the {} runtime handler is not available, wok was built without the {} feature",
+        name,
+        feature
+    ))
+}
+
+/// Create the backend exported by the shared library at the given path.
+fn load_backend(path: &Path) -> Result<Arc<dyn RuntimeBackend>, failure::Error> {
+    let library = libloading::Library::new(path).map_err(|e| {
+        format_err!(
+            "cannot load the runtime backend library {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let backend = unsafe {
+        let create: libloading::Symbol<unsafe extern "C" fn() -> *mut dyn RuntimeBackend> =
+            library.get(BACKEND_CREATE_SYMBOL).map_err(|e| {
+                format_err!(
+                    "{} is not a runtime backend library, see wok::declare_backend!: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+        Box::from_raw(create())
+    };
+    // the backend's code lives in the library, so it must never be unloaded
+    std::mem::forget(library);
+    Ok(Arc::from(backend))
+}
+
+/// WasiBackend runs WASI modules, each on a thread of its own.
+#[cfg(feature = "wasi")]
+#[derive(Debug)]
+pub struct WasiBackend;
+
+#[cfg(feature = "wasi")]
+#[tonic::async_trait]
+impl RuntimeBackend for WasiBackend {
+    fn check_policy(
+        &self,
+        policy: &Policy,
+        sandbox_config: &grpc::PodSandboxConfig,
+        config: &grpc::ContainerConfig,
+    ) -> Result<(), Status> {
+        let engine_config = sandbox_engine_config(&sandbox_config.annotations)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let restrictions = WasiRestrictions::from_config(config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        policy
+            .check_wasi(sandbox_config, &engine_config, &restrictions)
+            .map_err(|e| Status::permission_denied(e.to_string()))
+    }
+
+    async fn start(
+        &self,
+        runtime: &CriRuntimeService,
+        container: &UserContainer,
+        module: Vec<u8>,
+        engine_config: EngineConfig,
+    ) -> Result<ContainerCancellationToken, Status> {
+        let env: EnvVars = expansion::expand_envs(&container.config.envs);
+        let secrets = redact::secret_values(
+            &container.config.envs,
+            &runtime.options().await.secret_env_patterns,
+        );
+        let restrictions = WasiRestrictions::from_config(&container.config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let deadline = container_deadline(&container.config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let stack_size = container_stack_size(&container.config, &runtime.options().await)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        restrictions
+            .check(&module)
+            .and_then(|_| check_supported(&module))
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        // Like an image's entrypoint, the command selects what runs: its first element names the exported function
+        // to call, and the arguments are the command followed by the args. Without a command, the module's start
+        // function runs with the args alone.
+        let entrypoint = container
+            .config
+            .command
+            .first()
+            .map(|name| expansion::expand(name, &env));
+        let args = if restrictions.denies(WasiCapability::Args) {
+            vec![]
+        } else {
+            let mut args = expansion::expand_args(&container.config.command, &env);
+            args.extend(expansion::expand_args(&container.config.args, &env));
+            args
+        };
+        let env = if restrictions.denies(WasiCapability::Env) {
+            EnvVars::new()
+        } else {
+            env
+        };
+        // hostPath mounts and scratch directories in memory, the volumes with absolute host paths, are preopened
+        // as they are, see `server::mounts`. The working directory is preopened further down. None of them is when
+        // the file system is denied.
+        let mut dirs = HashMap::new();
+        if !restrictions.denies(WasiCapability::Fs) {
+            for volume in &container.volumes {
+                let host_dir = Path::new(&volume.host_path);
+                if host_dir.is_absolute() {
+                    mounts::check_host_dir(host_dir)
+                        .map_err(|e| Status::failed_precondition(e.to_string()))?;
+                    dirs.insert(
+                        volume.host_path.clone(),
+                        Some(volume.container_path.clone()),
+                    );
+                }
+            }
+        }
+        let output = runtime.output_broker(container).await?;
+        let warm_pool = runtime.warm_pool();
+        // the module is needed again to replace the warm instance used now
+        let warm_module = if warm_pool.is_enabled() {
+            Some(Arc::new(module.clone()))
+        } else {
+            None
+        };
+        let wasi = tokio::task::spawn_blocking(move || {
+            WasiRuntime::from_module_data(module, env, args, dirs, Some(output))
+        })
+        .await
+        .expect("Failed to create new thread for creating runtime")
+        .expect("Creating runtime failed")
+        .with_engine_config(engine_config);
+        let wasi = match entrypoint {
+            Some(entrypoint) => wasi.with_entrypoint(entrypoint),
+            None => wasi,
+        };
+        let wasi = match &container.working_dir {
+            Some(working_dir) if !restrictions.denies(WasiCapability::Fs) => {
+                wasi.with_working_dir(working_dir.clone(), container.config.working_dir.clone())
+            }
+            _ => wasi,
+        };
+        let wasi = match stack_size {
+            Some(stack_size) => wasi.with_stack_size(stack_size),
+            None => wasi,
+        };
+        let wasi = match runtime.options().await.max_open_handles {
+            0 => wasi,
+            max_open_handles => wasi.with_max_open_handles(max_open_handles),
+        };
+
+        // warm instances run on threads with the stack of the pool, so they can't run modules asking for another
+        let warm_instance = if warm_pool.stack_size() == stack_size {
+            warm_pool.take(&container.image_ref, engine_config).await
+        } else {
+            None
+        };
+        let token = match warm_instance {
+            Some(instance) => {
+                debug!("starting a warm instance of {}", container.image_ref);
+                RuntimeContainer::warm(wasi, instance)
+                    .with_secrets(secrets)
+                    .start(deadline)
+            }
+            None => RuntimeContainer::new(wasi)
+                .with_secrets(secrets)
+                .start(deadline),
+        };
+        if let Some(module) = warm_module {
+            warm_pool
+                .warm(&container.image_ref, engine_config, module)
+                .await;
+        }
+        Ok(token)
+    }
+
+    fn uses_warm_pool(&self) -> bool {
+        true
+    }
+}
+
+/// WapcBackend runs waPC guests, each on a thread of its own, without the waSCC host around them. Their operations
+/// are invoked by exec and health checks, and over HTTP on one of the sandbox's port mappings, see
+/// `wasm::wapc::serve_http`. The args of the container are passed through WASI; its command is ignored, as guests
+/// have no entrypoint.
+#[cfg(feature = "wapc")]
+#[derive(Debug)]
+pub struct WapcBackend;
+
+#[cfg(feature = "wapc")]
+#[tonic::async_trait]
+impl RuntimeBackend for WapcBackend {
+    /// Guests are WASI modules as far as the policy is concerned.
+    fn check_policy(
+        &self,
+        policy: &Policy,
+        sandbox_config: &grpc::PodSandboxConfig,
+        config: &grpc::ContainerConfig,
+    ) -> Result<(), Status> {
+        WasiBackend.check_policy(policy, sandbox_config, config)
+    }
+
+    async fn start(
+        &self,
+        runtime: &CriRuntimeService,
+        container: &UserContainer,
+        module: Vec<u8>,
+        engine_config: EngineConfig,
+    ) -> Result<ContainerCancellationToken, Status> {
+        let env: EnvVars = expansion::expand_envs(&container.config.envs);
+        let secrets = redact::secret_values(
+            &container.config.envs,
+            &runtime.options().await.secret_env_patterns,
+        );
+        if !container.config.command.is_empty() {
+            tracing::warn!(
+                "ignoring the command of container {}, waPC guests have no entrypoint",
+                container.id
+            );
+        }
+        let restrictions = WasiRestrictions::from_config(&container.config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let deadline = container_deadline(&container.config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        restrictions
+            .check(&module)
+            .and_then(|_| check_supported(&module))
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let args = if restrictions.denies(WasiCapability::Args) {
+            vec![]
+        } else {
+            expansion::expand_args(&container.config.args, &env)
+        };
+        let env = if restrictions.denies(WasiCapability::Env) {
+            EnvVars::new()
+        } else {
+            env
+        };
+        let output = runtime.output_broker(container).await?;
+        let (wapc, guest) = WapcRuntime::new(module, env, args, Some(output));
+        let token = RuntimeContainer::new(wapc.with_engine_config(engine_config))
+            .with_secrets(secrets)
+            .start_guest(guest.clone(), deadline);
+
+        let port = match runtime.reserve_http_port(container).await? {
+            Some(port) => match runtime.expose_http_port(container, port).await {
+                Ok(port) => Some(port),
+                Err(status) => {
+                    guest.stop();
+                    runtime.release_http_port(container).await;
+                    return Err(status);
+                }
+            },
+            None => None,
+        };
+        if let Some(port) = port {
+            let exited = token.clone();
+            match serve_http(guest.clone(), port, async move { exited.exited().await }) {
+                Ok(server) => {
+                    tokio::spawn(server);
+                }
+                Err(e) => {
+                    guest.stop();
+                    runtime.release_http_port(container).await;
+                    return Err(Status::unavailable(e.to_string()));
+                }
+            }
+        }
+        Ok(token)
+    }
+}
+
+/// WasccBackend runs waSCC actors, serving HTTP on one of the sandbox's port mappings.
+#[cfg(feature = "wascc")]
+#[derive(Debug)]
+pub struct WasccBackend;
+
+#[cfg(feature = "wascc")]
+#[tonic::async_trait]
+impl RuntimeBackend for WasccBackend {
+    fn check_policy(
+        &self,
+        policy: &Policy,
+        sandbox_config: &grpc::PodSandboxConfig,
+        config: &grpc::ContainerConfig,
+    ) -> Result<(), Status> {
+        let capabilities = match config.annotations.get(CAPABILITIES_ANNOTATION) {
+            Some(raw) => {
+                parse_capabilities(raw).map_err(|e| Status::invalid_argument(e.to_string()))?
+            }
+            None => vec![],
+        };
+        let names: Vec<String> = capabilities.into_iter().map(|c| c.name).collect();
+        policy
+            .check_capabilities(sandbox_config, &names)
+            .map_err(|e| Status::permission_denied(e.to_string()))
+    }
+
+    async fn start(
+        &self,
+        runtime: &CriRuntimeService,
+        container: &UserContainer,
+        module: Vec<u8>,
+        _engine_config: EngineConfig,
+    ) -> Result<ContainerCancellationToken, Status> {
+        let env: EnvVars = expansion::expand_envs(&container.config.envs);
+        if !container.config.command.is_empty() {
+            tracing::warn!(
+                "ignoring the command of container {}, actors have no entrypoint",
+                container.id
+            );
+        }
+        // Get the key out of the signed module, checking it against the pinned key if given
+        let pinned = container
+            .config
+            .annotations
+            .get(ACTOR_KEY_ANNOTATION)
+            .map(String::as_str);
+        let key =
+            actor_key(&module, pinned).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let mut capabilities = match container.config.annotations.get(CAPABILITIES_ANNOTATION) {
+            Some(raw) => {
+                parse_capabilities(raw).map_err(|e| Status::invalid_argument(e.to_string()))?
+            }
+            None => vec![],
+        };
+        // Route the actor's logs into the CRI log file
+        if let Some(log_path) = &container.log_path {
+            let mut env = EnvVars::new();
+            env.insert(
+                LOG_PATH_KEY.to_owned(),
+                log_path.to_string_lossy().into_owned(),
+            );
+            capabilities.push(Capability {
+                name: LOGGING_CAPABILITY.to_owned(),
+                env,
+            });
+        }
+
+        let port = match runtime.reserve_http_port(container).await? {
+            Some(port) => match runtime.expose_http_port(container, port).await {
+                Ok(port) => Some(port),
+                Err(status) => {
+                    runtime.release_http_port(container).await;
+                    return Err(status);
+                }
+            },
+            None => None,
+        };
+        if let Err(e) = wascc_run_http(module, env, &key, port, capabilities) {
+            runtime.release_http_port(container).await;
+            return Err(Status::internal(e.to_string()));
+        }
+
+        // Fake token. Needs to be replaced with a real cancellation token, which should come from wascc.
+        Ok(ContainerCancellationToken::WasccCancelationToken(key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "wapc")]
+    #[test]
+    fn test_backends() {
+        let backends = Backends::default();
+        assert!(backends.get("WASI").unwrap().uses_warm_pool());
+        // the empty handler is the default one
+        assert!(backends.get("").unwrap().uses_warm_pool());
+        backends.get("runc").expect_err("unknown handler");
+        // waPC guests are long-lived, there is nothing to gain from compiling them ahead of time
+        assert!(!backends.get("WAPC").unwrap().uses_warm_pool());
+
+        let backends = backends.without("WASI");
+        backends.get("WASI").expect_err("disabled handler");
+
+        let backends = Backends::new().with_backend("WASM", WasiBackend);
+        assert_eq!(vec!["WASM"], backends.names());
+        backends.get("").expect_err("no default handler");
+    }
+
+    #[cfg(feature = "wascc")]
+    #[test]
+    fn test_wascc_backend() {
+        let backends = Backends::default();
+        assert!(backends.names().contains(&"WASCC"));
+        assert!(!backends.get("WASCC").unwrap().uses_warm_pool());
+        assert!(unavailable_handler("WASCC").is_none());
+    }
+
+    #[cfg(not(feature = "wascc"))]
+    #[test]
+    fn test_wascc_unavailable() {
+        let backends = Backends::default();
+        assert!(!backends.names().contains(&"WASCC"));
+        let err = backends.get("WASCC").expect_err("WASCC is left out");
+        assert!(err.to_string().contains("wascc feature"));
+    }
+
+    #[cfg(not(feature = "wasi"))]
+    #[test]
+    fn test_wasi_unavailable() {
+        let backends = Backends::default();
+        let err = backends.get("WASI").expect_err("WASI is left out");
+        assert!(err.to_string().contains("wasi feature"));
+        // the empty handler is the default one
+        backends.get("").expect_err("WASI is left out");
+        let err = backends.get("WAPC").expect_err("WAPC is left out");
+        assert!(err.to_string().contains("wapc feature"));
+    }
+
+    #[cfg(feature = "wasi")]
+    #[test]
+    fn test_with_aliases() {
+        let alias = |name: &str, handler: &str| HandlerAliasOptions {
+            name: name.to_owned(),
+            handler: handler.to_owned(),
+            annotations: BTreeMap::new(),
+        };
+        let backends = Backends::default()
+            .with_aliases(&[alias("wasmtime", "WASI")])
+            .unwrap();
+        assert!(backends.get("wasmtime").unwrap().uses_warm_pool());
+
+        Backends::default()
+            .with_aliases(&[alias("WASI", "WASI")])
+            .expect_err("hides a handler");
+        Backends::default()
+            .with_aliases(&[alias("wasmtime", "WASI"), alias("wasmtime", "WASI")])
+            .expect_err("defined twice");
+        Backends::default()
+            .without("WASI")
+            .with_aliases(&[alias("wasmtime", "WASI")])
+            .expect_err("disabled handler");
+    }
+
+    #[test]
+    fn test_with_libraries() {
+        let backends = Backends::default()
+            .with_libraries(&[])
+            .expect("no libraries");
+        assert_eq!(Backends::default().names(), backends.names());
+
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let missing = BackendLibraryOptions {
+            handler: "WASMER".to_owned(),
+            path: dir.path().join("libwok_wasmer.so"),
+        };
+        let err = Backends::default()
+            .with_libraries(&[missing])
+            .expect_err("missing library");
+        assert!(err.to_string().contains("libwok_wasmer.so"));
+    }
+}
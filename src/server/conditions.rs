@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+
+use super::grpc;
+
+/// The runtime is up and can run modules.
+pub const RUNTIME_READY: &str = "RuntimeReady";
+/// Pod networking is set up.
+pub const NETWORK_READY: &str = "NetworkReady";
+/// The module store can take new modules.
+pub const IMAGE_STORE_READY: &str = "ImageStoreReady";
+/// The modules in the store matched their recorded sizes and digests when wok started, see `ModuleStore::verify`.
+pub const IMAGE_STORE_INTACT: &str = "ImageStoreIntact";
+/// The native waSCC capability providers are loaded.
+pub const CAPABILITIES_READY: &str = "CapabilitiesReady";
+/// The waSCC actors with a health check pass it, see `health::HealthChecks`.
+pub const ACTORS_HEALTHY: &str = "ActorsHealthy";
+
+/// Conditions is the registry of the conditions reported by the runtime status.
+///
+/// Cloning it gives another handle on the same registry, so the subsystems owning a condition can update it while
+/// the runtime service reports it.
+#[derive(Clone, Debug)]
+pub struct Conditions {
+    conditions: Arc<RwLock<Vec<grpc::RuntimeCondition>>>,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Conditions {
+            conditions: Arc::new(RwLock::new(vec![
+                grpc::RuntimeCondition {
+                    r#type: RUNTIME_READY.to_owned(),
+                    status: true,
+                    // NOTE: We should make these reasons an enum once we
+                    // actually define more of them
+                    reason: "RuntimeStarted".to_owned(),
+                    message: "Runtime has been started and is ready to run modules".to_owned(),
+                },
+                grpc::RuntimeCondition {
+                    r#type: NETWORK_READY.to_owned(),
+                    status: false, // False until we figure out networking support
+                    reason: "Unimplemented".to_owned(),
+                    message: "Networking is currently unimplemented".to_owned(),
+                },
+            ])),
+        }
+    }
+}
+
+impl Conditions {
+    /// Set a condition, replacing the previous state of a condition of the same type. New types are reported after
+    /// the existing ones.
+    pub async fn set(&self, r#type: &str, status: bool, reason: &str, message: &str) {
+        let condition = grpc::RuntimeCondition {
+            r#type: r#type.to_owned(),
+            status,
+            reason: reason.to_owned(),
+            message: message.to_owned(),
+        };
+        let mut conditions = self.conditions.write().await;
+        match conditions.iter_mut().find(|c| c.r#type == r#type) {
+            Some(c) => *c = condition,
+            None => conditions.push(condition),
+        }
+    }
+
+    /// All conditions, in the order they were first set.
+    pub async fn list(&self) -> Vec<grpc::RuntimeCondition> {
+        self.conditions.read().await.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_set() {
+        let conditions = Conditions::default();
+        conditions
+            .set(IMAGE_STORE_READY, false, "StoreFull", "no space left")
+            .await;
+        conditions
+            .set(NETWORK_READY, true, "NetworkReady", "CNI is set up")
+            .await;
+
+        let list = conditions.list().await;
+        let types: Vec<_> = list.iter().map(|c| c.r#type.as_str()).collect();
+        assert_eq!(vec![RUNTIME_READY, NETWORK_READY, IMAGE_STORE_READY], types);
+        assert!(list[1].status);
+        assert!(!list[2].status);
+        assert_eq!("no space left", list[2].message);
+
+        // clones share the registry
+        conditions
+            .clone()
+            .set(IMAGE_STORE_READY, true, "StoreReady", "")
+            .await;
+        assert!(conditions.list().await[2].status);
+    }
+}
@@ -0,0 +1,166 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tower_service::Service;
+
+/// How often `Drain::wait` checks whether the RPCs in flight completed.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Drain tells the services it is applied to that wok is shutting down, and keeps track of the RPCs they are still
+/// serving meanwhile.
+#[derive(Clone, Debug, Default)]
+pub struct Drain {
+    state: Arc<DrainState>,
+}
+
+#[derive(Debug, Default)]
+struct DrainState {
+    closed: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Drain::default()
+    }
+
+    /// Refuse the RPCs called from now on with `Unavailable`, including the ones of connections already
+    /// established, e.g. the kubelet's.
+    pub fn close(&self) {
+        self.state.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// The number of RPCs being served.
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Wait up to `timeout` for the RPCs in flight to complete. Returns whether they all did.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+        true
+    }
+}
+
+/// Counts an RPC as in flight until it is dropped.
+struct InFlight(Arc<DrainState>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Draining wraps a gRPC service so its RPCs fail with `Unavailable` once the drain is closed, while the ones called
+/// before are counted until they complete, so wok stops its containers only once nothing can start one anymore.
+#[derive(Clone, Debug)]
+pub struct Draining<S> {
+    inner: S,
+    drain: Drain,
+}
+
+impl<S> Draining<S> {
+    pub fn new(inner: S, drain: Drain) -> Self {
+        Draining { inner, drain }
+    }
+}
+
+impl<S: NamedService> NamedService for Draining<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Draining<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        // counted before the drain is checked, so `wait` can't miss an RPC let through right before `close`
+        let state = self.drain.state.clone();
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(state);
+        if self.drain.state.closed.load(Ordering::SeqCst) {
+            drop(in_flight);
+            return Box::pin(async { Ok(unavailable()) });
+        }
+        let call = self.inner.call(req);
+        Box::pin(async move {
+            let _in_flight = in_flight;
+            call.await
+        })
+    }
+}
+
+/// The response of a call made while wok is shutting down.
+fn unavailable() -> http::Response<BoxBody> {
+    http::Response::builder()
+        .status(200)
+        .header("content-type", "application/grpc")
+        .header("grpc-status", "14")
+        .header("grpc-message", "wok is shutting down")
+        .body(BoxBody::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone)]
+    struct Slow;
+
+    impl Service<http::Request<()>> for Slow {
+        type Response = http::Response<BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            Box::pin(async {
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+                Ok(http::Response::new(BoxBody::empty()))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_draining() {
+        let drain = Drain::new();
+        let mut service = Draining::new(Slow, drain.clone());
+        let in_flight = tokio::spawn(service.call(http::Request::new(())));
+        assert_eq!(1, drain.in_flight());
+
+        drain.close();
+        let res = service.call(http::Request::new(())).await.unwrap();
+        assert_eq!("14", res.headers()["grpc-status"]);
+        assert_eq!(1, drain.in_flight());
+
+        assert!(!drain.wait(Duration::from_millis(0)).await);
+        assert!(drain.wait(Duration::from_secs(5)).await);
+        let res = in_flight.await.unwrap().unwrap();
+        assert!(res.headers().get("grpc-status").is_none());
+    }
+}
@@ -0,0 +1,185 @@
+//! Notify integrators of lifecycle transitions, e.g. to turn them into Kubernetes Events or chat alerts.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::config::EventOptions;
+
+/// How long a webhook has to take an event before it is dropped.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Event describes a lifecycle transition.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "PascalCase")]
+pub enum Event {
+    SandboxCreated {
+        sandbox_id: String,
+        name: String,
+        namespace: String,
+    },
+    ContainerStarted {
+        container_id: String,
+        sandbox_id: String,
+    },
+    /// a WASI module exited. waSCC actors run until they are stopped.
+    ContainerExited {
+        container_id: String,
+        reason: String,
+        error: Option<String>,
+    },
+    PullFailed {
+        image: String,
+        error: String,
+    },
+}
+
+/// EventSink is told about every event. It is called on the RPC's task, so it must not block.
+pub trait EventSink: fmt::Debug + Send + Sync {
+    fn notify(&self, event: &Event);
+}
+
+/// Events passes events on to the configured sinks.
+///
+/// Cloning it gives another handle on the same sinks, so the runtime and image services share them.
+#[derive(Clone, Debug, Default)]
+pub struct Events {
+    sinks: Arc<Vec<Box<dyn EventSink>>>,
+}
+
+impl Events {
+    pub fn new(sinks: Vec<Box<dyn EventSink>>) -> Self {
+        Events {
+            sinks: Arc::new(sinks),
+        }
+    }
+
+    /// Set up the sinks of the given options.
+    pub fn from_options(options: &EventOptions) -> Result<Self, failure::Error> {
+        let mut sinks: Vec<Box<dyn EventSink>> = vec![];
+        if options.log {
+            sinks.push(Box::new(LogSink));
+        }
+        if let Some(url) = &options.webhook {
+            sinks.push(Box::new(WebhookSink::new(url)?));
+        }
+        Ok(Self::new(sinks))
+    }
+
+    /// Whether any sink is configured, e.g. to skip watching for events nobody is told about.
+    pub fn is_enabled(&self) -> bool {
+        !self.sinks.is_empty()
+    }
+
+    pub fn notify(&self, event: Event) {
+        for sink in self.sinks.iter() {
+            sink.notify(&event);
+        }
+    }
+}
+
+/// LogSink logs every event at info level.
+#[derive(Debug)]
+pub struct LogSink;
+
+impl EventSink for LogSink {
+    fn notify(&self, event: &Event) {
+        let event = serde_json::to_string(event).expect("events serialize to JSON");
+        tracing::info!(%event, "event");
+    }
+}
+
+/// WebhookSink posts every event as JSON to a URL, with the time it happened in its `time` field. Events the
+/// webhook doesn't take in time are dropped. Only plain HTTP is supported.
+#[derive(Debug)]
+pub struct WebhookSink {
+    url: hyper::Uri,
+    client: hyper::Client<hyper::client::HttpConnector>,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Result<Self, failure::Error> {
+        let uri: hyper::Uri = url
+            .parse()
+            .map_err(|e| format_err!("invalid webhook URL {}: {}", url, e))?;
+        if uri.scheme_str() != Some("http") {
+            failure::bail!("invalid webhook URL {}: only http:// is supported", url);
+        }
+        Ok(WebhookSink {
+            url: uri,
+            client: hyper::Client::new(),
+        })
+    }
+}
+
+impl EventSink for WebhookSink {
+    fn notify(&self, event: &Event) {
+        let mut body = serde_json::to_value(event).expect("events serialize to JSON");
+        body["time"] = serde_json::Value::String(Utc::now().to_rfc3339());
+        let request = hyper::Request::post(self.url.clone())
+            .header("content-type", "application/json")
+            .body(hyper::Body::from(body.to_string()))
+            .expect("valid webhook request");
+        let response = self.client.request(request);
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(WEBHOOK_TIMEOUT, response).await {
+                Ok(Ok(response)) if response.status().is_success() => {}
+                Ok(Ok(response)) => {
+                    tracing::warn!("webhook {} rejected an event: {}", url, response.status())
+                }
+                Ok(Err(e)) => tracing::warn!("cannot post an event to webhook {}: {}", url, e),
+                Err(_) => tracing::warn!("webhook {} did not take an event in time", url),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// RecordingSink keeps the events it is told about.
+    #[derive(Debug, Default)]
+    struct RecordingSink(Arc<Mutex<Vec<Event>>>);
+
+    impl EventSink for RecordingSink {
+        fn notify(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let events = Events::new(vec![Box::new(RecordingSink(recorded.clone()))]);
+        assert!(events.is_enabled());
+        assert!(!Events::default().is_enabled());
+
+        let event = Event::PullFailed {
+            image: "example.com/app:v1".to_owned(),
+            error: "registry is unavailable".to_owned(),
+        };
+        events.clone().notify(event.clone());
+        assert_eq!(vec![event.clone()], *recorded.lock().unwrap());
+        assert_eq!(
+            serde_json::json!({
+                "kind": "PullFailed",
+                "image": "example.com/app:v1",
+                "error": "registry is unavailable",
+            }),
+            serde_json::to_value(&event).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_webhook_url() {
+        WebhookSink::new("http://127.0.0.1:8080/events").expect("valid URL");
+        WebhookSink::new("https://example.com/events").expect_err("no TLS");
+        WebhookSink::new("not a url").expect_err("invalid URL");
+    }
+}
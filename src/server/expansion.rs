@@ -0,0 +1,128 @@
+//! Expansion of `$(VAR)` references in container args and environment values, with the semantics
+//! of Kubernetes:
+//!
+//! - `$(VAR)` is replaced with the value of `VAR`, or left as it is when `VAR` is not defined.
+//! - `$$` is an escaped `$`, so `$$(VAR)` becomes the literal `$(VAR)`.
+//! - a `$` that doesn't start a reference, and an unterminated `$(`, are kept.
+//!
+//! Environment values can refer to the variables defined before them. Args can refer to any of the
+//! container's variables.
+
+use std::collections::HashMap;
+
+use super::grpc;
+
+const OPERATOR: char = '$';
+const OPENER: char = '(';
+const CLOSER: char = ')';
+
+/// Build the container's environment, expanding each value with the variables defined before it.
+pub fn expand_envs(envs: &[grpc::KeyValue]) -> HashMap<String, String> {
+    let mut expanded = HashMap::new();
+    for pair in envs {
+        let value = expand(&pair.value, &expanded);
+        expanded.insert(pair.key.clone(), value);
+    }
+    expanded
+}
+
+/// Expand the references to the environment in the args.
+pub fn expand_args(args: &[String], env: &HashMap<String, String>) -> Vec<String> {
+    args.iter().map(|arg| expand(arg, env)).collect()
+}
+
+/// Expand the references in `input` with the variables in `env`.
+pub fn expand(input: &str, env: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(at) = rest.find(OPERATOR) {
+        output.push_str(&rest[..at]);
+        let after = &rest[at + 1..];
+        match after.chars().next() {
+            Some(OPERATOR) => {
+                output.push(OPERATOR);
+                rest = &after[1..];
+            }
+            Some(OPENER) => match after.find(CLOSER) {
+                Some(close) => {
+                    let name = &after[1..close];
+                    match env.get(name) {
+                        Some(value) => output.push_str(value),
+                        None => output.push_str(&rest[at..at + close + 2]),
+                    }
+                    rest = &after[close + 1..];
+                }
+                None => {
+                    output.push(OPERATOR);
+                    output.push(OPENER);
+                    rest = &after[1..];
+                }
+            },
+            Some(c) => {
+                output.push(OPERATOR);
+                output.push(c);
+                rest = &after[c.len_utf8()..];
+            }
+            None => {
+                output.push(OPERATOR);
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let env: HashMap<String, String> = vec![("VAR", "value"), ("EMPTY", "")]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        for (input, expected) in &[
+            ("$(VAR)", "value"),
+            ("a-$(VAR)-b", "a-value-b"),
+            ("$(VAR)$(VAR)", "valuevalue"),
+            ("x$(EMPTY)y", "xy"),
+            ("$(UNDEFINED)", "$(UNDEFINED)"),
+            ("$$(VAR)", "$(VAR)"),
+            ("$$$(VAR)", "$value"),
+            ("$$", "$"),
+            ("$VAR", "$VAR"),
+            ("$(VAR", "$(VAR"),
+            ("100$", "100$"),
+            ("$é", "$é"),
+            ("no refs", "no refs"),
+        ] {
+            assert_eq!(*expected, expand(input, &env), "expanding {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_expand_envs() {
+        let envs: Vec<grpc::KeyValue> = vec![
+            ("A", "a"),
+            ("B", "$(A)-b"),
+            // C is only defined later
+            ("D", "$(C)"),
+            ("C", "c"),
+        ]
+        .into_iter()
+        .map(|(key, value)| grpc::KeyValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+        .collect();
+        let env = expand_envs(&envs);
+        assert_eq!("a-b", env["B"]);
+        assert_eq!("$(C)", env["D"]);
+        assert_eq!(
+            vec!["--name=c", "$(B)"],
+            expand_args(&["--name=$(C)".to_owned(), "$$(B)".to_owned()], &env)
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Periodic health checks of waSCC actors and waPC guests, the counterpart of the kubelet's liveness probes for
+//! workloads it can't probe itself. The outcome is reported per container in its status, and for all of them in the
+//! `ActorsHealthy` runtime condition.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use super::conditions::{Conditions, ACTORS_HEALTHY};
+
+/// Health is the outcome of a container's latest health checks.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Health {
+    /// the number of checks in a row that failed, 0 if the latest one passed
+    pub failures: u32,
+    /// why the latest check failed
+    pub last_error: Option<String>,
+    /// when the latest check ran, in nanoseconds since the epoch
+    pub checked_at: i64,
+}
+
+/// HealthChecks records the health of the checked containers.
+///
+/// Cloning it gives another handle on the same records, so the tasks checking each container can share them with
+/// the runtime service.
+#[derive(Clone, Debug, Default)]
+pub struct HealthChecks {
+    health: Arc<RwLock<HashMap<String, Health>>>,
+    conditions: Conditions,
+    /// the time between two checks of a container, None if health checks are disabled
+    interval: Option<Duration>,
+    /// the number of failures in a row that make a container unhealthy
+    failure_threshold: u32,
+}
+
+impl HealthChecks {
+    /// Check every `interval_secs`, 0 disables health checks. A container is unhealthy once `failure_threshold`
+    /// checks in a row failed.
+    pub fn new(conditions: Conditions, interval_secs: u64, failure_threshold: u32) -> Self {
+        HealthChecks {
+            health: Arc::default(),
+            conditions,
+            interval: match interval_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            failure_threshold: failure_threshold.max(1),
+        }
+    }
+
+    /// The time between two checks of a container, None if health checks are disabled.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Record the outcome of a check of the container.
+    pub async fn record(&self, container_id: &str, result: Result<(), String>) {
+        {
+            let mut health = self.health.write().await;
+            let health = health.entry(container_id.to_owned()).or_default();
+            health.checked_at = Utc::now().timestamp_nanos();
+            match result {
+                Ok(()) => {
+                    health.failures = 0;
+                    health.last_error = None;
+                }
+                Err(e) => {
+                    health.failures += 1;
+                    health.last_error = Some(e);
+                }
+            }
+        }
+        self.update_condition().await;
+    }
+
+    /// The health of the container, if it was checked.
+    pub async fn get(&self, container_id: &str) -> Option<Health> {
+        self.health.read().await.get(container_id).cloned()
+    }
+
+    /// Whether the container failed too many checks in a row.
+    pub fn is_unhealthy(&self, health: &Health) -> bool {
+        health.failures >= self.failure_threshold
+    }
+
+    /// Stop reporting the container, e.g. because it was stopped.
+    pub async fn forget(&self, container_id: &str) {
+        if self.health.write().await.remove(container_id).is_some() {
+            self.update_condition().await;
+        }
+    }
+
+    async fn update_condition(&self) {
+        let mut unhealthy: Vec<String> = self
+            .health
+            .read()
+            .await
+            .iter()
+            .filter(|(_, health)| self.is_unhealthy(health))
+            .map(|(id, _)| id.clone())
+            .collect();
+        if unhealthy.is_empty() {
+            self.conditions
+                .set(ACTORS_HEALTHY, true, "HealthChecksPassing", "")
+                .await;
+        } else {
+            unhealthy.sort();
+            self.conditions
+                .set(
+                    ACTORS_HEALTHY,
+                    false,
+                    "HealthChecksFailing",
+                    &format!(
+                        "containers {} failed their health checks",
+                        unhealthy.join(", ")
+                    ),
+                )
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::grpc;
+
+    async fn condition(conditions: &Conditions) -> Option<grpc::RuntimeCondition> {
+        conditions
+            .list()
+            .await
+            .into_iter()
+            .find(|c| c.r#type == ACTORS_HEALTHY)
+    }
+
+    #[tokio::test]
+    async fn test_health_checks() {
+        let conditions = Conditions::default();
+        let checks = HealthChecks::new(conditions.clone(), 10, 2);
+        assert_eq!(Some(Duration::from_secs(10)), checks.interval());
+        assert_eq!(None, HealthChecks::new(conditions.clone(), 0, 2).interval());
+        assert_eq!(None, condition(&conditions).await);
+
+        checks.record("app", Err("no reply".to_owned())).await;
+        let health = checks.get("app").await.unwrap();
+        assert_eq!(1, health.failures);
+        assert!(!checks.is_unhealthy(&health));
+        assert!(condition(&conditions).await.unwrap().status);
+
+        checks.record("app", Err("no reply".to_owned())).await;
+        assert!(checks.is_unhealthy(&checks.get("app").await.unwrap()));
+        let unhealthy = condition(&conditions).await.unwrap();
+        assert!(!unhealthy.status);
+        assert_eq!(
+            "containers app failed their health checks",
+            unhealthy.message
+        );
+
+        checks.record("app", Ok(())).await;
+        assert_eq!(0, checks.get("app").await.unwrap().failures);
+        assert!(condition(&conditions).await.unwrap().status);
+
+        checks.record("app", Err("no reply".to_owned())).await;
+        checks.record("app", Err("no reply".to_owned())).await;
+        checks.forget("app").await;
+        assert_eq!(None, checks.get("app").await);
+        assert!(condition(&conditions).await.unwrap().status);
+    }
+}
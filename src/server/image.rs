@@ -1,58 +1,205 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use chrono::Utc;
 use tokio::sync::Mutex;
-use tonic::{Request, Response};
+use tonic::{Request, Response, Status};
 
+use super::conditions::{Conditions, IMAGE_STORE_INTACT, IMAGE_STORE_READY};
+use super::events::{Event, Events};
 use super::grpc;
 
+use crate::config::{MetadataBackend, PullOptions, StoreOptions};
 use crate::docker::Reference;
 use crate::server::CriResult;
-use crate::store::ModuleStore;
+use crate::store::{ModuleStore, ModuleStoreError};
+use crate::wasm::{EngineConfig, WarmPool};
 
 /// Implement a CRI Image Service
 #[derive(Debug, Default)]
 pub struct CriImageService {
     module_store: Mutex<ModuleStore>,
+    conditions: Conditions,
+    warm_pool: WarmPool,
+    events: Events,
 }
 
 impl CriImageService {
     pub async fn new(root_dir: PathBuf) -> Self {
-        tokio::fs::create_dir_all(&root_dir)
+        Self::with_options(StoreOptions {
+            dir: root_dir,
+            pull: PullOptions::default(),
+            compress: false,
+            metadata: MetadataBackend::Memory,
+            sideload_interval_secs: 0,
+            namespaced: false,
+            prepull: vec![],
+            usage_interval_secs: 0,
+            verify_on_start: false,
+        })
+        .await
+    }
+
+    pub async fn with_options(options: StoreOptions) -> Self {
+        tokio::fs::create_dir_all(&options.dir)
             .await
             .expect("cannot create root directory for image service");
+        let module_store = ModuleStore::with_options(options.dir, options.pull)
+            .await
+            .with_compression(options.compress)
+            .with_namespaces(options.namespaced)
+            .with_metadata(options.metadata)
+            .expect("cannot open the metadata of the image service");
         CriImageService {
-            module_store: Mutex::new(ModuleStore::new(root_dir).await),
+            module_store: Mutex::new(module_store),
+            conditions: Conditions::default(),
+            warm_pool: WarmPool::default(),
+            events: Events::default(),
+        }
+    }
+
+    /// Report the state of the module store in the given conditions, usually the runtime service's.
+    pub fn with_conditions(mut self, conditions: Conditions) -> Self {
+        self.conditions = conditions;
+        self
+    }
+
+    /// Compile pulled modules into the given pool, usually the runtime service's, so they are ready by the time
+    /// their containers start.
+    pub fn with_warm_pool(mut self, warm_pool: WarmPool) -> Self {
+        self.warm_pool = warm_pool;
+        self
+    }
+
+    /// Tell the given sinks, usually the runtime service's, about failed pulls.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// A handle to the module store. It shares its state with the store used by the service.
+    pub async fn module_store(&self) -> ModuleStore {
+        self.module_store.lock().await.clone()
+    }
+
+    /// Drop the modules of every namespace whose files don't match their metadata, e.g. after an unclean shutdown,
+    /// and report them in the `ImageStoreIntact` condition. Meant to be run before the service serves requests,
+    /// so no container is started from a corrupt module.
+    pub async fn verify_store(&self) {
+        let mut dropped = vec![];
+        for (_, mut store) in self.stores().await {
+            dropped.extend(store.verify().await);
+        }
+        if dropped.is_empty() {
+            self.conditions
+                .set(IMAGE_STORE_INTACT, true, "ModulesVerified", "")
+                .await;
+        } else {
+            let message = format!(
+                "dropped corrupt modules {}, they are pulled again when needed",
+                dropped.join(", ")
+            );
+            self.conditions
+                .set(IMAGE_STORE_INTACT, false, "CorruptModulesDropped", &message)
+                .await;
         }
     }
 
-    async fn pull_module(&self, module_ref: Reference) -> Result<(), failure::Error> {
-        self.module_store.lock().await.pull(&module_ref).await?;
+    /// Pull the module into the store of the given namespace.
+    async fn pull_module(
+        &self,
+        module_ref: Reference,
+        namespace: &str,
+    ) -> Result<(), ModuleStoreError> {
+        let mut module_store = self.module_store.lock().await.namespace(namespace).await?;
+        let result = module_store.pull(&module_ref).await;
+        if let Err(e) = &result {
+            self.events.notify(Event::PullFailed {
+                image: module_ref.whole().to_owned(),
+                error: e.to_string(),
+            });
+        }
+        match &result {
+            Ok(()) => {
+                self.warm_in_background(module_ref, module_store).await;
+                self.conditions
+                    .set(IMAGE_STORE_READY, true, "StoreWritable", "")
+                    .await
+            }
+            Err(ModuleStoreError::CannotWriteStore(e)) => {
+                self.conditions
+                    .set(IMAGE_STORE_READY, false, "StoreNotWritable", e)
+                    .await
+            }
+            // registry failures say nothing about the store
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Start compiling the pulled module. The module is only handed to the compiler once it is complete, as the
+    /// pull happens in the Go library and wasmtime can't compile a module as it streams in.
+    async fn warm_in_background(&self, module_ref: Reference, module_store: ModuleStore) {
+        if !self.warm_pool.is_enabled() {
+            return;
+        }
+        let warm_pool = self.warm_pool.clone();
+        tokio::spawn(async move {
+            match module_store.read(&module_ref).await {
+                // the sandbox may ask for other features, but most don't
+                Ok(module) => {
+                    warm_pool
+                        .warm(
+                            module_ref.whole(),
+                            EngineConfig::default(),
+                            Arc::new(module),
+                        )
+                        .await
+                }
+                Err(e) => tracing::debug!("not warming {}: {}", module_ref.whole(), e),
+            }
+        });
+    }
 
-        Ok(())
+    /// The store shared by all namespaces, followed by the store of every namespace if the store is partitioned.
+    async fn stores(&self) -> Vec<(String, ModuleStore)> {
+        let module_store = self.module_store.lock().await.clone();
+        let mut stores = vec![(String::new(), module_store.clone())];
+        stores.extend(module_store.namespaces().await);
+        stores
     }
 }
 
 #[tonic::async_trait]
 impl grpc::image_service_server::ImageService for CriImageService {
+    /// List the modules of every namespace. A module pulled into several namespaces is listed once.
     async fn list_images(
         &self,
         _request: Request<grpc::ListImagesRequest>,
     ) -> CriResult<grpc::ListImagesResponse> {
-        let resp = grpc::ListImagesResponse {
-            images: self.module_store.lock().await.list().await,
-        };
-        Ok(Response::new(resp))
+        let mut images: Vec<grpc::Image> = vec![];
+        for (_, store) in self.stores().await {
+            for image in store.list().await {
+                if !images.iter().any(|i| i.id == image.id) {
+                    images.push(image);
+                }
+            }
+        }
+        Ok(Response::new(grpc::ListImagesResponse { images }))
     }
 
+    /// Report the module of the store shared by all namespaces. The kubelet doesn't say which pod asks, so the
+    /// modules of a namespace's store are never reported: the kubelet pulls them again for every pod, which puts
+    /// them into the pod's namespace. The verbose info lists the namespaces the module was pulled into.
     async fn image_status(
         &self,
         req: Request<grpc::ImageStatusRequest>,
     ) -> CriResult<grpc::ImageStatusResponse> {
         // TODO(rylev): handle error of image in request not being there.
-        let image_id = req.into_inner().image.unwrap().image;
+        let request = req.into_inner();
+        let image_id = request.image.unwrap().image;
         let image = self
             .module_store
             .lock()
@@ -63,10 +210,25 @@ impl grpc::image_service_server::ImageService for CriImageService {
             .find(|i| i.id == image_id)
             .cloned();
 
-        let resp = grpc::ImageStatusResponse {
-            image,
-            info: HashMap::new(), // TODO(rylev): figure out what goes here
-        };
+        let mut info = HashMap::new();
+        if request.verbose {
+            let stats = self.module_store.lock().await.pull_stats().await;
+            info.insert(
+                "pull_stats".to_owned(),
+                serde_json::to_string(&stats).expect("pull stats serialize to JSON"),
+            );
+            let mut namespaces = vec![];
+            for (namespace, store) in self.module_store.lock().await.namespaces().await {
+                if store.list().await.iter().any(|i| i.id == image_id) {
+                    namespaces.push(namespace);
+                }
+            }
+            info.insert(
+                "namespaces".to_owned(),
+                serde_json::to_string(&namespaces).expect("namespaces serialize to JSON"),
+            );
+        }
+        let resp = grpc::ImageStatusResponse { image, info };
         Ok(Response::new(resp))
     }
 
@@ -74,11 +236,28 @@ impl grpc::image_service_server::ImageService for CriImageService {
         &self,
         request: Request<grpc::PullImageRequest>,
     ) -> CriResult<grpc::PullImageResponse> {
-        let image_ref = request.into_inner().image.unwrap().image;
-        let reference = Reference::try_from(image_ref.clone()).expect("Image ref is malformed");
-        self.pull_module(reference)
+        let request = request.into_inner();
+        let image_ref = request.image.unwrap().image;
+        let namespace = request
+            .sandbox_config
+            .and_then(|c| c.metadata)
+            .map(|m| m.namespace)
+            .unwrap_or_default();
+        let reference = Reference::try_from(image_ref.clone()).map_err(|e| {
+            Status::invalid_argument(format!("invalid image reference {}: {}", image_ref, e))
+        })?;
+        self.pull_module(reference, &namespace)
             .await
-            .expect("cannot pull module");
+            .map_err(|e| match e {
+                ModuleStoreError::RegistryUnavailable | ModuleStoreError::RateLimited(_) => {
+                    Status::unavailable(e.to_string())
+                }
+                ModuleStoreError::PullTimedOut => Status::deadline_exceeded(e.to_string()),
+                ModuleStoreError::NotWasm(_)
+                | ModuleStoreError::InvalidModule(_)
+                | ModuleStoreError::InvalidNamespace(_) => Status::invalid_argument(e.to_string()),
+                _ => Status::internal(e.to_string()),
+            })?;
         let resp = grpc::PullImageResponse { image_ref };
 
         // TODO(bacongobbler): add to the image store
@@ -91,7 +270,13 @@ impl grpc::image_service_server::ImageService for CriImageService {
         &self,
         _request: Request<grpc::ImageFsInfoRequest>,
     ) -> CriResult<grpc::ImageFsInfoResponse> {
-        let module_store = self.module_store.lock().await;
+        let module_store = self.module_store.lock().await.clone();
+        let mut used_bytes = 0;
+        let mut used_inodes = 0;
+        for (_, store) in self.stores().await {
+            used_bytes += store.used_bytes().await;
+            used_inodes += store.used_inodes().await;
+        }
         let resp = grpc::ImageFsInfoResponse {
             image_filesystems: vec![grpc::FilesystemUsage {
                 timestamp: Utc::now().timestamp_nanos(),
@@ -103,12 +288,8 @@ impl grpc::image_service_server::ImageService for CriImageService {
                         .into_string()
                         .unwrap(),
                 }),
-                used_bytes: Some(grpc::UInt64Value {
-                    value: module_store.used_bytes().await,
-                }),
-                inodes_used: Some(grpc::UInt64Value {
-                    value: module_store.used_inodes().await,
-                }),
+                used_bytes: Some(grpc::UInt64Value { value: used_bytes }),
+                inodes_used: Some(grpc::UInt64Value { value: used_inodes }),
             }],
         };
         Ok(Response::new(resp))
@@ -119,6 +300,7 @@ impl grpc::image_service_server::ImageService for CriImageService {
 mod tests {
     use super::*;
     use grpc::image_service_server::ImageService;
+    use tonic::Code;
 
     #[tokio::test]
     async fn test_image_status() {
@@ -132,4 +314,53 @@ mod tests {
         let response = service.image_status(Request::new(req)).await;
         assert!(response.unwrap().into_inner().image.is_none());
     }
+
+    #[tokio::test]
+    async fn test_verify_store() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let service = CriImageService::new(dir.path().to_owned()).await;
+        service.verify_store().await;
+        let intact = |conditions: Vec<grpc::RuntimeCondition>| {
+            conditions
+                .into_iter()
+                .find(|c| c.r#type == IMAGE_STORE_INTACT)
+                .expect("the condition is reported")
+        };
+        assert!(intact(service.conditions.list().await).status);
+
+        // a module whose file was never written
+        service
+            .module_store()
+            .await
+            .add(grpc::Image {
+                id: "example.com/app:v1".to_owned(),
+                size: 8,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        service.verify_store().await;
+        let condition = intact(service.conditions.list().await);
+        assert!(!condition.status);
+        assert_eq!("CorruptModulesDropped", condition.reason);
+        assert!(service.module_store().await.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pull_image_invalid_reference() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let service = CriImageService::new(dir.path().to_owned()).await;
+        let req = grpc::PullImageRequest {
+            image: Some(grpc::ImageSpec {
+                image: "example.com/Foo:v1".to_owned(),
+            }),
+            ..Default::default()
+        };
+        let status = service
+            .pull_image(Request::new(req))
+            .await
+            .expect_err("invalid references are rejected");
+        assert_eq!(Code::InvalidArgument, status.code());
+        assert!(status.message().contains("invalid character 'F'"));
+    }
 }
@@ -1,5 +1,23 @@
+pub mod admin;
+pub mod backend;
+pub mod conditions;
+pub mod drain;
+pub mod events;
+pub mod expansion;
+pub mod health;
 pub mod image;
+pub mod mounts;
+pub mod policy;
+pub mod proxy;
+pub mod ratelimit;
+pub mod redact;
+pub mod reflection;
+pub mod resources;
+pub mod restrictions;
 pub mod runtime;
+pub mod stats;
+pub mod timeout;
+pub mod trace;
 
 // Tonic will autogenerate the module's body.
 pub mod grpc {
@@ -10,8 +28,17 @@ pub use grpc::image_service_server::ImageServiceServer;
 pub use grpc::runtime_service_server::RuntimeServiceServer;
 pub use grpc::Image as Module;
 
+pub use admin::AdminService;
+pub use backend::{Backends, RuntimeBackend};
+pub use conditions::Conditions;
+pub use drain::{Drain, Draining};
+pub use events::{Event, EventSink, Events};
 pub use image::CriImageService;
-pub use runtime::CriRuntimeService;
+pub use ratelimit::{RateLimited, RateLimiter};
+pub use reflection::{ReflectionService, ServerReflectionServer};
+pub use runtime::{CriRuntimeService, LogFilterHandle};
+pub use timeout::{TimeLimited, Timeouts};
+pub use trace::Traced;
 
 /// CriResult describes a Result that has a Response<T> and a Status
 pub type CriResult<T> = std::result::Result<tonic::Response<T>, tonic::Status>;
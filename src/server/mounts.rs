@@ -0,0 +1,128 @@
+//! HostPath mounts: host directories preopened for the WASI modules of containers mounting them.
+//!
+//! Only directories below one of the prefixes in `runtime.host_path_prefixes` are preopened, so node files are
+//! shared with wasm workloads on purpose only. The host path is resolved when the container is created, and a
+//! path leading out of its prefix through a symlink, or through `..`, is rejected. Other mounts, e.g. the ones the
+//! kubelet adds to every container, are not preopened.
+//!
+//! wasi-common gives every preopened directory the full set of rights, so read-only mounts cannot be enforced and
+//! are rejected rather than preopened writable.
+
+use std::path::{Path, PathBuf};
+
+use super::grpc;
+
+/// The directory to preopen for the mount, resolved to its canonical path, or None if the mount's host path is
+/// not below any of the prefixes.
+pub(crate) fn host_dir(
+    mount: &grpc::Mount,
+    prefixes: &[PathBuf],
+) -> Result<Option<PathBuf>, failure::Error> {
+    let host_path = Path::new(&mount.host_path);
+    let prefix = match prefixes.iter().find(|p| host_path.starts_with(p)) {
+        Some(prefix) => prefix,
+        None => return Ok(None),
+    };
+    let dir = host_path
+        .canonicalize()
+        .map_err(|e| format_err!("cannot mount {}: {}", mount.host_path, e))?;
+    let prefix = prefix
+        .canonicalize()
+        .map_err(|e| format_err!("cannot resolve {}: {}", prefix.display(), e))?;
+    if !dir.starts_with(&prefix) {
+        return Err(format_err!(
+            "cannot mount {}: it resolves to {}, outside of {}",
+            mount.host_path,
+            dir.display(),
+            prefix.display()
+        ));
+    }
+    if !dir.is_dir() {
+        return Err(format_err!(
+            "cannot mount {}: only directories can be mounted",
+            mount.host_path
+        ));
+    }
+    if mount.readonly {
+        return Err(format_err!(
+            "cannot mount {}: read-only mounts are not supported",
+            mount.host_path
+        ));
+    }
+    Ok(Some(dir))
+}
+
+/// Fail unless the directory resolved when the container was created still resolves to itself, i.e. no symlink
+/// was put in its place since.
+pub(crate) fn check_host_dir(dir: &Path) -> Result<(), failure::Error> {
+    match dir.canonicalize() {
+        Ok(resolved) if resolved == dir => Ok(()),
+        Ok(resolved) => Err(format_err!(
+            "cannot mount {}: it resolves to {} now",
+            dir.display(),
+            resolved.display()
+        )),
+        Err(e) => Err(format_err!("cannot mount {}: {}", dir.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mount(host_path: &Path, readonly: bool) -> grpc::Mount {
+        grpc::Mount {
+            container_path: "/data".to_owned(),
+            host_path: host_path.to_str().unwrap().to_owned(),
+            readonly,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_host_dir() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let root = dir.path().canonicalize().unwrap();
+        let allowed = root.join("allowed");
+        std::fs::create_dir_all(allowed.join("data")).unwrap();
+        std::fs::write(allowed.join("file"), b"").unwrap();
+        std::fs::create_dir(root.join("secret")).unwrap();
+        let prefixes = vec![allowed.clone()];
+
+        assert_eq!(
+            Some(allowed.join("data")),
+            host_dir(&mount(&allowed.join("data"), false), &prefixes).unwrap()
+        );
+        assert_eq!(
+            None,
+            host_dir(&mount(&root.join("secret"), false), &prefixes).unwrap()
+        );
+        host_dir(&mount(&allowed.join("../secret"), false), &prefixes)
+            .expect_err("leads out of the prefix");
+        host_dir(&mount(&allowed.join("missing"), false), &prefixes).expect_err("does not exist");
+        host_dir(&mount(&allowed.join("file"), false), &prefixes).expect_err("not a directory");
+        host_dir(&mount(&allowed.join("data"), true), &prefixes).expect_err("read-only");
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("secret"), allowed.join("escape")).unwrap();
+            host_dir(&mount(&allowed.join("escape"), false), &prefixes)
+                .expect_err("symlink out of the prefix");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_host_dir() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let root = dir.path().canonicalize().unwrap();
+        let data = root.join("data");
+        std::fs::create_dir(&data).unwrap();
+        check_host_dir(&data).unwrap();
+
+        std::fs::remove_dir(&data).unwrap();
+        std::fs::create_dir(root.join("secret")).unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), &data).unwrap();
+        check_host_dir(&data).expect_err("replaced by a symlink");
+    }
+}
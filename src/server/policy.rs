@@ -0,0 +1,279 @@
+//! A node level policy restricting what the containers of each pod may use.
+//!
+//! The policy file lists rules. The first rule whose selectors all match a pod's sandbox config applies to the
+//! pod, and pods no rule matches are unrestricted. A rule allows:
+//!
+//! | Key             | What the pod's containers may use                                       |
+//! |-----------------|-------------------------------------------------------------------------|
+//! | `capabilities`  | the waSCC capabilities actors bind to, e.g. `wascc:keyvalue`            |
+//! | `wasm_features` | the wasm features of the sandbox, see `deislabs.io/wasm-features`       |
+//! | `wasi`          | the WASI capabilities containers don't deny, see `deislabs.io/wasi-deny` |
+//!
+//! A list that is left out allows everything. Containers using anything else are rejected with
+//! `PermissionDenied` when they are created or started. WASI capabilities a container's profile denies, see
+//! `deislabs.io/wasi-profile`, need not be allowed, and neither does `host-fs` for containers denying `fs`:
+//!
+//! ```toml
+//! [[rules]]
+//! namespace = "batch"
+//! labels = { team = "data" }
+//! capabilities = ["wascc:keyvalue"]
+//! wasm_features = []
+//! wasi = ["args", "env"]
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use super::grpc;
+use super::restrictions::{Capability, WasiRestrictions, WASI_DENY_ANNOTATION};
+use crate::wasm::EngineConfig;
+
+/// Policy holds the rules of the policy file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+/// Rule restricts the pods matching all of its selectors.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// the namespace of the pod
+    pub namespace: Option<String>,
+    /// labels the pod must have
+    pub labels: HashMap<String, String>,
+    /// annotations the pod must have
+    pub annotations: HashMap<String, String>,
+    /// the waSCC capabilities the pod's actors may bind to
+    pub capabilities: Option<Vec<String>>,
+    /// the wasm features the pod's sandbox may enable
+    pub wasm_features: Option<Vec<String>>,
+    /// the WASI capabilities the pod's containers may keep
+    pub wasi: Option<Vec<String>>,
+}
+
+impl Policy {
+    /// Read the policy from a TOML file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("cannot read policy file {}: {}", path.display(), e))?;
+        let policy: Policy = toml::from_str(&raw)
+            .map_err(|e| format_err!("invalid policy file {}: {}", path.display(), e))?;
+        policy
+            .validate()
+            .map_err(|e| format_err!("invalid policy file {}: {}", path.display(), e))?;
+        Ok(policy)
+    }
+
+    /// Check that the features and WASI capabilities named by the rules exist.
+    fn validate(&self) -> Result<(), failure::Error> {
+        for rule in &self.rules {
+            for feature in rule.wasm_features.iter().flatten() {
+                EngineConfig::from_str(feature)?;
+            }
+            for capability in rule.wasi.iter().flatten() {
+                Capability::from_str(capability)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The rule applying to the pod, if any.
+    fn rule(&self, sandbox: &grpc::PodSandboxConfig) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(sandbox))
+    }
+
+    /// Check that the pod may bind its actor to the given waSCC capabilities.
+    pub fn check_capabilities(
+        &self,
+        sandbox: &grpc::PodSandboxConfig,
+        capabilities: &[String],
+    ) -> Result<(), failure::Error> {
+        let allowed = match self.rule(sandbox).and_then(|r| r.capabilities.as_ref()) {
+            Some(allowed) => allowed,
+            None => return Ok(()),
+        };
+        match capabilities.iter().find(|c| !allowed.contains(c)) {
+            Some(capability) => Err(format_err!(
+                "pod {} may not use the {} capability",
+                pod_name(sandbox),
+                capability
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the pod may run a WASI module with the given features and restrictions.
+    pub fn check_wasi(
+        &self,
+        sandbox: &grpc::PodSandboxConfig,
+        engine_config: &EngineConfig,
+        restrictions: &WasiRestrictions,
+    ) -> Result<(), failure::Error> {
+        let rule = match self.rule(sandbox) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        if let Some(allowed) = &rule.wasm_features {
+            if let Some(feature) = engine_config
+                .features()
+                .into_iter()
+                .find(|f| !allowed.iter().any(|a| a == f))
+            {
+                failure::bail!(
+                    "pod {} may not use the {} wasm feature",
+                    pod_name(sandbox),
+                    feature
+                );
+            }
+        }
+        if let Some(allowed) = &rule.wasi {
+            if let Some(capability) = Capability::ALL
+                .iter()
+                .find(|c| !restrictions.denies(**c) && !allowed.iter().any(|a| *a == c.to_string()))
+            {
+                failure::bail!(
+                    "pod {} may not use the {} WASI capability, deny it with the {} annotation",
+                    pod_name(sandbox),
+                    capability,
+                    WASI_DENY_ANNOTATION
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn matches(&self, sandbox: &grpc::PodSandboxConfig) -> bool {
+        let namespace = sandbox
+            .metadata
+            .as_ref()
+            .map(|m| m.namespace.as_str())
+            .unwrap_or_default();
+        self.namespace.as_ref().map_or(true, |n| n == namespace)
+            && subset(&self.labels, &sandbox.labels)
+            && subset(&self.annotations, &sandbox.annotations)
+    }
+}
+
+/// Whether all the entries of `wanted` are in `map`.
+fn subset(wanted: &HashMap<String, String>, map: &HashMap<String, String>) -> bool {
+    wanted.iter().all(|(k, v)| map.get(k) == Some(v))
+}
+
+/// The pod's name for error messages, e.g. `batch/report`.
+fn pod_name(sandbox: &grpc::PodSandboxConfig) -> String {
+    match &sandbox.metadata {
+        Some(m) => format!("{}/{}", m.namespace, m.name),
+        None => "without metadata".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> Policy {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rules]]
+            namespace = "batch"
+            labels = { team = "data" }
+            capabilities = ["wascc:keyvalue"]
+            wasm_features = []
+            wasi = ["args", "env"]
+
+            [[rules]]
+            namespace = "batch"
+            "#,
+        )
+        .expect("parsed policy");
+        policy.validate().expect("valid policy");
+        policy
+    }
+
+    fn sandbox(namespace: &str, team: &str) -> grpc::PodSandboxConfig {
+        let mut sandbox = grpc::PodSandboxConfig::default();
+        sandbox.metadata = Some(grpc::PodSandboxMetadata {
+            name: "report".to_owned(),
+            namespace: namespace.to_owned(),
+            ..Default::default()
+        });
+        sandbox.labels.insert("team".to_owned(), team.to_owned());
+        sandbox
+    }
+
+    fn restrictions(deny: &str) -> WasiRestrictions {
+        let mut config = grpc::ContainerConfig::default();
+        config
+            .annotations
+            .insert(WASI_DENY_ANNOTATION.to_owned(), deny.to_owned());
+        WasiRestrictions::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_check_capabilities() {
+        let policy = policy();
+        let data = sandbox("batch", "data");
+        policy
+            .check_capabilities(&data, &["wascc:keyvalue".to_owned()])
+            .expect("allowed capability");
+        let err = policy
+            .check_capabilities(&data, &["wascc:http_server".to_owned()])
+            .expect_err("capability not allowed");
+        assert!(err.to_string().contains("batch/report"));
+        // the second rule applies and allows everything
+        policy
+            .check_capabilities(&sandbox("batch", "web"), &["wascc:http_server".to_owned()])
+            .expect("unrestricted rule");
+        // no rule applies
+        policy
+            .check_capabilities(
+                &sandbox("default", "data"),
+                &["wascc:http_server".to_owned()],
+            )
+            .expect("unmatched pod");
+    }
+
+    #[test]
+    fn test_check_wasi() {
+        let policy = policy();
+        let data = sandbox("batch", "data");
+        let simd = EngineConfig {
+            simd: true,
+            ..Default::default()
+        };
+        policy
+            .check_wasi(
+                &data,
+                &EngineConfig::default(),
+                &restrictions("fs,clock,random"),
+            )
+            .expect("denies what the policy doesn't allow");
+        policy
+            .check_wasi(&data, &simd, &restrictions("fs,clock,random"))
+            .expect_err("simd is not allowed");
+        let err = policy
+            .check_wasi(&data, &EngineConfig::default(), &restrictions("fs"))
+            .expect_err("the clock is not denied");
+        assert!(err.to_string().contains("clock"));
+        policy
+            .check_wasi(&sandbox("default", "data"), &simd, &restrictions(""))
+            .expect("unmatched pod");
+    }
+
+    #[test]
+    fn test_invalid_policy() {
+        let policy: Policy = toml::from_str("[[rules]]\nwasi = [\"network\"]").unwrap();
+        policy.validate().expect_err("unknown WASI capability");
+        let policy: Policy = toml::from_str("[[rules]]\nwasm_features = [\"gc\"]").unwrap();
+        policy.validate().expect_err("unknown wasm feature");
+    }
+}
@@ -0,0 +1,123 @@
+//! Forward the host ports of a pod to the ports its containers actually listen on.
+//!
+//! WebAssembly modules share the host's network, so two pods serving on the same container port would fight over
+//! it. With port isolation enabled, every container serving HTTP listens on a free local port of its own instead,
+//! and a proxy forwards each of its pod's host port mappings there.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use futures::future::{abortable, AbortHandle};
+use tokio::net::{TcpListener, TcpStream};
+
+/// PortProxy accepts connections on a host address and forwards them to a local port, until it is dropped.
+#[derive(Debug)]
+pub(crate) struct PortProxy {
+    listen: SocketAddr,
+    target: SocketAddr,
+    abort: AbortHandle,
+}
+
+impl PortProxy {
+    /// Start forwarding the connections to `listen` to the given local port.
+    pub(crate) async fn start(listen: SocketAddr, port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(listen).await?;
+        let listen = listener.local_addr()?;
+        let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        let (serve, abort) = abortable(serve(listener, target));
+        tokio::spawn(serve);
+        tracing::debug!(%listen, %target, "proxy started");
+        Ok(PortProxy {
+            listen,
+            target,
+            abort,
+        })
+    }
+
+    /// The address the proxy accepts connections on.
+    pub(crate) fn listen(&self) -> SocketAddr {
+        self.listen
+    }
+}
+
+impl Drop for PortProxy {
+    fn drop(&mut self) {
+        tracing::debug!(listen = %self.listen, target = %self.target, "proxy stopped");
+        self.abort.abort();
+    }
+}
+
+async fn serve(mut listener: TcpListener, target: SocketAddr) {
+    loop {
+        let (inbound, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("proxy to {} cannot accept a connection: {}", target, e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = forward(inbound, target).await {
+                tracing::debug!("proxying {} to {} failed: {}", peer, target, e);
+            }
+        });
+    }
+}
+
+/// Copy the bytes of the connection to the target and back, until both sides are done.
+async fn forward(mut inbound: TcpStream, target: SocketAddr) -> io::Result<()> {
+    let mut outbound = TcpStream::connect(target).await?;
+    let (mut ri, mut wi) = inbound.split();
+    let (mut ro, mut wo) = outbound.split();
+    futures::future::try_join(
+        tokio::io::copy(&mut ri, &mut wo),
+        tokio::io::copy(&mut ro, &mut wi),
+    )
+    .await?;
+    Ok(())
+}
+
+/// A local port that nothing listens on right now, for a container to listen on.
+pub(crate) fn free_local_port() -> io::Result<u16> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_port_proxy() {
+        let mut backend = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(b"ping", &request);
+            stream.write_all(b"pong").await.unwrap();
+        });
+
+        let proxy = PortProxy::start((Ipv4Addr::LOCALHOST, 0).into(), port)
+            .await
+            .expect("proxy started");
+        let listen = proxy.listen();
+        let mut client = TcpStream::connect(listen).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut response = [0; 4];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(b"pong", &response);
+
+        // the port is given back once the proxy is dropped
+        drop(proxy);
+        for _ in 0..10 {
+            if TcpListener::bind(listen).await.is_ok() {
+                return;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("the proxy's port was not freed");
+    }
+}
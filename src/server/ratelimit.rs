@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either, Ready};
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tower_service::Service;
+
+use crate::config::RateLimitOptions;
+
+/// The header clients are told apart by, for limits kept per client.
+const CLIENT_HEADER: &str = "user-agent";
+/// The number of budgets kept before the ones of clients that went quiet are dropped.
+const MAX_BUCKETS: usize = 1024;
+/// How long a client has to be quiet for its budget to be dropped.
+const IDLE_BUCKET: Duration = Duration::from_secs(60);
+
+/// RateLimiter keeps the budgets of the rate limited RPCs. It is shared by the services it is applied to.
+///
+/// Each budget is a token bucket: it holds up to `burst` calls and refills at `per_second` calls per second.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    /// the limits by RPC name
+    limits: Arc<HashMap<String, RateLimitOptions>>,
+    /// the budgets by RPC name and client
+    buckets: Arc<Mutex<HashMap<(String, String), Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &[RateLimitOptions]) -> Result<Self, failure::Error> {
+        for limit in limits {
+            if limit.per_second.is_nan() || limit.per_second <= 0.0 || limit.burst == 0 {
+                failure::bail!(
+                    "invalid rate limit of {}: per_second and burst must be positive",
+                    limit.rpc
+                );
+            }
+        }
+        Ok(RateLimiter {
+            limits: Arc::new(limits.iter().map(|l| (l.rpc.clone(), l.clone())).collect()),
+            buckets: Arc::default(),
+        })
+    }
+
+    /// Take a call to the RPC from its budget. Returns false if the budget is used up.
+    fn allow(&self, rpc: &str, client: &str, now: Instant) -> bool {
+        let limit = match self.limits.get(rpc) {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let client = if limit.per_client { client } else { "" };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, b| now.saturating_duration_since(b.updated) < IDLE_BUCKET);
+        }
+        buckets
+            .entry((rpc.to_owned(), client.to_owned()))
+            .or_insert_with(|| Bucket {
+                tokens: f64::from(limit.burst),
+                updated: now,
+            })
+            .take(limit, now)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: &RateLimitOptions, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * limit.per_second).min(f64::from(limit.burst));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// RateLimited wraps a gRPC service so calls to its rate limited RPCs fail with `ResourceExhausted` once their
+/// budget is used up, e.g. when the kubelet retries failing pulls in a tight loop.
+#[derive(Clone, Debug)]
+pub struct RateLimited<S> {
+    inner: S,
+    limiter: RateLimiter,
+}
+
+impl<S> RateLimited<S> {
+    pub fn new(inner: S, limiter: RateLimiter) -> Self {
+        RateLimited { inner, limiter }
+    }
+}
+
+impl<S: NamedService> NamedService for RateLimited<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for RateLimited<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<S::Response, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let rpc = req.uri().path().rsplit('/').next().unwrap_or_default();
+        let client = req
+            .headers()
+            .get(CLIENT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if self.limiter.allow(rpc, client, Instant::now()) {
+            Either::Left(self.inner.call(req))
+        } else {
+            tracing::warn!("rate limit of {} exceeded by {:?}", rpc, client);
+            Either::Right(future::ok(resource_exhausted(rpc)))
+        }
+    }
+}
+
+/// The response of a call rejected by the rate limit.
+fn resource_exhausted(rpc: &str) -> http::Response<BoxBody> {
+    http::Response::builder()
+        .status(200)
+        .header("content-type", "application/grpc")
+        .header("grpc-status", "8")
+        .header(
+            "grpc-message",
+            format!("rate limit of {} exceeded, try again later", rpc),
+        )
+        .body(BoxBody::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limit(rpc: &str, per_client: bool) -> RateLimitOptions {
+        RateLimitOptions {
+            rpc: rpc.to_owned(),
+            per_second: 1.0,
+            burst: 2,
+            per_client,
+        }
+    }
+
+    #[test]
+    fn test_allow() {
+        let limiter =
+            RateLimiter::new(&[limit("PullImage", false), limit("StartContainer", true)]).unwrap();
+        let now = Instant::now();
+        assert!(limiter.allow("PullImage", "kubelet", now));
+        assert!(limiter.allow("PullImage", "crictl", now));
+        // the budget is shared by all clients
+        assert!(!limiter.allow("PullImage", "kubelet", now));
+        // and refills over time
+        assert!(limiter.allow("PullImage", "kubelet", now + Duration::from_secs(1)));
+        assert!(!limiter.allow("PullImage", "kubelet", now + Duration::from_secs(1)));
+
+        assert!(limiter.allow("StartContainer", "kubelet", now));
+        assert!(limiter.allow("StartContainer", "kubelet", now));
+        assert!(!limiter.allow("StartContainer", "kubelet", now));
+        // every client has a budget of its own
+        assert!(limiter.allow("StartContainer", "crictl", now));
+
+        // RPCs without a limit are always allowed
+        for _ in 0..10 {
+            assert!(limiter.allow("ListContainers", "kubelet", now));
+        }
+    }
+
+    #[test]
+    fn test_invalid_limit() {
+        let mut invalid = limit("PullImage", false);
+        invalid.per_second = 0.0;
+        RateLimiter::new(&[invalid]).expect_err("no calls per second");
+        let mut invalid = limit("PullImage", false);
+        invalid.burst = 0;
+        RateLimiter::new(&[invalid]).expect_err("no burst");
+    }
+}
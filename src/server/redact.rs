@@ -0,0 +1,111 @@
+//! Keeping the values of secret environment variables out of what wok reports about a container.
+//!
+//! A variable is secret when its key contains one of `runtime.secret_env_patterns`, ignoring case, e.g. `DB_PASSWORD`
+//! for `PASSWORD`. The verbose container status shows such variables with their values redacted. A module that
+//! fails with a message echoing a secret value, e.g. a connection string, has the value scrubbed from the message
+//! before it reaches wok's logs, the container status and the `ContainerExited` event.
+
+use std::collections::HashMap;
+
+use serde_json::json;
+
+use super::expansion;
+use super::grpc;
+
+/// What secret values are replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Values shorter than this are not scrubbed from messages, as they would match all over the place.
+const MIN_SCRUBBED_LEN: usize = 4;
+
+/// Whether the variable with the given key is secret.
+pub fn is_secret(key: &str, patterns: &[String]) -> bool {
+    let key = key.to_uppercase();
+    patterns.iter().any(|p| key.contains(&p.to_uppercase()))
+}
+
+/// The container's environment, with the values of secret variables redacted.
+pub fn redact_envs(envs: &[grpc::KeyValue], patterns: &[String]) -> serde_json::Value {
+    let redacted: HashMap<&str, &str> = envs
+        .iter()
+        .map(|pair| {
+            let value = if is_secret(&pair.key, patterns) {
+                REDACTED
+            } else {
+                pair.value.as_str()
+            };
+            (pair.key.as_str(), value)
+        })
+        .collect();
+    json!(redacted)
+}
+
+/// The values of the container's secret variables, as the module sees them, longest first.
+pub fn secret_values(envs: &[grpc::KeyValue], patterns: &[String]) -> Vec<String> {
+    let mut secrets: Vec<String> = expansion::expand_envs(envs)
+        .into_iter()
+        .filter(|(key, value)| is_secret(key, patterns) && value.len() >= MIN_SCRUBBED_LEN)
+        .map(|(_, value)| value)
+        .collect();
+    // a secret containing another one is scrubbed as a whole
+    secrets.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    secrets.dedup();
+    secrets
+}
+
+/// Replace the secret values in the message.
+pub fn scrub(message: &str, secrets: &[String]) -> String {
+    secrets.iter().fold(message.to_owned(), |message, secret| {
+        message.replace(secret.as_str(), REDACTED)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn envs() -> Vec<grpc::KeyValue> {
+        vec![
+            grpc::KeyValue {
+                key: "db_password".to_owned(),
+                value: "hunter22".to_owned(),
+            },
+            grpc::KeyValue {
+                key: "DB_URL".to_owned(),
+                value: "postgres://app:$(db_password)@db".to_owned(),
+            },
+            grpc::KeyValue {
+                key: "API_TOKEN".to_owned(),
+                value: "abc".to_owned(),
+            },
+        ]
+    }
+
+    fn patterns() -> Vec<String> {
+        vec!["PASSWORD".to_owned(), "TOKEN".to_owned()]
+    }
+
+    #[test]
+    fn test_redact_envs() {
+        assert_eq!(
+            json!({
+                "db_password": REDACTED,
+                "DB_URL": "postgres://app:$(db_password)@db",
+                "API_TOKEN": REDACTED,
+            }),
+            redact_envs(&envs(), &patterns())
+        );
+    }
+
+    #[test]
+    fn test_scrub() {
+        // the token is too short to be scrubbed
+        let secrets = secret_values(&envs(), &patterns());
+        assert_eq!(vec!["hunter22"], secrets);
+        assert_eq!(
+            "cannot connect to postgres://app:[REDACTED]@db",
+            scrub("cannot connect to postgres://app:hunter22@db", &secrets)
+        );
+        assert_eq!("nothing to see", scrub("nothing to see", &secrets));
+    }
+}
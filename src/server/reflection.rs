@@ -0,0 +1,279 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use futures::StreamExt;
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+use proto::server_reflection_request::MessageRequest;
+use proto::server_reflection_response::MessageResponse;
+use proto::{
+    ErrorResponse, FileDescriptorResponse, ListServiceResponse, ServerReflectionRequest,
+    ServerReflectionResponse, ServiceResponse,
+};
+
+// Tonic will autogenerate the module's body.
+pub mod proto {
+    tonic::include_proto!("grpc.reflection.v1alpha");
+}
+
+pub use proto::server_reflection_server::ServerReflectionServer;
+
+/// The descriptors of every proto served by wok, together with their imports.
+const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/descriptor.bin"));
+
+/// Implement the gRPC server reflection protocol for the services served by wok, so that tools
+/// like grpcurl can call them without having the protos at hand.
+#[derive(Clone, Debug)]
+pub struct ReflectionService {
+    inner: Arc<Descriptors>,
+}
+
+#[derive(Debug, Default)]
+struct Descriptors {
+    /// the fully qualified names of all services
+    services: Vec<String>,
+    /// the files, keyed by file name
+    files: HashMap<String, FileDescriptorProto>,
+    /// the name of the file declaring each fully qualified symbol
+    symbols: HashMap<String, String>,
+}
+
+impl ReflectionService {
+    pub fn new() -> Result<Self, failure::Error> {
+        Self::from_descriptor_set(FILE_DESCRIPTOR_SET)
+    }
+
+    fn from_descriptor_set(raw: &[u8]) -> Result<Self, failure::Error> {
+        let set = FileDescriptorSet::decode(raw)
+            .map_err(|e| format_err!("invalid file descriptor set: {}", e))?;
+
+        let mut descriptors = Descriptors::default();
+        for file in set.file {
+            let name = file.name.clone().unwrap_or_default();
+            let prefix = match file.package.as_ref() {
+                Some(package) if !package.is_empty() => format!("{}.", package),
+                _ => String::new(),
+            };
+
+            for service in &file.service {
+                let service_name = format!("{}{}", prefix, service.name());
+                for method in &service.method {
+                    descriptors
+                        .symbols
+                        .insert(format!("{}.{}", service_name, method.name()), name.clone());
+                }
+                descriptors
+                    .symbols
+                    .insert(service_name.clone(), name.clone());
+                descriptors.services.push(service_name);
+            }
+            for message in &file.message_type {
+                add_message_symbols(&mut descriptors.symbols, &prefix, message, &name);
+            }
+            for enum_type in &file.enum_type {
+                descriptors
+                    .symbols
+                    .insert(format!("{}{}", prefix, enum_type.name()), name.clone());
+            }
+            descriptors.files.insert(name, file);
+        }
+        descriptors.services.sort();
+
+        Ok(ReflectionService {
+            inner: Arc::new(descriptors),
+        })
+    }
+
+    fn handle(&self, req: ServerReflectionRequest) -> ServerReflectionResponse {
+        let response = match &req.message_request {
+            Some(MessageRequest::ListServices(_)) => {
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .inner
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse { name: name.clone() })
+                        .collect(),
+                })
+            }
+            Some(MessageRequest::FileByFilename(name)) => self.file_response(name),
+            Some(MessageRequest::FileContainingSymbol(symbol)) => {
+                match self.inner.symbols.get(symbol) {
+                    Some(name) => self.file_response(name),
+                    None => error_response(Code::NotFound, format!("symbol {} not found", symbol)),
+                }
+            }
+            Some(MessageRequest::FileContainingExtension(_))
+            | Some(MessageRequest::AllExtensionNumbersOfType(_)) => error_response(
+                Code::Unimplemented,
+                "extensions are not supported".to_owned(),
+            ),
+            None => error_response(Code::InvalidArgument, "empty request".to_owned()),
+        };
+
+        ServerReflectionResponse {
+            valid_host: req.host.clone(),
+            original_request: Some(req),
+            message_response: Some(response),
+        }
+    }
+
+    /// Respond with the named file and everything it imports, transitively.
+    fn file_response(&self, name: &str) -> MessageResponse {
+        if !self.inner.files.contains_key(name) {
+            return error_response(Code::NotFound, format!("file {} not found", name));
+        }
+
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![name.to_owned()];
+        let mut file_descriptor_proto = vec![];
+        while let Some(name) = pending.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            if let Some(file) = self.inner.files.get(&name) {
+                let mut buf = vec![];
+                // encoding into a Vec cannot run out of space
+                file.encode(&mut buf).expect("encoded file descriptor");
+                file_descriptor_proto.push(buf);
+                pending.extend(file.dependency.iter().cloned());
+            }
+        }
+
+        MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+            file_descriptor_proto,
+        })
+    }
+}
+
+fn add_message_symbols(
+    symbols: &mut HashMap<String, String>,
+    prefix: &str,
+    message: &DescriptorProto,
+    file: &str,
+) {
+    let name = format!("{}{}", prefix, message.name());
+    let nested_prefix = format!("{}.", name);
+    for nested in &message.nested_type {
+        add_message_symbols(symbols, &nested_prefix, nested, file);
+    }
+    for enum_type in &message.enum_type {
+        symbols.insert(
+            format!("{}{}", nested_prefix, enum_type.name()),
+            file.to_owned(),
+        );
+    }
+    symbols.insert(name, file.to_owned());
+}
+
+fn error_response(code: Code, error_message: String) -> MessageResponse {
+    MessageResponse::ErrorResponse(ErrorResponse {
+        error_code: code as i32,
+        error_message,
+    })
+}
+
+#[tonic::async_trait]
+impl proto::server_reflection_server::ServerReflection for ReflectionService {
+    type ServerReflectionInfoStream = mpsc::Receiver<Result<ServerReflectionResponse, Status>>;
+
+    async fn server_reflection_info(
+        &self,
+        req: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        let mut requests = req.into_inner();
+        let (mut tx, rx) = mpsc::channel(4);
+        let service = self.clone();
+
+        tokio::spawn(async move {
+            while let Some(req) = requests.next().await {
+                let response = req.map(|req| service.handle(req));
+                // stop once the client has gone away
+                if tx.send(response).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(message_request: MessageRequest) -> ServerReflectionRequest {
+        ServerReflectionRequest {
+            host: "".to_owned(),
+            message_request: Some(message_request),
+        }
+    }
+
+    #[test]
+    fn test_list_services() {
+        let service = ReflectionService::new().expect("descriptor set is valid");
+        match service
+            .handle(request(MessageRequest::ListServices("".to_owned())))
+            .message_response
+        {
+            Some(MessageResponse::ListServicesResponse(res)) => {
+                let names: Vec<_> = res.service.into_iter().map(|s| s.name).collect();
+                assert!(names.contains(&"runtime.v1alpha2.RuntimeService".to_owned()));
+                assert!(names.contains(&"runtime.v1alpha2.ImageService".to_owned()));
+                assert!(names.contains(&"grpc.reflection.v1alpha.ServerReflection".to_owned()));
+            }
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_file_containing_symbol() {
+        let service = ReflectionService::new().expect("descriptor set is valid");
+        for symbol in &[
+            "runtime.v1alpha2.RuntimeService",
+            "runtime.v1alpha2.RuntimeService.RunPodSandbox",
+            "runtime.v1alpha2.PodSandboxConfig",
+            "runtime.v1alpha2.PodSandboxState",
+        ] {
+            match service
+                .handle(request(MessageRequest::FileContainingSymbol(
+                    symbol.to_string(),
+                )))
+                .message_response
+            {
+                Some(MessageResponse::FileDescriptorResponse(res)) => {
+                    let files: Vec<_> = res
+                        .file_descriptor_proto
+                        .iter()
+                        .map(|raw| FileDescriptorProto::decode(raw.as_slice()).unwrap())
+                        .collect();
+                    assert_eq!(
+                        Some("runtime/v1alpha2/api.proto"),
+                        files[0].name.as_ref().map(String::as_str)
+                    );
+                    // the imports are sent along
+                    assert!(files
+                        .iter()
+                        .any(|f| f.name.as_ref().unwrap().ends_with("gogo.proto")));
+                }
+                res => panic!("unexpected response for {}: {:?}", symbol, res),
+            }
+        }
+
+        match service
+            .handle(request(MessageRequest::FileContainingSymbol(
+                "runtime.v1alpha2.Nope".to_owned(),
+            )))
+            .message_response
+        {
+            Some(MessageResponse::ErrorResponse(res)) => {
+                assert_eq!(Code::NotFound as i32, res.error_code)
+            }
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+}
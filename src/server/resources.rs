@@ -0,0 +1,181 @@
+//! The translation of the CRI's Linux container resources into constraints on a wasm module.
+//!
+//! | CRI field               | Translation                                                        |
+//! |-------------------------|--------------------------------------------------------------------|
+//! | `memory_limit_in_bytes` | the module's declared memories must fit into the limit            |
+//! | `cpu_period`            | unsupported: modules are not scheduled by the CFS                  |
+//! | `cpu_quota`             | unsupported: modules are not scheduled by the CFS                  |
+//! | `cpu_shares`            | unsupported: modules are not scheduled by the CFS                  |
+//! | `oom_score_adj`         | unsupported: modules run inside the wok process                    |
+//! | `cpuset_cpus`           | unsupported: modules run on the runtime's thread pool              |
+//! | `cpuset_mems`           | unsupported: modules run on the runtime's thread pool              |
+//! | `hugepage_limits`       | unsupported: linear memory does not use huge pages                 |
+//!
+//! The memory check is done against the module's declared memories before it starts. wasmtime does
+//! not let us cap memory growth at runtime yet, so a module without a declared maximum can still
+//! grow past the limit once it runs.
+
+use serde_json::json;
+use wasmparser::{ImportSectionEntryType, MemoryType, ModuleReader, SectionCode};
+
+use super::grpc;
+
+/// The size of a wasm memory page.
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// ResourcePolicy holds the constraints wok applies to a container, along with the requested
+/// resources it cannot apply.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourcePolicy {
+    /// the upper bound for the module's linear memories, in bytes
+    pub memory_limit: Option<u64>,
+    /// the requested resources that are not supported, by field name
+    pub unsupported: Vec<&'static str>,
+}
+
+impl ResourcePolicy {
+    /// Translate the requested resources into a policy.
+    pub fn from_resources(resources: &grpc::LinuxContainerResources) -> Self {
+        let mut unsupported = vec![];
+        let requested: &[(&'static str, bool)] = &[
+            ("cpu_period", resources.cpu_period != 0),
+            ("cpu_quota", resources.cpu_quota != 0),
+            ("cpu_shares", resources.cpu_shares != 0),
+            ("oom_score_adj", resources.oom_score_adj != 0),
+            ("cpuset_cpus", !resources.cpuset_cpus.is_empty()),
+            ("cpuset_mems", !resources.cpuset_mems.is_empty()),
+            ("hugepage_limits", !resources.hugepage_limits.is_empty()),
+        ];
+        for (field, set) in requested {
+            if *set {
+                unsupported.push(*field);
+            }
+        }
+
+        ResourcePolicy {
+            memory_limit: if resources.memory_limit_in_bytes > 0 {
+                Some(resources.memory_limit_in_bytes as u64)
+            } else {
+                None
+            },
+            unsupported,
+        }
+    }
+
+    /// Translate the resources of a container config, if it has any.
+    pub fn from_config(config: &grpc::ContainerConfig) -> Self {
+        config
+            .linux
+            .as_ref()
+            .and_then(|linux| linux.resources.as_ref())
+            .map(Self::from_resources)
+            .unwrap_or_default()
+    }
+
+    /// Check that the memories declared by the module fit into the policy.
+    pub fn check(&self, module: &[u8]) -> Result<(), failure::Error> {
+        let limit = match self.memory_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let required: u64 = declared_memories(module)?
+            .iter()
+            .map(|memory| u64::from(memory.limits.initial) * WASM_PAGE_SIZE)
+            .sum();
+        if required > limit {
+            return Err(format_err!(
+                "module needs {} bytes of memory, but the container is limited to {} bytes",
+                required,
+                limit
+            ));
+        }
+        Ok(())
+    }
+
+    /// Describe the policy for the verbose container status.
+    pub fn info(&self) -> serde_json::Value {
+        json!({
+            "applied": {
+                "memory_limit_in_bytes": self.memory_limit,
+            },
+            "unsupported": self.unsupported,
+        })
+    }
+}
+
+/// Read the memories a module defines or imports.
+fn declared_memories(module: &[u8]) -> Result<Vec<MemoryType>, failure::Error> {
+    let invalid = |e| format_err!("invalid wasm module: {:?}", e);
+    let mut memories = vec![];
+    let mut reader = ModuleReader::new(module).map_err(invalid)?;
+    while !reader.eof() {
+        let section = reader.read().map_err(invalid)?;
+        match section.code {
+            SectionCode::Memory => {
+                for memory in section.get_memory_section_reader().map_err(invalid)? {
+                    memories.push(memory.map_err(invalid)?);
+                }
+            }
+            SectionCode::Import => {
+                for import in section.get_import_section_reader().map_err(invalid)? {
+                    if let ImportSectionEntryType::Memory(memory) = import.map_err(invalid)?.ty {
+                        memories.push(memory);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(memories)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A module declaring a memory of `pages` pages.
+    fn module_with_memory(pages: u8) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // memory section: one memory with a minimum and no maximum
+        module.extend_from_slice(&[5, 3, 1, 0, pages]);
+        module
+    }
+
+    #[test]
+    fn test_from_resources() {
+        let policy = ResourcePolicy::from_resources(&grpc::LinuxContainerResources {
+            cpu_shares: 2,
+            memory_limit_in_bytes: 1024 * 1024,
+            cpuset_cpus: "0-1".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(Some(1024 * 1024), policy.memory_limit);
+        assert_eq!(vec!["cpu_shares", "cpuset_cpus"], policy.unsupported);
+
+        assert_eq!(
+            ResourcePolicy::default(),
+            ResourcePolicy::from_config(&grpc::ContainerConfig::default())
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = ResourcePolicy {
+            memory_limit: Some(2 * WASM_PAGE_SIZE),
+            ..Default::default()
+        };
+        policy
+            .check(&module_with_memory(2))
+            .expect("module fits into the limit");
+        policy
+            .check(&module_with_memory(3))
+            .expect_err("module needs more memory than allowed");
+        policy
+            .check(b"not wasm")
+            .expect_err("invalid modules are rejected");
+
+        ResourcePolicy::default()
+            .check(&module_with_memory(100))
+            .expect("no limit");
+    }
+}
@@ -0,0 +1,393 @@
+//! Restrictions of the WASI context of a container, the wasm analog to dropping Linux capabilities.
+//!
+//! A container lists what it does without in the `deislabs.io/wasi-deny` annotation, separated by
+//! commas, e.g. `env,clock,random`.
+//!
+//! | Capability | Restriction                                                                  |
+//! |------------|------------------------------------------------------------------------------|
+//! | `args`     | the module gets no command line arguments                                     |
+//! | `env`      | the module gets an empty environment                                          |
+//! | `fs`       | no host directories are preopened for the module                              |
+//! | `host-fs`  | the container may not mount host directories, see `server::mounts`            |
+//! | `clock`    | modules importing `clock_time_get`, `clock_res_get` or `poll_oneoff` are refused |
+//! | `random`   | modules importing `random_get` are refused                                    |
+//!
+//! Our wasmtime can't replace single WASI functions, so the clock and the random generator can't be
+//! taken away from a module that imports them. Such modules fail to start instead of running with
+//! capabilities they were denied.
+//!
+//! Rather than listing capabilities, a container can name a profile in the `deislabs.io/wasi-profile`
+//! annotation. The capabilities in `deislabs.io/wasi-deny` are denied on top of the profile's.
+//!
+//! | Profile      | Denied capabilities                   |
+//! |--------------|---------------------------------------|
+//! | `unconfined` | none                                  |
+//! | `baseline`   | `host-fs`                             |
+//! | `restricted` | `host-fs`, `env`, `clock`, `random`   |
+//!
+//! Containers without a profile get the node's `runtime.default_wasi_profile` when they are created,
+//! recorded in their annotation, so changing the default doesn't change the containers created before.
+//!
+//! Modules using wasi-threads, i.e. importing `wasi.thread-spawn`, are refused whatever the container's
+//! capabilities: our wasmtime can neither share a memory between instances nor run a store on more than one
+//! thread, so there is no way to spawn the threads they ask for.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use wasmparser::{ImportSectionEntryType, ModuleReader, SectionCode};
+
+use super::grpc;
+
+/// The container annotation listing the denied capabilities.
+pub const WASI_DENY_ANNOTATION: &str = "deislabs.io/wasi-deny";
+
+/// The container annotation naming the profile of denied capabilities.
+pub const WASI_PROFILE_ANNOTATION: &str = "deislabs.io/wasi-profile";
+
+/// The modules WASI functions are imported from.
+const WASI_MODULES: &[&str] = &["wasi_unstable", "wasi_snapshot_preview1"];
+
+/// The module and function wasi-threads modules spawn their threads with.
+const THREAD_SPAWN: (&str, &str) = ("wasi", "thread-spawn");
+
+/// A part of the WASI context that can be denied.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Capability {
+    Args,
+    Env,
+    Fs,
+    HostFs,
+    Clock,
+    Random,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::Args,
+        Capability::Env,
+        Capability::Fs,
+        Capability::HostFs,
+        Capability::Clock,
+        Capability::Random,
+    ];
+
+    /// The WASI functions a module denied the capability must not import.
+    fn functions(self) -> &'static [&'static str] {
+        match self {
+            Capability::Clock => &["clock_time_get", "clock_res_get", "poll_oneoff"],
+            Capability::Random => &["random_get"],
+            Capability::Args | Capability::Env | Capability::Fs | Capability::HostFs => &[],
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "args" => Ok(Capability::Args),
+            "env" => Ok(Capability::Env),
+            "fs" => Ok(Capability::Fs),
+            "host-fs" => Ok(Capability::HostFs),
+            "clock" => Ok(Capability::Clock),
+            "random" => Ok(Capability::Random),
+            _ => Err(format_err!(
+                "unknown WASI capability {:?}, expected one of args, env, fs, host-fs, clock, random",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Args => "args",
+            Capability::Env => "env",
+            Capability::Fs => "fs",
+            Capability::HostFs => "host-fs",
+            Capability::Clock => "clock",
+            Capability::Random => "random",
+        })
+    }
+}
+
+/// A named set of denied capabilities, so nodes can sandbox containers consistently without every pod listing
+/// capabilities.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// denies nothing
+    Unconfined,
+    /// keeps containers off the node's files
+    Baseline,
+    /// also takes away what makes a module's behavior depend on the node: its environment, the clock and
+    /// randomness
+    Restricted,
+}
+
+impl Profile {
+    /// The capabilities the profile denies.
+    pub fn denied(self) -> &'static [Capability] {
+        match self {
+            Profile::Unconfined => &[],
+            Profile::Baseline => &[Capability::HostFs],
+            Profile::Restricted => &[
+                Capability::HostFs,
+                Capability::Env,
+                Capability::Clock,
+                Capability::Random,
+            ],
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Unconfined
+    }
+}
+
+impl FromStr for Profile {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unconfined" => Ok(Profile::Unconfined),
+            "baseline" => Ok(Profile::Baseline),
+            "restricted" => Ok(Profile::Restricted),
+            _ => Err(format_err!(
+                "unknown WASI profile {:?}, expected one of unconfined, baseline, restricted",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Profile::Unconfined => "unconfined",
+            Profile::Baseline => "baseline",
+            Profile::Restricted => "restricted",
+        })
+    }
+}
+
+/// WasiRestrictions holds the capabilities denied to a container.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WasiRestrictions {
+    denied: BTreeSet<Capability>,
+}
+
+impl WasiRestrictions {
+    /// Read the restrictions from the container's annotations: the capabilities of its profile, and the ones it
+    /// denies on top.
+    pub fn from_config(config: &grpc::ContainerConfig) -> Result<Self, failure::Error> {
+        let mut denied: BTreeSet<Capability> = match config.annotations.get(WASI_PROFILE_ANNOTATION)
+        {
+            Some(profile) => Profile::from_str(profile.trim())
+                .map_err(|e| format_err!("invalid {} annotation: {}", WASI_PROFILE_ANNOTATION, e))?
+                .denied()
+                .iter()
+                .copied()
+                .collect(),
+            None => BTreeSet::new(),
+        };
+        if let Some(raw) = config.annotations.get(WASI_DENY_ANNOTATION) {
+            for capability in raw.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+                denied.insert(Capability::from_str(capability).map_err(|e| {
+                    format_err!("invalid {} annotation: {}", WASI_DENY_ANNOTATION, e)
+                })?);
+            }
+        }
+        Ok(WasiRestrictions { denied })
+    }
+
+    /// Whether the capability is denied. Denying `fs` denies `host-fs` too.
+    pub fn denies(&self, capability: Capability) -> bool {
+        self.denied.contains(&capability)
+            || (capability == Capability::HostFs && self.denied.contains(&Capability::Fs))
+    }
+
+    /// Check that the module doesn't import a WASI function it was denied.
+    pub fn check(&self, module: &[u8]) -> Result<(), failure::Error> {
+        if self.denied.iter().all(|c| c.functions().is_empty()) {
+            return Ok(());
+        }
+        for (module_name, field) in wasi_imports(module)? {
+            if let Some(capability) = self
+                .denied
+                .iter()
+                .find(|c| c.functions().contains(&field.as_str()))
+            {
+                return Err(format_err!(
+                    "module imports {}.{}, but the container is denied the {} capability",
+                    module_name,
+                    field,
+                    capability
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The denied capabilities, for the verbose container status.
+    pub fn info(&self) -> Vec<String> {
+        self.denied.iter().map(ToString::to_string).collect()
+    }
+}
+
+/// Check that the module doesn't need a WASI proposal we can't provide, i.e. wasi-threads.
+#[cfg_attr(not(feature = "wasi"), allow(dead_code))]
+pub fn check_supported(module: &[u8]) -> Result<(), failure::Error> {
+    let (module_name, field) = THREAD_SPAWN;
+    if function_imports(module, &[module_name])?
+        .iter()
+        .any(|(_, f)| f == field)
+    {
+        return Err(format_err!(
+            "module imports {}.{}, but wasi-threads is not supported",
+            module_name,
+            field
+        ));
+    }
+    Ok(())
+}
+
+/// Read the functions a module imports from WASI, as pairs of module and field name.
+fn wasi_imports(module: &[u8]) -> Result<Vec<(String, String)>, failure::Error> {
+    function_imports(module, WASI_MODULES)
+}
+
+/// Read the functions a module imports from the given modules, as pairs of module and field name.
+fn function_imports(
+    module: &[u8],
+    modules: &[&str],
+) -> Result<Vec<(String, String)>, failure::Error> {
+    let invalid = |e| format_err!("invalid wasm module: {:?}", e);
+    let mut imports = vec![];
+    let mut reader = ModuleReader::new(module).map_err(invalid)?;
+    while !reader.eof() {
+        let section = reader.read().map_err(invalid)?;
+        if let SectionCode::Import = section.code {
+            for import in section.get_import_section_reader().map_err(invalid)? {
+                let import = import.map_err(invalid)?;
+                if let ImportSectionEntryType::Function(_) = import.ty {
+                    if modules.contains(&import.module) {
+                        imports.push((import.module.to_owned(), import.field.to_owned()));
+                    }
+                }
+            }
+        }
+    }
+    Ok(imports)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A module importing the given function of `wasi_unstable`.
+    fn module_importing(field: &str) -> Vec<u8> {
+        module_importing_from("wasi_unstable", field)
+    }
+
+    /// A module importing the given function of the given module.
+    fn module_importing_from(module_name: &str, field: &str) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // type section: one type, () -> ()
+        module.extend_from_slice(&[1, 4, 1, 0x60, 0, 0]);
+        // import section: one function import of type 0
+        let mut import = vec![1, module_name.len() as u8];
+        import.extend_from_slice(module_name.as_bytes());
+        import.push(field.len() as u8);
+        import.extend_from_slice(field.as_bytes());
+        import.extend_from_slice(&[0, 0]);
+        module.push(2);
+        module.push(import.len() as u8);
+        module.extend(import);
+        module
+    }
+
+    fn config(deny: &str) -> grpc::ContainerConfig {
+        let mut config = grpc::ContainerConfig::default();
+        config
+            .annotations
+            .insert(WASI_DENY_ANNOTATION.to_owned(), deny.to_owned());
+        config
+    }
+
+    #[test]
+    fn test_from_config() {
+        let restrictions = WasiRestrictions::from_config(&config("env, random,")).unwrap();
+        assert!(restrictions.denies(Capability::Env));
+        assert!(restrictions.denies(Capability::Random));
+        assert!(!restrictions.denies(Capability::Fs));
+        assert_eq!(vec!["env", "random"], restrictions.info());
+        assert!(WasiRestrictions::from_config(&config("fs"))
+            .unwrap()
+            .denies(Capability::HostFs));
+
+        assert_eq!(
+            WasiRestrictions::default(),
+            WasiRestrictions::from_config(&grpc::ContainerConfig::default()).unwrap()
+        );
+        WasiRestrictions::from_config(&config("env,network"))
+            .expect_err("unknown capabilities are rejected");
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut config = config("args");
+        config
+            .annotations
+            .insert(WASI_PROFILE_ANNOTATION.to_owned(), "restricted".to_owned());
+        let restrictions = WasiRestrictions::from_config(&config).unwrap();
+        assert_eq!(
+            vec!["args", "env", "host-fs", "clock", "random"],
+            restrictions.info()
+        );
+
+        config
+            .annotations
+            .insert(WASI_PROFILE_ANNOTATION.to_owned(), "baseline".to_owned());
+        let restrictions = WasiRestrictions::from_config(&config).unwrap();
+        assert_eq!(vec!["args", "host-fs"], restrictions.info());
+
+        config
+            .annotations
+            .insert(WASI_PROFILE_ANNOTATION.to_owned(), "privileged".to_owned());
+        WasiRestrictions::from_config(&config).expect_err("unknown profiles are rejected");
+    }
+
+    #[test]
+    fn test_check() {
+        let restrictions = WasiRestrictions::from_config(&config("clock")).unwrap();
+        restrictions
+            .check(&module_importing("fd_write"))
+            .expect("fd_write is allowed");
+        let err = restrictions
+            .check(&module_importing("clock_time_get"))
+            .expect_err("clock_time_get is denied");
+        assert!(err.to_string().contains("wasi_unstable.clock_time_get"));
+
+        // restrictions that don't need a look at the module don't parse it
+        WasiRestrictions::from_config(&config("env"))
+            .unwrap()
+            .check(b"not wasm")
+            .expect("nothing to check");
+    }
+
+    #[test]
+    fn test_check_supported() {
+        check_supported(&module_importing("fd_write")).expect("plain WASI");
+        let err = check_supported(&module_importing_from("wasi", "thread-spawn"))
+            .expect_err("wasi-threads");
+        assert!(err.to_string().contains("wasi.thread-spawn"));
+    }
+}
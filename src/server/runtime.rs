@@ -1,24 +1,50 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::Utc;
 use ipnet::IpNet;
-use log::{error, info};
+use serde_json::json;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::sync::{Mutex, RwLock};
-use tokio::task::JoinHandle;
+use tokio::sync::{watch, Mutex, RwLock, Semaphore, SemaphorePermit};
 use tonic::{Request, Response, Status};
+use tracing::{debug, error, info, info_span, warn};
+use tracing_futures::Instrument;
 use uuid::Uuid;
 
 // RuntimeService is converted to a package runtime_service_server
+use super::backend::{unavailable_handler, Backends, RuntimeBackend};
+use super::conditions::Conditions;
+use super::events::{Event, Events};
 use super::grpc::{self, runtime_service_server::RuntimeService};
+use super::health::HealthChecks;
+use super::mounts;
+use super::policy::Policy;
+use super::proxy::{free_local_port, PortProxy};
+use super::redact;
+use super::resources::ResourcePolicy;
+use super::restrictions::{
+    Capability as WasiCapability, Profile as WasiProfile, WasiRestrictions, WASI_PROFILE_ANNOTATION,
+};
+use super::stats::{dir_usage, log_usage, Orphans, SandboxStats};
+use super::trace::{record_container_id, record_pod_sandbox_id};
 use super::CriResult;
+use crate::config::{HandlerAliasOptions, RuntimeOptions};
 use crate::docker::Reference;
-use crate::store::ModuleStore;
+use crate::store::{ModuleStore, ModuleStoreError, Pruned};
+#[cfg(feature = "wasi")]
+use crate::wasm::pool::WarmInstance;
 use crate::wasm::wascc::*;
-use crate::wasm::{Result, Runtime};
+#[cfg(feature = "wapc")]
+use crate::wasm::WapcGuest;
+#[cfg(feature = "wasi")]
+use crate::wasm::WasiRuntime;
+use crate::wasm::{EngineConfig, LogLine, OutputBroker, Result, Runtime, WarmPool, WarmPoolStats};
 
 /// The version of the runtime API that this tool knows.
 /// See CRI-O for reference (since docs don't explain this)
@@ -27,11 +53,58 @@ const RUNTIME_API_VERSION: &str = "v1alpha2";
 /// The API version of this CRI plugin.
 const API_VERSION: &str = "0.1.0";
 
-/// The Actor's public key is a required annotation on a container that runs a waSCC actor.
+/// An optional annotation pinning the public key of a container that runs a waSCC actor.
 ///
-/// The key is used to verify that the WASM that is retrieved is signed by the correct
-/// signing key.
-const ACTOR_KEY_ANNOTATION: &str = "deislabs.io/actor-key";
+/// The key itself is read from the signed module. When this annotation is set, it is used to verify
+/// that the WASM that is retrieved is signed by the correct signing key.
+#[cfg(feature = "wascc")]
+pub(crate) const ACTOR_KEY_ANNOTATION: &str = "deislabs.io/actor-key";
+
+/// Capability configuration for a waSCC actor.
+///
+/// The value is a JSON object mapping each capability ID the actor binds to onto the configuration
+/// values for that capability, e.g. `{"wascc:keyvalue": {"URL": "redis://127.0.0.1:6379"}}`.
+#[cfg(feature = "wascc")]
+pub(crate) const CAPABILITIES_ANNOTATION: &str = "deislabs.io/capabilities";
+
+/// An optional annotation naming the operation of a waSCC actor or waPC guest wok invokes periodically, with an empty
+/// payload, to check its health, e.g. `HealthRequest`. The check fails when the operation returns an error.
+const HEALTH_CHECK_ANNOTATION: &str = "deislabs.io/health-check";
+
+/// An optional sandbox annotation listing modules to pull as soon as the sandbox is created, separated by commas,
+/// so they are in the store by the time the kubelet creates the sandbox's containers.
+const PREPULL_ANNOTATION: &str = "deislabs.io/prepull";
+
+/// An optional annotation overriding the runtime handler of the sandbox for a single container, e.g. to run a WASI
+/// sidecar next to waSCC actors.
+const RUNTIME_HANDLER_ANNOTATION: &str = "deislabs.io/runtime-handler";
+
+/// An optional annotation limiting how many seconds a WASI container may run, e.g. for Job-style workloads. The
+/// container is reported as exited with the `DeadlineExceeded` reason once the deadline passes.
+const DEADLINE_ANNOTATION: &str = "deislabs.io/deadline-seconds";
+
+/// An optional annotation setting the size of the stack a WASI container runs on, in bytes or with a `Ki` or `Mi`
+/// suffix, e.g. `8Mi` for a deeply recursive module. It overrides `runtime.wasm_stack_size` and may not exceed
+/// `runtime.max_wasm_stack_size`.
+const WASM_STACK_SIZE_ANNOTATION: &str = "deislabs.io/wasm-stack-size";
+
+/// The smallest stack a container may run on. wasmtime runs on the same stack as the module, and it overflowing
+/// there would abort wok rather than trap.
+const MIN_WASM_STACK_SIZE: usize = 1024 * 1024;
+
+/// An optional sandbox annotation enabling WebAssembly proposals for the WASI modules of the sandbox, separated by
+/// commas, e.g. `simd,threads`. See `EngineConfig` for the supported features.
+const WASM_FEATURES_ANNOTATION: &str = "deislabs.io/wasm-features";
+
+/// An optional container or sandbox annotation raising the level wok logs the container's lifecycle at, e.g.
+/// `debug`, without raising it for every other workload. The container's annotation takes precedence over its
+/// sandbox's. It only has an effect when the log filter can be changed at runtime.
+const LOG_LEVEL_ANNOTATION: &str = "deislabs.io/log-level";
+
+/// An optional annotation listing container paths to back with scratch directories in memory, separated by commas,
+/// e.g. `/tmp,/cache`, like emptyDir volumes with the Memory medium. The directories are created below
+/// `runtime.memory_dir`, count towards the container's memory usage and are removed with the container.
+const MEMORY_DIRS_ANNOTATION: &str = "deislabs.io/memory-dirs";
 
 /// UserContainer is an internal mapping between the Container and the ContainerConfig objects provided by the kubelet.
 /// We use this to map between what the CRI requested and what we created. (e.g. the volume mount mappings between
@@ -39,22 +112,26 @@ const ACTOR_KEY_ANNOTATION: &str = "deislabs.io/actor-key";
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct UserContainer {
     /// the container ID.
-    id: String,
+    pub(crate) id: String,
     /// the pod sandbox ID this container belongs to.
     pod_sandbox_id: String,
     /// the resolved image reference.
-    image_ref: String,
+    pub(crate) image_ref: String,
     /// the time this container was created, in nanoseconds.
     created_at: i64,
     /// the container's current state.
     state: i32,
     /// the CRI container config.
-    config: grpc::ContainerConfig,
+    pub(crate) config: grpc::ContainerConfig,
     /// Absolute path for the container to store the logs (STDOUT and STDERR) on the host.
     ///
     /// If the log_path is None, logging is disabled, either because the sandbox or the container did not specify a log path.
-    log_path: Option<PathBuf>,
-    /// volume paths for the container. host_path is a relative filepath from the container's root directory to the volume mount.
+    pub(crate) log_path: Option<PathBuf>,
+    /// the symlink to the log file in the legacy log directory, if one was made.
+    legacy_log_link: Option<PathBuf>,
+    /// volume paths for the container. host_path is a relative filepath from the container's root directory to the volume mount,
+    /// or the absolute path of the host directory preopened for a hostPath mount, see `server::mounts`, or for a scratch
+    /// directory in memory.
     /// container_path is the filepath specified from the container config's requested volume. This is used to map between the
     /// volume and the requested host_path/container_path.
     ///
@@ -62,7 +139,42 @@ pub struct UserContainer {
     ///     volumes = vec![Mount{container_path: "/app", host_path: "volumes/aaaa-bbbb-cccc-dddd", ...}]
     ///     config.mounts[0].container_path = "/app"
     ///     config.mounts[0].host_path = "/tmp/app"
-    volumes: Vec<grpc::Mount>,
+    pub(crate) volumes: Vec<grpc::Mount>,
+    /// the host directory backing the container's working directory, below the container's root directory. None
+    /// when the container config has no working directory.
+    pub(crate) working_dir: Option<PathBuf>,
+    /// the directory below `runtime.memory_dir` holding the container's scratch directories in memory, which are
+    /// among its volumes. None when the container asks for none.
+    memory_dir: Option<PathBuf>,
+    /// the constraints translated from the requested Linux resources.
+    resources: ResourcePolicy,
+}
+
+/// Accessors for backends built outside of wok, see `server::backend`.
+impl UserContainer {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn pod_sandbox_id(&self) -> &str {
+        &self.pod_sandbox_id
+    }
+
+    pub fn image_ref(&self) -> &str {
+        &self.image_ref
+    }
+
+    pub fn config(&self) -> &grpc::ContainerConfig {
+        &self.config
+    }
+
+    pub fn log_path(&self) -> Option<&Path> {
+        self.log_path.as_deref()
+    }
+
+    pub fn working_dir(&self) -> Option<&Path> {
+        self.working_dir.as_deref()
+    }
 }
 
 impl From<UserContainer> for grpc::Container {
@@ -95,8 +207,7 @@ impl From<UserContainer> for grpc::ContainerStats {
             // handle errors from trying to get the data
             cpu: None,
             memory: None,
-            // We don't have an attached filesystem like containers do, so this
-            // shouldn't matter.
+            // the usage of the container's directory, filled in by `CriRuntimeService::stats_of`
             writable_layer: None,
         }
     }
@@ -122,38 +233,1549 @@ impl From<grpc::PodSandbox> for grpc::PodSandboxStatus {
 struct UserSandbox {
     inner: grpc::PodSandbox,
     running_containers: Vec<String>,
+    /// the port mappings requested for the sandbox.
+    port_mappings: Vec<grpc::PortMapping>,
+    /// the container port each waSCC HTTP actor in the sandbox listens on, keyed by container ID.
+    http_ports: HashMap<String, u16>,
+    /// the local port each waSCC HTTP actor actually listens on when ports are isolated, keyed by container ID.
+    local_ports: HashMap<String, u16>,
+    /// the directory the logs of the sandbox's containers are written to, if logging is enabled.
+    log_directory: Option<PathBuf>,
+    /// the config the sandbox was created with.
+    config: grpc::PodSandboxConfig,
+}
+
+impl UserSandbox {
+    /// Find the first TCP port mapping of the sandbox that isn't already served by one of its containers.
+    fn free_http_port(&self) -> Option<u16> {
+        self.port_mappings
+            .iter()
+            .filter(|p| p.protocol == grpc::Protocol::Tcp as i32 && p.container_port > 0)
+            .map(|p| p.container_port as u16)
+            .find(|port| !self.http_ports.values().any(|p| p == port))
+    }
+
+    /// Describe the sandbox for the verbose sandbox status, given the ID and state of each of its containers.
+    fn info(&self, containers: &[(String, i32)], ip: Option<&str>) -> serde_json::Value {
+        let config = &self.config;
+        json!({
+            "id": self.inner.id,
+            "runtimeHandler": self.inner.runtime_handler,
+            "containers": containers
+                .iter()
+                .map(|(id, state)| json!({
+                    "id": id,
+                    "state": grpc::ContainerState::from_i32(*state)
+                        .map(|state| format!("{:?}", state)),
+                }))
+                .collect::<Vec<_>>(),
+            "ip": ip,
+            "config": {
+                "metadata": config.metadata.as_ref().map(|m| json!({
+                    "name": m.name,
+                    "namespace": m.namespace,
+                    "uid": m.uid,
+                    "attempt": m.attempt,
+                })),
+                "hostname": config.hostname,
+                "logDirectory": config.log_directory,
+                "portMappings": config
+                    .port_mappings
+                    .iter()
+                    .map(|p| json!({
+                        "protocol": p.protocol,
+                        "containerPort": p.container_port,
+                        "hostPort": p.host_port,
+                        "hostIp": p.host_ip,
+                    }))
+                    .collect::<Vec<_>>(),
+                "labels": config.labels,
+                "annotations": config.annotations,
+            },
+        })
+    }
+}
+
+/// Implement a CRI runtime service.
+///
+/// Cloning the service is cheap and gives another handle on the same state, e.g. for shutting it down
+/// after the service itself has been handed to the gRPC server.
+#[derive(Clone, Debug, Default)]
+pub struct CriRuntimeService {
+    module_store: Arc<Mutex<ModuleStore>>,
+    // NOTE: we could replace this with evmap or crossbeam
+    sandboxes: Arc<RwLock<BTreeMap<String, UserSandbox>>>,
+    containers: Arc<RwLock<HashMap<String, UserContainer>>>,
+    running_containers: Arc<RwLock<HashMap<String, ContainerCancellationToken>>>,
+    /// the IDs of the containers that are being started.
+    starting: Arc<Mutex<HashSet<String>>>,
+    /// the permits a start holds while it runs, if the number of concurrent starts is limited.
+    start_permits: Option<Arc<Semaphore>>,
+    /// the permits a sandbox creation holds while it runs, if the number of concurrent creations is limited.
+    sandbox_permits: Option<Arc<Semaphore>>,
+    /// the number of sandbox creations waiting for a permit.
+    sandbox_queue: Arc<AtomicUsize>,
+    pod_cidr: Arc<RwLock<Option<IpNet>>>,
+    options: Arc<RwLock<RuntimeOptions>>,
+    log_filter: Option<LogFilterHandle>,
+    conditions: Conditions,
+    warm_pool: WarmPool,
+    policy: Arc<Policy>,
+    events: Events,
+    backends: Arc<Backends>,
+    /// whether containers serve HTTP on local ports of their own, behind proxies
+    isolate_ports: bool,
+    /// the proxies forwarding host ports to the containers, keyed by container ID
+    proxies: Arc<Mutex<HashMap<String, Vec<PortProxy>>>>,
+    health: HealthChecks,
+    /// the brokers of the containers' output, keyed by container ID
+    outputs: Arc<RwLock<HashMap<String, OutputBroker>>>,
+    /// the directories of unknown containers removed when wok started
+    orphans: Arc<RwLock<Orphans>>,
+}
+
+impl CriRuntimeService {
+    pub async fn new(dir: PathBuf, pod_cidr: Option<IpNet>) -> Self {
+        Self::with_options(dir, pod_cidr, RuntimeOptions::default()).await
+    }
+
+    pub async fn with_options(
+        dir: PathBuf,
+        pod_cidr: Option<IpNet>,
+        options: RuntimeOptions,
+    ) -> Self {
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .expect("cannot create root directory for runtime service");
+        let conditions = Conditions::default();
+        CriRuntimeService {
+            module_store: Arc::new(Mutex::new(ModuleStore::new(dir).await)),
+            sandboxes: Arc::new(RwLock::new(BTreeMap::default())),
+            containers: Arc::new(RwLock::new(HashMap::new())),
+            running_containers: Arc::new(RwLock::new(HashMap::new())),
+            starting: Arc::new(Mutex::new(HashSet::new())),
+            start_permits: match options.max_concurrent_starts {
+                0 => None,
+                permits => Some(Arc::new(Semaphore::new(permits))),
+            },
+            sandbox_permits: match options.max_concurrent_sandbox_creations {
+                0 => None,
+                permits => Some(Arc::new(Semaphore::new(permits))),
+            },
+            sandbox_queue: Arc::default(),
+            pod_cidr: Arc::new(RwLock::new(pod_cidr)),
+            warm_pool: match options.wasm_stack_size {
+                0 => WarmPool::new(options.warm_pool_size),
+                stack_size => WarmPool::new(options.warm_pool_size).with_stack_size(stack_size),
+            }
+            .with_max_modules(options.warm_pool_max_modules),
+            log_filter: None,
+            health: HealthChecks::new(
+                conditions.clone(),
+                options.health_check_interval_secs,
+                options.health_check_failure_threshold,
+            ),
+            conditions,
+            policy: Arc::new(Policy::default()),
+            events: Events::default(),
+            backends: Arc::new(
+                options
+                    .disabled_handlers
+                    .iter()
+                    .fold(Backends::default(), |backends, name| backends.without(name)),
+            ),
+            options: Arc::new(RwLock::new(options)),
+            isolate_ports: false,
+            proxies: Arc::default(),
+            outputs: Arc::default(),
+            orphans: Arc::default(),
+        }
+    }
+
+    /// A handle on the conditions reported by `status`, for the subsystems to report their state.
+    pub fn conditions(&self) -> Conditions {
+        self.conditions.clone()
+    }
+
+    /// Let `update_runtime_config` change the daemon's log filter through the given handle.
+    pub fn with_log_filter(mut self, log_filter: LogFilterHandle) -> Self {
+        self.log_filter = Some(log_filter);
+        self
+    }
+
+    /// Read modules from the given store, usually the image service's, so a store partitioned by namespace
+    /// gives each container the modules pulled into its pod's namespace.
+    pub fn with_module_store(mut self, module_store: ModuleStore) -> Self {
+        self.module_store = Arc::new(Mutex::new(module_store));
+        self
+    }
+
+    /// Restrict what the containers of each pod may use to what the node's policy allows.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Run containers with the given backends instead of the built-in ones.
+    pub fn with_backends(mut self, backends: Backends) -> Self {
+        self.backends = Arc::new(backends);
+        self
+    }
+
+    /// Let containers serve HTTP on local ports of their own, and forward the host ports of their pods there.
+    pub fn with_port_isolation(mut self, isolate_ports: bool) -> Self {
+        self.isolate_ports = isolate_ports;
+        self
+    }
+
+    /// The backends containers run with, by runtime handler.
+    pub fn backends(&self) -> &Backends {
+        &self.backends
+    }
+
+    /// Tell the given sinks about sandboxes being created and containers starting and exiting.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// A handle on the event sinks, e.g. for the image service to report failed pulls.
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    /// The broker the output of the container's module goes to, writing it to the container's CRI log file and to
+    /// the live subscribers of `follow_logs`. Backends call it when they start the container.
+    pub async fn output_broker(&self, container: &UserContainer) -> Result<OutputBroker, Status> {
+        let mut outputs = self.outputs.write().await;
+        if let Some(output) = outputs.get(&container.id) {
+            return Ok(output.clone());
+        }
+        let output = OutputBroker::new(container.log_path.as_deref())
+            .map_err(|e| Status::internal(format!("cannot open the container's log: {}", e)))?;
+        outputs.insert(container.id.clone(), output.clone());
+        Ok(output)
+    }
+
+    /// The log the container wrote so far, and the lines it writes from now on until its module returns. None if
+    /// the container wasn't started.
+    pub async fn follow_logs(
+        &self,
+        id: &str,
+    ) -> Option<std::io::Result<(Vec<u8>, futures::channel::mpsc::UnboundedReceiver<LogLine>)>>
+    {
+        let output = self.outputs.read().await.get(id).cloned()?;
+        Some(output.follow())
+    }
+
+    /// A handle on the pool of precompiled modules, e.g. for the image service to warm modules as soon as they
+    /// are pulled.
+    pub fn warm_pool(&self) -> WarmPool {
+        self.warm_pool.clone()
+    }
+
+    /// The options currently in effect.
+    pub async fn options(&self) -> RuntimeOptions {
+        self.options.read().await.clone()
+    }
+
+    /// Compile instances of the module into the warm pool, so that starting the container doesn't have to.
+    fn warm_in_background(&self, image_ref: &str, namespace: &str, engine_config: EngineConfig) {
+        let module_store = self.module_store.clone();
+        let namespace = namespace.to_owned();
+        let warm_pool = self.warm_pool.clone();
+        let image_ref = image_ref.to_owned();
+        tokio::spawn(
+            async move {
+                let module = match Reference::try_from(image_ref.clone()) {
+                    Ok(reference) => match module_store.lock().await.namespace(&namespace).await {
+                        Ok(store) => store.read(&reference).await.map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    },
+                    Err(e) => Err(e.to_string()),
+                };
+                match module {
+                    Ok(module) => {
+                        warm_pool
+                            .warm(&image_ref, engine_config, Arc::new(module))
+                            .await
+                    }
+                    Err(e) => debug!("not warming {}: {}", image_ref, e),
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Stop all running containers, giving them up to `timeout` to exit.
+    ///
+    /// This is meant to be called once the server stopped serving requests. The containers aren't locked while
+    /// they exit, so the calls still in flight don't wait on them.
+    ///
+    /// TODO: persist the sandboxes and containers, so the next wok can report them to the kubelet. There is nothing
+    /// to restore them from yet: the modules can't be checkpointed, see `WasiRuntime::run_compiled`, so containers
+    /// don't survive a restart, and the directories they leave behind are removed by `remove_orphaned_dirs`.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let tokens: Vec<ContainerCancellationToken> = self
+            .running_containers
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect();
+        info!("stopping {} running containers", tokens.len());
+        for token in &tokens {
+            token.stop();
+        }
+
+        let exits = futures::future::join_all(tokens.iter().map(|t| t.exited()));
+        if tokio::time::timeout(timeout, exits).await.is_err() {
+            warn!(
+                "not all containers exited within {:?}, abandoning them",
+                timeout
+            );
+        }
+        self.running_containers.write().await.clear();
+    }
+
+    /// Remove the modules no container uses from the store and the stores of all namespaces.
+    ///
+    /// The containers are locked meanwhile, so none is created from a module being removed.
+    pub async fn prune_images(&self) -> std::result::Result<Pruned, ModuleStoreError> {
+        let containers = self.containers.read().await;
+        let in_use: HashSet<String> = containers.values().map(|c| c.image_ref.clone()).collect();
+        let mut store = self.module_store.lock().await.clone();
+        let mut pruned = store.prune(&in_use).await?;
+        for (_, mut namespace) in store.namespaces().await {
+            pruned.extend(namespace.prune(&in_use).await?);
+        }
+        drop(containers);
+        for image_ref in &pruned.removed {
+            self.warm_pool.evict(image_ref).await;
+        }
+        info!(
+            "pruned {} modules, reclaiming {} bytes",
+            pruned.removed.len(),
+            pruned.reclaimed_bytes
+        );
+        Ok(pruned)
+    }
+
+    /// Make the module stored for `source` available under `target` too, in the store of the given namespace, or
+    /// the node's store if it is empty.
+    pub async fn tag_image(
+        &self,
+        source: &Reference,
+        target: &Reference,
+        namespace: &str,
+    ) -> std::result::Result<grpc::Image, ModuleStoreError> {
+        let mut store = self.module_store.lock().await.namespace(namespace).await?;
+        let module = store.tag(source, target).await?;
+        // instances warmed for a module tagged over are stale
+        self.warm_pool.evict(target.whole()).await;
+        Ok(module)
+    }
+
+    /// The number of running instances of each kind, e.g. `{"wasi": 2, "wascc": 1, "wapc": 0}`.
+    async fn instance_counts(&self) -> serde_json::Value {
+        let mut wasi = 0;
+        let mut wascc = 0;
+        #[cfg_attr(not(feature = "wapc"), allow(unused_mut))]
+        let mut wapc = 0;
+        for token in self.running_containers.read().await.values() {
+            let running = token.exit_state() == Some(ExitState::Running);
+            match token {
+                ContainerCancellationToken::WasccCancelationToken(_) => wascc += 1,
+                ContainerCancellationToken::WasiCancelationToken(_) if running => wasi += 1,
+                #[cfg(feature = "wapc")]
+                ContainerCancellationToken::WapcCancelationToken(..) if running => wapc += 1,
+                _ => {}
+            }
+        }
+        json!({ "wasi": wasi, "wascc": wascc, "wapc": wapc })
+    }
+
+    /// Remove the directories of the containers wok doesn't know about, e.g. because it crashed before removing
+    /// them: their root directories below `containers`, which hold their volumes, and their scratch directories in
+    /// memory. Only directories named like container IDs are removed, as `runtime.memory_dir` may be shared with
+    /// other programs. It is meant to be called when wok starts, before any container is created.
+    pub async fn remove_orphaned_dirs(&self) -> Orphans {
+        let root_dir = self.module_store.lock().await.root_dir().clone();
+        let memory_dir = self.options.read().await.memory_dir.clone();
+        let mut known = HashSet::new();
+        for container in self.containers.read().await.values() {
+            known.insert(root_dir.join("containers").join(&container.id));
+            known.insert(memory_dir.join(&container.id));
+        }
+        let parents = vec![root_dir.join("containers"), memory_dir];
+        let dirs = tokio::task::spawn_blocking(move || orphaned_dirs(&parents, &known))
+            .await
+            .unwrap();
+
+        let mut orphans = Orphans::default();
+        for dir in dirs {
+            let (bytes, _) = dir_usage(dir.clone()).await;
+            match tokio::fs::remove_dir_all(&dir).await {
+                Ok(()) => {
+                    debug!("removed orphaned directory {}", dir.display());
+                    orphans.reclaimed_bytes += bytes;
+                    orphans.removed.push(dir);
+                }
+                Err(e) => warn!("cannot remove orphaned directory {}: {}", dir.display(), e),
+            }
+        }
+        if !orphans.removed.is_empty() {
+            info!(
+                "removed {} orphaned directories, reclaiming {} bytes",
+                orphans.removed.len(),
+                orphans.reclaimed_bytes
+            );
+        }
+        *self.orphans.write().await = orphans.clone();
+        orphans
+    }
+
+    /// The directories of unknown containers removed when wok started.
+    pub async fn orphans(&self) -> Orphans {
+        self.orphans.read().await.clone()
+    }
+
+    /// How useful the warm pool was since wok started.
+    pub async fn warm_pool_stats(&self) -> WarmPoolStats {
+        self.warm_pool.stats().await
+    }
+
+    /// The usage of every pod sandbox, summed over its containers.
+    pub async fn sandbox_stats(&self) -> Vec<SandboxStats> {
+        let sandboxes: Vec<grpc::PodSandbox> = self
+            .sandboxes
+            .read()
+            .await
+            .values()
+            .map(|s| s.inner.clone())
+            .collect();
+        let mut stats = Vec::with_capacity(sandboxes.len());
+        for sandbox in sandboxes {
+            stats.push(self.stats_of_sandbox(&sandbox).await);
+        }
+        stats
+    }
+
+    /// The usage of the sandbox, summed over its containers, including the exited ones, and the module instances
+    /// still running in it.
+    async fn stats_of_sandbox(&self, sandbox: &grpc::PodSandbox) -> SandboxStats {
+        let containers: Vec<UserContainer> = self
+            .containers
+            .read()
+            .await
+            .values()
+            .filter(|c| c.pod_sandbox_id == sandbox.id)
+            .cloned()
+            .collect();
+        let mut stats = SandboxStats::new(sandbox);
+        stats.instances = {
+            let tokens = self.running_containers.read().await;
+            containers
+                .iter()
+                .filter_map(|c| tokens.get(&c.id))
+                .filter(|t| t.exit_state().map_or(true, |s| s == ExitState::Running))
+                .count() as u64
+        };
+        for container in containers {
+            stats.add(&self.stats_of(container).await);
+        }
+        stats
+    }
+
+    /// The stats of the container, with the usage of its directory and of its log, rotated files included, as its
+    /// writable layer. The kubelet evicts pods under disk pressure by this usage, so chatty modules count too.
+    async fn stats_of(&self, container: UserContainer) -> grpc::ContainerStats {
+        let root_dir = self.container_root_dir(&container.id).await;
+        let (mut used_bytes, mut inodes_used) = dir_usage(root_dir.clone()).await;
+        if let Some(log_path) = &container.log_path {
+            let (log_bytes, log_files) = log_usage(log_path.clone()).await;
+            used_bytes += log_bytes;
+            inodes_used += log_files;
+        }
+        let container_memory_dir = container.memory_dir.clone();
+        let mut stats = grpc::ContainerStats::from(container);
+        stats.writable_layer = Some(grpc::FilesystemUsage {
+            timestamp: Utc::now().timestamp_nanos(),
+            fs_id: Some(grpc::FilesystemIdentifier {
+                mountpoint: root_dir.to_string_lossy().into_owned(),
+            }),
+            used_bytes: Some(grpc::UInt64Value { value: used_bytes }),
+            inodes_used: Some(grpc::UInt64Value { value: inodes_used }),
+        });
+        // files in memory take memory, as they do in the memory cgroup of a container with emptyDir volumes
+        if let Some(memory_dir) = container_memory_dir {
+            let (memory_bytes, _) = dir_usage(memory_dir).await;
+            stats.memory = Some(grpc::MemoryUsage {
+                timestamp: Utc::now().timestamp_nanos(),
+                working_set_bytes: Some(grpc::UInt64Value {
+                    value: memory_bytes,
+                }),
+            });
+        }
+        stats
+    }
+
+    /// The directory holding the files of the container, e.g. its working directory and volumes.
+    async fn container_root_dir(&self, id: &str) -> PathBuf {
+        self.module_store
+            .lock()
+            .await
+            .root_dir()
+            .join("containers")
+            .join(id)
+    }
+
+    /// Dump the runtime's internal state for debugging.
+    ///
+    /// Each map is locked on its own, so the dump is not a consistent snapshot when requests are
+    /// being served at the same time.
+    pub async fn dump(&self) -> serde_json::Value {
+        let sandboxes: Vec<_> = self
+            .sandboxes
+            .read()
+            .await
+            .values()
+            .map(|s| {
+                json!({
+                    "id": s.inner.id,
+                    "metadata": s.inner.metadata.as_ref().map(|m| json!({
+                        "name": m.name,
+                        "namespace": m.namespace,
+                        "uid": m.uid,
+                        "attempt": m.attempt,
+                    })),
+                    "state": grpc::PodSandboxState::from_i32(s.inner.state)
+                        .map(|state| format!("{:?}", state)),
+                    "runtime_handler": s.inner.runtime_handler,
+                    "created_at": s.inner.created_at,
+                    "containers": s.running_containers,
+                    "http_ports": s.http_ports,
+                })
+            })
+            .collect();
+
+        let tokens: HashMap<String, serde_json::Value> = self
+            .running_containers
+            .read()
+            .await
+            .iter()
+            .map(|(id, token)| (id.clone(), token.dump()))
+            .collect();
+
+        let containers: Vec<_> = self
+            .containers
+            .read()
+            .await
+            .values()
+            .map(|c| {
+                json!({
+                    "id": c.id,
+                    "pod_sandbox_id": c.pod_sandbox_id,
+                    "name": c.config.metadata.as_ref().map(|m| m.name.clone()),
+                    "image_ref": c.image_ref,
+                    "state": grpc::ContainerState::from_i32(c.state)
+                        .map(|state| format!("{:?}", state)),
+                    "created_at": c.created_at,
+                    "log_path": c.log_path,
+                    "token": tokens.get(&c.id),
+                })
+            })
+            .collect();
+
+        let warm_pool = self.warm_pool.counts().await;
+        json!({
+            "sandboxes": sandboxes,
+            "containers": containers,
+            "pod_cidr": self.pod_cidr.read().await.map(|cidr| cidr.to_string()),
+            "warm_pool": warm_pool,
+            "warm_pool_stats": self.warm_pool.stats().await,
+            "start_permits_available": self.start_permits.as_ref().map(|p| p.available_permits()),
+            "sandbox_permits_available": self.sandbox_permits.as_ref().map(|p| p.available_permits()),
+            "sandbox_queue": self.sandbox_queue.load(Ordering::SeqCst),
+        })
+    }
+
+    /// Wait for a permit to start a container, if the number of concurrent starts is limited. The permit is
+    /// returned when it is dropped.
+    async fn start_permit(&self) -> std::result::Result<Option<SemaphorePermit<'_>>, Status> {
+        let permits = match &self.start_permits {
+            Some(permits) => permits,
+            None => return Ok(None),
+        };
+        let wait = Duration::from_secs(self.options.read().await.start_queue_timeout_secs);
+        match tokio::time::timeout(wait, permits.acquire()).await {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(Status::resource_exhausted(format!(
+                "too many containers are starting, gave up waiting after {}s",
+                wait.as_secs()
+            ))),
+        }
+    }
+
+    /// Wait for a permit to create a sandbox, if the number of concurrent creations is limited. Fails right away
+    /// when the admission queue is full, so a burst of creations is pushed back to the kubelet instead of piling
+    /// up. The permit is returned when it is dropped.
+    async fn sandbox_permit(&self) -> std::result::Result<Option<SemaphorePermit<'_>>, Status> {
+        let permits = match &self.sandbox_permits {
+            Some(permits) => permits,
+            None => return Ok(None),
+        };
+        if let Ok(permit) = permits.try_acquire() {
+            return Ok(Some(permit));
+        }
+        let queue_size = self.options.read().await.sandbox_queue_size;
+        let queued = Queued::new(&self.sandbox_queue);
+        if queued.position >= queue_size {
+            return Err(Status::resource_exhausted(format!(
+                "too many sandboxes are being created, {} are waiting already",
+                queue_size
+            )));
+        }
+        let permit = permits.acquire().await;
+        drop(queued);
+        Ok(Some(permit))
+    }
+
+    /// Check that the node's policy allows the container to run in the sandbox with the given config and handler.
+    fn check_policy(
+        &self,
+        sandbox_config: &grpc::PodSandboxConfig,
+        config: &grpc::ContainerConfig,
+        sandbox_handler: &str,
+    ) -> std::result::Result<(), Status> {
+        self.backend(container_runtime_handler(config, sandbox_handler))?
+            .check_policy(&self.policy, sandbox_config, config)
+    }
+
+    /// Log the container's lifecycle at the given level, or at the daemon's level again if None. A log filter that
+    /// cannot be changed only gets a warning, as the container runs all the same.
+    fn set_container_log_level(&self, id: &str, level: Option<&str>) {
+        let log_filter = match &self.log_filter {
+            Some(log_filter) => log_filter,
+            None => {
+                if level.is_some() {
+                    warn!("the log filter cannot be changed at runtime, ignoring the log level of the container");
+                }
+                return;
+            }
+        };
+        if let Err(e) = log_filter.set_container_level(id, level) {
+            warn!("cannot change the log level of container {}: {}", id, e);
+        }
+    }
+
+    /// Remove what create_container created for a container it didn't add after all: its root directory, the one
+    /// of its scratch directories in memory and the legacy link to its log.
+    async fn remove_created_files(&self, container: &UserContainer, root_dir: &Path) {
+        tokio::fs::remove_dir_all(root_dir).await.unwrap_or(());
+        if let Some(memory_dir) = &container.memory_dir {
+            tokio::fs::remove_dir_all(memory_dir).await.unwrap_or(());
+        }
+        if let Some(link) = &container.legacy_log_link {
+            tokio::fs::remove_file(link).await.unwrap_or(());
+        }
+    }
+
+    /// The backend of the runtime handler with the given name.
+    fn backend(&self, handler: &str) -> std::result::Result<Arc<dyn RuntimeBackend>, Status> {
+        self.backends
+            .get(handler)
+            .map_err(|e| Status::invalid_argument(e.to_string()))
+    }
+
+    /// Start the container with the given ID.
+    ///
+    /// Reading and compiling a module can take a while, so the containers and sandboxes are only locked
+    /// to take a snapshot of the container before it starts and to record the result afterwards.
+    /// Otherwise a slow start would block every other RPC.
+    async fn start(&self, id: &str) -> std::result::Result<(), Status> {
+        let (container, sandbox_handler, engine_config, sandbox_config) = {
+            let containers = self.containers.read().await;
+            let container = containers
+                .get(id)
+                .cloned()
+                .ok_or_else(|| Status::not_found("Container not found"))?;
+            record_pod_sandbox_id(&container.pod_sandbox_id);
+            let sandboxes = self.sandboxes.read().await;
+            let sandbox = sandboxes
+                .get(&container.pod_sandbox_id)
+                .ok_or_else(|| Status::not_found("Sandbox not found"))?;
+            let engine_config = sandbox_engine_config(&sandbox.inner.annotations)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            (
+                container,
+                sandbox.inner.runtime_handler.clone(),
+                engine_config,
+                sandbox.config.clone(),
+            )
+        };
+        // the policy may have changed since the container was created
+        self.check_policy(&sandbox_config, &container.config, &sandbox_handler)?;
+
+        let backend = self.backend(container_runtime_handler(
+            &container.config,
+            &sandbox_handler,
+        ))?;
+
+        // Get the WASM data from the image
+        let image_ref = Reference::try_from(container.image_ref.clone()).map_err(|e| {
+            Status::invalid_argument(format!(
+                "invalid image reference {}: {}",
+                container.image_ref, e
+            ))
+        })?;
+        let module_store = self
+            .module_store
+            .lock()
+            .await
+            .namespace(sandbox_namespace(&sandbox_config))
+            .await
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let module = module_store.read(&image_ref).await?;
+
+        // enforce the memory limit before running anything
+        if container.resources.memory_limit.is_some() {
+            container
+                .resources
+                .check(&module)
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        }
+
+        let token = backend
+            .start(self, &container, module, engine_config)
+            .await?;
+
+        // same lock order as remove_container
+        let mut running_containers = self.running_containers.write().await;
+        let mut containers = self.containers.write().await;
+        match containers.get_mut(id) {
+            Some(c) => c.state = grpc::ContainerState::ContainerRunning as i32,
+            None => {
+                // the container was removed while it was starting
+                token.remove();
+                drop(containers);
+                self.release_http_port(&container).await;
+                return Err(Status::not_found("Container was removed while starting"));
+            }
+        }
+        self.watch_exit(&container.id, &token);
+        self.watch_health(&container, &token);
+        self.events.notify(Event::ContainerStarted {
+            container_id: container.id.clone(),
+            sandbox_id: container.pod_sandbox_id.clone(),
+        });
+        running_containers.insert(container.id, token);
+        Ok(())
+    }
+
+    /// Tell the event sinks when the container's WASI module or waPC guest exits.
+    fn watch_exit(&self, container_id: &str, token: &ContainerCancellationToken) {
+        let mut exited = match token.exit_receiver() {
+            Some(exited) => exited.clone(),
+            None => return,
+        };
+        if !self.events.is_enabled() {
+            return;
+        }
+        let container_id = container_id.to_owned();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            while let Some(state) = exited.recv().await {
+                if let Some(reason) = state.reason() {
+                    events.notify(Event::ContainerExited {
+                        container_id,
+                        reason: reason.to_owned(),
+                        error: state.error().map(ToOwned::to_owned),
+                    });
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Invoke the health check operation of the container's waSCC actor or waPC guest every interval, for as long
+    /// as the container runs.
+    fn watch_health(&self, container: &UserContainer, token: &ContainerCancellationToken) {
+        if !token.takes_calls() {
+            return;
+        }
+        let token = token.clone();
+        let (operation, interval) = match (
+            container.config.annotations.get(HEALTH_CHECK_ANNOTATION),
+            self.health.interval(),
+        ) {
+            (Some(operation), Some(interval)) => (operation.clone(), interval),
+            _ => return,
+        };
+        let container_id = container.id.clone();
+        let running_containers = self.running_containers.clone();
+        let health = self.health.clone();
+        tokio::spawn(async move {
+            let mut ticks =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                ticks.tick().await;
+                if !running_containers.read().await.contains_key(&container_id) {
+                    health.forget(&container_id).await;
+                    break;
+                }
+                let result = token.call(operation.clone(), vec![]).await;
+                if let Err(e) = &result {
+                    debug!("health check of container {} failed: {}", container_id, e);
+                }
+                health
+                    .record(&container_id, result.map(|_| ()).map_err(|e| e.to_string()))
+                    .await;
+            }
+        });
+    }
+
+    /// Describe wok's view of a container for the verbose container status.
+    async fn container_info(&self, container: &UserContainer) -> serde_json::Value {
+        let (sandbox_handler, namespace) = self
+            .sandboxes
+            .read()
+            .await
+            .get(&container.pod_sandbox_id)
+            .map(|s| {
+                (
+                    s.inner.runtime_handler.clone(),
+                    sandbox_namespace(&s.config).to_owned(),
+                )
+            })
+            .unwrap_or_default();
+        let runtime_handler = container_runtime_handler(&container.config, &sandbox_handler);
+        let module_store = self.module_store.lock().await.namespace(&namespace).await;
+        let module_path = match (
+            Reference::try_from(container.image_ref.clone()),
+            module_store,
+        ) {
+            (Ok(reference), Ok(module_store)) => Some(module_store.pull_file_path(&reference)),
+            _ => None,
+        };
+        let token = self
+            .running_containers
+            .read()
+            .await
+            .get(&container.id)
+            .map(ContainerCancellationToken::dump);
+        let last_trap = token
+            .as_ref()
+            .and_then(|t| t["error"].as_str().map(ToOwned::to_owned));
+        let wasi_denied = WasiRestrictions::from_config(&container.config)
+            .map(|r| r.info())
+            .unwrap_or_default();
+        let health = self.health.get(&container.id).await;
+        let envs = redact::redact_envs(
+            &container.config.envs,
+            &self.options().await.secret_env_patterns,
+        );
+
+        json!({
+            "id": container.id,
+            "sandboxId": container.pod_sandbox_id,
+            "runtimeHandler": runtime_handler,
+            "modulePath": module_path,
+            "wasiDenied": wasi_denied,
+            "workingDir": container.working_dir,
+            "envs": envs,
+            "volumes": container
+                .volumes
+                .iter()
+                .map(|v| json!({
+                    "containerPath": v.container_path,
+                    "hostPath": v.host_path,
+                    "readonly": v.readonly,
+                }))
+                .collect::<Vec<_>>(),
+            // modules run on a thread of the wok process, there is no pid of their own
+            "token": token,
+            "lastTrap": last_trap,
+            "health": health,
+        })
+    }
+
+    /// The runtime config currently in effect, including the changes made through `update_runtime_config`.
+    async fn effective_config(&self) -> serde_json::Value {
+        let options = self.options().await;
+        let pod_cidr = *self.pod_cidr.read().await;
+        json!({
+            "runtime": options,
+            "pod_cidr": pod_cidr.map(|cidr| cidr.to_string()),
+            "log_level": self.log_filter.as_ref().map(LogFilterHandle::current),
+            "container_log_levels": self.log_filter.as_ref().map(LogFilterHandle::container_levels),
+        })
+    }
+
+    /// Reserve one of the port mappings of the container's sandbox for the container to serve HTTP on, if the
+    /// sandbox declared any. The port is reserved up front so that two actors starting at the same time don't pick
+    /// the same one.
+    pub(crate) async fn reserve_http_port(
+        &self,
+        container: &UserContainer,
+    ) -> std::result::Result<Option<u16>, Status> {
+        let mut sandboxes = self.sandboxes.write().await;
+        let sandbox = sandboxes
+            .get_mut(&container.pod_sandbox_id)
+            .ok_or_else(|| Status::not_found("Sandbox not found"))?;
+        let port = sandbox.free_http_port();
+        if let Some(port) = port {
+            sandbox.http_ports.insert(container.id.clone(), port);
+        }
+        Ok(port)
+    }
+
+    /// The port the container has to listen on to serve HTTP on the given container port. Without port isolation,
+    /// that's the container port itself. With it, it's a free local port, and every host port the sandbox maps to
+    /// the container port is forwarded there.
+    pub(crate) async fn expose_http_port(
+        &self,
+        container: &UserContainer,
+        container_port: u16,
+    ) -> std::result::Result<u16, Status> {
+        if !self.isolate_ports {
+            return Ok(container_port);
+        }
+        let local_port = free_local_port()
+            .map_err(|e| Status::unavailable(format!("cannot find a free local port: {}", e)))?;
+        let mappings: Vec<grpc::PortMapping> = self
+            .sandboxes
+            .read()
+            .await
+            .get(&container.pod_sandbox_id)
+            .ok_or_else(|| Status::not_found("Sandbox not found"))?
+            .port_mappings
+            .iter()
+            .filter(|p| {
+                p.protocol == grpc::Protocol::Tcp as i32
+                    && p.container_port == i32::from(container_port)
+                    && p.host_port > 0
+            })
+            .cloned()
+            .collect();
+        let mut proxies = vec![];
+        for mapping in mappings {
+            let ip = match mapping.host_ip.as_str() {
+                "" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                ip => ip.parse().map_err(|_| {
+                    Status::invalid_argument(format!("invalid host IP {:?}", mapping.host_ip))
+                })?,
+            };
+            let listen = SocketAddr::new(ip, mapping.host_port as u16);
+            let proxy = PortProxy::start(listen, local_port).await.map_err(|e| {
+                Status::unavailable(format!("cannot forward host port {}: {}", listen, e))
+            })?;
+            debug!(
+                "forwarding {} to port {} of container {}",
+                proxy.listen(),
+                local_port,
+                container.id
+            );
+            proxies.push(proxy);
+        }
+        if let Some(sandbox) = self
+            .sandboxes
+            .write()
+            .await
+            .get_mut(&container.pod_sandbox_id)
+        {
+            sandbox.local_ports.insert(container.id.clone(), local_port);
+        }
+        self.proxies
+            .lock()
+            .await
+            .insert(container.id.clone(), proxies);
+        Ok(local_port)
+    }
+
+    /// Give the HTTP port reserved by the container back to its sandbox, and stop forwarding to it.
+    pub(crate) async fn release_http_port(&self, container: &UserContainer) {
+        if let Some(sandbox) = self
+            .sandboxes
+            .write()
+            .await
+            .get_mut(&container.pod_sandbox_id)
+        {
+            sandbox.http_ports.remove(&container.id);
+            sandbox.local_ports.remove(&container.id);
+        }
+        self.proxies.lock().await.remove(&container.id);
+    }
+}
+
+/// Queued counts a call waiting in a queue for as long as it lives.
+struct Queued<'a> {
+    queue: &'a AtomicUsize,
+    /// the number of calls that were waiting before this one
+    position: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn new(queue: &'a AtomicUsize) -> Self {
+        let position = queue.fetch_add(1, Ordering::SeqCst);
+        Queued { queue, position }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queue.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Turn the errors of the containers a sandbox operation was applied to into a single error, so the operation only
+/// succeeds when every container was handled.
+fn check_children(
+    action: &str,
+    sandbox_id: &str,
+    errors: Vec<(String, Status)>,
+) -> std::result::Result<(), Status> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let details: Vec<String> = errors
+        .iter()
+        .map(|(id, e)| format!("{}: {}", id, e.message()))
+        .collect();
+    Err(Status::internal(format!(
+        "failed to {} {} containers of sandbox {}: {}",
+        action,
+        errors.len(),
+        sandbox_id,
+        details.join("; ")
+    )))
+}
+
+/// The directories in the given ones that are named like container IDs and aren't known. Parents that don't exist
+/// have none.
+fn orphaned_dirs(parents: &[PathBuf], known: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    let mut orphans = vec![];
+    for parent in parents {
+        let entries = match std::fs::read_dir(parent) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                warn!("cannot look for orphans in {}: {}", parent.display(), e);
+                continue;
+            }
+        };
+        for entry in entries.filter_map(std::result::Result::ok) {
+            let path = entry.path();
+            let container_id = entry
+                .file_name()
+                .to_str()
+                .map_or(false, |name| Uuid::parse_str(name).is_ok());
+            if container_id && path.is_dir() && !known.contains(&path) {
+                orphans.push(path);
+            }
+        }
+    }
+    orphans.sort();
+    orphans
+}
+
+/// Warn about a log file or directory that could not be removed. One that is already gone is fine.
+fn warn_on_cleanup_error(path: &Path, result: std::io::Result<()>) {
+    match result {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            warn!("cannot remove logs at {}: {}", path.display(), e)
+        }
+        _ => {}
+    }
+}
+
+/// Link the container's log file into the legacy log directory, named like the kubelet names its links:
+/// `<pod>_<namespace>_<container>-<id>.log`. Returns the link, or None if it could not be made.
+async fn link_legacy_log(
+    dir: &Path,
+    sandbox_config: &grpc::PodSandboxConfig,
+    container: &UserContainer,
+    log_path: &Path,
+) -> Option<PathBuf> {
+    let link = legacy_log_link(dir, sandbox_config, container)?;
+    let result = async {
+        tokio::fs::create_dir_all(dir).await?;
+        // a link left behind by an earlier wok would be in the way
+        if let Err(e) = tokio::fs::remove_file(&link).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        symlink(log_path, &link).await
+    };
+    match result.await {
+        Ok(()) => Some(link),
+        Err(e) => {
+            warn!("cannot link the log to {}: {}", link.display(), e);
+            None
+        }
+    }
+}
+
+/// The path of the container's link in the legacy log directory, or None if the pod or the container has no
+/// metadata to name it after.
+fn legacy_log_link(
+    dir: &Path,
+    sandbox_config: &grpc::PodSandboxConfig,
+    container: &UserContainer,
+) -> Option<PathBuf> {
+    let pod = sandbox_config.metadata.as_ref()?;
+    let name = &container.config.metadata.as_ref()?.name;
+    Some(dir.join(format!(
+        "{}_{}_{}-{}.log",
+        pod.name, pod.namespace, name, container.id
+    )))
+}
+
+#[cfg(unix)]
+async fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::os::unix::symlink(target, link).await
+}
+
+#[cfg(windows)]
+async fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::os::windows::symlink_file(target, link).await
+}
+
+/// Request metadata carrying the settings `update_runtime_config` can change on top of the pod CIDR. The CRI message
+/// has no room for wok's own settings, so they are passed alongside it.
+const LOG_LEVEL_METADATA: &str = "wok-log-level";
+const DEFAULT_HANDLER_METADATA: &str = "wok-default-handler";
+const RETAIN_LOGS_METADATA: &str = "wok-retain-logs";
+const SHUTDOWN_TIMEOUT_METADATA: &str = "wok-shutdown-timeout";
+
+/// The settings requested in an `update_runtime_config` call. Unset settings are left alone.
+#[derive(Debug, Default, PartialEq)]
+struct RuntimeSettings {
+    log_level: Option<String>,
+    default_handler: Option<String>,
+    retain_logs: Option<bool>,
+    shutdown_timeout: Option<u64>,
+}
+
+impl RuntimeSettings {
+    /// Read and validate the settings from the request metadata.
+    fn from_metadata(metadata: &tonic::metadata::MetadataMap) -> Result<Self> {
+        let get = |key: &str| -> Result<Option<String>> {
+            match metadata.get(key) {
+                Some(value) => value
+                    .to_str()
+                    .map(|v| Some(v.to_owned()))
+                    .map_err(|_| format_err!("{} is not valid ASCII", key)),
+                None => Ok(None),
+            }
+        };
+
+        let default_handler = get(DEFAULT_HANDLER_METADATA)?;
+        let retain_logs = match get(RETAIN_LOGS_METADATA)? {
+            Some(raw) => Some(raw.parse().map_err(|_| {
+                format_err!(
+                    "{} must be true or false, got {}",
+                    RETAIN_LOGS_METADATA,
+                    raw
+                )
+            })?),
+            None => None,
+        };
+        let shutdown_timeout = match get(SHUTDOWN_TIMEOUT_METADATA)? {
+            Some(raw) => Some(raw.parse().map_err(|_| {
+                format_err!(
+                    "{} must be a number of seconds, got {}",
+                    SHUTDOWN_TIMEOUT_METADATA,
+                    raw
+                )
+            })?),
+            None => None,
+        };
+
+        Ok(RuntimeSettings {
+            log_level: get(LOG_LEVEL_METADATA)?,
+            default_handler,
+            retain_logs,
+            shutdown_timeout,
+        })
+    }
+
+    /// Apply the settings to the runtime options. The log level is applied through the log filter handle instead.
+    fn apply(self, options: &mut RuntimeOptions) {
+        if let Some(handler) = self.default_handler {
+            options.default_handler = handler;
+        }
+        if let Some(retain_logs) = self.retain_logs {
+            options.retain_logs = retain_logs;
+        }
+        if let Some(shutdown_timeout) = self.shutdown_timeout {
+            options.shutdown_timeout = shutdown_timeout;
+        }
+    }
+}
+
+/// A handle to change the daemon's log filter while it runs.
+///
+/// The subscriber is set up by the binary, so the handle only wraps a function reloading its filter.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    current: Arc<std::sync::Mutex<String>>,
+    /// the levels requested for single containers, by container ID
+    containers: Arc<std::sync::Mutex<BTreeMap<String, String>>>,
+    reload: Arc<dyn Fn(&str) -> Result<()> + Send + Sync>,
+}
+
+impl LogFilterHandle {
+    /// Create a handle for a subscriber currently using the `current` filter.
+    pub fn new(
+        current: String,
+        reload: impl Fn(&str) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        LogFilterHandle {
+            current: Arc::new(std::sync::Mutex::new(current)),
+            containers: Arc::default(),
+            reload: Arc::new(reload),
+        }
+    }
+
+    /// The filter currently in effect, leaving out the levels of single containers.
+    pub fn current(&self) -> String {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Replace the filter, e.g. with `wok=debug`. The levels of single containers stay in effect.
+    pub fn set(&self, filter: &str) -> Result<()> {
+        let containers = self.containers.lock().unwrap();
+        (self.reload)(&with_container_levels(filter, &containers))?;
+        *self.current.lock().unwrap() = filter.to_owned();
+        Ok(())
+    }
+
+    /// The levels requested for single containers, by container ID.
+    pub fn container_levels(&self) -> BTreeMap<String, String> {
+        self.containers.lock().unwrap().clone()
+    }
+
+    /// Log what wok does for the container at the given level, e.g. `debug`, or at the level of the filter
+    /// again if None.
+    ///
+    /// Only events in spans recording the container's ID are affected. These are the spans of the RPCs for the
+    /// container created after the level was set, and the spans nested in them.
+    pub fn set_container_level(&self, container_id: &str, level: Option<&str>) -> Result<()> {
+        let mut containers = self.containers.lock().unwrap();
+        let mut updated = containers.clone();
+        match level {
+            Some(level) => updated.insert(container_id.to_owned(), level.to_owned()),
+            None => updated.remove(container_id),
+        };
+        if updated == *containers {
+            return Ok(());
+        }
+        (self.reload)(&with_container_levels(&self.current(), &updated))?;
+        *containers = updated;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for LogFilterHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("LogFilterHandle")
+            .field("current", &self.current())
+            .field("containers", &self.container_levels())
+            .finish()
+    }
+}
+
+/// Add a directive for the spans of each container to the filter, e.g. `wok[{container_id=1234}]=debug`.
+fn with_container_levels(filter: &str, containers: &BTreeMap<String, String>) -> String {
+    let mut directives = vec![filter.to_owned()];
+    directives.extend(
+        containers
+            .iter()
+            .map(|(id, level)| format!("wok[{{container_id={}}}]={}", id, level)),
+    );
+    directives.join(",")
+}
+
+/// The level the container's lifecycle should be logged at, as requested by its or its sandbox's annotations.
+pub(crate) fn container_log_level(
+    sandbox_annotations: &HashMap<String, String>,
+    config: &grpc::ContainerConfig,
+) -> Result<Option<String>> {
+    let level = match config
+        .annotations
+        .get(LOG_LEVEL_ANNOTATION)
+        .or_else(|| sandbox_annotations.get(LOG_LEVEL_ANNOTATION))
+    {
+        Some(level) => level.to_lowercase(),
+        None => return Ok(None),
+    };
+    if !["trace", "debug", "info", "warn", "error", "off"].contains(&level.as_str()) {
+        return Err(format_err!(
+            "invalid {} annotation {:?}, expected one of trace, debug, info, warn, error or off",
+            LOG_LEVEL_ANNOTATION,
+            level
+        ));
+    }
+    Ok(Some(level))
+}
+
+/// The engine configuration requested by the sandbox's annotations.
+pub(crate) fn sandbox_engine_config(annotations: &HashMap<String, String>) -> Result<EngineConfig> {
+    match annotations.get(WASM_FEATURES_ANNOTATION) {
+        Some(features) => features
+            .parse()
+            .map_err(|e| format_err!("invalid {} annotation: {}", WASM_FEATURES_ANNOTATION, e)),
+        None => Ok(EngineConfig::default()),
+    }
+}
+
+/// The Kubernetes namespace of the sandbox, or "" if it has no metadata.
+fn sandbox_namespace(config: &grpc::PodSandboxConfig) -> &str {
+    config
+        .metadata
+        .as_ref()
+        .map(|m| m.namespace.as_str())
+        .unwrap_or_default()
+}
+
+/// How long the container may run, as requested by its annotations.
+pub(crate) fn container_deadline(config: &grpc::ContainerConfig) -> Result<Option<Duration>> {
+    match config.annotations.get(DEADLINE_ANNOTATION) {
+        Some(seconds) => match seconds.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Ok(Some(Duration::from_secs(seconds))),
+            _ => Err(format_err!(
+                "invalid {} annotation {:?}, expected a positive number of seconds",
+                DEADLINE_ANNOTATION,
+                seconds
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The size of the stack the container runs on, as requested by its annotations or configured for every container,
+/// None for the default stack.
+pub(crate) fn container_stack_size(
+    config: &grpc::ContainerConfig,
+    options: &RuntimeOptions,
+) -> Result<Option<usize>> {
+    let stack_size = match config.annotations.get(WASM_STACK_SIZE_ANNOTATION) {
+        Some(size) => parse_stack_size(size).ok_or_else(|| {
+            format_err!(
+                "invalid {} annotation {:?}, expected a number of bytes, e.g. 8388608 or 8Mi",
+                WASM_STACK_SIZE_ANNOTATION,
+                size
+            )
+        })?,
+        None if options.wasm_stack_size == 0 => return Ok(None),
+        None => options.wasm_stack_size,
+    };
+    if stack_size < MIN_WASM_STACK_SIZE {
+        failure::bail!(
+            "a wasm stack of {} bytes is too small, it must be at least {}",
+            stack_size,
+            MIN_WASM_STACK_SIZE
+        );
+    }
+    if options.max_wasm_stack_size > 0 && stack_size > options.max_wasm_stack_size {
+        failure::bail!(
+            "a wasm stack of {} bytes exceeds the maximum of {}",
+            stack_size,
+            options.max_wasm_stack_size
+        );
+    }
+    Ok(Some(stack_size))
+}
+
+/// A number of bytes, optionally with a `Ki` or `Mi` suffix.
+fn parse_stack_size(size: &str) -> Option<usize> {
+    let (digits, unit) = if size.ends_with("Ki") {
+        (&size[..size.len() - 2], 1024)
+    } else if size.ends_with("Mi") {
+        (&size[..size.len() - 2], 1024 * 1024)
+    } else {
+        (size, 1)
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+/// The ID of the ready sandbox already created for the pod the config belongs to, going by the pod's UID.
+fn ready_sandbox_of_pod(
+    sandboxes: &BTreeMap<String, UserSandbox>,
+    config: &grpc::PodSandboxConfig,
+) -> Option<String> {
+    let uid = config.metadata.as_ref().map(|m| m.uid.as_str())?;
+    if uid == "" {
+        return None;
+    }
+    sandboxes
+        .values()
+        .find(|s| {
+            s.inner.state == grpc::PodSandboxState::SandboxReady as i32
+                && s.inner.metadata.as_ref().map(|m| m.uid.as_str()) == Some(uid)
+        })
+        .map(|s| s.inner.id.clone())
+}
+
+/// Answer a repeated RunPodSandbox request with the sandbox the first one created.
+fn existing_sandbox(id: String) -> Response<grpc::RunPodSandboxResponse> {
+    record_pod_sandbox_id(&id);
+    info!("pod sandbox already exists");
+    Response::new(grpc::RunPodSandboxResponse { pod_sandbox_id: id })
+}
+
+/// The container's volumes as mounts of the host directories backing them, below the container's root directory.
+fn resolved_mounts(container: &UserContainer, root_dir: &Path) -> Vec<grpc::Mount> {
+    container
+        .volumes
+        .iter()
+        .map(|volume| grpc::Mount {
+            host_path: root_dir
+                .join(&volume.host_path)
+                .to_string_lossy()
+                .into_owned(),
+            ..volume.clone()
+        })
+        .collect()
+}
+
+/// The modules the sandbox asks to be pre-pulled with its annotations.
+fn prepull_references(annotations: &HashMap<String, String>) -> Result<Vec<Reference>> {
+    let references = match annotations.get(PREPULL_ANNOTATION) {
+        Some(references) => references,
+        None => return Ok(vec![]),
+    };
+    references
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(|r| {
+            Reference::try_from(r.to_owned())
+                .map_err(|e| format_err!("invalid {} annotation: {}: {}", PREPULL_ANNOTATION, r, e))
+        })
+        .collect()
+}
+
+/// Fail with AlreadyExists if the sandbox already has a container with the name and attempt of the given
+/// config. The kubelet relies on it to tell a retried CreateContainer from a new container.
+fn check_unique_name(
+    containers: &HashMap<String, UserContainer>,
+    pod_sandbox_id: &str,
+    config: &grpc::ContainerConfig,
+) -> std::result::Result<(), Status> {
+    let metadata = match &config.metadata {
+        Some(metadata) => metadata,
+        None => return Ok(()),
+    };
+    let existing = containers.values().find(|c| {
+        c.pod_sandbox_id == pod_sandbox_id
+            && c.config.metadata.as_ref().map_or(false, |m| {
+                m.name == metadata.name && m.attempt == metadata.attempt
+            })
+    });
+    match existing {
+        Some(existing) => Err(Status::already_exists(format!(
+            "container {} attempt {} already exists in sandbox {} as {}",
+            metadata.name, metadata.attempt, pod_sandbox_id, existing.id
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The path of the container's working directory relative to the container's root directory, or None when no
+/// working directory is requested. The working directory must be absolute and must not leave the root directory.
+fn working_dir_path(working_dir: &str) -> Result<Option<PathBuf>> {
+    if working_dir.is_empty() {
+        return Ok(None);
+    }
+    let path = Path::new(working_dir);
+    let relative = path
+        .strip_prefix("/")
+        .map_err(|_| format_err!("working directory {} is not absolute", working_dir))?;
+    if relative.components().any(|c| c == Component::ParentDir) {
+        failure::bail!("working directory {} must not contain ..", working_dir);
+    }
+    Ok(Some(relative.to_path_buf()))
+}
+
+/// The number of directories preopened when the container starts: the allowed host paths it mounts, its scratch
+/// directories in memory and its working directory, which is preopened under two names. The volumes of other mounts
+/// aren't preopened, see `WasiBackend::start`, nor is anything when the container is denied the file system.
+fn preopened_dir_count(
+    host_dirs: &[Option<PathBuf>],
+    memory_dirs: usize,
+    working_dir: bool,
+    restrictions: &WasiRestrictions,
+) -> usize {
+    if restrictions.denies(WasiCapability::Fs) {
+        return 0;
+    }
+    let working_dirs = if working_dir { 2 } else { 0 };
+    host_dirs.iter().filter(|dir| dir.is_some()).count() + memory_dirs + working_dirs
 }
 
-/// Implement a CRI runtime service.
-#[derive(Debug, Default)]
-pub struct CriRuntimeService {
-    module_store: Mutex<ModuleStore>,
-    // NOTE: we could replace this with evmap or crossbeam
-    sandboxes: RwLock<BTreeMap<String, UserSandbox>>,
-    containers: RwLock<HashMap<String, UserContainer>>,
-    running_containers: RwLock<HashMap<String, ContainerCancellationToken>>,
-    pod_cidr: RwLock<Option<IpNet>>,
+/// The container paths the container asks to back with scratch directories in memory with its annotations.
+fn memory_dir_paths(config: &grpc::ContainerConfig) -> Result<Vec<String>> {
+    let paths = match config.annotations.get(MEMORY_DIRS_ANNOTATION) {
+        Some(paths) => paths,
+        None => return Ok(vec![]),
+    };
+    let mut checked: Vec<String> = vec![];
+    for path in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        if !path.starts_with('/') {
+            failure::bail!("memory directory {} is not absolute", path);
+        }
+        if Path::new(path)
+            .components()
+            .any(|c| c == Component::ParentDir)
+        {
+            failure::bail!("memory directory {} must not contain ..", path);
+        }
+        if checked.iter().any(|p| p == path)
+            || config.mounts.iter().any(|m| m.container_path == path)
+        {
+            failure::bail!("{} is mounted more than once", path);
+        }
+        checked.push(path.to_owned());
+    }
+    Ok(checked)
 }
 
-impl CriRuntimeService {
-    pub async fn new(dir: PathBuf, pod_cidr: Option<IpNet>) -> Self {
-        tokio::fs::create_dir_all(&dir)
-            .await
-            .expect("cannot create root directory for runtime service");
-        CriRuntimeService {
-            module_store: Mutex::new(ModuleStore::new(dir).await),
-            sandboxes: RwLock::new(BTreeMap::default()),
-            containers: RwLock::new(HashMap::new()),
-            running_containers: RwLock::new(HashMap::new()),
-            pod_cidr: RwLock::new(pod_cidr),
+/// Add the annotations of the handler alias, if the handler is one, that the annotations don't set themselves.
+fn add_alias_annotations(
+    aliases: &[HandlerAliasOptions],
+    handler: &str,
+    annotations: &mut HashMap<String, String>,
+) {
+    if let Some(alias) = aliases.iter().find(|a| a.name == handler) {
+        for (key, value) in &alias.annotations {
+            annotations
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
         }
     }
 }
 
+/// The runtime handler a container runs with. The container's own annotation takes precedence over the handler of
+/// its sandbox.
+fn container_runtime_handler<'a>(
+    config: &'a grpc::ContainerConfig,
+    sandbox_handler: &'a str,
+) -> &'a str {
+    config
+        .annotations
+        .get(RUNTIME_HANDLER_ANNOTATION)
+        .map(String::as_str)
+        .unwrap_or(sandbox_handler)
+}
+
 #[derive(Debug)]
 pub enum RuntimeHandler {
     WASI,
     WASCC,
+    WAPC,
 }
 
 impl ToString for RuntimeHandler {
@@ -161,17 +1783,22 @@ impl ToString for RuntimeHandler {
         match self {
             Self::WASI => "WASI".to_owned(),
             Self::WASCC => "WASCC".to_owned(),
+            Self::WAPC => "WAPC".to_owned(),
         }
     }
 }
 
 impl RuntimeHandler {
     pub fn from_string(s: &str) -> Result<Self> {
+        if let Some(e) = unavailable_handler(s) {
+            return Err(e);
+        }
         match s {
             // Per the spec, the empty string should use the default
             "" => Ok(Self::default()),
             "WASI" => Ok(Self::WASI),
             "WASCC" => Ok(Self::WASCC),
+            "WAPC" => Ok(Self::WAPC),
             _ => Err(format_err!("Invalid runtime handler {}", s)),
         }
     }
@@ -189,7 +1816,7 @@ impl RuntimeService for CriRuntimeService {
         &self,
         req: Request<grpc::VersionRequest>,
     ) -> CriResult<grpc::VersionResponse> {
-        log::info!("Version request from API version {:?}", req);
+        debug!("Version request from API version {:?}", req);
         Ok(Response::new(grpc::VersionResponse {
             version: API_VERSION.to_string(),
             runtime_name: env!("CARGO_PKG_NAME").to_string(),
@@ -204,23 +1831,43 @@ impl RuntimeService for CriRuntimeService {
         &self,
         req: Request<grpc::UpdateRuntimeConfigRequest>,
     ) -> CriResult<grpc::UpdateRuntimeConfigResponse> {
-        let raw = req
+        // validate everything before applying anything, so a bad setting doesn't leave a partial update behind
+        let settings = RuntimeSettings::from_metadata(req.metadata())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if let Some(handler) = &settings.default_handler {
+            self.backend(handler)?;
+        }
+        let network_config = req
             .into_inner()
             .runtime_config
             .unwrap_or_default()
-            .network_config
-            .unwrap_or_default()
-            .pod_cidr;
-        let pod_cidr = match raw.as_str() {
-            "" => None,
-            _ => Some(
-                IpNet::from_str(&raw)
-                    .map_err(|e| Status::invalid_argument(format!("invalid CIDR given: {}", e)))?,
-            ),
+            .network_config;
+        let pod_cidr = match network_config.as_ref().map(|n| n.pod_cidr.as_str()) {
+            None => None,
+            Some("") => Some(None),
+            Some(raw) => Some(Some(IpNet::from_str(raw).map_err(|e| {
+                Status::invalid_argument(format!("invalid CIDR given: {}", e))
+            })?)),
         };
+        if settings.log_level.is_some() && self.log_filter.is_none() {
+            return Err(Status::failed_precondition(
+                "the log filter cannot be changed at runtime",
+            ));
+        }
 
-        let mut cidr = self.pod_cidr.write().await;
-        *cidr = pod_cidr;
+        // the log filter is the only setting that can still fail to apply, so it goes first
+        if let (Some(level), Some(log_filter)) = (&settings.log_level, &self.log_filter) {
+            log_filter
+                .set(level)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        }
+        settings.apply(&mut *self.options.write().await);
+        // the kubelet always sends the network config, other clients may only want to change wok's settings
+        if let Some(pod_cidr) = pod_cidr {
+            *self.pod_cidr.write().await = pod_cidr;
+        }
+        let effective = self.effective_config().await;
+        info!(%effective, "runtime config updated");
         Ok(Response::new(grpc::UpdateRuntimeConfigResponse {}))
     }
 
@@ -235,30 +1882,33 @@ impl RuntimeService for CriRuntimeService {
                 "running_containers".to_owned(),
                 self.containers.read().await.len().to_string(),
             );
+            extra_info.insert(
+                "config".to_owned(),
+                self.effective_config().await.to_string(),
+            );
+            extra_info.insert(
+                "instances".to_owned(),
+                self.instance_counts().await.to_string(),
+            );
+            let warm_instances: usize = self.warm_pool.counts().await.values().sum();
+            extra_info.insert("warm_instances".to_owned(), warm_instances.to_string());
+            extra_info.insert(
+                "warm_pool".to_owned(),
+                json!(self.warm_pool.stats().await).to_string(),
+            );
+            let module_store = self.module_store.lock().await.clone();
+            let mut pulls = module_store.pulls().await.len();
+            for (_, store) in module_store.namespaces().await {
+                pulls += store.pulls().await.len();
+            }
+            extra_info.insert("pulls_in_flight".to_owned(), pulls.to_string());
+            // modules share the host's network, so no pod gets an IP of its own yet
+            extra_info.insert("allocated_ips".to_owned(), "0".to_owned());
         }
 
         Ok(Response::new(grpc::StatusResponse {
             status: Some(grpc::RuntimeStatus {
-                conditions: vec![
-                    // There isn't anything else to change on these right now,
-                    // so keep them hard coded. If we start needing to update
-                    // these (such as with networking) or add our own arbitrary
-                    // conditions, we can move them into the struct
-                    grpc::RuntimeCondition {
-                        r#type: "RuntimeReady".to_owned(),
-                        status: true,
-                        // NOTE: We should make these reasons an enum once we
-                        // actually define more of them
-                        reason: "RuntimeStarted".to_owned(),
-                        message: "Runtime has been started and is ready to run modules".to_owned(),
-                    },
-                    grpc::RuntimeCondition {
-                        r#type: "NetworkReady".to_owned(),
-                        status: false, // False until we figure out networking support
-                        reason: "Unimplemented".to_owned(),
-                        message: "Networking is currently unimplemented".to_owned(),
-                    },
-                ],
+                conditions: self.conditions.list().await,
             }),
             info: extra_info,
         }))
@@ -269,11 +1919,39 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::RunPodSandboxRequest>,
     ) -> CriResult<grpc::RunPodSandboxResponse> {
         let sandbox_req = req.into_inner();
-        let sandbox_conf = sandbox_req
+        let mut sandbox_conf = sandbox_req
             .config
             .ok_or_else(|| Status::invalid_argument("Sandbox request is missing config object"))?;
-        let handler = RuntimeHandler::from_string(&sandbox_req.runtime_handler)
+        let handler = match sandbox_req.runtime_handler.as_str() {
+            "" => self.options.read().await.default_handler.clone(),
+            requested => requested.to_owned(),
+        };
+        self.backend(&handler)?;
+        add_alias_annotations(
+            &self.options.read().await.handler_aliases,
+            &handler,
+            &mut sandbox_conf.annotations,
+        );
+        sandbox_engine_config(&sandbox_conf.annotations)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let prepull = prepull_references(&sandbox_conf.annotations)
             .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        // the kubelet retries a request that timed out, which must not leave the pod with two sandboxes
+        if let Some(id) = ready_sandbox_of_pod(&*self.sandboxes.read().await, &sandbox_conf) {
+            return Ok(existing_sandbox(id));
+        }
+        let _permit = self.sandbox_permit().await?;
+
+        if !prepull.is_empty() {
+            let module_store = self
+                .module_store
+                .lock()
+                .await
+                .namespace(sandbox_namespace(&sandbox_conf))
+                .await
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            tokio::spawn(module_store.prepull(prepull));
+        }
 
         // TODO(taylor): As of now, there isn't networking support in wasmtime,
         // so we can't necessarily set it up right now. Once it does, we'll need
@@ -285,8 +1963,21 @@ impl RuntimeService for CriRuntimeService {
         // All of the security context stuff pretty much doesn't matter for
         // WASM, but we can revisit this as things keep evolving
 
+        let config = sandbox_conf.clone();
         let mut sandboxes = self.sandboxes.write().await;
+        // a retry may have been waiting for a permit while the first request created the sandbox
+        if let Some(id) = ready_sandbox_of_pod(&sandboxes, &sandbox_conf) {
+            return Ok(existing_sandbox(id));
+        }
         let id = Uuid::new_v4().to_string();
+        record_pod_sandbox_id(&id);
+        info!(handler = %handler, "pod sandbox created");
+        let metadata = sandbox_conf.metadata.clone().unwrap_or_default();
+        self.events.notify(Event::SandboxCreated {
+            sandbox_id: id.clone(),
+            name: metadata.name,
+            namespace: metadata.namespace,
+        });
         sandboxes.insert(
             id.clone(),
             UserSandbox {
@@ -297,9 +1988,17 @@ impl RuntimeService for CriRuntimeService {
                     created_at: Utc::now().timestamp_nanos(),
                     labels: sandbox_conf.labels,
                     annotations: sandbox_conf.annotations,
-                    runtime_handler: handler.to_string(),
+                    runtime_handler: handler,
                 },
                 running_containers: vec![],
+                port_mappings: sandbox_conf.port_mappings,
+                http_ports: HashMap::new(),
+                local_ports: HashMap::new(),
+                log_directory: match sandbox_conf.log_directory.as_str() {
+                    "" => None,
+                    dir => Some(PathBuf::from(dir)),
+                },
+                config,
             },
         );
 
@@ -340,23 +2039,34 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::StopPodSandboxRequest>,
     ) -> CriResult<grpc::StopPodSandboxResponse> {
         let id = req.into_inner().pod_sandbox_id;
+        record_pod_sandbox_id(&id);
 
-        let mut sandboxes = self.sandboxes.write().await;
-        let mut sandbox = match sandboxes.get_mut(&id) {
-            Some(s) => s,
+        // the containers take the sandbox lock themselves, so it must not be held while they stop
+        let container_ids = match self.sandboxes.read().await.get(&id) {
+            Some(s) => s.running_containers.clone(),
             None => return Err(Status::not_found(format!("Sandbox {} does not exist", id))),
         };
 
         // Stop all containers inside the sandbox. This forcibly terminates all containers with no grace period.
-        for id in sandbox.running_containers.drain(0..) {
-            self.stop_container(Request::new(grpc::StopContainerRequest {
-                container_id: id.clone(),
+        let mut errors = vec![];
+        for container_id in container_ids {
+            let span = info_span!("container", container_id = container_id.as_str());
+            let req = Request::new(grpc::StopContainerRequest {
+                container_id: container_id.clone(),
                 timeout: 0,
-            }));
+            });
+            if let Err(e) = self.stop_container(req).instrument(span).await {
+                errors.push((container_id, e));
+            }
         }
+        check_children("stop", &id, errors)?;
 
         // mark the pod sandbox as not ready, preventing future container creation.
-        sandbox.inner.state = grpc::PodSandboxState::SandboxNotready as i32;
+        match self.sandboxes.write().await.get_mut(&id) {
+            Some(sandbox) => sandbox.inner.state = grpc::PodSandboxState::SandboxNotready as i32,
+            None => return Err(Status::not_found(format!("Sandbox {} does not exist", id))),
+        }
+        info!("pod sandbox stopped");
 
         // TODO(bacongobbler): when networking is implemented, here is where we should tear down the network.
 
@@ -370,33 +2080,56 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::RemovePodSandboxRequest>,
     ) -> CriResult<grpc::RemovePodSandboxResponse> {
         let id = &req.into_inner().pod_sandbox_id;
-
-        let mut sandboxes = self.sandboxes.write().await;
-        let sandbox = match sandboxes.get(id) {
-            Some(s) => s,
-            None => return Err(Status::not_found(format!("Sandbox {} does not exist", id))),
+        record_pod_sandbox_id(id);
+
+        let container_ids = {
+            let sandboxes = self.sandboxes.read().await;
+            let sandbox = match sandboxes.get(id) {
+                Some(s) => s,
+                None => return Err(Status::not_found(format!("Sandbox {} does not exist", id))),
+            };
+
+            // return an error if the sandbox container is still running.
+            if sandbox.inner.state == grpc::PodSandboxState::SandboxReady as i32 {
+                return Err(Status::failed_precondition(format!(
+                    "Sandbox container {} is not fully stopped",
+                    id
+                )));
+            }
+            sandbox.running_containers.clone()
         };
 
-        // return an error if the sandbox container is still running.
-        if sandbox.inner.state == grpc::PodSandboxState::SandboxReady as i32 {
-            return Err(Status::failed_precondition(format!(
-                "Sandbox container {} is not fully stopped",
-                id
-            )));
-        }
-
         // TODO(bacongobbler): when networking is implemented, here is where we should return an error if the sandbox's
         // network namespace is not closed yet.
 
-        // remove all containers inside the sandbox.
-        for container_id in sandbox.running_containers.iter() {
-            self.remove_container(Request::new(grpc::RemoveContainerRequest {
+        // remove all containers inside the sandbox. Each of them takes the sandbox lock to unregister itself.
+        let mut errors = vec![];
+        for container_id in container_ids {
+            let span = info_span!("container", container_id = container_id.as_str());
+            let req = Request::new(grpc::RemoveContainerRequest {
                 container_id: container_id.clone(),
-            }));
+            });
+            if let Err(e) = self.remove_container(req).instrument(span).await {
+                errors.push((container_id, e));
+            }
         }
+        check_children("remove", id, errors)?;
 
         // remove the sandbox.
-        sandboxes.remove(id);
+        let log_directory = self
+            .sandboxes
+            .write()
+            .await
+            .remove(id)
+            .and_then(|s| s.log_directory);
+        let retain_logs = self.options.read().await.retain_logs;
+        if let Some(log_directory) = log_directory.filter(|_| !retain_logs) {
+            warn_on_cleanup_error(
+                &log_directory,
+                tokio::fs::remove_dir_all(&log_directory).await,
+            );
+        }
+        info!("pod sandbox removed");
 
         Ok(Response::new(grpc::RemovePodSandboxResponse {}))
     }
@@ -406,10 +2139,11 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::PodSandboxStatusRequest>,
     ) -> CriResult<grpc::PodSandboxStatusResponse> {
         let request = req.into_inner();
+        record_pod_sandbox_id(&request.pod_sandbox_id);
 
-        let sandboxes = self.sandboxes.read().await;
-        let sandbox = match sandboxes.get(&request.pod_sandbox_id) {
-            Some(s) => s,
+        // take a copy, so the sandboxes aren't locked while the containers are looked at
+        let sandbox = match self.sandboxes.read().await.get(&request.pod_sandbox_id) {
+            Some(s) => s.clone(),
             None => {
                 return Err(Status::not_found(format!(
                     "Sandbox {} does not exist",
@@ -422,12 +2156,51 @@ impl RuntimeService for CriRuntimeService {
 
         // TODO(bacongobbler): report back status on the network and linux-specific sandbox status here (when implemented)
 
-        // TODO: generate any verbose information we want to report if requested.
-        //
-        // Right now, we don't have any extra information to provide to the user here, so we'll just return what we know.
+        let mut info = HashMap::new();
+        if request.verbose {
+            let ports: Vec<serde_json::Value> = sandbox
+                .port_mappings
+                .iter()
+                .map(|p| {
+                    let container_id = sandbox
+                        .http_ports
+                        .iter()
+                        .find(|(_, port)| i32::from(**port) == p.container_port)
+                        .map(|(id, _)| id);
+                    json!({
+                        "protocol": p.protocol,
+                        "containerPort": p.container_port,
+                        "hostPort": p.host_port,
+                        "hostIp": p.host_ip,
+                        "containerId": container_id,
+                        "localPort": container_id.and_then(|id| sandbox.local_ports.get(id)),
+                    })
+                })
+                .collect();
+            info.insert(
+                "ports".to_owned(),
+                serde_json::Value::from(ports).to_string(),
+            );
+
+            let containers: Vec<(String, i32)> = {
+                let containers = self.containers.read().await;
+                sandbox
+                    .running_containers
+                    .iter()
+                    .filter_map(|id| containers.get(id))
+                    .map(|c| (c.id.clone(), c.state))
+                    .collect()
+            };
+            let ip = status.network.as_ref().map(|n| n.ip.as_str());
+            info.insert("info".to_owned(), sandbox.info(&containers, ip).to_string());
+            info.insert(
+                "stats".to_owned(),
+                json!(self.stats_of_sandbox(&sandbox.inner).await).to_string(),
+            );
+        }
 
         Ok(Response::new(grpc::PodSandboxStatusResponse {
-            info: HashMap::new(),
+            info,
             status: Some(status),
         }))
     }
@@ -437,16 +2210,95 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::CreateContainerRequest>,
     ) -> CriResult<grpc::CreateContainerResponse> {
         let container_req = req.into_inner();
-        let container_config = container_req.config.unwrap_or_default();
+        let mut container_config = container_req.config.unwrap_or_default();
         let sandbox_config = container_req.sandbox_config.unwrap_or_default();
+        let sandbox_handler = self
+            .sandboxes
+            .read()
+            .await
+            .get(&container_req.pod_sandbox_id)
+            .map(|s| s.inner.runtime_handler.clone());
+        let sandbox_handler = match sandbox_handler {
+            Some(handler) => handler,
+            None => self.options.read().await.default_handler.clone(),
+        };
+        let handler = container_runtime_handler(&container_config, &sandbox_handler).to_owned();
+        add_alias_annotations(
+            &self.options.read().await.handler_aliases,
+            &handler,
+            &mut container_config.annotations,
+        );
+        // pin the node's default profile, so changing the default doesn't change the containers created before
+        let default_wasi_profile = self.options.read().await.default_wasi_profile;
+        if default_wasi_profile != WasiProfile::Unconfined {
+            container_config
+                .annotations
+                .entry(WASI_PROFILE_ANNOTATION.to_owned())
+                .or_insert_with(|| default_wasi_profile.to_string());
+        }
 
-        // generate a unique ID for the container
-        //
-        // TODO(bacongobbler): we should probably commit this to a RWLock'd map; that way concurrent calls to
-        // create_container() won't reserve the same name. Fow now, let's just generate a "unique enough" UUID.
+        // reject an invalid handler override now rather than when the container is started
+        if let Some(handler) = container_config.annotations.get(RUNTIME_HANDLER_ANNOTATION) {
+            self.backend(handler)?;
+        }
+        let restrictions = WasiRestrictions::from_config(&container_config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let working_dir = working_dir_path(&container_config.working_dir)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        container_deadline(&container_config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        container_stack_size(&container_config, &*self.options.read().await)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let log_level = container_log_level(&sandbox_config.annotations, &container_config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let memory_dir_paths = memory_dir_paths(&container_config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let host_path_prefixes = self.options.read().await.host_path_prefixes.clone();
+        let host_dirs = container_config
+            .mounts
+            .iter()
+            .map(|mount| mounts::host_dir(mount, &host_path_prefixes))
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if restrictions.denies(WasiCapability::HostFs) {
+            if let Some(mount) = container_config
+                .mounts
+                .iter()
+                .zip(&host_dirs)
+                .find_map(|(mount, dir)| dir.as_ref().map(|_| mount))
+            {
+                return Err(Status::invalid_argument(format!(
+                    "cannot mount {}: the container is denied the host-fs capability",
+                    mount.host_path
+                )));
+            }
+        }
+        let max_preopened_dirs = self.options.read().await.max_preopened_dirs;
+        let preopened_dirs = preopened_dir_count(
+            &host_dirs,
+            memory_dir_paths.len(),
+            working_dir.is_some(),
+            &restrictions,
+        );
+        if max_preopened_dirs > 0 && preopened_dirs > max_preopened_dirs {
+            return Err(Status::invalid_argument(format!(
+                "the container preopens {} directories, more than the maximum of {}",
+                preopened_dirs, max_preopened_dirs
+            )));
+        }
+        self.check_policy(&sandbox_config, &container_config, &sandbox_handler)?;
+        check_unique_name(
+            &*self.containers.read().await,
+            &container_req.pod_sandbox_id,
+            &container_config,
+        )?;
+
+        // the name is checked again when the container is added, as a concurrent call may have taken it meanwhile
         //
         // https://github.com/containerd/cri/blob/b2804c06934245b0ff4a9114c9f1f592a5120815/pkg/server/container_create.go#L63-L81
         let id = Uuid::new_v4().to_string();
+        record_pod_sandbox_id(&container_req.pod_sandbox_id);
+        record_container_id(&id);
 
         let mut container = UserContainer {
             id: id.to_owned(),
@@ -454,69 +2306,140 @@ impl RuntimeService for CriRuntimeService {
             state: grpc::ContainerState::ContainerCreated as i32,
             created_at: Utc::now().timestamp_nanos(),
             config: container_config.to_owned(),
-            log_path: None, // to be set further down
+            log_path: None,        // to be set further down
+            legacy_log_link: None, // to be set further down
             image_ref: container_config.image.as_ref().unwrap().image.clone(), // FIXME(rylev): understand what it means for the image to be None
-            volumes: vec![], // to be added further down
+            volumes: vec![],   // to be added further down
+            working_dir: None, // to be set further down
+            memory_dir: None,  // to be set further down
+            resources: ResourcePolicy::from_config(&container_config),
         };
-
-        // create container root directory.
-        let container_root_dir = self
-            .module_store
-            .lock()
-            .await
-            .root_dir()
-            .join("containers")
-            .join(&id);
-        tokio::fs::create_dir_all(&container_root_dir).await?;
-
-        // generate volume mounts.
-        for mount in container_config.mounts {
-            let volume_id = Uuid::new_v4().to_string();
-            container.volumes.push(grpc::Mount {
-                host_path: PathBuf::from("volumes")
-                    .join(volume_id)
-                    .into_os_string()
-                    .into_string()
-                    .unwrap(),
-                container_path: mount.container_path.to_owned(),
-                propagation: mount.propagation,
-                readonly: mount.readonly,
-                selinux_relabel: mount.selinux_relabel,
-            })
+        if !container.resources.unsupported.is_empty() {
+            debug!(
+                "ignoring unsupported resources {:?}",
+                container.resources.unsupported
+            );
         }
 
-        // validate log paths and compose full container log path.
-        if sandbox_config.log_directory != "" && container.config.log_path != "" {
-            let log_path =
-                PathBuf::from(&sandbox_config.log_directory).join(&container.config.log_path);
-            tokio::fs::create_dir_all(&log_path).await?;
-            container.log_path = Some(log_path);
-            log::debug!("composed container log path using sandbox log directory {} and container config log path {}", sandbox_config.log_directory, container.config.log_path);
-        } else {
-            // logging is disabled
-            log::info!(
-                "logging will be disabled due to empty log paths for sandbox {} or container {}",
-                sandbox_config.log_directory,
-                container.config.log_path
-            );
+        // create container root directory. What is created from here on is removed again if the container isn't
+        // added after all.
+        let container_root_dir = self.container_root_dir(&id).await;
+        let created: std::result::Result<(), Status> = async {
+            tokio::fs::create_dir_all(&container_root_dir).await?;
+
+            // create the working directory, so it can be preopened when the container starts.
+            if let Some(working_dir) = working_dir {
+                let working_dir = container_root_dir.join(working_dir);
+                tokio::fs::create_dir_all(&working_dir).await?;
+                container.working_dir = Some(working_dir);
+            }
+
+            // generate volume mounts. Allowed host paths are mounted as they are, the others get a directory of
+            // their own.
+            for (mount, host_dir) in container_config.mounts.into_iter().zip(host_dirs) {
+                let host_dir = host_dir
+                    .unwrap_or_else(|| PathBuf::from("volumes").join(Uuid::new_v4().to_string()));
+                container.volumes.push(grpc::Mount {
+                    host_path: host_dir.into_os_string().into_string().unwrap(),
+                    container_path: mount.container_path.to_owned(),
+                    propagation: mount.propagation,
+                    readonly: mount.readonly,
+                    selinux_relabel: mount.selinux_relabel,
+                })
+            }
+
+            // create the scratch directories in memory, resolved so they can be preopened like hostPath mounts.
+            if !memory_dir_paths.is_empty() {
+                let memory_dir = self.options.read().await.memory_dir.join(&id);
+                container.memory_dir = Some(memory_dir.clone());
+                for (i, container_path) in memory_dir_paths.into_iter().enumerate() {
+                    let dir = memory_dir.join(i.to_string());
+                    tokio::fs::create_dir_all(&dir).await?;
+                    let dir = tokio::fs::canonicalize(&dir).await?;
+                    container.volumes.push(grpc::Mount {
+                        container_path,
+                        host_path: dir.into_os_string().into_string().unwrap(),
+                        ..Default::default()
+                    });
+                }
+            }
+
+            // validate log paths and compose full container log path.
+            if sandbox_config.log_directory != "" && container.config.log_path != "" {
+                let log_path =
+                    PathBuf::from(&sandbox_config.log_directory).join(&container.config.log_path);
+                // the log path points to a file, so only create the directory containing it.
+                if let Some(log_dir) = log_path.parent() {
+                    tokio::fs::create_dir_all(log_dir).await?;
+                }
+                let legacy_log_dir = self.options.read().await.legacy_log_dir.clone();
+                if let Some(dir) = legacy_log_dir {
+                    container.legacy_log_link =
+                        link_legacy_log(&dir, &sandbox_config, &container, &log_path).await;
+                }
+                container.log_path = Some(log_path);
+                debug!("composed container log path using sandbox log directory {} and container config log path {}", sandbox_config.log_directory, container.config.log_path);
+            } else {
+                // logging is disabled
+                info!(
+                    "logging will be disabled due to empty log paths for sandbox {} or container {}",
+                    sandbox_config.log_directory, container.config.log_path
+                );
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(status) = created {
+            self.remove_created_files(&container, &container_root_dir)
+                .await;
+            return Err(status);
         }
 
-        // add container to the store.
+        // add container to the store, unless a container of the same name was added since it was checked.
+        let mut containers = self.containers.write().await;
+        if let Err(status) =
+            check_unique_name(&containers, &container.pod_sandbox_id, &container.config)
+        {
+            drop(containers);
+            self.remove_created_files(&container, &container_root_dir)
+                .await;
+            return Err(status);
+        }
         let mut sandboxes = self.sandboxes.write().await;
-        sandboxes
-            .get_mut(&container.pod_sandbox_id)
-            .ok_or_else(|| {
-                Status::not_found(format!(
+        let sandbox = match sandboxes.get_mut(&container.pod_sandbox_id) {
+            Some(sandbox) => sandbox,
+            None => {
+                drop(sandboxes);
+                drop(containers);
+                self.remove_created_files(&container, &container_root_dir)
+                    .await;
+                return Err(Status::not_found(format!(
                     "Could not found sandbox with id '{}'",
                     &container.pod_sandbox_id
-                ))
-            })?
-            .running_containers
-            .push(container.id.clone());
-        self.containers
-            .write()
-            .await
-            .insert(container.id.clone(), container);
+                )));
+            }
+        };
+        sandbox.running_containers.push(container.id.clone());
+        let backend = self.backend(container_runtime_handler(
+            &container.config,
+            &sandbox.inner.runtime_handler,
+        ));
+        let engine_config = sandbox_engine_config(&sandbox.inner.annotations);
+        let namespace = sandbox_namespace(&sandbox.config).to_owned();
+        drop(sandboxes);
+        let image_ref = container.image_ref.clone();
+        containers.insert(container.id.clone(), container);
+        drop(containers);
+        // only once the container exists, so removing it is what resets the level
+        if let Some(level) = &log_level {
+            self.set_container_log_level(&id, Some(level));
+        }
+        if let (Ok(backend), Ok(engine_config)) = (backend, engine_config) {
+            if backend.uses_warm_pool() && self.warm_pool.is_enabled() {
+                self.warm_in_background(&image_ref, &namespace, engine_config);
+            }
+        }
+        info!("container created");
 
         Ok(Response::new(grpc::CreateContainerResponse {
             container_id: id,
@@ -528,82 +2451,22 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::StartContainerRequest>,
     ) -> CriResult<grpc::StartContainerResponse> {
         let id = req.into_inner().container_id;
-        let mut containers = self.containers.write().await;
-
-        // Create specific scope for the container read lock
-        let mut container = containers
-            .get_mut(&id)
-            .ok_or_else(|| Status::not_found("Container not found"))?;
-        let sandboxes = self.sandboxes.read().await;
-        let sandbox = &sandboxes
-            .get(&container.pod_sandbox_id)
-            .ok_or_else(|| Status::not_found("Sandbox not found"))?
-            .inner;
-
-        let runtime = RuntimeHandler::from_string(&sandbox.runtime_handler)
-            .map_err(|_| Status::invalid_argument("Invalid runtime handler"))?;
-
-        let module_store = self.module_store.lock().await;
-
-        // Get the WASM data from the image
-        // TODO: handle error
-        let image_ref =
-            Reference::try_from(container.image_ref.clone()).expect("Failed to parse image_ref");
-        let module_path = module_store
-            .pull_file_path(&image_ref)
-            .into_os_string()
-            .into_string()
-            .unwrap();
-
-        let env: EnvVars = container
-            .config
-            .envs
-            .iter()
-            .cloned()
-            .map(|pair| (pair.key, pair.value))
-            .collect();
+        record_container_id(&id);
 
-        match runtime {
-            RuntimeHandler::WASCC => {
-                // Load the WASM
-                let wasm = tokio::fs::read(module_path).await?;
-                // Get the key out of the request
-                let key = container
-                    .config
-                    .annotations
-                    .get(ACTOR_KEY_ANNOTATION)
-                    .ok_or_else(|| Status::invalid_argument("actor key is required"))?;
-
-                wascc_run_http(wasm, env, key).map_err(|e| Status::internal(e.to_string()))?;
-                let mut running_containers = self.running_containers.write().await;
-
-                // Fake token. Needs to be replaced with a real cancellation token, which should come from wascc.
-                let token = ContainerCancellationToken::WasccCancelationToken(key.to_string());
-                running_containers.insert(container.id.clone(), token);
-            }
-            RuntimeHandler::WASI => {
-                let args = container.config.args.clone();
-                let log_path = container.log_path.clone();
-                let runtime = tokio::task::spawn_blocking(move || {
-                    crate::wasm::WasiRuntime::new(
-                        module_path,
-                        env,
-                        args,
-                        // TODO: dirs
-                        HashMap::new(),
-                        log_path.as_ref(),
-                    )
-                })
-                .await
-                .expect("Failed to create new thread for creating runtime")
-                .expect("Creating runtime failed");
-
-                let token = RuntimeContainer::new(runtime).start();
-                let mut running_containers = self.running_containers.write().await;
-                running_containers.insert(container.id.clone(), token);
-            }
+        // the maps are not locked while the module starts, so make sure nobody else starts it meanwhile
+        if !self.starting.lock().await.insert(id.clone()) {
+            return Err(Status::failed_precondition(
+                "Container is already being started",
+            ));
+        }
+        let result = match self.start_permit().await {
+            Ok(_permit) => self.start(&id).await,
+            Err(e) => Err(e),
         };
-        container.state = grpc::ContainerState::ContainerRunning as i32;
+        self.starting.lock().await.remove(&id);
+        result?;
+
+        info!("container started");
         Ok(Response::new(grpc::StartContainerResponse {}))
     }
 
@@ -611,9 +2474,12 @@ impl RuntimeService for CriRuntimeService {
         &self,
         req: Request<grpc::StopContainerRequest>,
     ) -> CriResult<grpc::StopContainerResponse> {
+        let id = req.into_inner().container_id;
+        record_container_id(&id);
         let tokens = self.running_containers.read().await;
-        if let Some(token) = tokens.get(&req.into_inner().container_id) {
-            token.stop()
+        if let Some(token) = tokens.get(&id) {
+            token.stop();
+            info!("container stopped");
         }
         Ok(Response::new(grpc::StopContainerResponse {}))
     }
@@ -624,6 +2490,7 @@ impl RuntimeService for CriRuntimeService {
     ) -> CriResult<grpc::RemoveContainerResponse> {
         let mut tokens = self.running_containers.write().await;
         let id = req.into_inner().container_id;
+        record_container_id(&id);
         match tokens.get(&id) {
             Some(token) => {
                 token.remove();
@@ -631,25 +2498,57 @@ impl RuntimeService for CriRuntimeService {
             }
             None => {
                 // Documentation seems to suggest that this is not an error case.
-                log::debug!("ID {} is not found in running containers", id)
+                debug!("ID {} is not found in running containers", id)
             }
         };
 
         let mut containers = self.containers.write().await;
-        let container = containers.get_mut(&id).unwrap();
+        let container = match containers.get(&id) {
+            Some(c) => c,
+            None => {
+                // removing a container that is already gone must not fail
+                debug!("ID {} is not found in containers", id);
+                return Ok(Response::new(grpc::RemoveContainerResponse {}));
+            }
+        };
 
         let mut sandboxes = self.sandboxes.write().await;
         if let Some(sandbox) = sandboxes.get_mut(&container.pod_sandbox_id) {
-            let pos = sandbox
-                .running_containers
-                .iter()
-                .position(|id| &container.id == id)
-                .unwrap();
-            sandbox.running_containers.remove(pos);
+            sandbox.running_containers.retain(|id| &container.id != id);
+            sandbox.http_ports.remove(&container.id);
+            sandbox.local_ports.remove(&container.id);
         }
         //TODO(rylev): handle error of there not being a sandbox
-
-        containers.remove(&id);
+        self.proxies.lock().await.remove(&id);
+        self.health.forget(&id).await;
+        self.set_container_log_level(&id, None);
+        self.outputs.write().await.remove(&id);
+
+        let removed = containers.remove(&id);
+        let image_ref = removed.as_ref().map(|c| c.image_ref.clone());
+        let (log_path, legacy_log_link, memory_dir) = removed
+            .map(|c| (c.log_path, c.legacy_log_link, c.memory_dir))
+            .unwrap_or_default();
+        // the warm instances of a module no container uses anymore would only take up threads
+        let unused_image = image_ref.filter(|r| !containers.values().any(|c| &c.image_ref == r));
+        drop(sandboxes);
+        drop(containers);
+        drop(tokens);
+        if let Some(image_ref) = unused_image {
+            self.warm_pool.evict(&image_ref).await;
+        }
+        if let Some(memory_dir) = memory_dir {
+            if let Err(e) = tokio::fs::remove_dir_all(&memory_dir).await {
+                warn!("cannot remove {}: {}", memory_dir.display(), e);
+            }
+        }
+        let retain_logs = self.options.read().await.retain_logs;
+        if !retain_logs {
+            for path in log_path.iter().chain(legacy_log_link.iter()) {
+                warn_on_cleanup_error(path, tokio::fs::remove_file(path).await);
+            }
+        }
+        info!("container removed");
 
         Ok(Response::new(grpc::RemoveContainerResponse {}))
     }
@@ -687,28 +2586,101 @@ impl RuntimeService for CriRuntimeService {
         &self,
         req: Request<grpc::ContainerStatusRequest>,
     ) -> CriResult<grpc::ContainerStatusResponse> {
-        let id = req.into_inner().container_id;
-        let containers = self.containers.read().await;
-        let container = containers
+        let request = req.into_inner();
+        let id = request.container_id;
+        record_container_id(&id);
+        let container = self
+            .containers
+            .read()
+            .await
             .get(&id)
+            .cloned()
             .ok_or_else(|| Status::not_found(format!("Container with ID {} does not exist", id)))?;
 
+        let mounts = resolved_mounts(&container, &self.container_root_dir(&id).await);
+        let mut info = HashMap::new();
+        if request.verbose {
+            info.insert(
+                "resources".to_owned(),
+                container.resources.info().to_string(),
+            );
+            info.insert(
+                "info".to_owned(),
+                self.container_info(&container).await.to_string(),
+            );
+        }
+
+        let exit_state = self
+            .running_containers
+            .read()
+            .await
+            .get(&id)
+            .and_then(ContainerCancellationToken::exit_state);
+        let health = self
+            .health
+            .get(&id)
+            .await
+            .filter(|health| self.health.is_unhealthy(health));
+        // the same reasons as the exit events, see `watch_exit`
+        let exit_reason = exit_state
+            .as_ref()
+            .and_then(ExitState::reason)
+            .unwrap_or_default();
+        let (state, exit_code, reason, message) = match (exit_state, health) {
+            // the module returned, e.g. a Job's that is done
+            (Some(ExitState::Exited), _) => (
+                grpc::ContainerState::ContainerExited as i32,
+                0,
+                exit_reason,
+                String::new(),
+            ),
+            (Some(ExitState::DeadlineExceeded), _) => (
+                grpc::ContainerState::ContainerExited as i32,
+                1,
+                exit_reason,
+                "container ran longer than its deadline".to_owned(),
+            ),
+            // the trap, or why the module aborted when its language tells, see `wasm::diagnostics`
+            (Some(ExitState::Failed(error)), _) => (
+                grpc::ContainerState::ContainerExited as i32,
+                1,
+                exit_reason,
+                error,
+            ),
+            (_, Some(health)) => (
+                container.state,
+                0,
+                "Unhealthy",
+                format!(
+                    "{} health checks in a row failed, the latest with: {}",
+                    health.failures,
+                    health.last_error.unwrap_or_default()
+                ),
+            ),
+            _ => (
+                container.state,
+                0,
+                "because I said so",
+                "hello earthlings".to_owned(),
+            ),
+        };
+
         Ok(Response::new(grpc::ContainerStatusResponse {
             status: Some(grpc::ContainerStatus {
                 id: container.id.clone(),
                 metadata: container.config.metadata.clone(),
-                state: container.state,
+                state,
                 created_at: container.created_at,
                 started_at: 0,
                 finished_at: 0,
-                exit_code: 0,
+                exit_code,
                 image: container.config.image.clone(),
                 image_ref: container.image_ref.clone(),
-                reason: "because I said so".to_owned(),
-                message: "hello earthlings".to_owned(),
+                reason: reason.to_owned(),
+                message,
                 labels: container.config.labels.clone(),
                 annotations: container.config.annotations.clone(),
-                mounts: vec![],
+                mounts,
                 log_path: container
                     .log_path
                     .clone()
@@ -717,7 +2689,7 @@ impl RuntimeService for CriRuntimeService {
                     .into_string()
                     .unwrap(),
             }),
-            info: HashMap::new(),
+            info,
         }))
     }
 
@@ -726,13 +2698,17 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::ContainerStatsRequest>,
     ) -> CriResult<grpc::ContainerStatsResponse> {
         let id = req.into_inner().container_id;
-        let containers = self.containers.read().await;
-        let container = containers
+        record_container_id(&id);
+        let container = self
+            .containers
+            .read()
+            .await
             .get(&id)
+            .cloned()
             .ok_or_else(|| Status::not_found(format!("Container with ID {} does not exist", id)))?;
 
         Ok(Response::new(grpc::ContainerStatsResponse {
-            stats: Some(container.clone().into()),
+            stats: Some(self.stats_of(container).await),
         }))
     }
 
@@ -741,8 +2717,10 @@ impl RuntimeService for CriRuntimeService {
         req: Request<grpc::ListContainerStatsRequest>,
     ) -> CriResult<grpc::ListContainerStatsResponse> {
         let filter = req.into_inner().filter.unwrap_or_default();
-        let containers = self.containers.read().await;
-        let container_stats: Vec<grpc::ContainerStats> = containers
+        let containers: Vec<UserContainer> = self
+            .containers
+            .read()
+            .await
             .values()
             .filter(|c| {
                 (filter.id == "" || c.id == filter.id)
@@ -751,12 +2729,76 @@ impl RuntimeService for CriRuntimeService {
                         || has_labels(&filter.label_selector, &c.config.labels))
             })
             .cloned()
-            .map(grpc::ContainerStats::from)
             .collect();
+        let mut container_stats = Vec::with_capacity(containers.len());
+        for container in containers {
+            container_stats.push(self.stats_of(container).await);
+        }
         Ok(Response::new(grpc::ListContainerStatsResponse {
             stats: container_stats,
         }))
     }
+
+    /// Invoke an operation of a waSCC actor or a waPC guest, e.g. for a probe. The command names the operation,
+    /// and the args following it, joined by spaces, are its payload. The reply is returned as stdout; a failed
+    /// invocation exits with 1 and the error on stderr.
+    ///
+    /// WASI modules run to completion without taking calls, so there is nothing to exec into.
+    async fn exec_sync(
+        &self,
+        req: Request<grpc::ExecSyncRequest>,
+    ) -> CriResult<grpc::ExecSyncResponse> {
+        let req = req.into_inner();
+        record_container_id(&req.container_id);
+        let token = match self.running_containers.read().await.get(&req.container_id) {
+            Some(token) if token.takes_calls() => Some(token.clone()),
+            Some(_) => {
+                return Err(Status::unimplemented(
+                    "exec is only supported in waSCC actors and waPC guests",
+                ))
+            }
+            None => None,
+        };
+        let token = match token {
+            Some(token) => token,
+            None if self.containers.read().await.contains_key(&req.container_id) => {
+                return Err(Status::failed_precondition("Container is not running"))
+            }
+            None => return Err(Status::not_found("Container not found")),
+        };
+        let (operation, args) = req.cmd.split_first().ok_or_else(|| {
+            Status::invalid_argument("exec needs a command naming the operation to invoke")
+        })?;
+        let operation = operation.clone();
+        let payload = args.join(" ").into_bytes();
+
+        let call = token.call(operation, payload);
+        let result = if req.timeout > 0 {
+            tokio::time::timeout(Duration::from_secs(req.timeout as u64), call)
+                .await
+                .map_err(|_| {
+                    Status::deadline_exceeded(format!(
+                        "the container did not reply within {}s",
+                        req.timeout
+                    ))
+                })?
+        } else {
+            call.await
+        };
+        let response = match result {
+            Ok(reply) => grpc::ExecSyncResponse {
+                stdout: reply,
+                stderr: vec![],
+                exit_code: 0,
+            },
+            Err(e) => grpc::ExecSyncResponse {
+                stdout: vec![],
+                stderr: e.to_string().into_bytes(),
+                exit_code: 1,
+            },
+        };
+        Ok(Response::new(response))
+    }
 }
 
 // For use in checking if label maps (or any String, String maps) contain all of
@@ -773,9 +2815,14 @@ pub(crate) fn has_labels(
     true
 }
 
-#[cfg(test)]
+// the sandboxes of the tests run with the default WASI handler
+#[cfg(all(test, feature = "wasi"))]
 mod test {
     use super::*;
+    use crate::server::conditions::{ACTORS_HEALTHY, IMAGE_STORE_READY};
+    #[cfg(feature = "wapc")]
+    use crate::wasm::WapcRuntime;
+    use futures::StreamExt;
     use ipnet::{IpNet, Ipv4Net};
     use std::net::Ipv4Addr;
     use tempfile::tempdir;
@@ -803,6 +2850,86 @@ mod test {
         assert!(has_labels(&search_labels, &target_labels));
     }
 
+    #[test]
+    fn test_free_http_port() {
+        let mut sandbox = UserSandbox {
+            port_mappings: vec![
+                grpc::PortMapping {
+                    protocol: grpc::Protocol::Udp as i32,
+                    container_port: 53,
+                    ..Default::default()
+                },
+                grpc::PortMapping {
+                    protocol: grpc::Protocol::Tcp as i32,
+                    container_port: 8080,
+                    ..Default::default()
+                },
+                grpc::PortMapping {
+                    protocol: grpc::Protocol::Tcp as i32,
+                    container_port: 8081,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        // UDP mappings are skipped
+        assert_eq!(Some(8080), sandbox.free_http_port());
+
+        // ports that are already served are skipped
+        sandbox.http_ports.insert("first".to_owned(), 8080);
+        assert_eq!(Some(8081), sandbox.free_http_port());
+        sandbox.http_ports.insert("second".to_owned(), 8081);
+        assert_eq!(None, sandbox.free_http_port());
+    }
+
+    #[tokio::test]
+    async fn test_expose_http_port() {
+        let host_port = free_local_port().unwrap();
+        let sandbox = UserSandbox {
+            port_mappings: vec![grpc::PortMapping {
+                protocol: grpc::Protocol::Tcp as i32,
+                container_port: 8080,
+                host_port: i32::from(host_port),
+                host_ip: "127.0.0.1".to_owned(),
+            }],
+            ..Default::default()
+        };
+        let container = UserContainer {
+            id: "app".to_owned(),
+            pod_sandbox_id: "test".to_owned(),
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), sandbox.clone());
+        // without isolation, the container listens on the container port itself
+        assert_eq!(8080, svc.expose_http_port(&container, 8080).await.unwrap());
+
+        let svc = CriRuntimeService::new(PathBuf::from(""), None)
+            .await
+            .with_port_isolation(true);
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), sandbox);
+        let local_port = svc.expose_http_port(&container, 8080).await.unwrap();
+        assert_ne!(8080, local_port);
+        assert_eq!(
+            Some(&local_port),
+            svc.sandboxes.read().await["test"].local_ports.get("app")
+        );
+        tokio::net::TcpStream::connect(("127.0.0.1", host_port))
+            .await
+            .expect("the host port is forwarded");
+
+        svc.release_http_port(&container).await;
+        assert!(svc.proxies.lock().await.is_empty());
+        assert!(svc.sandboxes.read().await["test"].local_ports.is_empty());
+    }
+
     #[tokio::test]
     async fn test_version() {
         let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
@@ -844,6 +2971,89 @@ mod test {
         )
     }
 
+    #[cfg(feature = "wascc")]
+    #[tokio::test]
+    async fn test_update_runtime_config_settings() {
+        let cidr = IpNet::from(Ipv4Net::new(Ipv4Addr::new(192, 168, 1, 0), 24).unwrap());
+        let svc = CriRuntimeService::new(PathBuf::from(""), Some(cidr))
+            .await
+            .with_log_filter(LogFilterHandle::new("wok=info".to_owned(), |_| Ok(())));
+
+        let mut req = Request::new(grpc::UpdateRuntimeConfigRequest::default());
+        req.metadata_mut()
+            .insert(DEFAULT_HANDLER_METADATA, "WASCC".parse().unwrap());
+        req.metadata_mut()
+            .insert(RETAIN_LOGS_METADATA, "true".parse().unwrap());
+        req.metadata_mut()
+            .insert(SHUTDOWN_TIMEOUT_METADATA, "5".parse().unwrap());
+        req.metadata_mut()
+            .insert(LOG_LEVEL_METADATA, "wok=debug".parse().unwrap());
+        svc.update_runtime_config(req)
+            .await
+            .expect("successful update config request");
+
+        let options = svc.options().await;
+        assert_eq!("WASCC", options.default_handler);
+        assert!(options.retain_logs);
+        assert_eq!(5, options.shutdown_timeout);
+        // without a network config, the pod CIDR is left alone
+        assert_eq!(Some(cidr), *svc.pod_cidr.read().await);
+
+        let config = svc.effective_config().await;
+        assert_eq!("wok=debug", config["log_level"]);
+        assert_eq!("WASCC", config["runtime"]["default_handler"]);
+
+        // nothing is applied when one of the settings is invalid
+        let mut req = Request::new(grpc::UpdateRuntimeConfigRequest::default());
+        req.metadata_mut()
+            .insert(DEFAULT_HANDLER_METADATA, "WASI".parse().unwrap());
+        req.metadata_mut()
+            .insert(SHUTDOWN_TIMEOUT_METADATA, "soon".parse().unwrap());
+        let status = svc
+            .update_runtime_config(req)
+            .await
+            .expect_err("invalid shutdown timeout");
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+        assert_eq!("WASCC", svc.options().await.default_handler);
+    }
+
+    #[tokio::test]
+    async fn test_update_runtime_config_log_level_unsupported() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        let mut req = Request::new(grpc::UpdateRuntimeConfigRequest::default());
+        req.metadata_mut()
+            .insert(LOG_LEVEL_METADATA, "wok=debug".parse().unwrap());
+        let status = svc
+            .update_runtime_config(req)
+            .await
+            .expect_err("no log filter handle");
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+    }
+
+    #[tokio::test]
+    async fn test_status_conditions() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        svc.conditions()
+            .set(
+                IMAGE_STORE_READY,
+                false,
+                "StoreNotWritable",
+                "No space left on device",
+            )
+            .await;
+        let conditions = svc
+            .status(Request::new(grpc::StatusRequest::default()))
+            .await
+            .expect("successful status request")
+            .into_inner()
+            .status
+            .unwrap()
+            .conditions;
+        assert_eq!(3, conditions.len());
+        assert_eq!("ImageStoreReady", conditions[2].r#type);
+        assert!(!conditions[2].status);
+    }
+
     #[tokio::test]
     async fn test_status() {
         let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
@@ -879,6 +3089,10 @@ mod test {
         assert!(res.get_ref().info.is_empty());
 
         // now double check that info gets data if verbose is requested
+        svc.running_containers.write().await.insert(
+            "actor".to_owned(),
+            ContainerCancellationToken::WasccCancelationToken("MKEY".to_owned()),
+        );
         let mut req = grpc::StatusRequest::default();
         req.verbose = true;
         let res = svc
@@ -889,6 +3103,10 @@ mod test {
         assert!(!info.is_empty());
         assert!(info.contains_key("running_sandboxes"));
         assert!(info.contains_key("running_containers"));
+        let instances: serde_json::Value = serde_json::from_str(&info["instances"]).unwrap();
+        assert_eq!(json!({"wasi": 0, "wascc": 1, "wapc": 0}), instances);
+        assert_eq!("0", info["warm_instances"]);
+        assert_eq!("0", info["pulls_in_flight"]);
     }
 
     #[tokio::test]
@@ -907,6 +3125,7 @@ mod test {
                     ..Default::default()
                 },
                 running_containers: vec![],
+                ..Default::default()
             },
         );
         sandboxes.insert(
@@ -918,6 +3137,7 @@ mod test {
                     ..Default::default()
                 },
                 running_containers: vec![],
+                ..Default::default()
             },
         );
         sandboxes.insert(
@@ -929,6 +3149,7 @@ mod test {
                     ..Default::default()
                 },
                 running_containers: vec![],
+                ..Default::default()
             },
         );
         drop(sandboxes);
@@ -990,24 +3211,42 @@ mod test {
                 annotations: HashMap::new(),
                 runtime_handler: RuntimeHandler::WASI.to_string(),
             },
-            running_containers: vec![],
+            running_containers: vec!["container".to_owned()],
+            config: grpc::PodSandboxConfig {
+                log_directory: "/var/log/pods/1".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
         };
         sandboxes.insert(sandbox.inner.id.clone(), sandbox);
         drop(sandboxes);
+        svc.containers.write().await.insert(
+            "container".to_owned(),
+            UserContainer {
+                id: "container".to_owned(),
+                pod_sandbox_id: "1".to_owned(),
+                state: grpc::ContainerState::ContainerRunning as i32,
+                ..Default::default()
+            },
+        );
         let req = Request::new(grpc::PodSandboxStatusRequest {
             pod_sandbox_id: "1".to_owned(),
             verbose: true,
         });
-        let res = svc.pod_sandbox_status(req).await;
+        let res = svc.pod_sandbox_status(req).await.expect("status result");
         assert_eq!(
             "1",
-            res.expect("status result")
-                .get_ref()
-                .status
-                .as_ref()
-                .expect("status result")
-                .id
+            res.get_ref().status.as_ref().expect("status result").id
         );
+        let info: serde_json::Value =
+            serde_json::from_str(&res.get_ref().info["info"]).expect("info is JSON");
+        assert_eq!("WASI", info["runtimeHandler"]);
+        assert_eq!(
+            json!([{"id": "container", "state": "ContainerRunning"}]),
+            info["containers"]
+        );
+        assert_eq!(json!(null), info["ip"]);
+        assert_eq!("/var/log/pods/1", info["config"]["logDirectory"]);
     }
 
     #[tokio::test]
@@ -1036,6 +3275,7 @@ mod test {
                     runtime_handler: RuntimeHandler::WASI.to_string(),
                 },
                 running_containers: vec![container_id],
+                ..Default::default()
             },
         );
         drop(sandboxes);
@@ -1045,12 +3285,80 @@ mod test {
         let res = svc.remove_pod_sandbox(req).await;
         // we expect an empty response object
         res.expect("remove sandbox result");
-        // TODO(bacongobbler): un-comment this once remove_container() has been implemented
-        //assert_eq!(0, svc.containers.read().unwrap().len());
+        assert_eq!(0, svc.containers.read().await.len());
 
         assert_eq!(0, svc.sandboxes.read().await.values().len());
     }
 
+    #[tokio::test]
+    async fn test_remove_pod_sandbox_logs() {
+        for retain_logs in &[false, true] {
+            let dir = tempdir().expect("Couldn't create temp directory");
+            let options = RuntimeOptions {
+                retain_logs: *retain_logs,
+                ..Default::default()
+            };
+            let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+            let log_directory = dir.path().join("logs");
+            let log_path = log_directory.join("container.log");
+            tokio::fs::create_dir_all(&log_directory)
+                .await
+                .expect("Couldn't create log directory");
+            tokio::fs::write(&log_path, "hello")
+                .await
+                .expect("Couldn't write log");
+
+            svc.containers.write().await.insert(
+                "container".to_owned(),
+                UserContainer {
+                    id: "container".to_owned(),
+                    pod_sandbox_id: "1".to_owned(),
+                    log_path: Some(log_path.clone()),
+                    ..Default::default()
+                },
+            );
+            svc.sandboxes.write().await.insert(
+                "1".to_owned(),
+                UserSandbox {
+                    inner: grpc::PodSandbox {
+                        state: grpc::PodSandboxState::SandboxNotready as i32,
+                        ..Default::default()
+                    },
+                    running_containers: vec!["container".to_owned()],
+                    log_directory: Some(log_directory.clone()),
+                    ..Default::default()
+                },
+            );
+
+            svc.remove_pod_sandbox(Request::new(grpc::RemovePodSandboxRequest {
+                pod_sandbox_id: "1".to_owned(),
+            }))
+            .await
+            .expect("remove sandbox result");
+            assert_eq!(*retain_logs, log_path.exists());
+            assert_eq!(*retain_logs, log_directory.exists());
+        }
+    }
+
+    #[test]
+    fn test_check_children() {
+        check_children("stop", "1", vec![]).expect("no errors");
+
+        let status = check_children(
+            "stop",
+            "1",
+            vec![
+                ("a".to_owned(), Status::internal("boom")),
+                ("b".to_owned(), Status::not_found("gone")),
+            ],
+        )
+        .expect_err("errors are aggregated");
+        assert_eq!(
+            "failed to stop 2 containers of sandbox 1: a: boom; b: gone",
+            status.message()
+        );
+    }
+
     #[tokio::test]
     async fn test_stop_pod_sandbox() {
         let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
@@ -1067,7 +3375,8 @@ mod test {
                     annotations: HashMap::new(),
                     runtime_handler: RuntimeHandler::WASI.to_string(),
                 },
-                running_containers: vec![],
+                running_containers: vec!["container".to_owned()],
+                ..Default::default()
             },
         );
         drop(sandboxes);
@@ -1078,6 +3387,14 @@ mod test {
 
         // Expect the stopped ID to be the same as the requested ID.
         res.expect("empty stop result");
+        let sandboxes = svc.sandboxes.read().await;
+        assert_eq!(
+            grpc::PodSandboxState::SandboxNotready as i32,
+            sandboxes["test"].inner.state
+        );
+        // the containers are stopped, but they still belong to the sandbox until they are removed
+        assert_eq!(vec!["container"], sandboxes["test"].running_containers);
+        drop(sandboxes);
 
         // test what happens when the requested pod sandbox doesn't exist
         let req = Request::new(grpc::StopPodSandboxRequest {
@@ -1104,6 +3421,7 @@ mod test {
                     runtime_handler: RuntimeHandler::WASI.to_string(),
                 },
                 running_containers: vec![],
+                ..Default::default()
             },
         );
         drop(sandboxes);
@@ -1114,18 +3432,558 @@ mod test {
         let req = Request::new(grpc::CreateContainerRequest {
             pod_sandbox_id: "test".to_owned(),
             config: Some(config),
-            sandbox_config: None,
+            sandbox_config: None,
+        });
+
+        let res = svc.create_container(req).await;
+        // We can't have a deterministic container id, so just check it is a valid uuid
+        uuid::Uuid::parse_str(
+            &res.expect("successful create container")
+                .get_ref()
+                .container_id,
+        )
+        .unwrap();
+        assert_eq!(1, svc.containers.read().await.len());
+    }
+
+    #[test]
+    fn test_container_runtime_handler() {
+        let mut config = grpc::ContainerConfig::default();
+        assert_eq!("WASCC", container_runtime_handler(&config, "WASCC"));
+
+        config.annotations.insert(
+            RUNTIME_HANDLER_ANNOTATION.to_owned(),
+            RuntimeHandler::WASI.to_string(),
+        );
+        assert_eq!("WASI", container_runtime_handler(&config, "WASCC"));
+
+        config
+            .annotations
+            .insert(RUNTIME_HANDLER_ANNOTATION.to_owned(), "runc".to_owned());
+        Backends::default()
+            .get(container_runtime_handler(&config, "WASCC"))
+            .expect_err("runc is not a wok handler");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_handler() {
+        let options = RuntimeOptions {
+            disabled_handlers: vec!["WASCC".to_owned()],
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(PathBuf::from(""), None, options).await;
+        assert!(!svc.backends().names().contains(&"WASCC"));
+
+        let mut req = grpc::RunPodSandboxRequest {
+            config: Some(grpc::PodSandboxConfig::default()),
+            runtime_handler: RuntimeHandler::WASCC.to_string(),
+        };
+        let err = svc
+            .run_pod_sandbox(Request::new(req.clone()))
+            .await
+            .expect_err("disabled handler");
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+        req.runtime_handler = RuntimeHandler::WASI.to_string();
+        svc.run_pod_sandbox(Request::new(req))
+            .await
+            .expect("enabled handler");
+    }
+
+    #[tokio::test]
+    async fn test_handler_aliases() {
+        let dir = tempdir().unwrap();
+        let aliases = vec![HandlerAliasOptions {
+            name: "wasmtime".to_owned(),
+            handler: "WASI".to_owned(),
+            annotations: vec![(WASI_PROFILE_ANNOTATION.to_owned(), "restricted".to_owned())]
+                .into_iter()
+                .collect(),
+        }];
+        let options = RuntimeOptions {
+            handler_aliases: aliases.clone(),
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        let backends = svc.backends().clone().with_aliases(&aliases).unwrap();
+        let svc = svc.with_backends(backends);
+
+        let id = svc
+            .run_pod_sandbox(Request::new(grpc::RunPodSandboxRequest {
+                config: Some(grpc::PodSandboxConfig::default()),
+                runtime_handler: "wasmtime".to_owned(),
+            }))
+            .await
+            .expect("alias of WASI")
+            .into_inner()
+            .pod_sandbox_id;
+        let sandbox = svc.sandboxes.read().await[&id].inner.clone();
+        assert_eq!("wasmtime", sandbox.runtime_handler);
+        assert_eq!("restricted", sandbox.annotations[WASI_PROFILE_ANNOTATION]);
+
+        let mut config = grpc::ContainerConfig {
+            image: Some(grpc::ImageSpec {
+                image: "foo/bar:baz".to_owned(),
+            }),
+            metadata: Some(grpc::ContainerMetadata {
+                name: "app".to_owned(),
+                attempt: 0,
+            }),
+            ..Default::default()
+        };
+        let container_id = svc
+            .create_container(Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: id.clone(),
+                config: Some(config.clone()),
+                sandbox_config: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .container_id;
+        assert_eq!(
+            "restricted",
+            svc.containers.read().await[&container_id]
+                .config
+                .annotations[WASI_PROFILE_ANNOTATION]
+        );
+
+        // the container's own annotations take precedence
+        config.metadata.as_mut().unwrap().name = "unconfined".to_owned();
+        config
+            .annotations
+            .insert(WASI_PROFILE_ANNOTATION.to_owned(), "unconfined".to_owned());
+        let container_id = svc
+            .create_container(Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: id,
+                config: Some(config),
+                sandbox_config: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .container_id;
+        assert_eq!(
+            "unconfined",
+            svc.containers.read().await[&container_id]
+                .config
+                .annotations[WASI_PROFILE_ANNOTATION]
+        );
+    }
+
+    #[test]
+    fn test_working_dir_path() {
+        assert_eq!(None, working_dir_path("").unwrap());
+        assert_eq!(
+            Some(PathBuf::from("app/data")),
+            working_dir_path("/app/./data/").unwrap()
+        );
+        assert_eq!(Some(PathBuf::from("")), working_dir_path("/").unwrap());
+        working_dir_path("app").expect_err("relative working directories are rejected");
+        working_dir_path("/app/../..").expect_err("the working directory can't leave the root");
+    }
+
+    #[tokio::test]
+    async fn test_default_wasi_profile() {
+        let dir = tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        let options = RuntimeOptions {
+            default_wasi_profile: WasiProfile::Baseline,
+            host_path_prefixes: vec![shared.clone()],
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), UserSandbox::default());
+        let request = |name: &str, profile: Option<&str>, mount: bool| {
+            let mut config = grpc::ContainerConfig::default();
+            config.image = Some(grpc::ImageSpec {
+                image: "foo/bar:baz".to_owned(),
+            });
+            config.metadata = Some(grpc::ContainerMetadata {
+                name: name.to_owned(),
+                attempt: 0,
+            });
+            if let Some(profile) = profile {
+                config
+                    .annotations
+                    .insert(WASI_PROFILE_ANNOTATION.to_owned(), profile.to_owned());
+            }
+            if mount {
+                config.mounts.push(grpc::Mount {
+                    container_path: "/shared".to_owned(),
+                    host_path: shared.to_str().unwrap().to_owned(),
+                    ..Default::default()
+                });
+            }
+            Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: "test".to_owned(),
+                config: Some(config),
+                sandbox_config: None,
+            })
+        };
+
+        let id = svc
+            .create_container(request("app", None, false))
+            .await
+            .unwrap()
+            .into_inner()
+            .container_id;
+        assert_eq!(
+            "baseline",
+            svc.containers.read().await[&id].config.annotations[WASI_PROFILE_ANNOTATION]
+        );
+        let err = svc
+            .create_container(request("mounting", None, true))
+            .await
+            .expect_err("baseline denies host directories");
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+        svc.create_container(request("unconfined", Some("unconfined"), true))
+            .await
+            .expect("the container's own profile takes precedence");
+    }
+
+    #[test]
+    fn test_memory_dir_paths() {
+        let mut config = grpc::ContainerConfig::default();
+        assert!(memory_dir_paths(&config).unwrap().is_empty());
+        config.annotations.insert(
+            MEMORY_DIRS_ANNOTATION.to_owned(),
+            "/tmp, /cache,".to_owned(),
+        );
+        assert_eq!(vec!["/tmp", "/cache"], memory_dir_paths(&config).unwrap());
+        for invalid in &["tmp", "/tmp/../etc", "/tmp,/tmp"] {
+            config
+                .annotations
+                .insert(MEMORY_DIRS_ANNOTATION.to_owned(), (*invalid).to_owned());
+            memory_dir_paths(&config).expect_err(invalid);
+        }
+        config
+            .annotations
+            .insert(MEMORY_DIRS_ANNOTATION.to_owned(), "/data".to_owned());
+        config.mounts.push(grpc::Mount {
+            container_path: "/data".to_owned(),
+            ..Default::default()
+        });
+        memory_dir_paths(&config).expect_err("the path is mounted already");
+    }
+
+    #[tokio::test]
+    async fn test_memory_dirs() {
+        let dir = tempdir().unwrap();
+        let options = RuntimeOptions {
+            memory_dir: dir.path().join("shm"),
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), UserSandbox::default());
+        let mut config = grpc::ContainerConfig::default();
+        config.image = Some(grpc::ImageSpec {
+            image: "foo/bar:baz".to_owned(),
+        });
+        config
+            .annotations
+            .insert(MEMORY_DIRS_ANNOTATION.to_owned(), "/tmp".to_owned());
+        let id = svc
+            .create_container(Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: "test".to_owned(),
+                config: Some(config),
+                sandbox_config: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .container_id;
+
+        let container = svc.containers.read().await[&id].clone();
+        let memory_dir = dir.path().canonicalize().unwrap().join("shm").join(&id);
+        assert_eq!(Some(dir.path().join("shm").join(&id)), container.memory_dir);
+        assert_eq!("/tmp", container.volumes[0].container_path);
+        assert_eq!(
+            memory_dir.join("0").to_str().unwrap(),
+            container.volumes[0].host_path
+        );
+        std::fs::write(memory_dir.join("0/data"), b"hello").unwrap();
+        let stats = svc.stats_of(container).await;
+        assert_eq!(5, stats.memory.unwrap().working_set_bytes.unwrap().value);
+
+        svc.remove_container(Request::new(grpc::RemoveContainerRequest {
+            container_id: id,
+        }))
+        .await
+        .unwrap();
+        assert!(!memory_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_max_preopened_dirs() {
+        let dir = tempdir().unwrap();
+        let options = RuntimeOptions {
+            memory_dir: dir.path().join("shm"),
+            max_preopened_dirs: 2,
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), UserSandbox::default());
+        let request = |memory_dirs: &str, working_dir: &str| {
+            let mut config = grpc::ContainerConfig::default();
+            config.image = Some(grpc::ImageSpec {
+                image: "foo/bar:baz".to_owned(),
+            });
+            config
+                .annotations
+                .insert(MEMORY_DIRS_ANNOTATION.to_owned(), memory_dirs.to_owned());
+            config.working_dir = working_dir.to_owned();
+            Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: "test".to_owned(),
+                config: Some(config),
+                sandbox_config: None,
+            })
+        };
+
+        svc.create_container(request("/tmp,/cache", ""))
+            .await
+            .expect("as many directories as allowed");
+        // the working directory counts twice
+        let err = svc
+            .create_container(request("/tmp", "/app"))
+            .await
+            .expect_err("too many directories");
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+        assert_eq!(1, svc.containers.read().await.len());
+    }
+
+    #[tokio::test]
+    async fn test_remove_orphaned_dirs() {
+        let dir = tempdir().unwrap();
+        let options = RuntimeOptions {
+            memory_dir: dir.path().join("shm"),
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), UserSandbox::default());
+        let mut config = grpc::ContainerConfig::default();
+        config.image = Some(grpc::ImageSpec {
+            image: "foo/bar:baz".to_owned(),
+        });
+        config
+            .annotations
+            .insert(MEMORY_DIRS_ANNOTATION.to_owned(), "/tmp".to_owned());
+        let id = svc
+            .create_container(Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: "test".to_owned(),
+                config: Some(config),
+                sandbox_config: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .container_id;
+        let crashed = Uuid::new_v4().to_string();
+        let orphans = vec![
+            dir.path().join("containers").join(&crashed),
+            dir.path().join("shm").join(&crashed),
+        ];
+        for orphan in &orphans {
+            std::fs::create_dir_all(orphan.join("volumes/1")).unwrap();
+        }
+        std::fs::write(orphans[0].join("volumes/1/data"), b"hello").unwrap();
+        // memory_dir may be shared with other programs
+        let other = dir.path().join("shm/other-program");
+        std::fs::create_dir_all(&other).unwrap();
+
+        let removed = svc.remove_orphaned_dirs().await;
+        assert_eq!(5, removed.reclaimed_bytes);
+        assert_eq!(2, removed.removed.len());
+        assert_eq!(removed, svc.orphans().await);
+        assert!(orphans.iter().all(|orphan| !orphan.exists()));
+        assert!(other.exists());
+        assert!(dir.path().join("containers").join(&id).exists());
+        assert!(dir.path().join("shm").join(&id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_log_link() {
+        let dir = tempdir().unwrap();
+        let options = RuntimeOptions {
+            legacy_log_dir: Some(dir.path().join("containers")),
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), UserSandbox::default());
+        let mut config = grpc::ContainerConfig::default();
+        config.image = Some(grpc::ImageSpec {
+            image: "foo/bar:baz".to_owned(),
+        });
+        config.metadata = Some(grpc::ContainerMetadata {
+            name: "app".to_owned(),
+            attempt: 0,
+        });
+        config.log_path = "app/0.log".to_owned();
+        let mut sandbox_config = grpc::PodSandboxConfig::default();
+        sandbox_config.metadata = Some(grpc::PodSandboxMetadata {
+            name: "web".to_owned(),
+            namespace: "default".to_owned(),
+            ..Default::default()
+        });
+        sandbox_config.log_directory = dir.path().join("pods").to_str().unwrap().to_owned();
+        let req = Request::new(grpc::CreateContainerRequest {
+            pod_sandbox_id: "test".to_owned(),
+            config: Some(config),
+            sandbox_config: Some(sandbox_config),
+        });
+
+        let id = svc
+            .create_container(req)
+            .await
+            .expect("successful create container")
+            .into_inner()
+            .container_id;
+        let link = dir
+            .path()
+            .join("containers")
+            .join(format!("web_default_app-{}.log", id));
+        assert_eq!(
+            dir.path().join("pods/app/0.log"),
+            std::fs::read_link(&link).expect("log is linked")
+        );
+
+        svc.remove_container(Request::new(grpc::RemoveContainerRequest {
+            container_id: id,
+        }))
+        .await
+        .expect("successful remove container");
+        assert!(std::fs::symlink_metadata(&link).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_create_container_invalid_handler() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        let mut config = grpc::ContainerConfig::default();
+        config.image = Some(grpc::ImageSpec {
+            image: "foo/bar:baz".to_owned(),
+        });
+        config
+            .annotations
+            .insert(RUNTIME_HANDLER_ANNOTATION.to_owned(), "runc".to_owned());
+        let req = Request::new(grpc::CreateContainerRequest {
+            pod_sandbox_id: "test".to_owned(),
+            config: Some(config),
+            sandbox_config: None,
+        });
+
+        let err = svc
+            .create_container(req)
+            .await
+            .expect_err("invalid handler is rejected");
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+        assert!(svc.containers.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_container_duplicate_name() {
+        let dir = tempdir().unwrap();
+        let svc = CriRuntimeService::new(dir.path().to_owned(), None).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), UserSandbox::default());
+        let request = |attempt| {
+            let mut config = grpc::ContainerConfig::default();
+            config.image = Some(grpc::ImageSpec {
+                image: "foo/bar:baz".to_owned(),
+            });
+            config.metadata = Some(grpc::ContainerMetadata {
+                name: "app".to_owned(),
+                attempt,
+            });
+            Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: "test".to_owned(),
+                config: Some(config),
+                sandbox_config: None,
+            })
+        };
+
+        svc.create_container(request(0))
+            .await
+            .expect("successful create container");
+        let err = svc
+            .create_container(request(0))
+            .await
+            .expect_err("the name is taken");
+        assert_eq!(tonic::Code::AlreadyExists, err.code());
+        let containers_dir = dir.path().join("containers");
+        assert_eq!(1, std::fs::read_dir(&containers_dir).unwrap().count());
+        // nothing is left behind either when the sandbox is gone
+        let mut req = request(2);
+        req.get_mut().pod_sandbox_id = "gone".to_owned();
+        let err = svc.create_container(req).await.expect_err("no sandbox");
+        assert_eq!(tonic::Code::NotFound, err.code());
+        assert_eq!(1, std::fs::read_dir(&containers_dir).unwrap().count());
+        // a restarted container comes with the next attempt
+        svc.create_container(request(1))
+            .await
+            .expect("successful create container");
+        assert_eq!(2, svc.containers.read().await.len());
+    }
+
+    #[cfg(feature = "wascc")]
+    #[tokio::test]
+    async fn test_create_container_policy() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rules]]
+            namespace = "batch"
+            capabilities = ["wascc:keyvalue"]
+            "#,
+        )
+        .unwrap();
+        let svc = CriRuntimeService::new(PathBuf::from(""), None)
+            .await
+            .with_policy(policy);
+        let mut config = grpc::ContainerConfig::default();
+        config.image = Some(grpc::ImageSpec {
+            image: "foo/bar:baz".to_owned(),
+        });
+        config
+            .annotations
+            .insert(RUNTIME_HANDLER_ANNOTATION.to_owned(), "WASCC".to_owned());
+        config.annotations.insert(
+            CAPABILITIES_ANNOTATION.to_owned(),
+            r#"{"wascc:http_server": {}}"#.to_owned(),
+        );
+        let mut sandbox_config = grpc::PodSandboxConfig::default();
+        sandbox_config.metadata = Some(grpc::PodSandboxMetadata {
+            name: "report".to_owned(),
+            namespace: "batch".to_owned(),
+            ..Default::default()
+        });
+        let req = Request::new(grpc::CreateContainerRequest {
+            pod_sandbox_id: "test".to_owned(),
+            config: Some(config),
+            sandbox_config: Some(sandbox_config),
         });
 
-        let res = svc.create_container(req).await;
-        // We can't have a deterministic container id, so just check it is a valid uuid
-        uuid::Uuid::parse_str(
-            &res.expect("successful create container")
-                .get_ref()
-                .container_id,
-        )
-        .unwrap();
-        assert_eq!(1, svc.containers.read().await.len());
+        let err = svc
+            .create_container(req)
+            .await
+            .expect_err("capability is not allowed");
+        assert_eq!(tonic::Code::PermissionDenied, err.code());
+        assert!(svc.containers.read().await.is_empty());
     }
 
     #[tokio::test]
@@ -1178,6 +4036,7 @@ mod test {
                 UserSandbox {
                     inner: sandbox,
                     running_containers: vec![],
+                    ..Default::default()
                 },
             );
             container_id
@@ -1194,6 +4053,248 @@ mod test {
             grpc::ContainerState::ContainerRunning as i32,
             containers[&id].state
         );
+        assert!(svc.starting.lock().await.is_empty());
+    }
+
+    /// RecordingSink keeps the events it is told about.
+    #[derive(Debug)]
+    struct RecordingSink(Arc<std::sync::Mutex<Vec<Event>>>);
+
+    impl crate::server::EventSink for RecordingSink {
+        fn notify(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let dir = tempdir().expect("Couldn't create temp directory");
+        let recorded = Arc::new(std::sync::Mutex::new(vec![]));
+        let svc = CriRuntimeService::new(dir.path().to_owned(), None)
+            .await
+            .with_events(Events::new(vec![Box::new(RecordingSink(recorded.clone()))]));
+
+        let mut config = grpc::PodSandboxConfig::default();
+        config.metadata = Some(grpc::PodSandboxMetadata {
+            name: "report".to_owned(),
+            namespace: "batch".to_owned(),
+            ..Default::default()
+        });
+        config.log_directory = dir.path().join("logs").to_string_lossy().into_owned();
+        let sandbox_id = svc
+            .run_pod_sandbox(Request::new(grpc::RunPodSandboxRequest {
+                config: Some(config),
+                ..Default::default()
+            }))
+            .await
+            .expect("run pod sandbox")
+            .into_inner()
+            .pod_sandbox_id;
+
+        let image_ref = Reference::try_from("foo/bar:baz".to_owned()).unwrap();
+        let image_file = ModuleStore::new(dir.path().to_path_buf())
+            .await
+            .pull_file_path(&image_ref);
+        tokio::fs::create_dir_all(image_file.parent().unwrap())
+            .await
+            .expect("Couldn't create wasm file directory");
+        tokio::fs::copy("examples/printer.wasm", image_file)
+            .await
+            .expect("couldn't write wasm");
+        let container = UserContainer {
+            pod_sandbox_id: sandbox_id.clone(),
+            image_ref: image_ref.into(),
+            ..Default::default()
+        };
+        let container_id = container.id.clone();
+        svc.containers
+            .write()
+            .await
+            .insert(container_id.clone(), container);
+
+        svc.start_container(Request::new(grpc::StartContainerRequest {
+            container_id: container_id.clone(),
+        }))
+        .await
+        .expect("start container result");
+        let running_containers = svc.running_containers.read().await;
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            running_containers[&container_id].exited(),
+        )
+        .await
+        .expect("module exited");
+        drop(running_containers);
+        // the watcher may not have run yet
+        for _ in 0..100 {
+            if recorded.lock().unwrap().len() == 3 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(
+            vec![
+                Event::SandboxCreated {
+                    sandbox_id: sandbox_id.clone(),
+                    name: "report".to_owned(),
+                    namespace: "batch".to_owned(),
+                },
+                Event::ContainerStarted {
+                    container_id: container_id.clone(),
+                    sandbox_id,
+                },
+                Event::ContainerExited {
+                    container_id,
+                    reason: "Completed".to_owned(),
+                    error: None,
+                },
+            ],
+            *recorded.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_container_output() {
+        let dir = tempdir().expect("Couldn't create temp directory");
+        let svc = CriRuntimeService::new(dir.path().to_owned(), None).await;
+        svc.sandboxes
+            .write()
+            .await
+            .insert("test".to_owned(), UserSandbox::default());
+        let image_ref = Reference::try_from("foo/bar:baz".to_owned()).unwrap();
+        let image_file = ModuleStore::new(dir.path().to_path_buf())
+            .await
+            .pull_file_path(&image_ref);
+        tokio::fs::create_dir_all(image_file.parent().unwrap())
+            .await
+            .expect("Couldn't create wasm file directory");
+        tokio::fs::copy("examples/printer.wasm", image_file)
+            .await
+            .expect("couldn't write wasm");
+        let log_path = dir.path().join("logs/app/0.log");
+        tokio::fs::create_dir_all(log_path.parent().unwrap())
+            .await
+            .unwrap();
+        svc.containers.write().await.insert(
+            "app".to_owned(),
+            UserContainer {
+                id: "app".to_owned(),
+                pod_sandbox_id: "test".to_owned(),
+                image_ref: image_ref.into(),
+                log_path: Some(log_path.clone()),
+                ..Default::default()
+            },
+        );
+        assert!(svc.follow_logs("app").await.is_none());
+
+        svc.start_container(Request::new(grpc::StartContainerRequest {
+            container_id: "app".to_owned(),
+        }))
+        .await
+        .expect("start container result");
+        let (mut followed, lines) = svc
+            .follow_logs("app")
+            .await
+            .expect("the container was started")
+            .unwrap();
+        // the stream ends once the module returned
+        let lines: Vec<_> = tokio::time::timeout(Duration::from_secs(10), lines.collect())
+            .await
+            .expect("module exited");
+        let (log, _) = svc.follow_logs("app").await.unwrap().unwrap();
+
+        // following the log misses no line, wherever the module was when it started
+        for line in lines {
+            followed.extend(line.to_cri());
+        }
+        assert_eq!(log, followed);
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.contains(" stdout F hello from stdout!\n"));
+        assert!(log.contains(" stderr F hello from stderr!\n"));
+    }
+
+    #[tokio::test]
+    async fn test_run_pod_sandbox_queue_full() {
+        let options = RuntimeOptions {
+            max_concurrent_sandbox_creations: 1,
+            sandbox_queue_size: 0,
+            ..Default::default()
+        };
+        let dir = tempdir().unwrap();
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        let permit = svc.sandbox_permit().await.expect("a creation may run");
+        assert!(permit.is_some());
+
+        let req = grpc::RunPodSandboxRequest {
+            config: Some(grpc::PodSandboxConfig::default()),
+            runtime_handler: RuntimeHandler::WASI.to_string(),
+        };
+        let err = svc
+            .run_pod_sandbox(Request::new(req.clone()))
+            .await
+            .expect_err("the queue is full");
+        assert_eq!(tonic::Code::ResourceExhausted, err.code());
+        assert_eq!(0, svc.dump().await["sandbox_queue"]);
+        assert_eq!(0, svc.dump().await["sandbox_permits_available"]);
+
+        drop(permit);
+        svc.run_pod_sandbox(Request::new(req))
+            .await
+            .expect("successful run pod sandbox");
+        assert_eq!(1, svc.dump().await["sandbox_permits_available"]);
+    }
+
+    #[tokio::test]
+    async fn test_start_container_queue_timeout() {
+        let options = RuntimeOptions {
+            max_concurrent_starts: 1,
+            start_queue_timeout_secs: 0,
+            ..Default::default()
+        };
+        let dir = tempdir().unwrap();
+        let svc = CriRuntimeService::with_options(dir.path().to_owned(), None, options).await;
+        let permit = svc.start_permit().await.expect("a start may run");
+        assert!(permit.is_some());
+
+        let err = svc
+            .start_container(Request::new(grpc::StartContainerRequest {
+                container_id: "queued".to_owned(),
+            }))
+            .await
+            .expect_err("the only permit is taken");
+        assert_eq!(tonic::Code::ResourceExhausted, err.code());
+        assert!(svc.starting.lock().await.is_empty());
+        assert_eq!(0, svc.dump().await["start_permits_available"]);
+
+        drop(permit);
+        let err = svc
+            .start_container(Request::new(grpc::StartContainerRequest {
+                container_id: "queued".to_owned(),
+            }))
+            .await
+            .expect_err("the container doesn't exist");
+        assert_eq!(tonic::Code::NotFound, err.code());
+    }
+
+    #[tokio::test]
+    async fn test_start_container_already_starting() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        svc.containers
+            .write()
+            .await
+            .insert("1".to_owned(), UserContainer::default());
+        svc.starting.lock().await.insert("1".to_owned());
+
+        let status = svc
+            .start_container(Request::new(grpc::StartContainerRequest {
+                container_id: "1".to_owned(),
+            }))
+            .await
+            .expect_err("container is already being started");
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+        // the start that is in progress still owns the container
+        assert!(svc.starting.lock().await.contains("1"));
     }
 
     #[tokio::test]
@@ -1220,7 +4321,11 @@ mod test {
                 state: grpc::ContainerState::ContainerRunning as i32,
                 config: grpc::ContainerConfig::default(),
                 log_path: None,
+                legacy_log_link: None,
                 volumes: Vec::default(),
+                working_dir: None,
+                memory_dir: None,
+                resources: ResourcePolicy::default(),
             },
         );
         containers.insert(
@@ -1233,7 +4338,11 @@ mod test {
                 state: grpc::ContainerState::ContainerRunning as i32,
                 config: grpc::ContainerConfig::default(),
                 log_path: None,
+                legacy_log_link: None,
                 volumes: Vec::default(),
+                working_dir: None,
+                memory_dir: None,
+                resources: ResourcePolicy::default(),
             },
         );
         drop(containers);
@@ -1396,6 +4505,225 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_container_status_exited() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        svc.containers.write().await.insert(
+            "test".to_owned(),
+            UserContainer {
+                id: "test".to_owned(),
+                state: grpc::ContainerState::ContainerRunning as i32,
+                ..Default::default()
+            },
+        );
+        let (_, exited) = watch::channel(ExitState::Exited);
+        svc.running_containers.write().await.insert(
+            "test".to_owned(),
+            ContainerCancellationToken::WasiCancelationToken(exited),
+        );
+        let status = svc
+            .container_status(Request::new(grpc::ContainerStatusRequest {
+                container_id: "test".to_owned(),
+                verbose: false,
+            }))
+            .await
+            .expect("successful container status")
+            .into_inner()
+            .status
+            .unwrap();
+        assert_eq!(grpc::ContainerState::ContainerExited as i32, status.state);
+        assert_eq!(0, status.exit_code);
+        assert_eq!("Completed", status.reason);
+        assert!(status.message.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_container_status_failed() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        svc.containers.write().await.insert(
+            "test".to_owned(),
+            UserContainer {
+                id: "test".to_owned(),
+                ..Default::default()
+            },
+        );
+        let (_, exited) = watch::channel(ExitState::Failed(
+            "module aborted: attempt to divide by zero at src/main.rs:3:5".to_owned(),
+        ));
+        svc.running_containers.write().await.insert(
+            "test".to_owned(),
+            ContainerCancellationToken::WasiCancelationToken(exited),
+        );
+        let status = svc
+            .container_status(Request::new(grpc::ContainerStatusRequest {
+                container_id: "test".to_owned(),
+                verbose: false,
+            }))
+            .await
+            .expect("successful container status")
+            .into_inner()
+            .status
+            .unwrap();
+        assert_eq!(grpc::ContainerState::ContainerExited as i32, status.state);
+        assert_eq!(1, status.exit_code);
+        assert_eq!("Error", status.reason);
+        assert_eq!(
+            "module aborted: attempt to divide by zero at src/main.rs:3:5",
+            status.message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_container_status_unhealthy() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        svc.containers.write().await.insert(
+            "test".to_owned(),
+            UserContainer {
+                id: "test".to_owned(),
+                state: grpc::ContainerState::ContainerRunning as i32,
+                ..Default::default()
+            },
+        );
+        for _ in 0..3 {
+            svc.health
+                .record("test", Err("actor did not reply".to_owned()))
+                .await;
+        }
+        let status = svc
+            .container_status(Request::new(grpc::ContainerStatusRequest {
+                container_id: "test".to_owned(),
+                verbose: false,
+            }))
+            .await
+            .expect("successful container status")
+            .into_inner()
+            .status
+            .unwrap();
+        assert_eq!(grpc::ContainerState::ContainerRunning as i32, status.state);
+        assert_eq!("Unhealthy", status.reason);
+        assert!(status.message.contains("actor did not reply"));
+        let condition = svc
+            .conditions()
+            .list()
+            .await
+            .into_iter()
+            .find(|c| c.r#type == ACTORS_HEALTHY)
+            .expect("health is reported");
+        assert!(!condition.status);
+    }
+
+    #[tokio::test]
+    async fn test_container_status_verbose_resources() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        svc.containers.write().await.insert(
+            "test".to_owned(),
+            UserContainer {
+                id: "test".to_owned(),
+                resources: ResourcePolicy {
+                    memory_limit: Some(1024),
+                    unsupported: vec!["cpu_shares"],
+                },
+                ..Default::default()
+            },
+        );
+        let req = Request::new(grpc::ContainerStatusRequest {
+            container_id: "test".to_owned(),
+            verbose: true,
+        });
+        let info = svc
+            .container_status(req)
+            .await
+            .expect("successful container status")
+            .into_inner()
+            .info;
+        let resources: serde_json::Value =
+            serde_json::from_str(&info["resources"]).expect("resources are JSON");
+        assert_eq!(1024, resources["applied"]["memory_limit_in_bytes"]);
+        assert_eq!(json!(["cpu_shares"]), resources["unsupported"]);
+    }
+
+    #[tokio::test]
+    async fn test_container_status_verbose_info() {
+        let dir = tempdir().expect("Couldn't create temp directory");
+        let svc = CriRuntimeService::new(dir.path().to_owned(), None).await;
+        svc.sandboxes.write().await.insert(
+            "1".to_owned(),
+            UserSandbox {
+                inner: grpc::PodSandbox {
+                    runtime_handler: RuntimeHandler::WASI.to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        svc.containers.write().await.insert(
+            "test".to_owned(),
+            UserContainer {
+                id: "test".to_owned(),
+                pod_sandbox_id: "1".to_owned(),
+                image_ref: "webassembly.azurecr.io/hello:v1".to_owned(),
+                config: grpc::ContainerConfig {
+                    envs: vec![
+                        grpc::KeyValue {
+                            key: "GREETING".to_owned(),
+                            value: "hello".to_owned(),
+                        },
+                        grpc::KeyValue {
+                            key: "API_TOKEN".to_owned(),
+                            value: "s3cr3t".to_owned(),
+                        },
+                    ],
+                    ..Default::default()
+                },
+                volumes: vec![grpc::Mount {
+                    container_path: "/app".to_owned(),
+                    host_path: "volumes/1".to_owned(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        );
+        let (_, exited) = watch::channel(ExitState::Failed("unreachable executed".to_owned()));
+        svc.running_containers.write().await.insert(
+            "test".to_owned(),
+            ContainerCancellationToken::WasiCancelationToken(exited),
+        );
+
+        let res = svc
+            .container_status(Request::new(grpc::ContainerStatusRequest {
+                container_id: "test".to_owned(),
+                verbose: true,
+            }))
+            .await
+            .expect("successful container status")
+            .into_inner();
+        let mounts = res.status.unwrap().mounts;
+        assert_eq!(1, mounts.len());
+        assert_eq!("/app", mounts[0].container_path);
+        assert_eq!(
+            dir.path()
+                .join("containers/test/volumes/1")
+                .to_str()
+                .unwrap(),
+            mounts[0].host_path
+        );
+        let info: serde_json::Value =
+            serde_json::from_str(&res.info["info"]).expect("info is JSON");
+        assert_eq!("WASI", info["runtimeHandler"]);
+        assert_eq!(
+            dir.path()
+                .join("webassembly.azurecr.io/hello/v1/module.wasm")
+                .to_str()
+                .unwrap(),
+            info["modulePath"]
+        );
+        assert_eq!("/app", info["volumes"][0]["containerPath"]);
+        assert_eq!("volumes/1", info["volumes"][0]["hostPath"]);
+        assert_eq!("hello", info["envs"]["GREETING"]);
+        assert_eq!(redact::REDACTED, info["envs"]["API_TOKEN"]);
+        assert_eq!("unreachable executed", info["lastTrap"]);
+    }
+
     #[tokio::test]
     async fn test_container_stats() {
         let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
@@ -1423,29 +4751,100 @@ mod test {
             container_id: "test".to_owned(),
         });
         let res = svc.container_stats(req).await;
-        // We expect an empty response object
         let stats = res
             .expect("remove container result")
             .into_inner()
             .stats
             .unwrap();
         assert_eq!(
-            stats,
-            grpc::ContainerStats {
-                attributes: Some(grpc::ContainerAttributes {
-                    id: "test".to_owned(),
-                    metadata: Some(grpc::ContainerMetadata {
-                        attempt: 1,
-                        name: "test".to_owned(),
-                    }),
-                    labels,
-                    annotations: HashMap::new(),
+            stats.attributes,
+            Some(grpc::ContainerAttributes {
+                id: "test".to_owned(),
+                metadata: Some(grpc::ContainerMetadata {
+                    attempt: 1,
+                    name: "test".to_owned(),
                 }),
-                cpu: None,
-                memory: None,
-                writable_layer: None,
-            }
+                labels,
+                annotations: HashMap::new(),
+            })
+        );
+        assert_eq!(None, stats.cpu);
+        assert_eq!(None, stats.memory);
+        // the container has no directory yet
+        let writable_layer = stats.writable_layer.unwrap();
+        assert_eq!(
+            Some(grpc::UInt64Value { value: 0 }),
+            writable_layer.used_bytes
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sandbox_stats() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let svc = CriRuntimeService::new(dir.path().to_owned(), None).await;
+        svc.sandboxes.write().await.insert(
+            "pod".to_owned(),
+            UserSandbox {
+                inner: grpc::PodSandbox {
+                    id: "pod".to_owned(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        // the log of a, and the file the kubelet rotated it to, count as well
+        let log_dir = dir.path().join("logs");
+        std::fs::create_dir_all(&log_dir).unwrap();
+        std::fs::write(log_dir.join("a.log"), b"hi\n").unwrap();
+        std::fs::write(log_dir.join("a.log.20200131-120000"), b"hi\n").unwrap();
+        let mut containers = svc.containers.write().await;
+        for id in &["a", "b"] {
+            containers.insert(
+                id.to_string(),
+                UserContainer {
+                    id: id.to_string(),
+                    pod_sandbox_id: "pod".to_owned(),
+                    log_path: Some(log_dir.join(format!("{}.log", id))),
+                    ..Default::default()
+                },
+            );
+            let root_dir = dir.path().join("containers").join(id);
+            std::fs::create_dir_all(&root_dir).unwrap();
+            std::fs::write(root_dir.join("data"), b"hello").unwrap();
+        }
+        drop(containers);
+        // a is still running, b exited
+        let (_, running) = watch::channel(ExitState::Running);
+        let (_, exited) = watch::channel(ExitState::Exited);
+        let mut tokens = svc.running_containers.write().await;
+        tokens.insert(
+            "a".to_owned(),
+            ContainerCancellationToken::WasiCancelationToken(running),
         );
+        tokens.insert(
+            "b".to_owned(),
+            ContainerCancellationToken::WasiCancelationToken(exited),
+        );
+        drop(tokens);
+
+        let stats = svc.sandbox_stats().await;
+        assert_eq!(1, stats.len());
+        assert_eq!("pod", stats[0].pod_sandbox_id);
+        assert_eq!(2, stats[0].containers);
+        assert_eq!(16, stats[0].fs_used_bytes);
+        assert_eq!(4, stats[0].fs_inodes_used);
+        assert_eq!(1, stats[0].instances);
+
+        let status = svc
+            .pod_sandbox_status(Request::new(grpc::PodSandboxStatusRequest {
+                pod_sandbox_id: "pod".to_owned(),
+                verbose: true,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let info: serde_json::Value = serde_json::from_str(&status.info["stats"]).unwrap();
+        assert_eq!(16, info["fsUsedBytes"]);
     }
 
     #[tokio::test]
@@ -1546,6 +4945,301 @@ mod test {
         assert_eq!(1, stats.len());
     }
 
+    struct NoopRuntime;
+
+    impl Runtime for NoopRuntime {
+        fn run(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn output(&self) -> Option<&OutputBroker> {
+            None
+        }
+    }
+
+    struct SleepRuntime;
+
+    impl Runtime for SleepRuntime {
+        fn run(&self) -> Result<()> {
+            std::thread::sleep(Duration::from_millis(500));
+            Ok(())
+        }
+
+        fn output(&self) -> Option<&OutputBroker> {
+            None
+        }
+    }
+
+    struct LargeStackRuntime;
+
+    impl Runtime for LargeStackRuntime {
+        fn run(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn output(&self) -> Option<&OutputBroker> {
+            None
+        }
+
+        fn stack_size(&self) -> Option<usize> {
+            Some(8 * 1024 * 1024)
+        }
+    }
+
+    struct FailingRuntime;
+
+    impl Runtime for FailingRuntime {
+        fn run(&self) -> Result<()> {
+            Err(format_err!("cannot connect to postgres://app:hunter22@db"))
+        }
+
+        fn output(&self) -> Option<&OutputBroker> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_secrets_scrubbed() {
+        let token = RuntimeContainer::new(FailingRuntime)
+            .with_secrets(vec!["hunter22".to_owned()])
+            .start(None);
+        token.exited().await;
+        assert_eq!(
+            Some(ExitState::Failed(
+                "cannot connect to postgres://app:[REDACTED]@db".to_owned()
+            )),
+            token.exit_state()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let token = RuntimeContainer::new(SleepRuntime).start(Some(Duration::from_millis(10)));
+        token.exited().await;
+        assert_eq!(Some(ExitState::DeadlineExceeded), token.exit_state());
+
+        let token = RuntimeContainer::new(NoopRuntime).start(Some(Duration::from_secs(5)));
+        token.exited().await;
+        assert_eq!(Some(ExitState::Exited), token.exit_state());
+    }
+
+    #[tokio::test]
+    async fn test_stack_size() {
+        // the module runs on a thread of its own, with the stack it asked for
+        let token = RuntimeContainer::new(LargeStackRuntime).start(None);
+        token.exited().await;
+        assert_eq!(Some(ExitState::Exited), token.exit_state());
+    }
+
+    #[test]
+    fn test_container_deadline() {
+        let mut config = grpc::ContainerConfig::default();
+        assert_eq!(None, container_deadline(&config).unwrap());
+        config
+            .annotations
+            .insert(DEADLINE_ANNOTATION.to_owned(), "30".to_owned());
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            container_deadline(&config).unwrap()
+        );
+        for invalid in &["0", "-1", "1.5", "soon"] {
+            config
+                .annotations
+                .insert(DEADLINE_ANNOTATION.to_owned(), (*invalid).to_owned());
+            container_deadline(&config).expect_err(invalid);
+        }
+    }
+
+    #[test]
+    fn test_container_stack_size() {
+        let mut options = RuntimeOptions::default();
+        let mut config = grpc::ContainerConfig::default();
+        assert_eq!(None, container_stack_size(&config, &options).unwrap());
+        options.wasm_stack_size = 4 * 1024 * 1024;
+        assert_eq!(
+            Some(4 * 1024 * 1024),
+            container_stack_size(&config, &options).unwrap()
+        );
+
+        config
+            .annotations
+            .insert(WASM_STACK_SIZE_ANNOTATION.to_owned(), "8Mi".to_owned());
+        assert_eq!(
+            Some(8 * 1024 * 1024),
+            container_stack_size(&config, &options).unwrap()
+        );
+        config
+            .annotations
+            .insert(WASM_STACK_SIZE_ANNOTATION.to_owned(), "2048Ki".to_owned());
+        assert_eq!(
+            Some(2 * 1024 * 1024),
+            container_stack_size(&config, &options).unwrap()
+        );
+
+        options.max_wasm_stack_size = 4 * 1024 * 1024;
+        config
+            .annotations
+            .insert(WASM_STACK_SIZE_ANNOTATION.to_owned(), "8Mi".to_owned());
+        container_stack_size(&config, &options).expect_err("above the maximum");
+        for invalid in &["64Ki", "0", "-1Mi", "8M", "large"] {
+            config
+                .annotations
+                .insert(WASM_STACK_SIZE_ANNOTATION.to_owned(), (*invalid).to_owned());
+            container_stack_size(&config, &options).expect_err(invalid);
+        }
+    }
+
+    #[test]
+    fn test_container_log_level() {
+        let mut sandbox_annotations = HashMap::new();
+        let mut config = grpc::ContainerConfig::default();
+        assert_eq!(
+            None,
+            container_log_level(&sandbox_annotations, &config).unwrap()
+        );
+        sandbox_annotations.insert(LOG_LEVEL_ANNOTATION.to_owned(), "DEBUG".to_owned());
+        assert_eq!(
+            Some("debug".to_owned()),
+            container_log_level(&sandbox_annotations, &config).unwrap()
+        );
+        config
+            .annotations
+            .insert(LOG_LEVEL_ANNOTATION.to_owned(), "trace".to_owned());
+        assert_eq!(
+            Some("trace".to_owned()),
+            container_log_level(&sandbox_annotations, &config).unwrap()
+        );
+        config
+            .annotations
+            .insert(LOG_LEVEL_ANNOTATION.to_owned(), "wok=debug".to_owned());
+        container_log_level(&sandbox_annotations, &config).expect_err("not a level");
+    }
+
+    #[tokio::test]
+    async fn test_container_log_level_filter() {
+        let reloaded = Arc::new(std::sync::Mutex::new(String::new()));
+        let log_filter = {
+            let reloaded = reloaded.clone();
+            LogFilterHandle::new("wok=info".to_owned(), move |filter| {
+                *reloaded.lock().unwrap() = filter.to_owned();
+                Ok(())
+            })
+        };
+        let svc = CriRuntimeService::new(PathBuf::from(""), None)
+            .await
+            .with_log_filter(log_filter.clone());
+        svc.sandboxes.write().await.insert(
+            "test".to_owned(),
+            UserSandbox {
+                inner: grpc::PodSandbox {
+                    id: "test".to_owned(),
+                    runtime_handler: RuntimeHandler::WASI.to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let mut config = grpc::ContainerConfig {
+            image: Some(grpc::ImageSpec {
+                image: "foo/bar:baz".to_owned(),
+            }),
+            ..Default::default()
+        };
+        config
+            .annotations
+            .insert(LOG_LEVEL_ANNOTATION.to_owned(), "debug".to_owned());
+        let id = svc
+            .create_container(Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: "test".to_owned(),
+                config: Some(config.clone()),
+                sandbox_config: None,
+            }))
+            .await
+            .expect("successful create container")
+            .into_inner()
+            .container_id;
+        assert_eq!(
+            format!("wok=info,wok[{{container_id={}}}]=debug", id),
+            *reloaded.lock().unwrap()
+        );
+        assert_eq!(
+            Some(&"debug".to_owned()),
+            log_filter.container_levels().get(&id)
+        );
+
+        // changing the daemon's filter keeps the container's level
+        log_filter.set("wok=warn").unwrap();
+        assert_eq!(
+            format!("wok=warn,wok[{{container_id={}}}]=debug", id),
+            *reloaded.lock().unwrap()
+        );
+
+        svc.remove_container(Request::new(grpc::RemoveContainerRequest {
+            container_id: id,
+        }))
+        .await
+        .expect("remove container result");
+        assert_eq!("wok=warn", *reloaded.lock().unwrap());
+        assert!(log_filter.container_levels().is_empty());
+
+        // a container that isn't added keeps no level
+        svc.create_container(Request::new(grpc::CreateContainerRequest {
+            pod_sandbox_id: "gone".to_owned(),
+            config: Some(config.clone()),
+            sandbox_config: None,
+        }))
+        .await
+        .expect_err("no sandbox");
+        assert!(log_filter.container_levels().is_empty());
+
+        config
+            .annotations
+            .insert(LOG_LEVEL_ANNOTATION.to_owned(), "loud".to_owned());
+        let status = svc
+            .create_container(Request::new(grpc::CreateContainerRequest {
+                pod_sandbox_id: "test".to_owned(),
+                config: Some(config),
+                sandbox_config: None,
+            }))
+            .await
+            .expect_err("invalid log level");
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+    }
+
+    #[test]
+    fn test_prepull_references() {
+        let mut annotations = HashMap::new();
+        assert!(prepull_references(&annotations).unwrap().is_empty());
+        annotations.insert(
+            PREPULL_ANNOTATION.to_owned(),
+            "example.com/app:v1, example.com/sidecar:v2,".to_owned(),
+        );
+        let references: Vec<_> = prepull_references(&annotations)
+            .unwrap()
+            .iter()
+            .map(|r| r.whole().to_owned())
+            .collect();
+        assert_eq!(
+            vec!["example.com/app:v1", "example.com/sidecar:v2"],
+            references
+        );
+        annotations.insert(PREPULL_ANNOTATION.to_owned(), "/app:v1".to_owned());
+        prepull_references(&annotations).expect_err("invalid reference");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        let token = RuntimeContainer::new(NoopRuntime).start(None);
+        svc.running_containers
+            .write()
+            .await
+            .insert("test".to_owned(), token);
+
+        svc.shutdown(Duration::from_secs(5)).await;
+        assert!(svc.running_containers.read().await.is_empty());
+    }
+
     #[tokio::test]
     async fn test_run_pod_sandbox() {
         let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
@@ -1567,6 +5261,195 @@ mod test {
         assert_eq!(true, log_dir_name.exists());
     }
 
+    #[tokio::test]
+    async fn test_run_pod_sandbox_retried() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        let dir = tempdir().unwrap();
+        let run = |uid: &str| {
+            let conf = grpc::PodSandboxConfig {
+                metadata: Some(grpc::PodSandboxMetadata {
+                    name: "app".to_owned(),
+                    uid: uid.to_owned(),
+                    ..Default::default()
+                }),
+                log_directory: dir.path().join(uid).to_str().unwrap().to_owned(),
+                ..Default::default()
+            };
+            svc.run_pod_sandbox(Request::new(grpc::RunPodSandboxRequest {
+                config: Some(conf),
+                runtime_handler: RuntimeHandler::WASI.to_string(),
+            }))
+        };
+        let id = run("1").await.unwrap().into_inner().pod_sandbox_id;
+        assert_eq!(id, run("1").await.unwrap().into_inner().pod_sandbox_id);
+        assert_eq!(1, svc.sandboxes.read().await.len());
+        assert_ne!(id, run("2").await.unwrap().into_inner().pod_sandbox_id);
+
+        // a stopped sandbox is replaced by a new one
+        svc.sandboxes
+            .write()
+            .await
+            .get_mut(&id)
+            .unwrap()
+            .inner
+            .state = grpc::PodSandboxState::SandboxNotready as i32;
+        assert_ne!(id, run("1").await.unwrap().into_inner().pod_sandbox_id);
+        assert_eq!(3, svc.sandboxes.read().await.len());
+    }
+
+    #[tokio::test]
+    async fn test_run_pod_sandbox_invalid_wasm_features() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        let dir = tempdir().unwrap();
+        let mut conf = grpc::PodSandboxConfig::default();
+        conf.log_directory = dir.path().join("testdir").to_str().unwrap().to_owned();
+        conf.annotations
+            .insert(WASM_FEATURES_ANNOTATION.to_owned(), "simd,gc".to_owned());
+        let req = Request::new(grpc::RunPodSandboxRequest {
+            config: Some(conf),
+            runtime_handler: RuntimeHandler::WASI.to_string(),
+        });
+        let err = svc
+            .run_pod_sandbox(req)
+            .await
+            .expect_err("unknown features are rejected");
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+        assert!(err.message().contains("\"gc\""));
+        assert!(svc.sandboxes.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dump() {
+        let svc = CriRuntimeService::default();
+        let sandbox = UserSandbox {
+            inner: grpc::PodSandbox {
+                id: "1".to_owned(),
+                state: grpc::PodSandboxState::SandboxReady as i32,
+                ..Default::default()
+            },
+            running_containers: vec!["2".to_owned()],
+            ..Default::default()
+        };
+        svc.sandboxes.write().await.insert("1".to_owned(), sandbox);
+        let container = UserContainer {
+            id: "2".to_owned(),
+            pod_sandbox_id: "1".to_owned(),
+            state: grpc::ContainerState::ContainerRunning as i32,
+            ..Default::default()
+        };
+        svc.containers
+            .write()
+            .await
+            .insert("2".to_owned(), container);
+        svc.running_containers.write().await.insert(
+            "2".to_owned(),
+            ContainerCancellationToken::WasccCancelationToken("MKEY".to_owned()),
+        );
+
+        let dump = svc.dump().await;
+        assert_eq!("SandboxReady", dump["sandboxes"][0]["state"]);
+        assert_eq!(json!(["2"]), dump["sandboxes"][0]["containers"]);
+        assert_eq!("ContainerRunning", dump["containers"][0]["state"]);
+        assert_eq!("wascc", dump["containers"][0]["token"]["kind"]);
+        assert_eq!("MKEY", dump["containers"][0]["token"]["actor"]);
+    }
+
+    #[tokio::test]
+    async fn test_exec_sync() {
+        let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
+        let exec = |id: &str, cmd: Vec<&str>| {
+            Request::new(grpc::ExecSyncRequest {
+                container_id: id.to_owned(),
+                cmd: cmd.into_iter().map(str::to_owned).collect(),
+                timeout: 0,
+            })
+        };
+        let err = svc
+            .exec_sync(exec("actor", vec!["HandleRequest"]))
+            .await
+            .expect_err("unknown container");
+        assert_eq!(tonic::Code::NotFound, err.code());
+
+        svc.containers.write().await.insert(
+            "actor".to_owned(),
+            UserContainer {
+                id: "actor".to_owned(),
+                ..Default::default()
+            },
+        );
+        let err = svc
+            .exec_sync(exec("actor", vec!["HandleRequest"]))
+            .await
+            .expect_err("container is not running");
+        assert_eq!(tonic::Code::FailedPrecondition, err.code());
+
+        svc.running_containers.write().await.insert(
+            "actor".to_owned(),
+            ContainerCancellationToken::WasccCancelationToken("MKEY".to_owned()),
+        );
+        let err = svc
+            .exec_sync(exec("actor", vec![]))
+            .await
+            .expect_err("no operation");
+        assert_eq!(tonic::Code::InvalidArgument, err.code());
+
+        let (_, exited) = watch::channel(ExitState::Running);
+        svc.running_containers.write().await.insert(
+            "module".to_owned(),
+            ContainerCancellationToken::WasiCancelationToken(exited),
+        );
+        let err = svc
+            .exec_sync(exec("module", vec!["ls"]))
+            .await
+            .expect_err("WASI modules take no calls");
+        assert_eq!(tonic::Code::Unimplemented, err.code());
+
+        // the guest never ran, so the invocation fails like one the guest fails
+        #[cfg(feature = "wapc")]
+        {
+            let (_, guest) = WapcRuntime::new(vec![], HashMap::new(), vec![], None);
+            let (_, exited) = watch::channel(ExitState::Running);
+            svc.running_containers.write().await.insert(
+                "guest".to_owned(),
+                ContainerCancellationToken::WapcCancelationToken(guest, exited),
+            );
+            let res = svc
+                .exec_sync(exec("guest", vec!["Echo", "hello"]))
+                .await
+                .expect("invoked the guest");
+            assert_eq!(1, res.get_ref().exit_code);
+            assert!(String::from_utf8_lossy(&res.get_ref().stderr).contains("not running"));
+        }
+    }
+
+    #[cfg(feature = "wascc")]
+    #[tokio::test]
+    async fn test_run_pod_sandbox_default_handler() {
+        let options = RuntimeOptions {
+            default_handler: RuntimeHandler::WASCC.to_string(),
+            ..Default::default()
+        };
+        let svc = CriRuntimeService::with_options(PathBuf::from(""), None, options).await;
+
+        let dir = tempdir().unwrap();
+        let mut conf = grpc::PodSandboxConfig::default();
+        conf.log_directory = dir.path().join("testdir").to_str().unwrap().to_owned();
+        let req = Request::new(grpc::RunPodSandboxRequest {
+            config: Some(conf),
+            runtime_handler: "".to_owned(),
+        });
+        let id = svc
+            .run_pod_sandbox(req)
+            .await
+            .expect("successful run pod sandbox")
+            .into_inner()
+            .pod_sandbox_id;
+        assert_eq!(
+            RuntimeHandler::WASCC.to_string(),
+            svc.sandboxes.read().await[&id].inner.runtime_handler
+        );
+    }
+
     #[tokio::test]
     async fn test_create_and_list() {
         let svc = CriRuntimeService::new(PathBuf::from(""), None).await;
@@ -1593,40 +5476,161 @@ mod test {
 }
 
 pub struct RuntimeContainer {
-    handle: JoinHandle<Result<()>>,
-    sender: UnboundedSender<()>,
+    /// starts the module, with the deadline it must finish by
+    sender: UnboundedSender<Option<Duration>>,
+    exited: watch::Receiver<ExitState>,
+    /// secret values scrubbed from the error the module fails with
+    secrets: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+/// How far a WASI module got running.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExitState {
+    Running,
+    /// the module returned
+    Exited,
+    /// the module trapped or could not be run at all
+    Failed(String),
+    /// the module ran longer than the container's deadline
+    DeadlineExceeded,
+}
+
+impl ExitState {
+    /// The error the module failed with, if it did.
+    fn error(&self) -> Option<&str> {
+        match self {
+            Self::Failed(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// The reason the container exited for, in the terms of the container status.
+    fn reason(&self) -> Option<&'static str> {
+        match self {
+            Self::Running => None,
+            Self::Exited => Some("Completed"),
+            Self::Failed(_) => Some("Error"),
+            Self::DeadlineExceeded => Some("DeadlineExceeded"),
+        }
+    }
 }
 
 impl RuntimeContainer {
     pub fn new<T: Runtime + Send + 'static>(rt: T) -> Self {
-        let (sender, mut receiver) = unbounded_channel::<()>();
-        let handle = tokio::spawn(async move {
-            receiver.recv().await.unwrap();
-            tokio::task::spawn_blocking(move || {
-                if let Err(e) = rt.run() {
-                    // TODO(taylor): Implement messaging here to indicate that there was a problem running the module
-                    error!("Error while running module: {}", e);
-                }
-                Ok(())
-            })
-            .await
-            .unwrap()
-        });
-        RuntimeContainer { handle, sender }
+        match rt.stack_size() {
+            // the blocking threads of tokio all have the same stack, so the module gets a thread of its own
+            Some(stack_size) => Self::spawn(async move {
+                let (done, result) = tokio::sync::oneshot::channel();
+                std::thread::Builder::new()
+                    .stack_size(stack_size)
+                    .spawn(move || done.send(rt.run()).unwrap_or(()))
+                    .map_err(|e| e.to_string())?;
+                result
+                    .await
+                    .map_err(|_| "module thread ended without a result".to_owned())
+            }),
+            None => Self::spawn(async move {
+                tokio::task::spawn_blocking(move || rt.run())
+                    .await
+                    .map_err(|e| e.to_string())
+            }),
+        }
+    }
+
+    /// Run the runtime with a module compiled ahead of time by the warm pool.
+    #[cfg(feature = "wasi")]
+    pub fn warm(rt: WasiRuntime, instance: WarmInstance) -> Self {
+        Self::spawn(instance.run(rt))
+    }
+
+    /// Run the module once the container is started. `run` resolves to the module's result, or to an error if
+    /// the thread running it failed.
+    fn spawn<F>(run: F) -> Self
+    where
+        F: std::future::Future<Output = std::result::Result<Result<()>, String>> + Send + 'static,
+    {
+        let (sender, mut receiver) = unbounded_channel::<Option<Duration>>();
+        let (exit_sender, exited) = watch::channel(ExitState::Running);
+        let secrets = Arc::new(std::sync::Mutex::new(vec![]));
+        let scrubbed = secrets.clone();
+        tokio::spawn(
+            async move {
+                let deadline = receiver.recv().await.unwrap();
+                let result = match deadline {
+                    // TODO: interrupt the module once wasmtime supports it. Until then, the module keeps running
+                    // on its thread after the deadline, but the container is reported as exited.
+                    Some(deadline) => match tokio::time::timeout(deadline, run).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            warn!("Module ran longer than its deadline of {:?}", deadline);
+                            exit_sender
+                                .broadcast(ExitState::DeadlineExceeded)
+                                .unwrap_or(());
+                            return;
+                        }
+                    },
+                    None => run.await,
+                };
+                let state = match result {
+                    Ok(Err(e)) => {
+                        let e = redact::scrub(&e.to_string(), &scrubbed.lock().unwrap());
+                        error!("Error while running module: {}", e);
+                        ExitState::Failed(e)
+                    }
+                    Err(e) => {
+                        error!("Module thread failed: {}", e);
+                        ExitState::Failed(format!("module thread failed: {}", e))
+                    }
+                    Ok(Ok(())) => ExitState::Exited,
+                };
+                // it's fine if nobody is waiting for the module to exit anymore
+                exit_sender.broadcast(state).unwrap_or(());
+            }
+            .in_current_span(),
+        );
+        RuntimeContainer {
+            sender,
+            exited,
+            secrets,
+        }
+    }
+
+    /// Scrub the given secret values from the error the module fails with, before it is logged or reported.
+    pub fn with_secrets(self, secrets: Vec<String>) -> Self {
+        *self.secrets.lock().unwrap() = secrets;
+        self
     }
 
-    pub fn start(self) -> ContainerCancellationToken {
-        self.sender.send(()).unwrap();
-        ContainerCancellationToken::WasiCancelationToken(self.handle)
+    /// Start running the module. The container exits with `ExitState::DeadlineExceeded` when it is still running
+    /// after `deadline`.
+    pub fn start(self, deadline: Option<Duration>) -> ContainerCancellationToken {
+        self.sender.send(deadline).unwrap();
+        ContainerCancellationToken::WasiCancelationToken(self.exited)
+    }
+
+    /// Start running the waPC guest the runtime was created with, see `start`. The guest takes invocations until
+    /// it is stopped.
+    #[cfg(feature = "wapc")]
+    pub fn start_guest(
+        self,
+        guest: WapcGuest,
+        deadline: Option<Duration>,
+    ) -> ContainerCancellationToken {
+        self.sender.send(deadline).unwrap();
+        ContainerCancellationToken::WapcCancelationToken(guest, self.exited)
     }
 }
 
 type WasccPublicKey = String;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ContainerCancellationToken {
     WasccCancelationToken(WasccPublicKey),
-    WasiCancelationToken(JoinHandle<Result<()>>),
+    /// Receives the state of the module once it has exited.
+    WasiCancelationToken(watch::Receiver<ExitState>),
+    /// Invokes the guest, and receives its state once it has exited.
+    #[cfg(feature = "wapc")]
+    WapcCancelationToken(WapcGuest, watch::Receiver<ExitState>),
 }
 
 impl ContainerCancellationToken {
@@ -1638,9 +5642,14 @@ impl ContainerCancellationToken {
                     info!("wascc module was not stopped: {}", e.to_string());
                 }
             }
-            Self::WasiCancelationToken(_handle) => {
-                todo!("Stopping a running container is not currently supported");
+            Self::WasiCancelationToken(_) => {
+                // TODO: interrupt the module once wasmtime supports it. Until then, the module keeps running until
+                // it exits on its own.
+                warn!("Stopping a running WASI module is not currently supported");
             }
+            // an invocation in progress is finished first
+            #[cfg(feature = "wapc")]
+            Self::WapcCancelationToken(guest, _) => guest.stop(),
         }
     }
     fn remove(&self) {
@@ -1651,8 +5660,81 @@ impl ContainerCancellationToken {
                     info!("wascc module was not stopped: {}", e.to_string());
                 }
             }
-            Self::WasiCancelationToken(_handle) => {
-                todo!("Removing a running container is not currently supported");
+            Self::WasiCancelationToken(_) => {
+                warn!("Removing a running WASI module is not currently supported");
+            }
+            #[cfg(feature = "wapc")]
+            Self::WapcCancelationToken(guest, _) => guest.stop(),
+        }
+    }
+
+    /// Receives the state of the module once it has exited. waSCC actors have no exit state.
+    fn exit_receiver(&self) -> Option<&watch::Receiver<ExitState>> {
+        match self {
+            Self::WasccCancelationToken(_) => None,
+            Self::WasiCancelationToken(exited) => Some(exited),
+            #[cfg(feature = "wapc")]
+            Self::WapcCancelationToken(_, exited) => Some(exited),
+        }
+    }
+
+    /// How far the module got running. waSCC actors have no exit state.
+    fn exit_state(&self) -> Option<ExitState> {
+        self.exit_receiver().map(|exited| exited.borrow().clone())
+    }
+
+    /// Whether the container takes invocations of its operations, i.e. runs a waSCC actor or a waPC guest.
+    fn takes_calls(&self) -> bool {
+        match self {
+            Self::WasccCancelationToken(_) => true,
+            #[cfg(feature = "wapc")]
+            Self::WapcCancelationToken(..) => true,
+            Self::WasiCancelationToken(_) => false,
+        }
+    }
+
+    /// Invoke an operation of the container's actor or guest with the given payload, returning its reply.
+    async fn call(&self, operation: String, payload: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Self::WasccCancelationToken(key) => {
+                let key = key.clone();
+                tokio::task::spawn_blocking(move || wascc_call(&key, &operation, &payload))
+                    .await
+                    .map_err(|e| format_err!("the thread invoking the actor failed: {}", e))?
+            }
+            #[cfg(feature = "wapc")]
+            Self::WapcCancelationToken(guest, _) => guest.call(&operation, payload).await,
+            Self::WasiCancelationToken(_) => {
+                failure::bail!("WASI modules run to completion without taking calls")
+            }
+        }
+    }
+
+    /// Describe the token for debugging.
+    fn dump(&self) -> serde_json::Value {
+        let kind = match self {
+            Self::WasccCancelationToken(key) => return json!({ "kind": "wascc", "actor": key }),
+            Self::WasiCancelationToken(_) => "wasi",
+            #[cfg(feature = "wapc")]
+            Self::WapcCancelationToken(..) => "wapc",
+        };
+        let state = self.exit_state().unwrap_or(ExitState::Running);
+        json!({
+            "kind": kind,
+            "exited": state != ExitState::Running,
+            "reason": state.reason(),
+            "error": state.error(),
+        })
+    }
+
+    /// Wait until the container exited. waSCC actors are gone as soon as they are stopped.
+    pub(crate) async fn exited(&self) {
+        if let Some(exited) = self.exit_receiver() {
+            let mut exited = exited.clone();
+            while let Some(state) = exited.recv().await {
+                if state != ExitState::Running {
+                    break;
+                }
             }
         }
     }
@@ -0,0 +1,347 @@
+//! Usage statistics of containers, and of pod sandboxes as the sum of their containers, since the kubelet and
+//! autoscalers reason about pods rather than containers. The admin endpoint serves them, along with the warm pool's
+//! counters and the space reclaimed from orphaned directories, as Prometheus metrics.
+
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde::Serialize;
+
+use super::grpc;
+use crate::wasm::WarmPoolStats;
+
+/// SandboxStats sums the usage of the containers of a pod sandbox.
+///
+/// CPU and memory are only reported if at least one container reports them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SandboxStats {
+    pub pod_sandbox_id: String,
+    pub name: String,
+    pub namespace: String,
+    /// the number of containers summed up
+    pub containers: u64,
+    /// the module instances running, i.e. the WASI modules that didn't exit yet and the started waSCC actors.
+    /// Modules run on threads of wok, so they are what the pod has for processes.
+    pub instances: u64,
+    pub cpu_usage_core_nano_seconds: Option<u64>,
+    pub memory_working_set_bytes: Option<u64>,
+    /// the bytes taken by the containers' directories and logs
+    pub fs_used_bytes: u64,
+    pub fs_inodes_used: u64,
+    /// when the stats were collected, in nanoseconds since the epoch
+    pub timestamp: i64,
+}
+
+impl SandboxStats {
+    pub fn new(sandbox: &grpc::PodSandbox) -> Self {
+        let metadata = sandbox.metadata.clone().unwrap_or_default();
+        SandboxStats {
+            pod_sandbox_id: sandbox.id.clone(),
+            name: metadata.name,
+            namespace: metadata.namespace,
+            timestamp: Utc::now().timestamp_nanos(),
+            ..Default::default()
+        }
+    }
+
+    /// Add the usage of one of the sandbox's containers.
+    pub fn add(&mut self, stats: &grpc::ContainerStats) {
+        self.containers += 1;
+        if let Some(cpu) = stats
+            .cpu
+            .as_ref()
+            .and_then(|c| c.usage_core_nano_seconds.as_ref())
+        {
+            *self.cpu_usage_core_nano_seconds.get_or_insert(0) += cpu.value;
+        }
+        if let Some(memory) = stats
+            .memory
+            .as_ref()
+            .and_then(|m| m.working_set_bytes.as_ref())
+        {
+            *self.memory_working_set_bytes.get_or_insert(0) += memory.value;
+        }
+        if let Some(fs) = &stats.writable_layer {
+            self.fs_used_bytes += fs.used_bytes.as_ref().map_or(0, |b| b.value);
+            self.fs_inodes_used += fs.inodes_used.as_ref().map_or(0, |i| i.value);
+        }
+    }
+}
+
+/// Render the stats of the sandboxes in the Prometheus text format.
+pub fn to_prometheus(stats: &[SandboxStats]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, help: &str, value: &dyn Fn(&SandboxStats) -> Option<u64>| {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        for s in stats {
+            if let Some(value) = value(s) {
+                writeln!(
+                    out,
+                    "{}{{pod_sandbox_id=\"{}\",namespace=\"{}\",pod=\"{}\"}} {}",
+                    name,
+                    escape(&s.pod_sandbox_id),
+                    escape(&s.namespace),
+                    escape(&s.name),
+                    value
+                )
+                .unwrap();
+            }
+        }
+    };
+    metric(
+        "wok_pod_containers",
+        "The number of containers of the pod sandbox.",
+        &|s| Some(s.containers),
+    );
+    metric(
+        "wok_pod_instances",
+        "The module instances running in the pod sandbox.",
+        &|s| Some(s.instances),
+    );
+    metric(
+        "wok_pod_cpu_usage_core_nanoseconds",
+        "The CPU time used by the containers of the pod sandbox.",
+        &|s| s.cpu_usage_core_nano_seconds,
+    );
+    metric(
+        "wok_pod_memory_working_set_bytes",
+        "The memory used by the containers of the pod sandbox.",
+        &|s| s.memory_working_set_bytes,
+    );
+    metric(
+        "wok_pod_fs_used_bytes",
+        "The bytes taken by the directories and logs of the containers of the pod sandbox.",
+        &|s| Some(s.fs_used_bytes),
+    );
+    metric(
+        "wok_pod_fs_inodes_used",
+        "The inodes taken by the directories and logs of the containers of the pod sandbox.",
+        &|s| Some(s.fs_inodes_used),
+    );
+    out
+}
+
+/// Render the stats of the warm pool in the Prometheus text format.
+pub fn warm_pool_to_prometheus(stats: &WarmPoolStats) -> String {
+    let mut out = String::new();
+    for (name, kind, help, value) in &[
+        (
+            "wok_warm_pool_hits_total",
+            "counter",
+            "Starts that took a warm instance.",
+            stats.hits,
+        ),
+        (
+            "wok_warm_pool_misses_total",
+            "counter",
+            "Starts that compiled their module themselves.",
+            stats.misses,
+        ),
+        (
+            "wok_warm_pool_evictions_total",
+            "counter",
+            "Modules evicted from the warm pool to make room for another.",
+            stats.evictions,
+        ),
+        (
+            "wok_warm_pool_modules",
+            "gauge",
+            "The modules the warm pool keeps instances of.",
+            stats.modules as u64,
+        ),
+    ] {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+    out
+}
+
+/// The directories of containers wok didn't know about when it started, e.g. left behind by a crash, and removed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Orphans {
+    /// the removed directories
+    pub removed: Vec<PathBuf>,
+    /// the bytes freed on disk or in memory
+    pub reclaimed_bytes: u64,
+}
+
+/// Render what was reclaimed from orphaned directories in the Prometheus text format.
+pub fn orphans_to_prometheus(orphans: &Orphans) -> String {
+    let mut out = String::new();
+    for (name, help, value) in &[
+        (
+            "wok_orphaned_dirs_removed_total",
+            "Directories of unknown containers removed when wok started.",
+            orphans.removed.len() as u64,
+        ),
+        (
+            "wok_orphaned_bytes_reclaimed_total",
+            "The bytes the directories of unknown containers took.",
+            orphans.reclaimed_bytes,
+        ),
+    ] {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+    out
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The bytes and inodes taken by the files below the directory, 0 if it does not exist.
+pub(crate) async fn dir_usage(dir: PathBuf) -> (u64, u64) {
+    tokio::task::spawn_blocking(move || {
+        let mut usage = (0, 0);
+        if let Err(e) = walk(&dir, &mut usage) {
+            if e.kind() != io::ErrorKind::NotFound {
+                tracing::debug!("cannot measure {}: {}", dir.display(), e);
+            }
+        }
+        usage
+    })
+    .await
+    .unwrap()
+}
+
+/// The bytes and files taken by the container's log: the current file, and the files the kubelet rotated it to,
+/// named after it with a suffix, e.g. `0.log.20200131-120000` or `0.log.20200131-120000.gz`. 0 if there is none.
+pub(crate) async fn log_usage(log_path: PathBuf) -> (u64, u64) {
+    tokio::task::spawn_blocking(move || {
+        let mut usage = (0, 0);
+        if let Err(e) = measure_log(&log_path, &mut usage) {
+            if e.kind() != io::ErrorKind::NotFound {
+                tracing::debug!("cannot measure {}: {}", log_path.display(), e);
+            }
+        }
+        usage
+    })
+    .await
+    .unwrap()
+}
+
+fn measure_log(log_path: &Path, usage: &mut (u64, u64)) -> io::Result<()> {
+    let (dir, name) = match (log_path.parent(), log_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return Ok(()),
+    };
+    let rotated = format!("{}.", name);
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if file_name != name && !file_name.starts_with(&rotated) {
+            continue;
+        }
+        let metadata = entry.path().symlink_metadata()?;
+        if metadata.is_file() {
+            usage.0 += metadata.len();
+            usage.1 += 1;
+        }
+    }
+    Ok(())
+}
+
+fn walk(dir: &Path, usage: &mut (u64, u64)) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        // symlinks, e.g. to mounted host paths, are not followed
+        let metadata = entry.path().symlink_metadata()?;
+        usage.1 += 1;
+        if metadata.is_dir() {
+            walk(&entry.path(), usage)?;
+        } else {
+            usage.0 += metadata.len();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn container_stats(fs_used_bytes: u64, memory: Option<u64>) -> grpc::ContainerStats {
+        grpc::ContainerStats {
+            memory: memory.map(|value| grpc::MemoryUsage {
+                working_set_bytes: Some(grpc::UInt64Value { value }),
+                ..Default::default()
+            }),
+            writable_layer: Some(grpc::FilesystemUsage {
+                used_bytes: Some(grpc::UInt64Value {
+                    value: fs_used_bytes,
+                }),
+                inodes_used: Some(grpc::UInt64Value { value: 1 }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_usage() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let log_path = dir.path().join("0.log");
+        assert_eq!((0, 0), log_usage(log_path.clone()).await);
+
+        std::fs::write(&log_path, b"hello\n").unwrap();
+        std::fs::write(dir.path().join("0.log.20200131-120000"), b"older\n").unwrap();
+        std::fs::write(dir.path().join("0.log.20200130-120000.gz"), b"gz").unwrap();
+        // other containers' logs don't count
+        std::fs::write(dir.path().join("1.log"), b"other\n").unwrap();
+        std::fs::write(dir.path().join("0.logs"), b"other\n").unwrap();
+        assert_eq!((14, 3), log_usage(log_path).await);
+    }
+
+    #[test]
+    fn test_sandbox_stats() {
+        let mut stats = SandboxStats::new(&grpc::PodSandbox {
+            id: "1".to_owned(),
+            metadata: Some(grpc::PodSandboxMetadata {
+                name: "app".to_owned(),
+                namespace: "default".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        stats.add(&container_stats(10, None));
+        assert_eq!(None, stats.memory_working_set_bytes);
+        stats.add(&container_stats(5, Some(100)));
+        assert_eq!(2, stats.containers);
+        assert_eq!(15, stats.fs_used_bytes);
+        assert_eq!(2, stats.fs_inodes_used);
+        assert_eq!(Some(100), stats.memory_working_set_bytes);
+        assert_eq!(None, stats.cpu_usage_core_nano_seconds);
+        stats.instances = 1;
+
+        let text = to_prometheus(&[stats]);
+        assert!(text.contains(
+            "wok_pod_instances{pod_sandbox_id=\"1\",namespace=\"default\",pod=\"app\"} 1\n"
+        ));
+        assert!(text.contains(
+            "wok_pod_fs_used_bytes{pod_sandbox_id=\"1\",namespace=\"default\",pod=\"app\"} 15\n"
+        ));
+        assert!(text.contains("wok_pod_memory_working_set_bytes{pod_sandbox_id=\"1\""));
+        assert!(!text.contains("wok_pod_cpu_usage_core_nanoseconds{"));
+    }
+
+    #[tokio::test]
+    async fn test_dir_usage() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        assert_eq!((0, 0), dir_usage(dir.path().join("missing")).await);
+        std::fs::create_dir(dir.path().join("app")).unwrap();
+        std::fs::write(dir.path().join("app/data"), b"hello").unwrap();
+        assert_eq!((5, 2), dir_usage(dir.path().to_owned()).await);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tower_service::Service;
+
+use crate::config::RpcTimeoutOptions;
+
+/// Timeouts keeps how long each RPC may take. It is shared by the services it is applied to.
+#[derive(Clone, Debug, Default)]
+pub struct Timeouts {
+    /// the timeout of the RPCs without one of their own, if any
+    default: Option<Duration>,
+    /// the timeouts by RPC name
+    rpcs: Arc<HashMap<String, Duration>>,
+}
+
+impl Timeouts {
+    /// Time RPCs out after `default_secs`, 0 for never, unless they have a timeout of their own.
+    pub fn new(default_secs: u64, timeouts: &[RpcTimeoutOptions]) -> Result<Self, failure::Error> {
+        for timeout in timeouts {
+            if timeout.secs == 0 {
+                failure::bail!("invalid timeout of {}: secs must be positive", timeout.rpc);
+            }
+        }
+        Ok(Timeouts {
+            default: Some(Duration::from_secs(default_secs))
+                .filter(|d| *d > Duration::from_secs(0)),
+            rpcs: Arc::new(
+                timeouts
+                    .iter()
+                    .map(|t| (t.rpc.clone(), Duration::from_secs(t.secs)))
+                    .collect(),
+            ),
+        })
+    }
+
+    /// How long the RPC may take, if it is bounded.
+    fn timeout(&self, rpc: &str) -> Option<Duration> {
+        self.rpcs.get(rpc).copied().or(self.default)
+    }
+}
+
+/// TimeLimited wraps a gRPC service so calls to its RPCs fail with `DeadlineExceeded` once they took longer than
+/// their timeout, e.g. a `PullImage` stuck on an unresponsive registry, rather than holding on to the connection's
+/// concurrency for good.
+///
+/// The timeout bounds the time until the response starts, which is the whole call for the unary RPCs of the CRI.
+/// The handler of a call timing out is dropped, at its next await point.
+#[derive(Clone, Debug)]
+pub struct TimeLimited<S> {
+    inner: S,
+    timeouts: Timeouts,
+}
+
+impl<S> TimeLimited<S> {
+    pub fn new(inner: S, timeouts: Timeouts) -> Self {
+        TimeLimited { inner, timeouts }
+    }
+}
+
+impl<S: NamedService> NamedService for TimeLimited<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for TimeLimited<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let rpc = req
+            .uri()
+            .path()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let timeout = self.timeouts.timeout(&rpc);
+        let call = self.inner.call(req);
+        Box::pin(async move {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return call.await,
+            };
+            match tokio::time::timeout(timeout, call).await {
+                Ok(result) => result,
+                Err(_) => {
+                    tracing::warn!("{} timed out after {:?}", rpc, timeout);
+                    Ok(deadline_exceeded(&rpc, timeout))
+                }
+            }
+        })
+    }
+}
+
+/// The response of a call that took longer than its timeout.
+fn deadline_exceeded(rpc: &str, timeout: Duration) -> http::Response<BoxBody> {
+    http::Response::builder()
+        .status(200)
+        .header("content-type", "application/grpc")
+        .header("grpc-status", "4")
+        .header(
+            "grpc-message",
+            format!("{} timed out after {}s", rpc, timeout.as_secs()),
+        )
+        .body(BoxBody::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn timeout(rpc: &str, secs: u64) -> RpcTimeoutOptions {
+        RpcTimeoutOptions {
+            rpc: rpc.to_owned(),
+            secs,
+        }
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let timeouts = Timeouts::new(30, &[timeout("PullImage", 300)]).unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(300)),
+            timeouts.timeout("PullImage")
+        );
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            timeouts.timeout("ListContainers")
+        );
+
+        // without a default, only the RPCs with a timeout of their own are bounded
+        let timeouts = Timeouts::new(0, &[timeout("PullImage", 300)]).unwrap();
+        assert_eq!(None, timeouts.timeout("ListContainers"));
+
+        Timeouts::new(0, &[timeout("PullImage", 0)]).expect_err("no secs");
+    }
+
+    #[derive(Clone)]
+    struct Slow;
+
+    impl Service<http::Request<()>> for Slow {
+        type Response = http::Response<BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            Box::pin(async {
+                tokio::time::delay_for(Duration::from_secs(5)).await;
+                Ok(http::Response::new(BoxBody::empty()))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_time_limited() {
+        let mut service = TimeLimited::new(
+            Slow,
+            Timeouts {
+                default: None,
+                rpcs: Arc::new(
+                    vec![("PullImage".to_owned(), Duration::from_millis(10))]
+                        .into_iter()
+                        .collect(),
+                ),
+            },
+        );
+        let req = http::Request::builder()
+            .uri("/runtime.v1alpha2.ImageService/PullImage")
+            .body(())
+            .unwrap();
+        let res = service.call(req).await.unwrap();
+        assert_eq!("4", res.headers()["grpc-status"]);
+    }
+}
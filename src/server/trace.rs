@@ -0,0 +1,103 @@
+use std::task::{Context, Poll};
+
+use tonic::transport::NamedService;
+use tower_service::Service;
+use tracing::field::Empty;
+use tracing_futures::{Instrument, Instrumented};
+use uuid::Uuid;
+
+/// The header a client can use to pass in its own request ID.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Traced wraps a gRPC service so every call runs inside its own tracing span.
+///
+/// Pulls, compiles and instantiations run in `pull`, `compile` and `instantiate` spans of their own, and log
+/// how long they took at debug level, which breaks a slow container start down.
+///
+/// TODO: export the spans to an OTLP collector. There is no OTLP exporter for the opentelemetry crates that work
+/// with our tracing and tokio versions yet, so for now the breakdown is only in the logs.
+///
+/// The span is named after the RPC and carries a request ID, taken from the `x-request-id` header
+/// when the client sends one. It also declares empty `pod_sandbox_id` and `container_id` fields
+/// which handlers fill in with [`record_pod_sandbox_id`] and [`record_container_id`], so all events
+/// for a single pod can be found by filtering on its ID.
+#[derive(Clone, Debug)]
+pub struct Traced<S> {
+    inner: S,
+}
+
+impl<S> Traced<S> {
+    pub fn new(inner: S) -> Self {
+        Traced { inner }
+    }
+}
+
+impl<S: NamedService> NamedService for Traced<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Traced<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Instrumented<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let span = rpc_span(&req);
+        span.in_scope(|| tracing::debug!("received request"));
+        self.inner.call(req).instrument(span)
+    }
+}
+
+/// Build the span for a request to `/<package>.<service>/<method>`.
+fn rpc_span<B>(req: &http::Request<B>) -> tracing::Span {
+    let rpc = req.uri().path().rsplit('/').next().unwrap_or_default();
+    tracing::info_span!(
+        "rpc",
+        rpc = rpc,
+        request_id = request_id(req.headers()).as_str(),
+        pod_sandbox_id = Empty,
+        container_id = Empty
+    )
+}
+
+/// Use the request ID sent by the client, or make one up.
+fn request_id(headers: &http::HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Record the pod sandbox the current RPC operates on.
+pub fn record_pod_sandbox_id(id: &str) {
+    tracing::Span::current().record("pod_sandbox_id", &id);
+}
+
+/// Record the container the current RPC operates on.
+pub fn record_container_id(id: &str) {
+    tracing::Span::current().record("container_id", &id);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_id() {
+        let mut headers = http::HeaderMap::new();
+        let generated = request_id(&headers);
+        Uuid::parse_str(&generated).expect("generated request id is a UUID");
+        assert_ne!(generated, request_id(&headers));
+
+        headers.insert(REQUEST_ID_HEADER, "abc".parse().unwrap());
+        assert_eq!("abc", request_id(&headers));
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::RwLock;
+
+use super::ModuleStoreError;
+use crate::config::MetadataBackend;
+use crate::server::Module;
+
+/// The file below the store's root holding the SQLite database.
+#[cfg(feature = "sqlite")]
+const SQLITE_FILE: &str = "metadata.db";
+
+/// ModuleIndex keeps the metadata of the modules in the store, by module ID.
+///
+/// Adding a module replaces the module with the same ID. The calls are short, so they are made straight from the
+/// async store methods.
+pub trait ModuleIndex: fmt::Debug + Send + Sync {
+    fn add(&self, module: Module) -> Result<(), ModuleStoreError>;
+    /// Remove the module with the given ID, returning it if there was one.
+    fn remove(&self, id: &str) -> Result<Option<Module>, ModuleStoreError>;
+    /// The modules in the order they were added.
+    fn list(&self) -> Result<Vec<Module>, ModuleStoreError>;
+}
+
+/// Open the index of the given backend for the store in `root_dir`.
+pub fn open(
+    backend: MetadataBackend,
+    root_dir: &Path,
+) -> Result<Box<dyn ModuleIndex>, ModuleStoreError> {
+    match backend {
+        MetadataBackend::Memory => Ok(Box::new(MemoryIndex::default())),
+        #[cfg(feature = "sqlite")]
+        MetadataBackend::Sqlite => Ok(Box::new(SqliteIndex::open(&root_dir.join(SQLITE_FILE))?)),
+        #[cfg(not(feature = "sqlite"))]
+        MetadataBackend::Sqlite => Err(ModuleStoreError::Metadata(format!(
+            "cannot keep the metadata of {} in SQLite, wok was built without the sqlite feature",
+            root_dir.display()
+        ))),
+    }
+}
+
+/// MemoryIndex keeps the metadata in memory. It is lost when wok restarts.
+#[derive(Debug, Default)]
+pub struct MemoryIndex {
+    modules: RwLock<Vec<Module>>,
+}
+
+impl ModuleIndex for MemoryIndex {
+    fn add(&self, module: Module) -> Result<(), ModuleStoreError> {
+        let mut modules = self.modules.write().unwrap();
+        modules.retain(|m| m.id != module.id);
+        modules.push(module);
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<Option<Module>, ModuleStoreError> {
+        let mut modules = self.modules.write().unwrap();
+        Ok(modules
+            .iter()
+            .position(|m| m.id == id)
+            .map(|i| modules.remove(i)))
+    }
+
+    fn list(&self) -> Result<Vec<Module>, ModuleStoreError> {
+        Ok(self.modules.read().unwrap().clone())
+    }
+}
+
+/// SqliteIndex keeps the metadata in an SQLite database, so it survives restarts and every change is atomic.
+/// Modules are stored as their encoded CRI image message.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteIndex {
+    conn: std::sync::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteIndex {
+    pub fn open(path: &Path) -> Result<Self, ModuleStoreError> {
+        let conn = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS modules (id TEXT PRIMARY KEY, image BLOB NOT NULL);",
+        )
+        .map_err(sqlite_error)?;
+        Ok(SqliteIndex {
+            conn: std::sync::Mutex::new(conn),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ModuleIndex for SqliteIndex {
+    fn add(&self, module: Module) -> Result<(), ModuleStoreError> {
+        use prost::Message;
+
+        let mut image = Vec::with_capacity(module.encoded_len());
+        module
+            .encode(&mut image)
+            .map_err(|e| ModuleStoreError::Metadata(e.to_string()))?;
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO modules (id, image) VALUES (?1, ?2)",
+                rusqlite::params![module.id, image],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<Option<Module>, ModuleStoreError> {
+        use rusqlite::OptionalExtension;
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sqlite_error)?;
+        let image: Option<Vec<u8>> = tx
+            .query_row(
+                "SELECT image FROM modules WHERE id = ?1",
+                rusqlite::params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_error)?;
+        tx.execute("DELETE FROM modules WHERE id = ?1", rusqlite::params![id])
+            .map_err(sqlite_error)?;
+        tx.commit().map_err(sqlite_error)?;
+        image.map(decode).transpose()
+    }
+
+    fn list(&self) -> Result<Vec<Module>, ModuleStoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT image FROM modules ORDER BY rowid")
+            .map_err(sqlite_error)?;
+        let images = stmt
+            .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, Vec<u8>>(0))
+            .map_err(sqlite_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sqlite_error)?;
+        images.into_iter().map(decode).collect()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn decode(image: Vec<u8>) -> Result<Module, ModuleStoreError> {
+    use prost::Message;
+
+    Module::decode(image).map_err(|e| ModuleStoreError::Metadata(e.to_string()))
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(e: rusqlite::Error) -> ModuleStoreError {
+    ModuleStoreError::Metadata(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn module(id: &str, size: u64) -> Module {
+        Module {
+            id: id.to_owned(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    fn check_index(index: &dyn ModuleIndex) {
+        index.add(module("a", 1)).unwrap();
+        index.add(module("b", 2)).unwrap();
+        // adding a module again replaces it
+        index.add(module("a", 3)).unwrap();
+        let ids: Vec<_> = index
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.id, m.size))
+            .collect();
+        assert_eq!(vec![("b".to_owned(), 2), ("a".to_owned(), 3)], ids);
+
+        assert_eq!(Some(module("b", 2)), index.remove("b").unwrap());
+        assert_eq!(None, index.remove("b").unwrap());
+        assert_eq!(1, index.list().unwrap().len());
+    }
+
+    #[test]
+    fn test_memory_index() {
+        check_index(&MemoryIndex::default());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_index() {
+        let dir = tempfile::tempdir().unwrap();
+        check_index(&*open(MetadataBackend::Sqlite, dir.path()).unwrap());
+        // the modules survive reopening the database
+        let index = open(MetadataBackend::Sqlite, dir.path()).unwrap();
+        assert_eq!(vec![module("a", 3)], index.list().unwrap());
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    #[test]
+    fn test_sqlite_index_unavailable() {
+        open(MetadataBackend::Sqlite, Path::new("/tmp")).expect_err("sqlite is not built in");
+    }
+}
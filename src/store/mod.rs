@@ -1,26 +1,146 @@
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::ffi::CString;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::sync::RwLock;
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{Mutex, RwLock, Semaphore};
+use tracing_futures::Instrument;
+use uuid::Uuid;
 
-use crate::docker::Reference;
-use crate::oci::{GoString, Pull};
+use crate::config::{MetadataBackend, PullOptions};
+use crate::docker::{Reference, Source};
+use crate::oci::{Fetch, GoString, Pull, PullWapm};
 use crate::server::Module;
+use crate::wasm::BinaryFormat;
+use ratelimit::RegistryLimits;
 
-#[derive(Clone, Debug, Default)]
+mod index;
+mod namespace;
+mod ratelimit;
+mod sideload;
+mod stats;
+mod tag;
+mod usage;
+mod verify;
+
+pub use index::{MemoryIndex, ModuleIndex};
+pub use stats::PullStats;
+pub use usage::Usage;
+
+/// The directory below the root holding the modules downloaded from a URL.
+const URL_MODULES_DIR: &str = "https";
+/// The directory below the root holding the modules pulled from WAPM.
+const WAPM_MODULES_DIR: &str = "wapm";
+/// The directory below the root operators drop modules into, to make them available without a registry.
+const SIDELOAD_DIR: &str = "sideload";
+/// The directory below the root holding one file per distinct module content, named by its sha256 digest.
+/// Module files with the same content are hard links to the same blob.
+const BLOBS_DIR: &str = "blobs/sha256";
+/// The first bytes of every WebAssembly binary.
+const WASM_MAGIC: [u8; 4] = *b"\0asm";
+/// The extension of compressed module files and blobs.
+const COMPRESSED_EXTENSION: &str = "zst";
+/// The results of the Go pulls from this one on are 429 responses with a Retry-After header, asking to wait the
+/// result minus this many seconds.
+const RETRY_AFTER_RESULT: i64 = 1000;
+/// The zstd compression level. The default level compresses modules well at a fraction of the time the higher
+/// levels take.
+const COMPRESSION_LEVEL: i32 = 0;
+
+/// A pull shared by everyone asking for the same reference while it runs.
+type SharedPull = Shared<BoxFuture<'static, Result<(), ModuleStoreError>>>;
+
+/// The pulls in progress or waiting for a permit, by reference.
+#[derive(Clone, Default)]
+struct InFlight(Arc<Mutex<BTreeMap<String, SharedPull>>>);
+
+impl fmt::Debug for InFlight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InFlight").finish()
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct ModuleStore {
     root_dir: PathBuf,
-    modules: Arc<RwLock<Vec<Module>>>,
+    /// the metadata of the modules in the store
+    modules: Arc<dyn ModuleIndex>,
+    /// the references currently being pulled, with the time the pull started.
+    pulls: Arc<RwLock<BTreeMap<String, DateTime<Utc>>>>,
+    /// Pulling a reference that is in here waits for the pull in progress instead of downloading the module
+    /// again.
+    in_flight: InFlight,
+    /// limits how many pulls download at a time, shared by the stores of all namespaces. None if unlimited.
+    pull_permits: Option<Arc<Semaphore>>,
+    /// spaces out the pulls from each registry, shared by the stores of all namespaces
+    registry_limits: RegistryLimits,
+    pull_options: PullOptions,
+    /// the docker config file with the registry credentials of the namespace the store pulls for, None for
+    /// docker's default
+    credentials: Option<PathBuf>,
+    /// whether pulled modules are kept compressed
+    compress: bool,
+    /// what the pulls did so far
+    stats: Arc<RwLock<PullStats>>,
+    /// what the store took on disk when it was last measured, None if it never was
+    usage: Arc<RwLock<Option<Usage>>>,
+    /// where the metadata of the modules is kept
+    metadata: MetadataBackend,
+    /// the stores of the namespaces opened so far, or None if the store is not partitioned by namespace.
+    namespaces: Option<Arc<RwLock<BTreeMap<String, ModuleStore>>>>,
+}
+
+/// Pruned tells what a prune removed from the store.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Pruned {
+    /// the IDs of the removed modules
+    pub removed: Vec<String>,
+    /// the bytes freed on disk
+    pub reclaimed_bytes: u64,
+}
+
+impl Pruned {
+    /// Add what another prune removed, e.g. from the store of another namespace.
+    pub fn extend(&mut self, other: Pruned) {
+        self.removed.extend(other.removed);
+        self.reclaimed_bytes += other.reclaimed_bytes;
+    }
 }
 
 /// An error which can be returned when there was an error
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ModuleStoreError {
     CannotFetchModuleMetadata,
     CannotPullModule,
+    /// the registry could not be reached or failed with a server error. Pulling again later may work.
+    RegistryUnavailable,
+    /// the registry answered with 429 Too Many Requests, asking to wait for the given time if it said
+    RateLimited(Option<Duration>),
+    /// the pull did not finish before its deadline
+    PullTimedOut,
+    /// the downloaded module does not have the digest given in its reference
+    DigestMismatch,
+    /// the reference points to something that is not a WebAssembly module, e.g. a container image
+    NotWasm(String),
+    /// the module is a WebAssembly binary, but a corrupt or invalid one
+    InvalidModule(String),
+    /// the store's directory cannot be written to, e.g. because the disk is full
+    CannotWriteStore(String),
+    /// the metadata of the modules cannot be read or written
+    Metadata(String),
+    /// the name is not a valid Kubernetes namespace
+    InvalidNamespace(String),
     InvalidPullPath,
     InvalidReference,
     LockNotAcquired,
@@ -34,6 +154,21 @@ impl fmt::Display for ModuleStoreError {
                 f.write_str("cannot fetch metadata from the module")
             }
             ModuleStoreError::CannotPullModule => f.write_str("cannot pull module"),
+            ModuleStoreError::RegistryUnavailable => f.write_str("registry is unavailable"),
+            ModuleStoreError::RateLimited(_) => f.write_str("registry is rate limiting pulls"),
+            ModuleStoreError::PullTimedOut => f.write_str("pull timed out"),
+            ModuleStoreError::DigestMismatch => f.write_str("module does not match its digest"),
+            ModuleStoreError::NotWasm(ref reason) => {
+                write!(f, "not a WebAssembly module: {}", reason)
+            }
+            ModuleStoreError::InvalidModule(ref reason) => {
+                write!(f, "invalid WebAssembly module: {}", reason)
+            }
+            ModuleStoreError::CannotWriteStore(ref e) => write!(f, "cannot write to store: {}", e),
+            ModuleStoreError::Metadata(ref e) => write!(f, "cannot access module metadata: {}", e),
+            ModuleStoreError::InvalidNamespace(ref namespace) => {
+                write!(f, "invalid namespace {:?}", namespace)
+            }
             ModuleStoreError::InvalidPullPath => f.write_str("invalid pull path"),
             ModuleStoreError::InvalidReference => f.write_str("invalid reference"),
             ModuleStoreError::LockNotAcquired => f.write_str("cannot acquire lock on store"),
@@ -47,6 +182,15 @@ impl Error for ModuleStoreError {
         match *self {
             ModuleStoreError::CannotFetchModuleMetadata => "Cannot fetch metadata from the module",
             ModuleStoreError::CannotPullModule => "Cannot pull module",
+            ModuleStoreError::RegistryUnavailable => "Registry is unavailable",
+            ModuleStoreError::RateLimited(_) => "Registry is rate limiting pulls",
+            ModuleStoreError::PullTimedOut => "Pull timed out",
+            ModuleStoreError::DigestMismatch => "Module does not match its digest",
+            ModuleStoreError::NotWasm(_) => "Not a WebAssembly module",
+            ModuleStoreError::InvalidModule(_) => "Invalid WebAssembly module",
+            ModuleStoreError::CannotWriteStore(_) => "Cannot write to store",
+            ModuleStoreError::Metadata(_) => "Cannot access module metadata",
+            ModuleStoreError::InvalidNamespace(_) => "Invalid namespace",
             ModuleStoreError::InvalidPullPath => "Invalid pull path",
             ModuleStoreError::InvalidReference => "Invalid reference",
             ModuleStoreError::LockNotAcquired => "Cannot acquire lock on store",
@@ -55,127 +199,773 @@ impl Error for ModuleStoreError {
     }
 }
 
+impl Default for ModuleStore {
+    fn default() -> Self {
+        ModuleStore {
+            root_dir: PathBuf::default(),
+            modules: Arc::new(MemoryIndex::default()),
+            pulls: Arc::default(),
+            in_flight: InFlight::default(),
+            pull_permits: None,
+            registry_limits: RegistryLimits::default(),
+            pull_options: PullOptions::default(),
+            credentials: None,
+            compress: false,
+            stats: Arc::default(),
+            usage: Arc::default(),
+            metadata: MetadataBackend::Memory,
+            namespaces: None,
+        }
+    }
+}
+
 impl ModuleStore {
     pub async fn new(root_dir: PathBuf) -> Self {
+        Self::with_options(root_dir, PullOptions::default()).await
+    }
+
+    pub async fn with_options(root_dir: PathBuf, pull_options: PullOptions) -> Self {
         // TODO(bacongobbler): populate `modules` using `root_dir`
         ModuleStore {
             root_dir,
-            modules: Arc::new(RwLock::new(vec![])),
+            modules: Arc::new(MemoryIndex::default()),
+            pulls: Arc::new(RwLock::new(BTreeMap::new())),
+            in_flight: InFlight::default(),
+            pull_permits: match pull_options.max_concurrent {
+                0 => None,
+                permits => Some(Arc::new(Semaphore::new(permits))),
+            },
+            registry_limits: RegistryLimits::new(&pull_options),
+            credentials: pull_options.default_credentials.clone(),
+            pull_options,
+            compress: false,
+            stats: Arc::new(RwLock::new(PullStats::default())),
+            usage: Arc::default(),
+            metadata: MetadataBackend::Memory,
+            namespaces: None,
         }
     }
 
-    pub async fn add(&mut self, module: Module) {
-        let mut modules = self.modules.write().await;
+    /// Keep pulled modules compressed. Modules already in the store are left as they are.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Keep the metadata of the modules in the given backend, e.g. SQLite so it survives restarts.
+    pub fn with_metadata(mut self, backend: MetadataBackend) -> Result<Self, ModuleStoreError> {
+        self.modules = Arc::from(index::open(backend, &self.root_dir)?);
+        self.metadata = backend;
+        Ok(self)
+    }
 
-        modules.push(module);
+    pub async fn add(&mut self, module: Module) -> Result<(), ModuleStoreError> {
+        self.modules.add(module)
     }
 
     pub async fn list(&self) -> Vec<Module> {
-        let modules = self.modules.read().await;
-        modules.clone()
+        self.modules.list().unwrap_or_else(|e| {
+            tracing::error!("cannot list modules: {}", e);
+            vec![]
+        })
     }
 
-    pub async fn remove(&mut self, key: String) -> Result<Module, ModuleStoreError> {
-        let mut modules = self.modules.write().await;
-        let i = modules
-            .iter()
-            .position(|i| i.id == key)
+    /// Remove the module and delete its files, along with the directories left empty and its blob unless another
+    /// module shares it. Returns the module and the bytes reclaimed on disk.
+    pub async fn remove(&mut self, key: String) -> Result<(Module, u64), ModuleStoreError> {
+        let module = self
+            .modules
+            .remove(&key)?
             .ok_or(ModuleStoreError::NotFound)?;
-        Ok(modules.remove(i))
+        // modules added without being pulled, e.g. in tests, have no files
+        let file = match Reference::try_from(module.id.clone()) {
+            Ok(reference) => self.pull_file_path(&reference),
+            Err(_) => return Ok((module, 0)),
+        };
+        let mut files = vec![compressed_path(&file), file];
+        let shared = match content_digest(&module) {
+            Some(digest) => self
+                .list()
+                .await
+                .iter()
+                .any(|m| content_digest(m) == Some(digest)),
+            None => true,
+        };
+        match content_digest(&module) {
+            Some(digest) if !shared && digest.starts_with("sha256:") => {
+                let blob = self
+                    .root_dir
+                    .join(BLOBS_DIR)
+                    .join(&digest["sha256:".len()..]);
+                files.push(compressed_path(&blob));
+                files.push(blob);
+            }
+            _ => {}
+        }
+
+        let root_dir = self.root_dir.clone();
+        let reclaimed = tokio::task::spawn_blocking(move || delete_files(&root_dir, &files))
+            .await
+            .unwrap()
+            .map_err(|e| ModuleStoreError::CannotWriteStore(e.to_string()))?;
+        tracing::debug!(module = %module.id, reclaimed, "removed module");
+        Ok((module, reclaimed))
     }
 
+    /// Remove the modules whose ID is not in `in_use`, e.g. because no container uses them, and delete their files.
+    pub async fn prune(&mut self, in_use: &HashSet<String>) -> Result<Pruned, ModuleStoreError> {
+        let mut pruned = Pruned::default();
+        for module in self.list().await {
+            if in_use.contains(&module.id) {
+                continue;
+            }
+            match self.remove(module.id.clone()).await {
+                Ok((module, reclaimed)) => {
+                    pruned.removed.push(module.id);
+                    pruned.reclaimed_bytes += reclaimed;
+                }
+                // removed in the meantime
+                Err(ModuleStoreError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// What the pulls did since the store was created.
+    pub async fn pull_stats(&self) -> PullStats {
+        self.stats.read().await.clone()
+    }
+
+    /// The references currently being pulled, with the time each pull started.
+    pub async fn pulls(&self) -> BTreeMap<String, DateTime<Utc>> {
+        self.pulls.read().await.clone()
+    }
+
+    /// Pull the module into the store. Concurrent pulls of the same reference are coalesced into one, and at most
+    /// `max_concurrent` pulls download at a time; the others wait for their turn.
     pub async fn pull(&mut self, reference: &Reference) -> Result<(), ModuleStoreError> {
+        let pull = {
+            let mut in_flight = self.in_flight.0.lock().await;
+            match in_flight.get(reference.whole()) {
+                Some(pull) => {
+                    tracing::debug!(image = reference.whole(), "joining the pull in progress");
+                    pull.clone()
+                }
+                None => {
+                    let mut store = self.clone();
+                    let reference = reference.clone();
+                    let pull = async move {
+                        let result = store.pull_alone(&reference).await;
+                        store.in_flight.0.lock().await.remove(reference.whole());
+                        result
+                    }
+                    .boxed()
+                    .shared();
+                    in_flight.insert(reference.whole().to_owned(), pull.clone());
+                    pull
+                }
+            }
+        };
+        pull.await
+    }
+
+    /// Pull the modules that are not in the store yet, ahead of the containers using them. Failures are only
+    /// logged, the kubelet pulls the modules again before it creates the containers.
+    pub async fn prepull(self, references: Vec<Reference>) {
+        let present: HashSet<String> = self.list().await.into_iter().map(|m| m.id).collect();
+        let pulls = references
+            .into_iter()
+            .filter(|reference| !present.contains(reference.whole()))
+            .map(|reference| {
+                let mut store = self.clone();
+                async move {
+                    match store.pull(&reference).await {
+                        Ok(()) => tracing::info!("pre-pulled {}", reference.whole()),
+                        Err(e) => tracing::warn!("cannot pre-pull {}: {}", reference.whole(), e),
+                    }
+                }
+            });
+        futures::future::join_all(pulls).await;
+    }
+
+    /// Pull the module once its registry's rate limit and a permit allow it, ignoring pulls of the same reference.
+    ///
+    /// The rate limit is waited for first, so pulls held back by their registry don't keep pulls from other
+    /// registries from getting a permit.
+    async fn pull_alone(&mut self, reference: &Reference) -> Result<(), ModuleStoreError> {
+        self.throttle(reference).await;
+        let permits = self.pull_permits.clone();
+        let _permit = match &permits {
+            Some(permits) => {
+                let queued = Instant::now();
+                let permit = permits.acquire().await;
+                tracing::debug!(
+                    image = reference.whole(),
+                    queued_ms = queued.elapsed().as_millis() as u64,
+                    "pull may start"
+                );
+                Some(permit)
+            }
+            None => None,
+        };
+        self.pulls
+            .write()
+            .await
+            .insert(reference.whole().to_owned(), Utc::now());
+        let span = tracing::info_span!("pull", image = reference.whole());
+        let started = Instant::now();
+        let timeout_secs = self.pull_options.timeout_secs;
+        let result = async {
+            let result = match timeout_secs {
+                0 => self.pull_with_retries(reference).await,
+                secs => tokio::time::timeout(
+                    Duration::from_secs(secs),
+                    self.pull_with_retries(reference),
+                )
+                .await
+                .unwrap_or(Err(ModuleStoreError::PullTimedOut)),
+            };
+            tracing::debug!(
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                ok = result.is_ok(),
+                "pull finished"
+            );
+            result
+        }
+        .instrument(span)
+        .await;
+        let timed_out = if let Err(ModuleStoreError::PullTimedOut) = result {
+            true
+        } else {
+            false
+        };
+        self.stats
+            .write()
+            .await
+            .record_pull(result.is_ok(), timed_out, started.elapsed());
+        self.pulls.write().await.remove(reference.whole());
+        result
+    }
+
+    /// Pull the module, retrying transient failures with an exponential backoff.
+    ///
+    /// A registry rate limiting the pull is left alone for as long as it asks, or for the backoff if it doesn't
+    /// say, by every pull from it.
+    async fn pull_with_retries(&mut self, reference: &Reference) -> Result<(), ModuleStoreError> {
+        let mut attempt = 0;
+        loop {
+            match self.pull_and_add(reference).await {
+                Err(ModuleStoreError::RegistryUnavailable)
+                    if attempt < self.pull_options.retries =>
+                {
+                    let wait = jitter(backoff(&self.pull_options, attempt));
+                    tracing::warn!(
+                        "registry is unavailable while pulling {}, retrying in {:?}",
+                        reference.whole(),
+                        wait
+                    );
+                    self.stats.write().await.retries += 1;
+                    tokio::time::delay_for(wait).await;
+                }
+                Err(ModuleStoreError::RateLimited(retry_after))
+                    if attempt < self.pull_options.retries =>
+                {
+                    let wait =
+                        retry_after.unwrap_or_else(|| jitter(backoff(&self.pull_options, attempt)));
+                    tracing::warn!(
+                        "registry {} is rate limiting pulls, retrying {} in {:?}",
+                        reference.registry(),
+                        reference.whole(),
+                        wait
+                    );
+                    {
+                        let mut stats = self.stats.write().await;
+                        stats.retries += 1;
+                        stats.rate_limited += 1;
+                    }
+                    self.registry_limits.pause(reference.registry(), wait);
+                }
+                result @ Err(ModuleStoreError::RateLimited(_)) => {
+                    self.stats.write().await.rate_limited += 1;
+                    return result;
+                }
+                result => return result,
+            }
+            attempt += 1;
+            self.throttle(reference).await;
+        }
+    }
+
+    /// Wait until the rate limit of the reference's registry lets a pull from it start.
+    async fn throttle(&self, reference: &Reference) {
+        let waited = self.registry_limits.acquire(reference.registry()).await;
+        if waited > Duration::from_millis(0) {
+            self.stats.write().await.throttled_ms += waited.as_millis() as u64;
+        }
+    }
+
+    async fn pull_and_add(&mut self, reference: &Reference) -> Result<(), ModuleStoreError> {
         let pull_path = self.pull_path(reference);
         tokio::fs::create_dir_all(&pull_path)
             .await
-            .or(Err(ModuleStoreError::CannotPullModule))?;
+            .map_err(|e| ModuleStoreError::CannotWriteStore(e.to_string()))?;
 
-        pull_wasm(&reference, self.pull_file_path(&reference)).await?;
+        let started = Instant::now();
+        pull_wasm(
+            &reference,
+            self.pull_file_path(&reference),
+            self.credentials.as_deref(),
+        )
+        .await?;
+        let downloaded = Instant::now();
+        if let Err(e) = check_module(&self.pull_file_path(&reference)).await {
+            tokio::fs::remove_file(self.pull_file_path(&reference))
+                .await
+                .unwrap_or(());
+            if let ModuleStoreError::InvalidModule(reason) = &e {
+                tracing::warn!("rejecting invalid module {}: {}", reference.whole(), reason);
+                self.stats.write().await.invalid_modules += 1;
+            }
+            return Err(e);
+        }
+        let verified = Instant::now();
+        let bytes = tokio::fs::metadata(self.pull_file_path(&reference))
+            .await
+            .or(Err(ModuleStoreError::CannotFetchModuleMetadata))?
+            .len();
+        let (file, digest, blob_hit) = self.store(self.pull_file_path(&reference)).await?;
+        self.stats.write().await.record_download(
+            bytes,
+            blob_hit,
+            downloaded - started,
+            verified - downloaded,
+            verified.elapsed(),
+        );
+        self.add_stored(reference, &file, &digest).await
+    }
 
-        let attrs = tokio::fs::metadata(self.pull_file_path(&reference))
+    /// Add the metadata of a module that was stored with the given digest.
+    async fn add_stored(
+        &mut self,
+        reference: &Reference,
+        file: &Path,
+        digest: &str,
+    ) -> Result<(), ModuleStoreError> {
+        let attrs = tokio::fs::metadata(file)
             .await
             .or(Err(ModuleStoreError::CannotFetchModuleMetadata))?;
         // TODO(bacongobbler): fetch image information from the module
         let m = Module {
             id: reference.whole().to_owned(),
-            repo_digests: vec![],
+            repo_digests: vec![format!(
+                "{}/{}@{}",
+                reference.registry(),
+                reference.repository(),
+                digest
+            )],
             repo_tags: vec![],
             size: attrs.len(),
             uid: None,
             username: "".to_owned(),
         };
-        self.add(m).await;
-        Ok(())
+        self.add(m).await
     }
 
     pub(crate) fn root_dir(&self) -> &PathBuf {
         &self.root_dir
     }
 
+    /// Put a freshly pulled module file into its final form: compress it if the store keeps modules compressed,
+    /// and replace it with a hard link to the blob with the same content, or make it that blob if there is none
+    /// yet. Returns the path of the stored file, the digest of the module, and whether the blob already existed.
+    async fn store(&self, file: PathBuf) -> Result<(PathBuf, String, bool), ModuleStoreError> {
+        let blobs = self.root_dir.join(BLOBS_DIR);
+        let compress = self.compress;
+        tokio::task::spawn_blocking(move || {
+            let hex = sha256(&file)?;
+            let (file, blob) = if compress {
+                let compressed = compressed_path(&file);
+                let mut writer = std::fs::File::create(&compressed)?;
+                zstd::stream::copy_encode(
+                    std::fs::File::open(&file)?,
+                    &mut writer,
+                    COMPRESSION_LEVEL,
+                )?;
+                writer.sync_all()?;
+                std::fs::remove_file(&file)?;
+                (
+                    compressed,
+                    blobs.join(format!("{}.{}", hex, COMPRESSED_EXTENSION)),
+                )
+            } else {
+                // drop a compressed copy from before compression was turned off
+                std::fs::remove_file(compressed_path(&file)).unwrap_or(());
+                (file, blobs.join(&hex))
+            };
+
+            std::fs::create_dir_all(&blobs)?;
+            let blob_hit = blob.exists();
+            if blob_hit {
+                // link next to the module file first, so it's never missing
+                let link = file.with_extension(format!("{}.link", Uuid::new_v4()));
+                std::fs::hard_link(&blob, &link)?;
+                std::fs::rename(&link, &file)?;
+            } else {
+                std::fs::hard_link(&file, &blob)?;
+            }
+            Ok((file, format!("sha256:{}", hex), blob_hit))
+        })
+        .await
+        .unwrap()
+        .map_err(|e: std::io::Error| ModuleStoreError::CannotWriteStore(e.to_string()))
+    }
+
+    /// Read the module for the given reference, decompressing it if it is stored compressed.
+    pub async fn read(&self, r: &Reference) -> std::io::Result<Vec<u8>> {
+        let file = self.pull_file_path(r);
+        let compressed = compressed_path(&file);
+        tokio::task::spawn_blocking(move || match std::fs::File::open(&compressed) {
+            Ok(f) => zstd::stream::decode_all(f),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::read(&file),
+            Err(e) => Err(e),
+        })
+        .await
+        .unwrap()
+    }
+
+    /// The bytes taken by the store, as last measured on disk if the store is measured, see `watch_usage`, or
+    /// else as recorded in the metadata of its modules.
     pub(crate) async fn used_bytes(&self) -> u64 {
-        let modules = self.modules.read().await;
-        modules.iter().map(|i| i.size).sum()
+        match self.usage().await {
+            Some(usage) => usage.bytes,
+            None => self.indexed_bytes().await,
+        }
+    }
+
+    /// The bytes taken by the modules according to their metadata. Modules with the same content share a blob, so
+    /// it is only counted once.
+    async fn indexed_bytes(&self) -> u64 {
+        let modules = self.list().await;
+        let mut seen = HashSet::new();
+        modules
+            .iter()
+            .filter(|m| content_digest(m).map_or(true, |d| seen.insert(d)))
+            .map(|m| m.size)
+            .sum()
     }
 
     pub(crate) async fn used_inodes(&self) -> u64 {
-        self.modules.read().await.len() as u64
+        match self.usage().await {
+            Some(usage) => usage.inodes,
+            None => self.list().await.len() as u64,
+        }
     }
 
     pub(crate) fn pull_path(&self, r: &Reference) -> PathBuf {
-        self.root_dir
+        // a digest pins the content, so it wins over the tag when both are given
+        let version = r
+            .digest()
+            .or_else(|| r.tag())
+            .expect("a reference without a digest has a tag");
+        // modules not pulled from an OCI registry are kept apart from those of a registry on the same host
+        let root_dir = match r.source() {
+            Source::Registry => self.root_dir.clone(),
+            Source::Url(_) => self.root_dir.join(URL_MODULES_DIR),
+            Source::Wapm => self.root_dir.join(WAPM_MODULES_DIR),
+        };
+        root_dir
             .join(r.registry())
             .join(r.repository())
-            .join(r.tag())
+            .join(version)
     }
 
-    pub(crate) fn pull_file_path(&self, r: &Reference) -> PathBuf {
+    /// The path of the module file for the given reference.
+    pub fn pull_file_path(&self, r: &Reference) -> PathBuf {
         self.pull_path(r).join("module.wasm")
     }
 }
 
-async fn pull_wasm(reference: &Reference, fp: PathBuf) -> Result<(), ModuleStoreError> {
-    let filepath = fp.to_str().ok_or(ModuleStoreError::InvalidPullPath)?;
-    println!("pulling {} into {}", reference.whole(), filepath);
-    let c_ref = CString::new(reference.whole()).or(Err(ModuleStoreError::InvalidReference))?;
-    let c_file = CString::new(filepath).or(Err(ModuleStoreError::InvalidPullPath))?;
+async fn pull_wasm(
+    reference: &Reference,
+    fp: PathBuf,
+    credentials: Option<&Path>,
+) -> Result<(), ModuleStoreError> {
+    // the module is downloaded next to its final path and only moved into place once the download completed
+    let partial = fp.with_extension(format!("{}.partial", Uuid::new_v4()));
+    let partial_path = partial.to_str().ok_or(ModuleStoreError::InvalidPullPath)?;
+    tracing::info!(reference = %reference.whole(), path = %fp.display(), "pulling");
+    let c_file = CString::new(partial_path).or(Err(ModuleStoreError::InvalidPullPath))?;
+    let c_str = |s: &str| CString::new(s).or(Err(ModuleStoreError::InvalidReference));
+    // the arguments of the Go function pulling from the reference's source, besides the file
+    let c_args = match reference.source() {
+        Source::Registry => vec![
+            c_str(reference.whole())?,
+            // an empty path makes the library read docker's default config file
+            c_str(
+                credentials
+                    .map(|path| path.to_str().ok_or(ModuleStoreError::InvalidPullPath))
+                    .transpose()?
+                    .unwrap_or_default(),
+            )?,
+        ],
+        Source::Url(url) => vec![c_str(url)?, c_str(reference.digest().unwrap_or_default())?],
+        Source::Wapm => vec![
+            c_str(reference.repository())?,
+            c_str(reference.tag().unwrap_or_default())?,
+        ],
+    };
+    let source = reference.source().clone();
 
+    // The Go library cannot be interrupted, so a pull that is given up on, e.g. because it ran into its deadline,
+    // keeps downloading in the background. The flag makes it throw the download away once it is done.
+    let abandoned = AbandonOnDrop::default();
+    let flag = abandoned.0.clone();
     let result = tokio::task::spawn_blocking(move || {
-        let go_str_ref = GoString {
-            p: c_ref.as_ptr(),
-            n: c_ref.as_bytes().len() as isize,
-        };
-        let go_str_file = GoString {
-            p: c_file.as_ptr(),
-            n: c_file.as_bytes().len() as isize,
+        let file = go_string(&c_file);
+        let result = unsafe {
+            match source {
+                Source::Registry => Pull(go_string(&c_args[0]), file, go_string(&c_args[1])),
+                Source::Url(_) => Fetch(go_string(&c_args[0]), file, go_string(&c_args[1])),
+                Source::Wapm => PullWapm(go_string(&c_args[0]), go_string(&c_args[1]), file),
+            }
         };
-        unsafe { Pull(go_str_ref, go_str_file) }
+        if result != 0 || flag.load(Ordering::SeqCst) {
+            std::fs::remove_file(&partial).unwrap_or(());
+            return Ok(result);
+        }
+        std::fs::rename(&partial, &fp).map(|()| result)
     })
     .await
-    .unwrap();
+    .unwrap()
+    .map_err(|e| ModuleStoreError::CannotWriteStore(e.to_string()))?;
+    // see libwasm2oci for the meaning of the results
     match result {
         0 => Ok(()),
+        2 => Err(ModuleStoreError::RegistryUnavailable),
+        3 => Err(ModuleStoreError::DigestMismatch),
+        4 => Err(ModuleStoreError::NotWasm(
+            "the image has no WebAssembly layer".to_owned(),
+        )),
+        5 => Err(ModuleStoreError::RateLimited(None)),
+        seconds if seconds >= RETRY_AFTER_RESULT => Err(ModuleStoreError::RateLimited(Some(
+            Duration::from_secs((seconds - RETRY_AFTER_RESULT) as u64),
+        ))),
         _ => Err(ModuleStoreError::CannotPullModule),
     }
 }
 
+/// Fail unless the file is a valid WebAssembly module, so a corrupt module is rejected when it is added to the
+/// store rather than when a container instantiates it. Components are told apart before validating, as the parser
+/// only knows core modules, see `BinaryFormat`.
+async fn check_module(file: &Path) -> Result<(), ModuleStoreError> {
+    check_magic(file).await?;
+    let file = file.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let bytes = std::fs::read(&file).or(Err(ModuleStoreError::CannotFetchModuleMetadata))?;
+        if BinaryFormat::detect(&bytes) == BinaryFormat::Component {
+            return Err(ModuleStoreError::InvalidModule(
+                "it is a WebAssembly component, wok only runs core modules, using WASI preview1"
+                    .to_owned(),
+            ));
+        }
+        validate(&bytes)
+    })
+    .await
+    .unwrap()
+}
+
+/// Validate the module with every wasm feature enabled: the features a container may use are only known once it
+/// is created, and modules using features the container doesn't enable are rejected then.
+fn validate(bytes: &[u8]) -> Result<(), ModuleStoreError> {
+    let config = wasmparser::ValidatingParserConfig {
+        operator_config: wasmparser::OperatorValidatorConfig {
+            enable_threads: true,
+            enable_reference_types: true,
+            enable_simd: true,
+            enable_bulk_memory: true,
+            enable_multi_value: true,
+        },
+    };
+    wasmparser::validate(bytes, Some(config)).map_err(|e| {
+        ModuleStoreError::InvalidModule(format!("{} at offset {}", e.message, e.offset))
+    })
+}
+
+/// Fail unless the file starts with the magic bytes of a WebAssembly binary.
+async fn check_magic(file: &Path) -> Result<(), ModuleStoreError> {
+    let mut magic = [0; 4];
+    let mut f = tokio::fs::File::open(file)
+        .await
+        .or(Err(ModuleStoreError::CannotFetchModuleMetadata))?;
+    match f.read_exact(&mut magic).await {
+        Ok(_) if magic == WASM_MAGIC => Ok(()),
+        _ => Err(ModuleStoreError::NotWasm(
+            "the file does not start with the WebAssembly magic bytes".to_owned(),
+        )),
+    }
+}
+
+/// The path of the compressed version of the module file, e.g. `module.wasm.zst`.
+fn compressed_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(COMPRESSED_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Delete the files, and then their parent directories below the root as long as they are empty. Returns the bytes
+/// freed, i.e. the sizes of the deleted files that had no other hard link.
+fn delete_files(root_dir: &Path, files: &[PathBuf]) -> std::io::Result<u64> {
+    let mut reclaimed = 0;
+    for file in files {
+        let metadata = match std::fs::symlink_metadata(file) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        std::fs::remove_file(file)?;
+        if links(&metadata) <= 1 {
+            reclaimed += metadata.len();
+        }
+        let mut dir = file.parent();
+        while let Some(d) = dir.filter(|d| d.starts_with(root_dir) && *d != root_dir) {
+            // fails once the directory is not empty
+            if std::fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+    Ok(reclaimed)
+}
+
+#[cfg(unix)]
+fn links(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn links(_metadata: &std::fs::Metadata) -> u64 {
+    1
+}
+
+/// The hex encoded sha256 digest of the file's content.
+fn sha256(file: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(file)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// The digest of the module's content, taken from its first repo digest, e.g. `sha256:6c3c...` for
+/// `example.com/app@sha256:6c3c...`.
+fn content_digest(module: &Module) -> Option<&str> {
+    module
+        .repo_digests
+        .first()
+        .and_then(|d| d.rfind('@').map(|at| &d[at + 1..]))
+}
+
+/// Borrow the string for a call into Go. It must outlive the call.
+fn go_string(s: &CString) -> GoString {
+    GoString {
+        p: s.as_ptr(),
+        n: s.as_bytes().len() as isize,
+    }
+}
+
+/// Flags a pull as abandoned when the future waiting for it is dropped.
+#[derive(Default)]
+struct AbandonOnDrop(Arc<AtomicBool>);
+
+impl Drop for AbandonOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The wait before the given retry, starting at 0: the initial backoff doubles with every retry, up to the maximum.
+fn backoff(options: &PullOptions, attempt: u32) -> Duration {
+    let backoff = options
+        .initial_backoff_ms
+        .checked_mul(2u64.saturating_pow(attempt))
+        .unwrap_or(std::u64::MAX)
+        .min(options.max_backoff_ms);
+    Duration::from_millis(backoff)
+}
+
+/// Wait somewhere between half and all of the backoff, so that pulls failing together don't retry in lockstep.
+fn jitter(backoff: Duration) -> Duration {
+    let millis = backoff.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1))
+}
+
 #[tokio::test]
 async fn test_pull_wasm() {
-    use std::convert::TryFrom;
-
     // this is a public registry, so this test is both making sure the library is working,
     // as well as ensuring the registry is publicly accessible
     let module = "webassembly.azurecr.io/hello-wasm:v1".to_owned();
     let r = Reference::try_from(module).expect("Failed to parse reference");
-    pull_wasm(&r, PathBuf::from("target/pulled.wasm"))
+    pull_wasm(&r, PathBuf::from("target/pulled.wasm"), None)
         .await
         .unwrap();
 }
 
+#[test]
+fn test_backoff() {
+    let options = PullOptions {
+        retries: 10,
+        initial_backoff_ms: 100,
+        max_backoff_ms: 1000,
+        ..PullOptions::default()
+    };
+    assert_eq!(Duration::from_millis(100), backoff(&options, 0));
+    assert_eq!(Duration::from_millis(200), backoff(&options, 1));
+    assert_eq!(Duration::from_millis(800), backoff(&options, 3));
+    assert_eq!(Duration::from_millis(1000), backoff(&options, 4));
+    assert_eq!(Duration::from_millis(1000), backoff(&options, 100));
+
+    for _ in 0..100 {
+        let wait = jitter(Duration::from_millis(100));
+        assert!(wait >= Duration::from_millis(50) && wait <= Duration::from_millis(100));
+    }
+}
+
+#[tokio::test]
+async fn test_pull_path() {
+    let s = ModuleStore::new(PathBuf::from("/modules")).await;
+    let r = Reference::try_from("localhost:5000/org/app:v1".to_owned()).unwrap();
+    assert_eq!(
+        PathBuf::from("/modules/localhost:5000/org/app/v1"),
+        s.pull_path(&r)
+    );
+
+    let digest = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+    let r = Reference::try_from(format!("app:v1@{}", digest)).unwrap();
+    assert_eq!(
+        PathBuf::from(format!("/modules/docker.io/library/app/{}", digest)),
+        s.pull_path(&r)
+    );
+
+    let r = Reference::try_from("https://localhost:5000/org/app.wasm".to_owned()).unwrap();
+    assert_eq!(
+        PathBuf::from("/modules/https/localhost:5000/org/app/latest"),
+        s.pull_path(&r)
+    );
+
+    let r = Reference::try_from("wapm://_/cowsay@0.2.0".to_owned()).unwrap();
+    assert_eq!(
+        PathBuf::from("/modules/wapm/registry.wapm.io/_/cowsay/0.2.0"),
+        s.pull_path(&r)
+    );
+}
+
 #[tokio::test]
 async fn test_module_store_used_bytes() {
     let mut s = ModuleStore {
         root_dir: PathBuf::from("/"),
-        modules: Arc::new(RwLock::new(vec![])),
+        ..ModuleStore::default()
     };
     assert_eq!(0, s.used_bytes().await);
 
@@ -187,7 +977,7 @@ async fn test_module_store_used_bytes() {
         uid: None,
         username: "".to_owned(),
     };
-    s.add(m).await;
+    s.add(m).await.expect("added module");
     assert_eq!(1, s.used_bytes().await);
 
     let m2 = Module {
@@ -198,11 +988,188 @@ async fn test_module_store_used_bytes() {
         uid: None,
         username: "".to_owned(),
     };
-    s.add(m2).await;
+    s.add(m2).await.expect("added module");
     assert_eq!(3, s.used_bytes().await);
 
-    s.remove("1".to_owned())
+    let (removed, reclaimed) = s
+        .remove("1".to_owned())
         .await
         .expect("could not remove module");
+    assert_eq!("1", removed.id);
+    assert_eq!(0, reclaimed);
     assert_eq!(2, s.used_bytes().await);
+
+    // retagged modules share their blob
+    let digest = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+    for (id, tag) in &[("3", "v1"), ("4", "v2")] {
+        s.add(Module {
+            id: id.to_string(),
+            repo_digests: vec![format!("example.com/app@{}", digest)],
+            repo_tags: vec![format!("example.com/app:{}", tag)],
+            size: 4,
+            uid: None,
+            username: "".to_owned(),
+        })
+        .await
+        .expect("added module");
+    }
+    assert_eq!(6, s.used_bytes().await);
+}
+
+#[tokio::test]
+async fn test_remove_deletes_files() {
+    let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+    let mut s = ModuleStore::new(dir.path().to_owned()).await;
+    let digest = "sha256:6c3c624b58dbbcd3c0dd82b4c53f04194d1247c6eebdaab7c610cf7d66709b3b";
+    let blob = dir.path().join(BLOBS_DIR).join(&digest[7..]);
+    std::fs::create_dir_all(blob.parent().unwrap()).unwrap();
+    std::fs::write(&blob, b"\0asm\x01\0\0\0").unwrap();
+    for tag in &["v1", "v2"] {
+        let reference = Reference::try_from(format!("example.com/app:{}", tag)).unwrap();
+        std::fs::create_dir_all(s.pull_path(&reference)).unwrap();
+        std::fs::hard_link(&blob, s.pull_file_path(&reference)).unwrap();
+        s.add(Module {
+            id: reference.whole().to_owned(),
+            repo_digests: vec![format!("example.com/app@{}", digest)],
+            size: 8,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    }
+
+    // the blob is still used by v2
+    let (_, reclaimed) = s.remove("example.com/app:v1".to_owned()).await.unwrap();
+    assert_eq!(0, reclaimed);
+    assert!(!dir.path().join("example.com/app/v1").exists());
+    assert!(blob.exists());
+
+    let (removed, reclaimed) = s.remove("example.com/app:v2".to_owned()).await.unwrap();
+    assert_eq!("example.com/app:v2", removed.id);
+    assert_eq!(8, reclaimed);
+    assert!(!blob.exists());
+    // the directories left empty are gone, but not the root
+    assert!(!dir.path().join("example.com").exists());
+    assert!(dir.path().exists());
+    match s.remove("example.com/app:v2".to_owned()).await {
+        Err(ModuleStoreError::NotFound) => {}
+        r => panic!("expected NotFound, got {:?}", r),
+    }
+}
+
+#[tokio::test]
+async fn test_prune() {
+    let mut s = ModuleStore::default();
+    for id in &["example.com/app:v1", "example.com/app:v2"] {
+        s.add(Module {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    }
+    let in_use = vec!["example.com/app:v2".to_owned()].into_iter().collect();
+    let pruned = s.prune(&in_use).await.unwrap();
+    assert_eq!(vec!["example.com/app:v1"], pruned.removed);
+    let ids: Vec<_> = s.list().await.into_iter().map(|m| m.id).collect();
+    assert_eq!(vec!["example.com/app:v2"], ids);
+}
+
+#[tokio::test]
+async fn test_pull_joins_pull_in_progress() {
+    let mut s = ModuleStore::default();
+    let reference = Reference::try_from("example.com/app:v1".to_owned()).unwrap();
+    // a pull in progress that fails without going to the registry
+    let pull = futures::future::ready(Err(ModuleStoreError::NotWasm("test".to_owned())))
+        .boxed()
+        .shared();
+    s.in_flight
+        .0
+        .lock()
+        .await
+        .insert(reference.whole().to_owned(), pull);
+    match s.pull(&reference).await {
+        Err(ModuleStoreError::NotWasm(reason)) => assert_eq!("test", reason),
+        result => panic!(
+            "expected the result of the pull in progress, got {:?}",
+            result
+        ),
+    }
+    assert_eq!(0, s.pull_stats().await.pulls);
+}
+
+#[tokio::test]
+async fn test_check_magic() {
+    let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+    let file = dir.path().join("module.wasm");
+    std::fs::write(&file, b"\0asm\x01\0\0\0").unwrap();
+    check_magic(&file).await.expect("a module is accepted");
+
+    // e.g. the gzipped tarball of a container image's layer
+    std::fs::write(&file, b"\x1f\x8b\x08\0").unwrap();
+    match check_magic(&file).await {
+        Err(ModuleStoreError::NotWasm(_)) => {}
+        r => panic!("expected NotWasm, got {:?}", r),
+    }
+    std::fs::write(&file, b"\0a").unwrap();
+    assert!(check_magic(&file).await.is_err());
+}
+
+#[tokio::test]
+async fn test_check_module() {
+    let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+    let file = dir.path().join("module.wasm");
+    std::fs::write(&file, b"\0asm\x01\0\0\0").unwrap();
+    check_module(&file).await.expect("a module is accepted");
+
+    // a type section claiming more bytes than there are, e.g. a truncated download
+    std::fs::write(&file, b"\0asm\x01\0\0\0\x01\x05\x01").unwrap();
+    match check_module(&file).await {
+        Err(ModuleStoreError::InvalidModule(_)) => {}
+        r => panic!("expected InvalidModule, got {:?}", r),
+    }
+    std::fs::write(&file, b"\0asm\x0d\0\x01\0").unwrap();
+    match check_module(&file).await {
+        Err(ModuleStoreError::InvalidModule(reason)) => assert!(reason.contains("component")),
+        r => panic!("expected InvalidModule, got {:?}", r),
+    }
+    std::fs::write(&file, b"\x1f\x8b\x08\0").unwrap();
+    match check_module(&file).await {
+        Err(ModuleStoreError::NotWasm(_)) => {}
+        r => panic!("expected NotWasm, got {:?}", r),
+    }
+}
+
+#[tokio::test]
+async fn test_store() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+    let s = ModuleStore::new(dir.path().to_owned()).await;
+    let (a, b) = (dir.path().join("a.wasm"), dir.path().join("b.wasm"));
+    std::fs::write(&a, b"\0asm").unwrap();
+    std::fs::write(&b, b"\0asm").unwrap();
+
+    let (_, digest, blob_hit) = s.store(a).await.expect("stored a");
+    assert!(!blob_hit);
+    let (_, b_digest, blob_hit) = s.store(b).await.expect("stored b");
+    assert_eq!(digest, b_digest);
+    assert!(blob_hit);
+
+    let blob = dir.path().join(BLOBS_DIR).join(&digest["sha256:".len()..]);
+    assert_eq!(b"\0asm".to_vec(), std::fs::read(&blob).unwrap());
+    // both module files are links to the blob
+    assert_eq!(3, std::fs::metadata(&blob).unwrap().nlink());
+
+    // compressed modules are decompressed when they are read
+    let s = s.with_compression(true);
+    let r = Reference::try_from("example.com/app:v1".to_owned()).unwrap();
+    let file = s.pull_file_path(&r);
+    std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+    std::fs::write(&file, b"\0asm").unwrap();
+    let (stored, compressed_digest, _) = s.store(file.clone()).await.expect("stored compressed");
+    assert_eq!(digest, compressed_digest);
+    assert_eq!(compressed_path(&file), stored);
+    assert!(!file.exists());
+    assert_eq!(b"\0asm".to_vec(), s.read(&r).await.expect("read module"));
 }
@@ -0,0 +1,206 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{index, ModuleStore, ModuleStoreError};
+
+/// The directory below the root holding a store per namespace.
+pub(super) const NAMESPACES_DIR: &str = "namespaces";
+
+impl ModuleStore {
+    /// Keep the modules of each Kubernetes namespace in a store of their own, below `namespaces/<namespace>`, so
+    /// tenants sharing a node neither see nor run each other's modules.
+    pub fn with_namespaces(mut self, namespaced: bool) -> Self {
+        self.namespaces = if namespaced {
+            Some(Arc::default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Whether the store is partitioned by namespace.
+    pub fn is_namespaced(&self) -> bool {
+        self.namespaces.is_some()
+    }
+
+    /// The store holding the modules of the given namespace. That is the store itself if it is not partitioned,
+    /// or for pods without a namespace.
+    ///
+    /// A namespace's store keeps its own metadata and blobs, so a module is pulled once per namespace using it.
+    /// The pull statistics are kept for the whole node.
+    ///
+    /// Either way, the returned store pulls with the registry credentials configured for the namespace. Only a
+    /// partitioned store keeps a namespace from using the modules another namespace pulled with its credentials.
+    pub async fn namespace(&self, namespace: &str) -> Result<ModuleStore, ModuleStoreError> {
+        let namespaces = match &self.namespaces {
+            Some(namespaces) if !namespace.is_empty() => namespaces,
+            _ => {
+                let mut store = self.clone();
+                store.credentials = self.pull_options.credentials(namespace).map(Path::to_owned);
+                return Ok(store);
+            }
+        };
+        if let Some(store) = namespaces.read().await.get(namespace) {
+            return Ok(store.clone());
+        }
+        check_namespace(namespace)?;
+
+        let mut namespaces = namespaces.write().await;
+        // another request may have opened it in the meantime
+        if let Some(store) = namespaces.get(namespace) {
+            return Ok(store.clone());
+        }
+        let root_dir = self.root_dir.join(NAMESPACES_DIR).join(namespace);
+        tokio::fs::create_dir_all(&root_dir)
+            .await
+            .map_err(|e| ModuleStoreError::CannotWriteStore(e.to_string()))?;
+        let store = ModuleStore {
+            modules: Arc::from(index::open(self.metadata, &root_dir)?),
+            root_dir,
+            pulls: Arc::default(),
+            in_flight: Default::default(),
+            pull_permits: self.pull_permits.clone(),
+            registry_limits: self.registry_limits.clone(),
+            pull_options: self.pull_options.clone(),
+            credentials: self.pull_options.credentials(namespace).map(Path::to_owned),
+            compress: self.compress,
+            stats: self.stats.clone(),
+            usage: Arc::default(),
+            metadata: self.metadata,
+            namespaces: None,
+        };
+        namespaces.insert(namespace.to_owned(), store.clone());
+        Ok(store)
+    }
+
+    /// The stores of all namespaces that have one, by namespace, including the ones not opened since wok
+    /// started.
+    pub async fn namespaces(&self) -> Vec<(String, ModuleStore)> {
+        if !self.is_namespaced() {
+            return vec![];
+        }
+        let mut names = vec![];
+        match tokio::fs::read_dir(self.root_dir.join(NAMESPACES_DIR)).await {
+            Ok(mut entries) => {
+                while let Ok(Some(entry)) = entries.next_entry().await {
+                    if let Ok(name) = entry.file_name().into_string() {
+                        names.push(name);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!("cannot list the namespaces of the store: {}", e),
+        }
+        names.sort();
+
+        let mut stores = vec![];
+        for name in names {
+            match self.namespace(&name).await {
+                Ok(store) => stores.push((name, store)),
+                Err(e) => tracing::warn!("cannot open the store of namespace {}: {}", name, e),
+            }
+        }
+        stores
+    }
+}
+
+/// Check that the namespace is a DNS label, as Kubernetes requires, so it can't escape the namespaces directory.
+fn check_namespace(namespace: &str) -> Result<(), ModuleStoreError> {
+    let valid = namespace.len() <= 63
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !namespace.starts_with('-')
+        && !namespace.ends_with('-');
+    if valid {
+        Ok(())
+    } else {
+        Err(ModuleStoreError::InvalidNamespace(namespace.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_namespace() {
+        check_namespace("default").expect("valid namespace");
+        check_namespace("team-1").expect("valid namespace");
+        check_namespace("..").expect_err("not a DNS label");
+        check_namespace("a/b").expect_err("not a DNS label");
+        check_namespace("Team").expect_err("not lowercase");
+        check_namespace("-team").expect_err("leading dash");
+    }
+
+    #[tokio::test]
+    async fn test_namespace() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let store = ModuleStore::new(dir.path().to_owned()).await;
+        // without namespaces, every namespace shares the store
+        assert_eq!(
+            store.root_dir(),
+            store.namespace("team").await.unwrap().root_dir()
+        );
+
+        let store = store.with_namespaces(true);
+        let mut team = store.namespace("team").await.unwrap();
+        assert_eq!(
+            &dir.path().join(NAMESPACES_DIR).join("team"),
+            team.root_dir()
+        );
+        assert_eq!(
+            store.root_dir(),
+            store.namespace("").await.unwrap().root_dir()
+        );
+        store.namespace("..").await.expect_err("invalid namespace");
+
+        team.add(crate::server::Module {
+            id: "example.com/app:v1".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        // the modules of a namespace are only listed in its store, which is shared by its handles
+        assert!(store.list().await.is_empty());
+        assert_eq!(1, store.namespace("team").await.unwrap().list().await.len());
+        assert!(store
+            .namespace("other")
+            .await
+            .unwrap()
+            .list()
+            .await
+            .is_empty());
+
+        let names: Vec<_> = store
+            .namespaces()
+            .await
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(vec!["other", "team"], names);
+    }
+
+    #[tokio::test]
+    async fn test_namespace_credentials() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let mut options = crate::config::PullOptions::default();
+        options
+            .credentials
+            .insert("team".to_owned(), "/etc/wok/team.json".into());
+        let store = ModuleStore::with_options(dir.path().to_owned(), options).await;
+        let credentials = |store: ModuleStore| store.credentials;
+
+        assert_eq!(
+            Some("/etc/wok/team.json".into()),
+            credentials(store.namespace("team").await.unwrap())
+        );
+        assert_eq!(None, credentials(store.namespace("other").await.unwrap()));
+        let store = store.with_namespaces(true);
+        assert_eq!(
+            Some("/etc/wok/team.json".into()),
+            credentials(store.namespace("team").await.unwrap())
+        );
+        assert_eq!(None, credentials(store.namespace("other").await.unwrap()));
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{PullOptions, RegistryRateLimit};
+
+/// RegistryLimits spaces out the pulls from each registry, so a node pulling many modules at once stays below the
+/// rate the registry throttles at, e.g. Docker Hub's. It is shared by the stores of all namespaces.
+///
+/// Each registry with a rate limit has a token bucket: it holds up to `burst` pulls and refills at `per_second`
+/// pulls per second. A registry that answered with 429 Too Many Requests is paused for as long as it asked, whether
+/// it has a rate limit or not.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RegistryLimits {
+    /// the limits by registry
+    limits: Arc<BTreeMap<String, RegistryRateLimit>>,
+    /// the budgets by registry
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RegistryLimits {
+    pub(crate) fn new(options: &PullOptions) -> Self {
+        RegistryLimits {
+            limits: Arc::new(options.rate_limits.clone()),
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Wait until a pull from the registry may start. Returns how long that took.
+    pub(crate) async fn acquire(&self, registry: &str) -> Duration {
+        let started = Instant::now();
+        while let Some(wait) = self.take(registry, Instant::now()) {
+            tracing::debug!(
+                registry = registry,
+                "waiting {:?} for the registry's rate limit",
+                wait
+            );
+            tokio::time::delay_for(wait).await;
+        }
+        started.elapsed()
+    }
+
+    /// Hold back the pulls from the registry for the given time, e.g. because it answered with 429.
+    pub(crate) fn pause(&self, registry: &str, duration: Duration) {
+        let now = Instant::now();
+        let until = now + duration;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.bucket(&mut buckets, registry, now);
+        if bucket.paused_until.map_or(true, |paused| paused < until) {
+            bucket.paused_until = Some(until);
+        }
+    }
+
+    /// Take a pull from the registry's budget, or tell how long to wait before trying again.
+    fn take(&self, registry: &str, now: Instant) -> Option<Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let limit = self.limits.get(registry);
+        self.bucket(&mut buckets, registry, now).take(limit, now)
+    }
+
+    fn bucket<'a>(
+        &self,
+        buckets: &'a mut HashMap<String, Bucket>,
+        registry: &str,
+        now: Instant,
+    ) -> &'a mut Bucket {
+        let burst = self.limits.get(registry).map_or(0, |l| l.burst);
+        buckets
+            .entry(registry.to_owned())
+            .or_insert_with(|| Bucket {
+                tokens: f64::from(burst),
+                updated: now,
+                paused_until: None,
+            })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// no pull may start before then
+    paused_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: Option<&RegistryRateLimit>, now: Instant) -> Option<Duration> {
+        if let Some(paused_until) = self.paused_until {
+            if paused_until > now {
+                return Some(paused_until - now);
+            }
+            self.paused_until = None;
+        }
+        let limit = limit?;
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * limit.per_second).min(f64::from(limit.burst));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let mut options = PullOptions::default();
+        options.rate_limits.insert(
+            "docker.io".to_owned(),
+            RegistryRateLimit {
+                per_second: 1.0,
+                burst: 2,
+            },
+        );
+        let limits = RegistryLimits::new(&options);
+        let now = Instant::now();
+        assert_eq!(None, limits.take("docker.io", now));
+        assert_eq!(None, limits.take("docker.io", now));
+        assert_eq!(Some(Duration::from_secs(1)), limits.take("docker.io", now));
+        // the budget refills over time
+        assert_eq!(None, limits.take("docker.io", now + Duration::from_secs(1)));
+
+        // registries without a limit are always allowed
+        for _ in 0..10 {
+            assert_eq!(None, limits.take("example.com", now));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pause() {
+        let limits = RegistryLimits::new(&PullOptions::default());
+        limits.pause("example.com", Duration::from_millis(50));
+        // a shorter pause does not cut the longer one short
+        limits.pause("example.com", Duration::from_millis(10));
+        assert_eq!(None, limits.take("other.example.com", Instant::now()));
+        assert!(limits.acquire("example.com").await >= Duration::from_millis(50));
+        assert_eq!(None, limits.take("example.com", Instant::now()));
+    }
+}
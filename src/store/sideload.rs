@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use super::{check_module, ModuleStore, ModuleStoreError, SIDELOAD_DIR};
+use crate::docker::Reference;
+
+/// The modules found in the sideload directory, with the modification time they were added with.
+type Sideloaded = HashMap<PathBuf, SystemTime>;
+
+impl ModuleStore {
+    /// Add the modules dropped into the sideload directory every `interval`, e.g. by an operator or a DaemonSet
+    /// seeding an air-gapped node.
+    ///
+    /// A module is named after its path below the directory: `example.com/app/v1.wasm` is added as
+    /// `example.com/app:v1`, just as if it had been pulled. Changed files are added again, removed ones are left
+    /// in the store.
+    pub async fn watch_sideload(mut self, interval: Duration) {
+        let mut sideloaded = Sideloaded::new();
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            self.sideload(&mut sideloaded).await;
+        }
+    }
+
+    /// Add the modules of the sideload directory that are new or changed since they were added to `sideloaded`.
+    /// Returns the number of modules added.
+    pub(crate) async fn sideload(&mut self, sideloaded: &mut Sideloaded) -> usize {
+        let dir = self.root_dir.join(SIDELOAD_DIR);
+        let files = match tokio::task::spawn_blocking(move || wasm_files(&dir))
+            .await
+            .unwrap()
+        {
+            Ok(files) => files,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("cannot read the sideload directory: {}", e);
+                }
+                return 0;
+            }
+        };
+
+        let mut added = 0;
+        for (file, modified) in files {
+            if sideloaded.get(&file) == Some(&modified) {
+                continue;
+            }
+            // a file that can't be added is only retried once it changes
+            sideloaded.insert(file.clone(), modified);
+            let reference = match self.sideload_reference(&file) {
+                Some(reference) => reference,
+                None => {
+                    tracing::warn!("cannot name sideloaded module {}", file.display());
+                    continue;
+                }
+            };
+            match self.add_file(&reference, &file).await {
+                Ok(()) => {
+                    tracing::info!("sideloaded {} from {}", reference.whole(), file.display());
+                    added += 1;
+                }
+                Err(e) => tracing::warn!("cannot sideload {}: {}", file.display(), e),
+            }
+        }
+        added
+    }
+
+    /// The reference of a module in the sideload directory, e.g. `example.com/app:v1` for
+    /// `example.com/app/v1.wasm`.
+    fn sideload_reference(&self, file: &Path) -> Option<Reference> {
+        let relative = file.strip_prefix(self.root_dir.join(SIDELOAD_DIR)).ok()?;
+        let repository = relative.parent()?.to_str()?;
+        let tag = relative.file_stem()?.to_str()?;
+        if repository.is_empty() {
+            return None;
+        }
+        Reference::try_from(format!("{}:{}", repository, tag)).ok()
+    }
+
+    /// Copy a module file into the store, as the module of the given reference.
+    async fn add_file(
+        &mut self,
+        reference: &Reference,
+        file: &Path,
+    ) -> Result<(), ModuleStoreError> {
+        check_module(file).await?;
+        let write_error = |e: std::io::Error| ModuleStoreError::CannotWriteStore(e.to_string());
+        let target = self.pull_file_path(reference);
+        tokio::fs::create_dir_all(self.pull_path(reference))
+            .await
+            .map_err(write_error)?;
+        // copy next to the target first, so a module being read is never half written
+        let partial = target.with_extension(format!("{}.partial", Uuid::new_v4()));
+        tokio::fs::copy(file, &partial).await.map_err(write_error)?;
+        tokio::fs::rename(&partial, &target)
+            .await
+            .map_err(write_error)?;
+        let (stored, digest, _) = self.store(target).await?;
+        self.add_stored(reference, &stored, &digest).await
+    }
+}
+
+/// The `.wasm` files below the directory, with the time they were last modified.
+fn wasm_files(dir: &Path) -> std::io::Result<Vec<(PathBuf, SystemTime)>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            files.extend(wasm_files(&path)?);
+        } else if path.extension().map_or(false, |ext| ext == "wasm") {
+            files.push((path, metadata.modified()?));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sideload() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let mut s = ModuleStore::new(dir.path().to_owned()).await;
+        let mut sideloaded = Sideloaded::new();
+        // there is nothing to sideload until the directory exists
+        assert_eq!(0, s.sideload(&mut sideloaded).await);
+
+        let app = dir.path().join(SIDELOAD_DIR).join("example.com/app");
+        std::fs::create_dir_all(&app).unwrap();
+        std::fs::write(app.join("v1.wasm"), b"\0asm\x01\0\0\0").unwrap();
+        std::fs::write(app.join("broken.wasm"), b"not wasm").unwrap();
+        std::fs::write(app.join("truncated.wasm"), b"\0asm\x01\0\0\0\x01\x05\x01").unwrap();
+        std::fs::write(app.join("README"), b"not a module").unwrap();
+        assert_eq!(1, s.sideload(&mut sideloaded).await);
+
+        let ids: Vec<_> = s.list().await.into_iter().map(|m| m.id).collect();
+        assert_eq!(vec!["example.com/app:v1"], ids);
+        let r = Reference::try_from("example.com/app:v1".to_owned()).unwrap();
+        assert_eq!(
+            b"\0asm\x01\0\0\0".to_vec(),
+            s.read(&r).await.expect("read sideloaded module")
+        );
+
+        // unchanged files are not added again
+        assert_eq!(0, s.sideload(&mut sideloaded).await);
+    }
+}
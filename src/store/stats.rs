@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// PullStats counts what the store's pulls did since wok started.
+///
+/// Durations are summed over all pulls, per phase: downloading the module, validating it, and storing it, i.e.
+/// hashing, compressing and linking it to its blob.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PullStats {
+    /// pulls that finished with the module in the store
+    pub pulls: u64,
+    /// pulls that failed, including the ones that timed out
+    pub failures: u64,
+    /// pulls that did not finish before their deadline
+    pub timeouts: u64,
+    /// attempts repeated because the registry was unavailable or rate limiting
+    pub retries: u64,
+    /// attempts the registry rejected with 429 Too Many Requests
+    pub rate_limited: u64,
+    /// the time pulls waited for the rate limit of their registry
+    pub throttled_ms: u64,
+    /// the size of the downloaded modules, before compression
+    pub bytes_pulled: u64,
+    /// pulled modules whose content was already in the store, e.g. under another tag
+    pub blob_hits: u64,
+    /// pulled modules rejected because they failed validation, e.g. corrupt ones
+    pub invalid_modules: u64,
+    pub download_ms: u64,
+    pub verify_ms: u64,
+    pub store_ms: u64,
+    /// the time spent in pulls, including waiting between retries
+    pub total_ms: u64,
+}
+
+impl PullStats {
+    /// Count a downloaded module, with the time each phase took.
+    pub(crate) fn record_download(
+        &mut self,
+        bytes: u64,
+        blob_hit: bool,
+        download: Duration,
+        verify: Duration,
+        store: Duration,
+    ) {
+        self.bytes_pulled += bytes;
+        if blob_hit {
+            self.blob_hits += 1;
+        }
+        self.download_ms += millis(download);
+        self.verify_ms += millis(verify);
+        self.store_ms += millis(store);
+    }
+
+    /// Count a finished pull.
+    pub(crate) fn record_pull(&mut self, ok: bool, timed_out: bool, elapsed: Duration) {
+        if ok {
+            self.pulls += 1;
+        } else {
+            self.failures += 1;
+        }
+        if timed_out {
+            self.timeouts += 1;
+        }
+        self.total_ms += millis(elapsed);
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pull_stats() {
+        let mut stats = PullStats::default();
+        stats.record_download(
+            10,
+            false,
+            Duration::from_millis(3),
+            Duration::from_millis(1),
+            Duration::from_millis(2),
+        );
+        stats.record_pull(true, false, Duration::from_millis(7));
+        stats.record_download(
+            10,
+            true,
+            Duration::from_millis(3),
+            Duration::from_millis(1),
+            Duration::from_millis(2),
+        );
+        stats.record_pull(true, false, Duration::from_millis(7));
+        stats.record_pull(false, true, Duration::from_millis(5));
+        assert_eq!(
+            PullStats {
+                pulls: 2,
+                failures: 1,
+                timeouts: 1,
+                retries: 0,
+                rate_limited: 0,
+                throttled_ms: 0,
+                bytes_pulled: 20,
+                blob_hits: 1,
+                invalid_modules: 0,
+                download_ms: 6,
+                verify_ms: 2,
+                store_ms: 4,
+                total_ms: 19,
+            },
+            stats
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::{compressed_path, content_digest, ModuleStore, ModuleStoreError};
+use crate::docker::Reference;
+use crate::server::Module;
+
+impl ModuleStore {
+    /// Make the module stored for `source` available under `target` too, e.g. so a sideloaded module can be run by
+    /// the reference a pod spec uses without a registry round trip. Both references share the module's file. A
+    /// module stored for `target` before is replaced.
+    ///
+    /// Returns the module added for `target`.
+    pub async fn tag(
+        &mut self,
+        source: &Reference,
+        target: &Reference,
+    ) -> Result<Module, ModuleStoreError> {
+        let module = self
+            .list()
+            .await
+            .into_iter()
+            .find(|m| m.id == source.whole())
+            .ok_or(ModuleStoreError::NotFound)?;
+        if source.whole() == target.whole() {
+            return Ok(module);
+        }
+        let digest = match content_digest(&module) {
+            Some(digest) => digest.to_owned(),
+            None => {
+                let content = self
+                    .read(source)
+                    .await
+                    .or(Err(ModuleStoreError::CannotFetchModuleMetadata))?;
+                format!("sha256:{:x}", Sha256::digest(&content))
+            }
+        };
+        // a target pinned to a digest must not point to other content
+        if target.digest().map_or(false, |d| d != digest) {
+            return Err(ModuleStoreError::DigestMismatch);
+        }
+
+        let source_file = self.pull_file_path(source);
+        let target_file = self.pull_file_path(target);
+        let target_dir = self.pull_path(target);
+        tokio::task::spawn_blocking(move || {
+            let (from, to) = match std::fs::metadata(compressed_path(&source_file)) {
+                Ok(_) => (compressed_path(&source_file), compressed_path(&target_file)),
+                Err(_) => (source_file, target_file.clone()),
+            };
+            std::fs::create_dir_all(&target_dir)?;
+            // link next to the target first, so a module being read is never missing
+            let link = to.with_extension(format!("{}.link", Uuid::new_v4()));
+            if std::fs::hard_link(&from, &link).is_err() {
+                std::fs::copy(&from, &link)?;
+            }
+            std::fs::rename(&link, &to)?;
+            // drop the other form of a module stored for the target before
+            for stale in &[target_file.clone(), compressed_path(&target_file)] {
+                if *stale != to {
+                    std::fs::remove_file(stale).unwrap_or(());
+                }
+            }
+            Ok(())
+        })
+        .await
+        .unwrap()
+        .map_err(|e: std::io::Error| ModuleStoreError::CannotWriteStore(e.to_string()))?;
+
+        let tagged = Module {
+            id: target.whole().to_owned(),
+            repo_digests: vec![format!(
+                "{}/{}@{}",
+                target.registry(),
+                target.repository(),
+                digest
+            )],
+            repo_tags: vec![],
+            ..module
+        };
+        self.add(tagged.clone()).await?;
+        tracing::info!("tagged {} as {}", source.whole(), target.whole());
+        Ok(tagged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tag() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let mut store = ModuleStore::new(dir.path().to_owned()).await;
+        let content = b"\0asm\x01\0\0\0";
+        let source = Reference::try_from("localhost/app:dev".to_owned()).unwrap();
+        let target = Reference::try_from("example.com/app:v1".to_owned()).unwrap();
+        store
+            .tag(&source, &target)
+            .await
+            .expect_err("the source is not in the store");
+
+        std::fs::create_dir_all(store.pull_path(&source)).unwrap();
+        std::fs::write(store.pull_file_path(&source), content).unwrap();
+        store
+            .add(Module {
+                id: source.whole().to_owned(),
+                size: content.len() as u64,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let tagged = store.tag(&source, &target).await.unwrap();
+        let digest = format!("sha256:{:x}", Sha256::digest(content));
+        assert_eq!(target.whole(), tagged.id);
+        assert_eq!(
+            vec![format!("example.com/app@{}", digest)],
+            tagged.repo_digests
+        );
+        assert_eq!(content.len() as u64, tagged.size);
+        assert_eq!(content.to_vec(), store.read(&target).await.unwrap());
+        let ids: Vec<_> = store.list().await.into_iter().map(|m| m.id).collect();
+        assert_eq!(vec![source.whole(), target.whole()], ids);
+
+        let pinned =
+            Reference::try_from(format!("example.com/app@sha256:{}", "0".repeat(64))).unwrap();
+        match store.tag(&source, &pinned).await {
+            Err(ModuleStoreError::DigestMismatch) => {}
+            other => panic!("expected a digest mismatch, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use super::namespace::NAMESPACES_DIR;
+use super::{ModuleStore, SIDELOAD_DIR};
+
+/// The directory below the root the runtime service keeps the containers' files in. They are not modules.
+const CONTAINERS_DIR: &str = "containers";
+/// How long a partial download or link has to be left alone before it counts as the leftover of an interrupted
+/// pull. Sideloading writes them too, without showing up as a pull in progress.
+const LEFTOVER_AGE: Duration = Duration::from_secs(600);
+
+/// Usage is what the store takes on disk, as found by walking its directory.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Usage {
+    /// the bytes taken by the files of the store. Hard links to the same blob are counted once.
+    pub bytes: u64,
+    /// the files of the store, with hard links to the same blob counted once
+    pub inodes: u64,
+    /// what the metadata of the modules claims they take, for comparison
+    pub indexed_bytes: u64,
+    /// leftovers of interrupted pulls removed by the walk
+    pub removed_bytes: u64,
+    /// when the walk ran, in nanoseconds since the epoch
+    pub checked_at: i64,
+}
+
+impl ModuleStore {
+    /// Measure what the store and the stores of its namespaces take on disk every `interval`, and report that
+    /// from then on instead of the sizes recorded in the metadata of the modules. Those miss e.g. the files of
+    /// removed modules and the leftovers of interrupted pulls.
+    pub async fn watch_usage(self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            self.measure_usage().await;
+            for (_, store) in self.namespaces().await {
+                store.measure_usage().await;
+            }
+        }
+    }
+
+    /// What the store took on disk when it was last measured, None if it never was.
+    pub async fn usage(&self) -> Option<Usage> {
+        self.usage.read().await.clone()
+    }
+
+    /// Walk the store's directory, leaving out the namespaces' stores, and record what its files take.
+    ///
+    /// Unless a pull is in progress, the partial downloads of pulls that were interrupted, e.g. by a crash, are
+    /// removed along the way.
+    pub(crate) async fn measure_usage(&self) -> Usage {
+        let root_dir = self.root_dir.clone();
+        let leftover_age = if self.pulls.read().await.is_empty() {
+            Some(LEFTOVER_AGE)
+        } else {
+            None
+        };
+        let walked = tokio::task::spawn_blocking(move || {
+            let mut walk = Walk {
+                leftover_age,
+                ..Walk::default()
+            };
+            walk.dir(&root_dir, true).map(|()| walk)
+        })
+        .await
+        .unwrap();
+        let walk = match walked {
+            Ok(walk) => walk,
+            Err(e) => {
+                tracing::warn!(
+                    "cannot measure the store in {}: {}",
+                    self.root_dir.display(),
+                    e
+                );
+                return self.usage().await.unwrap_or_default();
+            }
+        };
+
+        let usage = Usage {
+            bytes: walk.bytes,
+            inodes: walk.seen.len() as u64,
+            indexed_bytes: self.indexed_bytes().await,
+            removed_bytes: walk.removed_bytes,
+            checked_at: Utc::now().timestamp_nanos(),
+        };
+        if usage.bytes != usage.indexed_bytes {
+            tracing::debug!(
+                dir = %self.root_dir.display(),
+                bytes = usage.bytes,
+                indexed_bytes = usage.indexed_bytes,
+                "the store takes more or less than its modules claim"
+            );
+        }
+        if usage.removed_bytes > 0 {
+            tracing::info!(
+                "removed {} bytes of interrupted pulls from {}",
+                usage.removed_bytes,
+                self.root_dir.display()
+            );
+        }
+        *self.usage.write().await = Some(usage.clone());
+        usage
+    }
+}
+
+/// The state of a walk of the store's directory.
+#[derive(Default)]
+struct Walk {
+    /// remove the leftovers of interrupted pulls untouched for this long, None to keep them
+    leftover_age: Option<Duration>,
+    /// the files seen so far, by device and inode
+    seen: HashSet<(u64, u64)>,
+    bytes: u64,
+    removed_bytes: u64,
+}
+
+impl Walk {
+    fn dir(&mut self, dir: &Path, root: bool) -> io::Result<()> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            // the namespaces are measured by their own stores, and sideloaded modules are copied into the store
+            if root
+                && [NAMESPACES_DIR, SIDELOAD_DIR, CONTAINERS_DIR]
+                    .iter()
+                    .any(|skipped| name == *skipped)
+            {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.dir(&entry.path(), false)?;
+            } else if self.is_leftover(&name.to_string_lossy(), &metadata) {
+                std::fs::remove_file(entry.path()).unwrap_or(());
+                self.removed_bytes += metadata.len();
+            } else if self.seen.insert(file_id(&metadata)) {
+                self.bytes += metadata.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the file is the partial download or link of a pull that did not finish, see `pull_wasm` and
+    /// `ModuleStore::store`.
+    fn is_leftover(&self, name: &str, metadata: &std::fs::Metadata) -> bool {
+        let age = match self.leftover_age {
+            Some(age) => age,
+            None => return false,
+        };
+        (name.ends_with(".partial") || name.ends_with(".link"))
+            && metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .map_or(false, |elapsed| elapsed >= age)
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+/// Without inodes, every file counts on its own.
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> (u64, u64) {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    (0, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::Module;
+
+    fn write(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_measure_usage() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let mut store = ModuleStore::new(dir.path().to_owned()).await;
+        assert_eq!(None, store.usage().await);
+
+        let module = dir.path().join("example.com/app/v1/module.wasm");
+        write(&module, b"\0asm\x01\0\0\0");
+        let blob = dir.path().join("blobs/sha256/1234");
+        std::fs::create_dir_all(blob.parent().unwrap()).unwrap();
+        std::fs::hard_link(&module, &blob).unwrap();
+        store
+            .add(Module {
+                id: "example.com/app:v1".to_owned(),
+                size: 8,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // neither the files of removed modules nor the containers' files are in the metadata
+        write(&dir.path().join("example.com/app/v0/module.wasm"), b"\0asm");
+        write(&dir.path().join(CONTAINERS_DIR).join("1/log"), b"hello");
+        // a fresh partial download may belong to a sideload in progress
+        let partial = dir.path().join("example.com/app/v2/module.1234.partial");
+        write(&partial, b"\0asm");
+
+        let usage = store.measure_usage().await;
+        // the module file and its blob are the same inode
+        assert_eq!(16, usage.bytes);
+        assert_eq!(3, usage.inodes);
+        assert_eq!(8, usage.indexed_bytes);
+        assert_eq!(0, usage.removed_bytes);
+        assert!(partial.exists());
+        assert_eq!(Some(usage), store.usage().await);
+        assert_eq!(16, store.used_bytes().await);
+        assert_eq!(3, store.used_inodes().await);
+
+        let mut walk = Walk {
+            leftover_age: Some(Duration::from_secs(0)),
+            ..Walk::default()
+        };
+        walk.dir(dir.path(), true).unwrap();
+        assert_eq!(4, walk.removed_bytes);
+        assert!(!partial.exists());
+    }
+}
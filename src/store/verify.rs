@@ -0,0 +1,108 @@
+use std::convert::TryFrom;
+
+use sha2::{Digest, Sha256};
+
+use super::{compressed_path, content_digest, ModuleStore};
+use crate::docker::Reference;
+use crate::server::Module;
+
+impl ModuleStore {
+    /// Check that the file of every module in the store has the size and the digest recorded for it, and drop the
+    /// modules that don't, e.g. because wok was killed while writing them. They are pulled again when a container
+    /// needs them. Returns the IDs of the dropped modules.
+    ///
+    /// The stores of the namespaces are left to their own check.
+    pub async fn verify(&mut self) -> Vec<String> {
+        let mut dropped = vec![];
+        for module in self.list().await {
+            let reason = match self.check(&module).await {
+                Ok(()) => continue,
+                Err(reason) => reason,
+            };
+            tracing::warn!("dropping corrupt module {}: {}", module.id, reason);
+            match self.remove(module.id.clone()).await {
+                Ok(_) => dropped.push(module.id),
+                Err(e) => tracing::error!("cannot drop corrupt module {}: {}", module.id, e),
+            }
+        }
+        dropped
+    }
+
+    /// Why the module's file does not match its metadata, if it doesn't.
+    async fn check(&self, module: &Module) -> Result<(), String> {
+        // modules added without being pulled, e.g. in tests, have no file to check
+        let reference = match Reference::try_from(module.id.clone()) {
+            Ok(reference) => reference,
+            Err(_) => return Ok(()),
+        };
+        let file = self.pull_file_path(&reference);
+        let stored = match tokio::fs::metadata(compressed_path(&file)).await {
+            Ok(metadata) => metadata,
+            Err(_) => tokio::fs::metadata(&file)
+                .await
+                .map_err(|e| format!("cannot read {}: {}", file.display(), e))?,
+        };
+        if stored.len() != module.size {
+            return Err(format!(
+                "it takes {} bytes instead of {}",
+                stored.len(),
+                module.size
+            ));
+        }
+
+        let digest = match content_digest(module) {
+            Some(digest) => digest,
+            None => return Ok(()),
+        };
+        let content = self
+            .read(&reference)
+            .await
+            .map_err(|e| format!("cannot read {}: {}", file.display(), e))?;
+        let actual = format!("sha256:{:x}", Sha256::digest(&content));
+        if actual != digest {
+            return Err(format!("its digest is {} instead of {}", actual, digest));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let mut store = ModuleStore::new(dir.path().to_owned()).await;
+        let content = b"\0asm\x01\0\0\0";
+        let digest = format!("sha256:{:x}", Sha256::digest(content));
+        for tag in &["intact", "truncated", "missing"] {
+            let reference = Reference::try_from(format!("example.com/app:{}", tag)).unwrap();
+            std::fs::create_dir_all(store.pull_path(&reference)).unwrap();
+            let file = store.pull_file_path(&reference);
+            match *tag {
+                "intact" => std::fs::write(&file, content).unwrap(),
+                "truncated" => std::fs::write(&file, &content[..4]).unwrap(),
+                _ => {}
+            }
+            store
+                .add(Module {
+                    id: reference.whole().to_owned(),
+                    repo_digests: vec![format!("example.com/app@{}", digest)],
+                    size: content.len() as u64,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+
+        let mut dropped = store.verify().await;
+        dropped.sort();
+        assert_eq!(
+            vec!["example.com/app:missing", "example.com/app:truncated"],
+            dropped
+        );
+        let ids: Vec<_> = store.list().await.into_iter().map(|m| m.id).collect();
+        assert_eq!(vec!["example.com/app:intact"], ids);
+    }
+}
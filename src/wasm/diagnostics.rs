@@ -0,0 +1,177 @@
+//! Making sense of modules that abort.
+//!
+//! Languages abort in ways of their own, which mostly end in a trap saying nothing more than `unreachable executed`.
+//! A Rust module writes the panic message to stderr before it traps, so the message is recovered from the end of
+//! its stderr. An AssemblyScript module calls the `abort` function it imports from `env` with the message and where
+//! it aborted, which is provided here and traps with them. The abort found replaces the trap in the error the module
+//! fails with, and so shows in the container's status.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::rc::Rc;
+
+use wasmtime::*;
+
+/// The module and the name of the function AssemblyScript modules import to abort.
+pub const ASSEMBLYSCRIPT_ABORT: (&str, &str) = ("env", "abort");
+
+/// How much of the end of stderr is searched for a panic message.
+const STDERR_TAIL: u64 = 16 * 1024;
+
+/// Why a module aborted, as the language it was written in tells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Abort {
+    pub message: String,
+    /// where the module aborted, e.g. `src/main.rs:2:5`
+    pub location: Option<String>,
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} at {}", self.message, location),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// The last panic of a Rust module in its stderr, in the format of Rust before 1.73,
+/// `thread 'main' panicked at 'message', src/main.rs:2:5`, or since, with the message on the lines after
+/// `thread 'main' panicked at src/main.rs:2:5:`.
+pub fn rust_panic(stderr: &str) -> Option<Abort> {
+    let panic = &stderr[stderr.rfind("panicked at ")? + "panicked at ".len()..];
+    if panic.starts_with('\'') {
+        let line = panic.lines().next().unwrap_or_default();
+        return Some(match line.rfind("', ") {
+            Some(end) => Abort {
+                message: line[1..end].to_owned(),
+                location: Some(line[end + 3..].trim().to_owned()),
+            },
+            None => Abort {
+                message: line[1..].trim_end_matches('\'').to_owned(),
+                location: None,
+            },
+        });
+    }
+    let mut lines = panic.lines();
+    let location = lines.next()?.trim().trim_end_matches(':').to_owned();
+    let message: Vec<&str> = lines
+        .take_while(|line| !line.starts_with("note: "))
+        .collect();
+    Some(Abort {
+        message: message.join("\n").trim().to_owned(),
+        location: Some(location),
+    })
+}
+
+/// The last panic of a Rust module in the end of the file its stderr went to.
+pub fn rust_panic_in(stderr: &Path) -> io::Result<Option<Abort>> {
+    let mut file = File::open(stderr)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(STDERR_TAIL)))?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail)?;
+    Ok(rust_panic(&String::from_utf8_lossy(&tail)))
+}
+
+/// The `abort` function of AssemblyScript, recording the abort it is called with before trapping.
+///
+/// The module's memory is needed to read the message and the file name, so `memory` is filled in once the module
+/// is instantiated. Aborts in the start function, before that, only tell the line and column.
+pub struct AssemblyScriptAbort {
+    pub memory: Rc<RefCell<Option<HostRef<Memory>>>>,
+    pub aborted: Rc<RefCell<Option<Abort>>>,
+}
+
+impl AssemblyScriptAbort {
+    /// The type of `abort`: the message, the file name, the line and the column.
+    pub fn func_type() -> FuncType {
+        FuncType::new(
+            vec![ValType::I32, ValType::I32, ValType::I32, ValType::I32].into_boxed_slice(),
+            vec![].into_boxed_slice(),
+        )
+    }
+
+    /// The AssemblyScript string at `ptr`: UTF-16 code units, preceded by their length in bytes.
+    fn read_string(&self, ptr: i32) -> Option<String> {
+        let memory = self.memory.borrow();
+        let memory = memory.as_ref()?.borrow();
+        let data = unsafe { std::slice::from_raw_parts(memory.data_ptr(), memory.data_size()) };
+        let ptr = ptr as u32 as usize;
+        let len_bytes = data.get(ptr.checked_sub(4)?..ptr)?;
+        let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]);
+        let units: Vec<u16> = data
+            .get(ptr..ptr.checked_add(len as usize)?)?
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        Some(String::from_utf16_lossy(&units))
+    }
+}
+
+impl Callable for AssemblyScriptAbort {
+    fn call(&self, params: &[Val], _results: &mut [Val]) -> std::result::Result<(), HostRef<Trap>> {
+        let message = Some(params[0].unwrap_i32())
+            .filter(|ptr| *ptr != 0)
+            .and_then(|ptr| self.read_string(ptr))
+            .unwrap_or_else(|| "aborted".to_owned());
+        let file = Some(params[1].unwrap_i32())
+            .filter(|ptr| *ptr != 0)
+            .and_then(|ptr| self.read_string(ptr));
+        let position = format!("{}:{}", params[2].unwrap_i32(), params[3].unwrap_i32());
+        let abort = Abort {
+            message,
+            location: Some(match file {
+                Some(file) => format!("{}:{}", file, position),
+                None => format!("line {}", position),
+            }),
+        };
+        let trap = HostRef::new(Trap::new(abort.to_string()));
+        *self.aborted.borrow_mut() = Some(abort);
+        Err(trap)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rust_panic() {
+        let stderr = "starting\nthread 'main' panicked at 'attempt to divide by zero', src/main.rs:3:5\n\
+                      note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n";
+        assert_eq!(
+            Some(Abort {
+                message: "attempt to divide by zero".to_owned(),
+                location: Some("src/main.rs:3:5".to_owned()),
+            }),
+            rust_panic(stderr)
+        );
+
+        let stderr = "thread 'main' panicked at src/main.rs:3:5:\nattempt to divide by zero\n\
+                      note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n";
+        let abort = rust_panic(stderr).expect("a panic");
+        assert_eq!(
+            "attempt to divide by zero at src/main.rs:3:5",
+            abort.to_string()
+        );
+
+        assert_eq!(None, rust_panic("cannot open config.toml\n"));
+    }
+
+    #[test]
+    fn test_rust_panic_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stderr");
+        let mut stderr = "x".repeat(STDERR_TAIL as usize * 2);
+        stderr.push_str("\nthread 'main' panicked at 'boom', src/lib.rs:1:1\n");
+        std::fs::write(&path, stderr).unwrap();
+        assert_eq!(
+            "boom at src/lib.rs:1:1",
+            rust_panic_in(&path).unwrap().unwrap().to_string()
+        );
+    }
+}
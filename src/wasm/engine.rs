@@ -0,0 +1,118 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "wasi")]
+use wasmtime::{Config, Engine};
+
+/// EngineConfig selects the WebAssembly proposals a module may use beyond the MVP.
+///
+/// It is parsed from a comma separated list of features, e.g. `simd,threads`. The maximum wasm stack can't be
+/// configured on the engine with the wasmtime version we use. Modules run on the native stack of their thread
+/// instead, whose size is set with `WasiRuntime::with_stack_size`.
+///
+/// `threads` only lets modules use the instructions of the threads proposal, e.g. atomics. Spawning threads with
+/// wasi-threads needs a memory shared between instances on several threads, which the wasmtime version we use
+/// can't do, so modules importing `wasi.thread-spawn` are refused, see `server::restrictions`.
+///
+/// TODO: a per container switch for jitdump profiling, writing the profile into the container's log directory. The
+/// wasmtime version we use has no profiling strategy to select, jitdump support starts with wasmtime 0.9.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct EngineConfig {
+    pub threads: bool,
+    pub simd: bool,
+    pub reference_types: bool,
+    pub bulk_memory: bool,
+    pub multi_value: bool,
+}
+
+impl EngineConfig {
+    /// Create an engine with the selected features enabled.
+    #[cfg(feature = "wasi")]
+    pub fn engine(&self) -> Engine {
+        let mut config = Config::new();
+        config
+            .wasm_threads(self.threads)
+            .wasm_simd(self.simd)
+            .wasm_reference_types(self.reference_types)
+            .wasm_bulk_memory(self.bulk_memory)
+            .wasm_multi_value(self.multi_value);
+        Engine::new(&config)
+    }
+
+    /// The names of the enabled features, as in the `deislabs.io/wasm-features` annotation.
+    pub fn features(&self) -> Vec<&'static str> {
+        let mut features = vec![];
+        if self.threads {
+            features.push("threads");
+        }
+        if self.simd {
+            features.push("simd");
+        }
+        if self.reference_types {
+            features.push("reference-types");
+        }
+        if self.bulk_memory {
+            features.push("bulk-memory");
+        }
+        if self.multi_value {
+            features.push("multi-value");
+        }
+        features
+    }
+}
+
+impl FromStr for EngineConfig {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> super::Result<Self> {
+        let mut config = EngineConfig::default();
+        for feature in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match feature {
+                "threads" => config.threads = true,
+                "simd" => config.simd = true,
+                "reference-types" => config.reference_types = true,
+                "bulk-memory" => config.bulk_memory = true,
+                "multi-value" => config.multi_value = true,
+                _ => failure::bail!(
+                    "unknown wasm feature {:?}, expected one of threads, simd, reference-types, bulk-memory, multi-value",
+                    feature
+                ),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Formats the enabled features the way they are parsed, or `default` when there are none.
+impl fmt::Display for EngineConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let features = self.features();
+        if features.is_empty() {
+            f.write_str("default")
+        } else {
+            f.write_str(&features.join(","))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_engine_config() {
+        let config: EngineConfig = "simd, threads,".parse().expect("parsed features");
+        assert_eq!(
+            EngineConfig {
+                threads: true,
+                simd: true,
+                ..Default::default()
+            },
+            config
+        );
+        assert_eq!("threads,simd", config.to_string());
+        assert_eq!(EngineConfig::default(), "".parse().unwrap());
+        assert_eq!("default", EngineConfig::default().to_string());
+        assert!("gc".parse::<EngineConfig>().is_err());
+    }
+}
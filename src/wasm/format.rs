@@ -0,0 +1,53 @@
+/// The kinds of WebAssembly binaries, told apart by the version and layer in their preamble.
+///
+/// Only core modules can be run: the wasmtime version we use predates the component model and WASI preview2.
+/// Components are recognized so the store refuses them with an error saying so, rather than failing to parse.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryFormat {
+    /// a core module, version 1
+    Module,
+    /// a component of the component model
+    Component,
+    /// not a WebAssembly binary, or of a version we don't know
+    Unknown,
+}
+
+const MAGIC: &[u8] = b"\0asm";
+
+impl BinaryFormat {
+    /// Detect the format of the binary from its preamble.
+    pub fn detect(binary: &[u8]) -> Self {
+        if binary.len() < 8 || &binary[..4] != MAGIC {
+            return BinaryFormat::Unknown;
+        }
+        // a 16 bit version followed by a 16 bit layer, 0 for core modules and 1 for components
+        match (&binary[4..6], &binary[6..8]) {
+            ([1, 0], [0, 0]) => BinaryFormat::Module,
+            (_, [1, 0]) => BinaryFormat::Component,
+            _ => BinaryFormat::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            BinaryFormat::Module,
+            BinaryFormat::detect(b"\0asm\x01\0\0\0\x01\x04")
+        );
+        assert_eq!(
+            BinaryFormat::Component,
+            BinaryFormat::detect(b"\0asm\x0d\0\x01\0\x01\x04")
+        );
+        assert_eq!(
+            BinaryFormat::Unknown,
+            BinaryFormat::detect(b"\0asm\x02\0\0\0")
+        );
+        assert_eq!(BinaryFormat::Unknown, BinaryFormat::detect(b"\0asm"));
+        assert_eq!(BinaryFormat::Unknown, BinaryFormat::detect(b"#!/bin/sh\n"));
+    }
+}
@@ -0,0 +1,211 @@
+//! Bounding the files and directories a module may have open at once.
+//!
+//! Every handle a WASI module opens is a descriptor of the wok process, which shares its descriptor limit between
+//! all the containers of the node. wasi-common has no limit of its own, so the `path_open`, `fd_close` and
+//! `fd_renumber` functions the module imports are wrapped: `path_open` fails with `EMFILE` once the module has as
+//! many handles open as it may, and closing one of them gives it back. The descriptors `path_open` returns are
+//! tracked, so closing stdio or a preopened directory gives nothing back: those don't count, they are bounded when
+//! the container is created.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use wasmtime::*;
+
+/// The WASI functions wrapped to track the handles the module opens and closes.
+pub const PATH_OPEN: &str = "path_open";
+pub const FD_CLOSE: &str = "fd_close";
+pub const FD_RENUMBER: &str = "fd_renumber";
+
+/// The errno of WASI for too many open files.
+const ERRNO_MFILE: i32 = 33;
+
+/// The handles a module opened with `path_open` and hasn't closed yet.
+///
+/// `path_open` writes the descriptor it opened to the module's memory, so `memory` is filled in once the module is
+/// instantiated, like the one of `diagnostics::AssemblyScriptAbort`.
+#[derive(Clone)]
+pub struct OpenHandles {
+    open: Rc<RefCell<HashSet<i32>>>,
+    memory: Rc<RefCell<Option<HostRef<Memory>>>>,
+    max: usize,
+}
+
+impl OpenHandles {
+    /// Allow the module to have up to `max` handles open.
+    pub fn new(max: usize, memory: Rc<RefCell<Option<HostRef<Memory>>>>) -> Self {
+        OpenHandles {
+            open: Rc::default(),
+            memory,
+            max,
+        }
+    }
+
+    /// Wrap the WASI function the module imports with the given name, if it opens or closes handles.
+    pub fn wrap(&self, store: &HostRef<Store>, name: &str, func: &HostRef<Func>) -> Option<Func> {
+        let kind = match name {
+            PATH_OPEN => Kind::Open,
+            FD_CLOSE => Kind::Close,
+            FD_RENUMBER => Kind::Renumber,
+            _ => return None,
+        };
+        let ty = func.borrow().r#type().clone();
+        let tracked = Tracked {
+            func: func.clone(),
+            handles: self.clone(),
+            kind,
+        };
+        Some(Func::new(store, ty, Rc::new(tracked)))
+    }
+
+    /// The descriptor `path_open` wrote at `ptr`.
+    fn read_fd(&self, ptr: i32) -> Option<i32> {
+        let memory = self.memory.borrow();
+        let memory = memory.as_ref()?.borrow();
+        let data = unsafe { std::slice::from_raw_parts(memory.data_ptr(), memory.data_size()) };
+        let ptr = ptr as u32 as usize;
+        let fd = data.get(ptr..ptr.checked_add(4)?)?;
+        Some(i32::from_le_bytes([fd[0], fd[1], fd[2], fd[3]]))
+    }
+}
+
+/// What a wrapped function does to the handles of the module.
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    /// `path_open`, writing the descriptor it opened where its last param points.
+    Open,
+    /// `fd_close` of the descriptor given first.
+    Close,
+    /// `fd_renumber`, moving the descriptor given first to the second one, which it closes.
+    Renumber,
+}
+
+/// A WASI function tracking the handles it opens or closes.
+struct Tracked {
+    func: HostRef<Func>,
+    handles: OpenHandles,
+    kind: Kind,
+}
+
+impl Callable for Tracked {
+    fn call(&self, params: &[Val], results: &mut [Val]) -> std::result::Result<(), HostRef<Trap>> {
+        if let Kind::Open = self.kind {
+            if self.handles.open.borrow().len() >= self.handles.max {
+                results[0] = Val::I32(ERRNO_MFILE);
+                return Ok(());
+            }
+        }
+        let returned = self.func.borrow().call(params)?;
+        results.clone_from_slice(&returned);
+        if returned[0].unwrap_i32() != 0 {
+            return Ok(());
+        }
+        let mut open = self.handles.open.borrow_mut();
+        match self.kind {
+            Kind::Open => {
+                if let Some(fd) = params
+                    .last()
+                    .and_then(|ptr| self.handles.read_fd(ptr.unwrap_i32()))
+                {
+                    open.insert(fd);
+                }
+            }
+            Kind::Close => {
+                open.remove(&params[0].unwrap_i32());
+            }
+            Kind::Renumber => {
+                let to = params[1].unwrap_i32();
+                if open.remove(&params[0].unwrap_i32()) {
+                    open.insert(to);
+                } else {
+                    open.remove(&to);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wasm::EngineConfig;
+
+    /// A WASI function that always succeeds.
+    struct Succeeds;
+
+    impl Callable for Succeeds {
+        fn call(&self, _: &[Val], results: &mut [Val]) -> std::result::Result<(), HostRef<Trap>> {
+            results[0] = Val::I32(0);
+            Ok(())
+        }
+    }
+
+    /// A `path_open` writing the next descriptor, from 10 on, where its last param points.
+    struct Opens {
+        memory: HostRef<Memory>,
+        next: RefCell<i32>,
+    }
+
+    impl Callable for Opens {
+        fn call(
+            &self,
+            params: &[Val],
+            results: &mut [Val],
+        ) -> std::result::Result<(), HostRef<Trap>> {
+            let mut next = self.next.borrow_mut();
+            let ptr = params.last().unwrap().unwrap_i32() as usize;
+            let memory = self.memory.borrow();
+            let data =
+                unsafe { std::slice::from_raw_parts_mut(memory.data_ptr(), memory.data_size()) };
+            data[ptr..ptr + 4].copy_from_slice(&next.to_le_bytes());
+            *next += 1;
+            results[0] = Val::I32(0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_open_handles() {
+        let engine = HostRef::new(EngineConfig::default().engine());
+        let store = HostRef::new(Store::new(&engine));
+        let memory = HostRef::new(Memory::new(&store, MemoryType::new(Limits::new(1, None))));
+        let func_type = |params: usize| {
+            FuncType::new(
+                vec![ValType::I32; params].into_boxed_slice(),
+                vec![ValType::I32].into_boxed_slice(),
+            )
+        };
+        let opens = Opens {
+            memory: memory.clone(),
+            next: RefCell::new(10),
+        };
+        let open_func = HostRef::new(Func::new(&store, func_type(2), Rc::new(opens)));
+        let close_func = HostRef::new(Func::new(&store, func_type(1), Rc::new(Succeeds)));
+        let renumber_func = HostRef::new(Func::new(&store, func_type(2), Rc::new(Succeeds)));
+        let handles = OpenHandles::new(1, Rc::new(RefCell::new(Some(memory))));
+        let path_open = handles.wrap(&store, PATH_OPEN, &open_func).unwrap();
+        let fd_close = handles.wrap(&store, FD_CLOSE, &close_func).unwrap();
+        let fd_renumber = handles.wrap(&store, FD_RENUMBER, &renumber_func).unwrap();
+        assert!(handles.wrap(&store, "fd_write", &close_func).is_none());
+
+        let errno = |func: &Func, params: &[i32]| {
+            let params: Vec<Val> = params.iter().map(|p| Val::I32(*p)).collect();
+            func.call(&params).unwrap()[0].unwrap_i32()
+        };
+        // the module opens descriptor 10
+        assert_eq!(0, errno(&path_open, &[3, 0]));
+        assert_eq!(ERRNO_MFILE, errno(&path_open, &[3, 0]));
+        // closing stdio or a preopened directory gives nothing back
+        assert_eq!(0, errno(&fd_close, &[1]));
+        assert_eq!(0, errno(&fd_close, &[3]));
+        assert_eq!(ERRNO_MFILE, errno(&path_open, &[3, 0]));
+        // the module's descriptor is closed as 1 once moved there
+        assert_eq!(0, errno(&fd_renumber, &[10, 1]));
+        assert_eq!(0, errno(&fd_close, &[10]));
+        assert_eq!(ERRNO_MFILE, errno(&path_open, &[3, 0]));
+        assert_eq!(0, errno(&fd_close, &[1]));
+        assert_eq!(0, errno(&path_open, &[3, 0]));
+    }
+}
@@ -1,6 +1,26 @@
+#[cfg(feature = "wasi")]
+pub mod diagnostics;
+pub mod engine;
+pub mod format;
+#[cfg(feature = "wasi")]
+pub mod handles;
+pub mod output;
+pub mod pool;
 pub mod runtime;
+#[cfg(feature = "wapc")]
+pub mod wapc;
 pub mod wascc;
+#[cfg(feature = "wascc")]
+pub mod wascc_logging;
+#[cfg(feature = "wasi")]
 pub mod wasi;
 
+pub use engine::EngineConfig;
+pub use format::BinaryFormat;
+pub use output::{LogLine, OutputBroker, Stream};
+pub use pool::{WarmPool, WarmPoolStats};
 pub use runtime::{Result, Runtime};
+#[cfg(feature = "wapc")]
+pub use wapc::{WapcGuest, WapcRuntime};
+#[cfg(feature = "wasi")]
 pub use wasi::WasiRuntime;
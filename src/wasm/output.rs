@@ -0,0 +1,289 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use tracing::warn;
+
+/// Lines longer than this are split into partial lines, like containerd does.
+const MAX_LINE: usize = 16 * 1024;
+
+/// How often a capture checks for more output once it has read everything written so far.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The stream a log line was written to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// A line of a module's output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogLine {
+    pub timestamp: DateTime<Utc>,
+    pub stream: Stream,
+    /// whether the line goes on in the next one, because it was too long
+    pub partial: bool,
+    /// the line, without its newline
+    pub content: Vec<u8>,
+}
+
+impl LogLine {
+    /// The line in the CRI logging format: `<RFC3339Nano timestamp> <stream> <P|F> <content>`.
+    pub fn to_cri(&self) -> Vec<u8> {
+        let mut line = format!(
+            "{} {} {} ",
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            self.stream.as_str(),
+            if self.partial { "P" } else { "F" }
+        )
+        .into_bytes();
+        line.extend_from_slice(&self.content);
+        line.push(b'\n');
+        line
+    }
+}
+
+/// OutputBroker takes the output of a container's module and writes it to the container's CRI log file, while
+/// handing every line to the live subscribers, e.g. `wokctl containers logs --follow`.
+///
+/// The module writes its stdout and stderr to files of its own, which are tailed by a `Capture` each. A pipe would
+/// be the obvious choice, but it would never be closed: the stores of the wasmtime version we use never free the
+/// instances they hold, so the module's end of the pipe would stay open after the module returned.
+///
+/// Cloning the broker is cheap and gives another handle on the same log.
+#[derive(Clone, Debug, Default)]
+pub struct OutputBroker {
+    state: Arc<Mutex<BrokerState>>,
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    log_path: Option<PathBuf>,
+    log: Option<File>,
+    subscribers: Vec<UnboundedSender<LogLine>>,
+    /// whether the module returned. Nothing is published anymore, and subscriptions end right away.
+    closed: bool,
+}
+
+impl OutputBroker {
+    /// Create a broker writing to the CRI log file at `log_path`, or only to its subscribers without one.
+    pub fn new(log_path: Option<&Path>) -> io::Result<Self> {
+        let log = match log_path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(OutputBroker {
+            state: Arc::new(Mutex::new(BrokerState {
+                log_path: log_path.map(Path::to_path_buf),
+                log,
+                ..Default::default()
+            })),
+        })
+    }
+
+    /// The CRI log file the output is written to.
+    pub fn log_path(&self) -> Option<PathBuf> {
+        self.state.lock().unwrap().log_path.clone()
+    }
+
+    /// Receive the lines published from now on. The stream ends once the module returned.
+    pub fn subscribe(&self) -> UnboundedReceiver<LogLine> {
+        let (sender, receiver) = unbounded();
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.subscribers.push(sender);
+        }
+        receiver
+    }
+
+    /// The log written so far, and the lines published from now on, with no line missing or repeated in between.
+    pub fn follow(&self) -> io::Result<(Vec<u8>, UnboundedReceiver<LogLine>)> {
+        let (sender, receiver) = unbounded();
+        let mut state = self.state.lock().unwrap();
+        let mut log = vec![];
+        if let Some(path) = &state.log_path {
+            match File::open(path) {
+                Ok(mut file) => {
+                    file.read_to_end(&mut log)?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !state.closed {
+            state.subscribers.push(sender);
+        }
+        Ok((log, receiver))
+    }
+
+    /// Write the line to the log file and hand it to the subscribers.
+    pub fn publish(&self, line: LogLine) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        if let Some(log) = &mut state.log {
+            if let Err(e) = log.write_all(&line.to_cri()) {
+                warn!("cannot write to the container's log: {}", e);
+            }
+        }
+        // subscribers that went away are dropped
+        state
+            .subscribers
+            .retain(|s| s.unbounded_send(line.clone()).is_ok());
+    }
+
+    /// End the subscriptions, once the module returned.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.subscribers.clear();
+    }
+
+    /// Publish what the module writes to `file` as lines of the given stream, until the capture is dropped.
+    pub(crate) fn capture(&self, stream: Stream, file: File) -> Capture {
+        let done = Arc::new(AtomicBool::new(false));
+        let broker = self.clone();
+        let finished = done.clone();
+        let thread = std::thread::spawn(move || {
+            if let Err(e) = broker.tail(stream, file, &finished) {
+                warn!("cannot read the module's {}: {}", stream.as_str(), e);
+            }
+        });
+        Capture {
+            done,
+            thread: Some(thread),
+        }
+    }
+
+    fn tail(&self, stream: Stream, mut file: File, done: &AtomicBool) -> io::Result<()> {
+        let mut line = vec![];
+        let mut chunk = [0; 8192];
+        loop {
+            // read once more after the module returned, so nothing it wrote last is lost
+            let finished = done.load(Ordering::SeqCst);
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                if finished {
+                    if !line.is_empty() {
+                        self.publish(log_line(stream, false, std::mem::take(&mut line)));
+                    }
+                    return Ok(());
+                }
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            for &byte in &chunk[..read] {
+                if byte == b'\n' {
+                    self.publish(log_line(stream, false, std::mem::take(&mut line)));
+                } else {
+                    line.push(byte);
+                    if line.len() == MAX_LINE {
+                        self.publish(log_line(stream, true, std::mem::take(&mut line)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn log_line(stream: Stream, partial: bool, content: Vec<u8>) -> LogLine {
+    LogLine {
+        timestamp: Utc::now(),
+        stream,
+        partial,
+        content,
+    }
+}
+
+/// Capture is a thread publishing the output a module writes to a file. Dropping it publishes what is left and
+/// waits for the thread to end, so it must only be dropped once the module returned.
+#[derive(Debug)]
+pub(crate) struct Capture {
+    done: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let dir = tempfile::tempdir().expect("Couldn't create temp directory");
+        let log_path = dir.path().join("0.log");
+        let broker = OutputBroker::new(Some(&log_path)).unwrap();
+        let mut early = broker.subscribe();
+        broker.publish(log_line(Stream::Stdout, false, b"hello".to_vec()));
+
+        let (log, mut late) = broker.follow().unwrap();
+        assert!(String::from_utf8(log)
+            .unwrap()
+            .ends_with(" stdout F hello\n"));
+        broker.publish(log_line(Stream::Stderr, true, b"oops".to_vec()));
+        broker.close();
+        broker.publish(log_line(Stream::Stdout, false, b"ignored".to_vec()));
+
+        let early: Vec<_> = early.by_ref().collect().await;
+        assert_eq!(
+            vec![b"hello".to_vec(), b"oops".to_vec()],
+            early.into_iter().map(|l| l.content).collect::<Vec<_>>()
+        );
+        let late = late.next().await.unwrap();
+        assert_eq!(Stream::Stderr, late.stream);
+        assert!(late.partial);
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert_eq!(2, log.lines().count());
+        assert!(log.ends_with(" stderr P oops\n"));
+        assert!(broker.subscribe().next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let broker = OutputBroker::new(None).unwrap();
+        let mut lines = broker.subscribe();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let capture = broker.capture(Stream::Stdout, file.reopen().unwrap());
+        file.write_all(b"first\nsec").unwrap();
+        file.write_all(b"ond\n").unwrap();
+        file.write_all(&vec![b'x'; MAX_LINE + 1]).unwrap();
+        drop(capture);
+        broker.close();
+
+        let lines: Vec<_> = lines.by_ref().collect().await;
+        let contents: Vec<_> = lines.iter().map(|l| l.content.len()).collect();
+        assert_eq!(b"first".to_vec(), lines[0].content);
+        assert_eq!(b"second".to_vec(), lines[1].content);
+        assert_eq!(vec![5, 6, MAX_LINE, 1], contents);
+        assert_eq!(
+            vec![false, false, true, false],
+            lines.iter().map(|l| l.partial).collect::<Vec<_>>()
+        );
+    }
+}
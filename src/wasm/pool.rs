@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use serde::Serialize;
+#[cfg(feature = "wasi")]
+use tokio::sync::oneshot;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::engine::EngineConfig;
+#[cfg(feature = "wasi")]
+use super::wasi::{CompiledModule, WasiRuntime};
+
+/// A job for a warm instance: the runtime to run, and where to send its result.
+#[cfg(feature = "wasi")]
+type Job = (WasiRuntime, oneshot::Sender<super::Result<()>>);
+
+/// Without the WASI runtime there are no instances to run jobs, see `WarmPool::is_enabled`.
+#[cfg(not(feature = "wasi"))]
+type Job = std::convert::Infallible;
+
+/// WarmPool keeps modules compiled ahead of time, so starting a container doesn't have to wait for its module to
+/// be compiled.
+///
+/// Each warm instance is a thread that compiled the module into a store of its own and waits for a runtime to
+/// run it with. Instances are used once; the pool is topped up again in the background.
+///
+/// The pool keeps the instances of at most `max_modules` modules. Warming another module evicts the instances of
+/// the module least recently warmed or taken from the pool.
+///
+/// Cloning the pool is cheap and gives another handle on the same instances.
+#[derive(Clone, Debug, Default)]
+pub struct WarmPool {
+    /// the number of instances kept per module. 0 disables the pool.
+    size: usize,
+    /// the number of modules instances are kept of. 0 keeps every module.
+    max_modules: usize,
+    /// the size of the stack of the instances' threads, if not the default
+    stack_size: Option<usize>,
+    state: Arc<Mutex<PoolState>>,
+}
+
+/// The key of the instances of a module: its image reference and the configuration of the engine they were
+/// compiled with.
+type Key = (String, EngineConfig);
+
+#[derive(Debug, Default)]
+struct PoolState {
+    /// the warm instances, with the tick they were last used at
+    instances: HashMap<Key, (Vec<WarmInstance>, u64)>,
+    /// increases with every use of the pool, to tell which module was used least recently
+    tick: u64,
+    stats: WarmPoolStats,
+}
+
+impl PoolState {
+    fn touch(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// WarmPoolStats counts how useful the pool was since wok started.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct WarmPoolStats {
+    /// starts that took a warm instance
+    pub hits: u64,
+    /// starts that found no warm instance and compiled their module themselves
+    pub misses: u64,
+    /// modules whose instances were dropped to make room for another module
+    pub evictions: u64,
+    /// the modules the pool currently keeps instances of
+    pub modules: usize,
+}
+
+impl WarmPool {
+    pub fn new(size: usize) -> Self {
+        WarmPool {
+            size,
+            max_modules: 0,
+            stack_size: None,
+            state: Arc::default(),
+        }
+    }
+
+    /// Keep the instances of at most `max_modules` modules, evicting the least recently used ones. 0 keeps every
+    /// module.
+    pub fn with_max_modules(mut self, max_modules: usize) -> Self {
+        self.max_modules = max_modules;
+        self
+    }
+
+    /// Run the instances on threads with a stack of the given size in bytes, see `WasiRuntime::with_stack_size`.
+    /// Only runtimes asking for that size may be run with them.
+    pub fn with_stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// The size of the stack of the instances' threads, None for the default.
+    pub fn stack_size(&self) -> Option<usize> {
+        self.stack_size
+    }
+
+    /// Whether the pool keeps any instances at all. It never does in a build without the wasi feature.
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0 && cfg!(feature = "wasi")
+    }
+
+    /// There is nothing to compile instances with in a build without the wasi feature.
+    #[cfg(not(feature = "wasi"))]
+    pub async fn warm(
+        &self,
+        _image_ref: &str,
+        _engine_config: EngineConfig,
+        _module_data: Arc<Vec<u8>>,
+    ) {
+    }
+
+    /// Compile instances of the module until the pool has `size` of them.
+    #[cfg(feature = "wasi")]
+    pub async fn warm(
+        &self,
+        image_ref: &str,
+        engine_config: EngineConfig,
+        module_data: Arc<Vec<u8>>,
+    ) {
+        if self.size == 0 {
+            return;
+        }
+        let mut state = self.state.lock().await;
+        let key = (image_ref.to_owned(), engine_config);
+        if self.max_modules > 0 && !state.instances.contains_key(&key) {
+            while state.instances.len() >= self.max_modules {
+                let lru = state
+                    .instances
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(key, _)| key.clone())
+                    .expect("the pool is not empty");
+                debug!("evicted the warm instances of {} to make room", lru.0);
+                state.instances.remove(&lru);
+                state.stats.evictions += 1;
+            }
+        }
+        let tick = state.touch();
+        let (warm, last_used) = state.instances.entry(key).or_default();
+        *last_used = tick;
+        if warm.len() < self.size {
+            debug!(
+                "warming {} instances of {} with {} features",
+                self.size - warm.len(),
+                image_ref,
+                engine_config
+            );
+        }
+        while warm.len() < self.size {
+            warm.push(WarmInstance::spawn(
+                module_data.clone(),
+                engine_config,
+                self.stack_size,
+            ));
+        }
+    }
+
+    /// Take a warm instance of the module compiled with the given configuration, if there is one.
+    pub async fn take(&self, image_ref: &str, engine_config: EngineConfig) -> Option<WarmInstance> {
+        let mut state = self.state.lock().await;
+        let tick = state.touch();
+        let instance = state
+            .instances
+            .get_mut(&(image_ref.to_owned(), engine_config))
+            .and_then(|(warm, last_used)| {
+                *last_used = tick;
+                warm.pop()
+            });
+        if instance.is_some() {
+            state.stats.hits += 1;
+        } else {
+            state.stats.misses += 1;
+        }
+        instance
+    }
+
+    /// Drop the instances of the module, whatever their configuration, e.g. because no container uses it anymore.
+    pub async fn evict(&self, image_ref: &str) {
+        let mut state = self.state.lock().await;
+        let before = state.instances.len();
+        state.instances.retain(|(r, _), _| r != image_ref);
+        if state.instances.len() < before {
+            debug!("evicted the warm instances of {}", image_ref);
+        }
+    }
+
+    /// The number of warm instances of each module, by image reference and features, e.g.
+    /// `example.com/app:v1 (simd)`.
+    pub async fn counts(&self) -> HashMap<String, usize> {
+        self.state
+            .lock()
+            .await
+            .instances
+            .iter()
+            .map(|((image_ref, engine_config), (warm, _))| {
+                (format!("{} ({})", image_ref, engine_config), warm.len())
+            })
+            .collect()
+    }
+
+    /// How useful the pool was since it was created.
+    pub async fn stats(&self) -> WarmPoolStats {
+        let state = self.state.lock().await;
+        WarmPoolStats {
+            modules: state.instances.len(),
+            ..state.stats.clone()
+        }
+    }
+}
+
+/// A thread holding a compiled module, waiting to run it.
+///
+/// Dropping the instance ends the thread without running anything.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "wasi"), allow(dead_code))]
+pub struct WarmInstance {
+    jobs: mpsc::Sender<Job>,
+}
+
+#[cfg(feature = "wasi")]
+impl WarmInstance {
+    fn spawn(
+        module_data: Arc<Vec<u8>>,
+        engine_config: EngineConfig,
+        stack_size: Option<usize>,
+    ) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let thread = match stack_size {
+            Some(stack_size) => std::thread::Builder::new().stack_size(stack_size),
+            None => std::thread::Builder::new(),
+        };
+        thread
+            .spawn(move || {
+                // a module that doesn't compile fails when it is run, just like it would without the pool
+                let compiled = CompiledModule::compile(&module_data, &engine_config);
+                if let Ok((runtime, done)) = receiver.recv() {
+                    let result = compiled.and_then(|compiled| runtime.run_compiled(compiled));
+                    done.send(result).unwrap_or(());
+                }
+            })
+            .expect("failed to spawn a warm instance thread");
+        WarmInstance { jobs }
+    }
+
+    /// Run the runtime with the compiled module. The runtime must have been created for that module, with the
+    /// configuration the module was compiled with.
+    pub async fn run(self, runtime: WasiRuntime) -> Result<super::Result<()>, String> {
+        let (done, result) = oneshot::channel();
+        self.jobs
+            .send((runtime, done))
+            .map_err(|_| "warm instance is gone".to_owned())?;
+        result
+            .await
+            .map_err(|_| "warm instance ended without a result".to_owned())
+    }
+}
+
+#[cfg(all(test, feature = "wasi"))]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_warm_pool() {
+        let module = Arc::new(std::fs::read("examples/printer.wasm").expect("read module"));
+        let pool = WarmPool::new(2);
+        let engine_config = EngineConfig::default();
+        pool.warm("printer", engine_config, module.clone()).await;
+        assert_eq!(Some(&2), pool.counts().await.get("printer (default)"));
+
+        let instance = pool
+            .take("printer", engine_config)
+            .await
+            .expect("a warm instance");
+        assert!(pool.take("other", engine_config).await.is_none());
+        let simd = EngineConfig {
+            simd: true,
+            ..Default::default()
+        };
+        assert!(pool.take("printer", simd).await.is_none());
+        let runtime = WasiRuntime::from_module_data(
+            module.to_vec(),
+            HashMap::new(),
+            vec![],
+            HashMap::new(),
+            None,
+        )
+        .expect("runtime");
+        instance
+            .run(runtime)
+            .await
+            .expect("instance ran")
+            .expect("module ran");
+
+        // only the missing instance is compiled again
+        pool.warm("printer", engine_config, module).await;
+        assert_eq!(Some(&2), pool.counts().await.get("printer (default)"));
+        pool.evict("printer").await;
+        assert!(pool.counts().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_warm_pool_lru() {
+        let module = Arc::new(std::fs::read("examples/printer.wasm").expect("read module"));
+        let pool = WarmPool::new(1).with_max_modules(2);
+        let engine_config = EngineConfig::default();
+        pool.warm("a", engine_config, module.clone()).await;
+        pool.warm("b", engine_config, module.clone()).await;
+        // taking an instance of "a", which misses for other features, makes it the most recently used module
+        assert!(pool
+            .take(
+                "a",
+                EngineConfig {
+                    simd: true,
+                    ..Default::default()
+                }
+            )
+            .await
+            .is_none());
+        assert!(pool.take("a", engine_config).await.is_some());
+        pool.warm("c", engine_config, module).await;
+
+        let counts = pool.counts().await;
+        assert!(counts.contains_key("a (default)"));
+        assert!(!counts.contains_key("b (default)"));
+        assert!(counts.contains_key("c (default)"));
+        assert_eq!(
+            WarmPoolStats {
+                hits: 1,
+                misses: 1,
+                evictions: 1,
+                modules: 2,
+            },
+            pool.stats().await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_warm_pool() {
+        let pool = WarmPool::new(0);
+        pool.warm("printer", EngineConfig::default(), Arc::new(vec![]))
+            .await;
+        assert!(!pool.is_enabled());
+        assert!(pool
+            .take("printer", EngineConfig::default())
+            .await
+            .is_none());
+    }
+}
@@ -1,10 +1,14 @@
-use std::fs::File;
-use std::io::BufReader;
+use super::output::OutputBroker;
 
 /// Result describes a Runtime result that may return a failure::Error if things go wrong.
 pub type Result<T> = std::result::Result<T, failure::Error>;
 
 pub trait Runtime {
     fn run(&self) -> Result<()>;
-    fn output(&self) -> Result<(BufReader<File>, BufReader<File>)>;
+    /// The broker the module's output goes to, None if it isn't captured.
+    fn output(&self) -> Option<&OutputBroker>;
+    /// The size of the stack the module has to run on, None for the default stack of the thread running it.
+    fn stack_size(&self) -> Option<usize> {
+        None
+    }
 }
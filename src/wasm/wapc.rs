@@ -0,0 +1,626 @@
+//! Hosting waPC guests without the waSCC host.
+//!
+//! A waPC guest is a module exporting `__guest_call`, which the host calls with the lengths of an operation name
+//! and a payload. The guest then asks for both with `__guest_request` and answers with `__guest_response` or
+//! `__guest_error`, all imported from the `wapc` module. waSCC actors are waPC guests too, but they expect the waSCC
+//! host and its capability providers around them. Plain waPC guests only need the calls to be dispatched, so they
+//! are run here on wasmtime directly, each on a thread of its own like WASI modules.
+//!
+//! Calls from the guest to the host, `__host_call`, fail: there are no capability providers to handle them.
+//!
+//! The guest's operations are invoked with `WapcGuest::call`, e.g. for exec or health checks, and over HTTP with
+//! `serve_http`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::TryStreamExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use tracing::{debug, info, warn};
+use wasi_common::*;
+use wasmtime::*;
+use wasmtime_wasi::*;
+
+use super::diagnostics::{Abort, AssemblyScriptAbort, ASSEMBLYSCRIPT_ABORT};
+use super::engine::EngineConfig;
+use super::output::{LogLine, OutputBroker, Stream};
+use super::wasi::CompiledModule;
+use super::Runtime;
+
+/// The module the host functions of the waPC protocol are imported from.
+const WAPC_MODULE: &str = "wapc";
+
+/// The functions a guest may export to set itself up, e.g. to register its operations, called in this order.
+const INIT_FUNCTIONS: &[&str] = &["_start", "wapc_init"];
+
+/// A call of an operation of the guest, answered on `reply`.
+struct Invocation {
+    operation: String,
+    payload: Vec<u8>,
+    reply: oneshot::Sender<super::Result<Vec<u8>>>,
+}
+
+/// WapcRuntime runs a waPC guest, dispatching the invocations of its `WapcGuest` handle until the guest is stopped.
+pub struct WapcRuntime {
+    /// binary module data of the guest
+    module_data: Vec<u8>,
+    /// key/value environment variables made available to the guest through WASI
+    env: HashMap<String, String>,
+    /// the arguments passed as the command-line arguments list through WASI
+    args: Vec<String>,
+    /// the broker the guest's console log goes to
+    output: Option<OutputBroker>,
+    /// the wasm features the guest may use
+    engine_config: EngineConfig,
+    /// the invocations to dispatch, taken once the guest runs
+    invocations: Mutex<Option<UnboundedReceiver<Invocation>>>,
+}
+
+/// WapcGuest invokes the operations of a running waPC guest. Cloning it gives another handle on the same guest.
+#[derive(Clone)]
+pub struct WapcGuest {
+    invocations: UnboundedSender<Invocation>,
+}
+
+impl fmt::Debug for WapcGuest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WapcGuest")
+            .field("running", &!self.invocations.is_closed())
+            .finish()
+    }
+}
+
+impl WapcGuest {
+    /// Invoke the operation of the guest with the given payload, returning the guest's reply. Invocations are
+    /// dispatched one at a time, in the order they were made.
+    pub async fn call(&self, operation: &str, payload: Vec<u8>) -> super::Result<Vec<u8>> {
+        let (reply, replied) = oneshot::channel();
+        self.invocations
+            .unbounded_send(Invocation {
+                operation: operation.to_owned(),
+                payload,
+                reply,
+            })
+            .map_err(|_| format_err!("cannot invoke {}: the guest is not running", operation))?;
+        replied.await.map_err(|_| {
+            format_err!(
+                "cannot invoke {}: the guest stopped before replying",
+                operation
+            )
+        })?
+    }
+
+    /// Stop the guest once the invocations made so far are dispatched.
+    pub fn stop(&self) {
+        self.invocations.close_channel();
+    }
+}
+
+/// Serve HTTP on the given port until `shutdown` completes, invoking the operation named by the path of each POST
+/// request with the request's body as payload, e.g. `POST /HandleRequest`. The guest's reply is the response's body.
+///
+/// The port is bound right away, so a port that is taken fails here rather than in the returned server.
+pub fn serve_http(
+    guest: WapcGuest,
+    port: u16,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> super::Result<impl Future<Output = ()> + Send> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_svc = make_service_fn(move |_| {
+        let guest = guest.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let guest = guest.clone();
+                async move { Ok::<_, Infallible>(handle_http(&guest, req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format_err!("cannot serve HTTP on {}: {}", addr, e))?
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown);
+    Ok(async move {
+        if let Err(e) = server.await {
+            warn!("serving HTTP on {} failed: {}", addr, e);
+        }
+    })
+}
+
+/// Invoke the operation named by the path of the request, see `serve_http`.
+async fn handle_http(guest: &WapcGuest, req: Request<Body>) -> Response<Body> {
+    let response = |status, body: Body| {
+        Response::builder()
+            .status(status)
+            .body(body)
+            .expect("valid response")
+    };
+    let operation = req.uri().path().trim_start_matches('/').to_owned();
+    if operation.is_empty() || operation.contains('/') {
+        return response(StatusCode::NOT_FOUND, Body::empty());
+    }
+    if req.method() != Method::POST {
+        return response(StatusCode::METHOD_NOT_ALLOWED, Body::empty());
+    }
+    let payload = match req.into_body().try_concat().await {
+        Ok(payload) => payload.to_vec(),
+        Err(e) => return response(StatusCode::BAD_REQUEST, Body::from(e.to_string())),
+    };
+    match guest.call(&operation, payload).await {
+        Ok(reply) => response(StatusCode::OK, Body::from(reply)),
+        Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, Body::from(e.to_string())),
+    }
+}
+
+impl Runtime for WapcRuntime {
+    fn run(&self) -> super::Result<()> {
+        let invocations = self
+            .invocations
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| format_err!("the guest already ran"))?;
+        let compiled = CompiledModule::compile(&self.module_data, &self.engine_config)?;
+        let guest = self.instantiate(compiled)?;
+        info!("waPC guest is ready for invocations");
+        for invocation in futures::executor::block_on_stream(invocations) {
+            let result = guest.call(&invocation.operation, invocation.payload);
+            if let Err(e) = &result {
+                debug!("invocation of {} failed: {}", invocation.operation, e);
+            }
+            // it's fine if the caller doesn't wait for the reply anymore
+            invocation.reply.send(result).unwrap_or(());
+        }
+        info!("waPC guest stopped");
+        Ok(())
+    }
+
+    fn output(&self) -> Option<&OutputBroker> {
+        self.output.as_ref()
+    }
+}
+
+/// Subscribers of the guest's output are done once the runtime is, whether the guest ran or not.
+impl Drop for WapcRuntime {
+    fn drop(&mut self) {
+        if let Some(output) = &self.output {
+            output.close();
+        }
+    }
+}
+
+impl WapcRuntime {
+    /// Creates a new WapcRuntime, and the handle to invoke the guest with once it runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `module_data` - the contents of the WebAssembly binary
+    /// * `env` - a collection of key/value pairs containing the environment variables
+    /// * `args` - the arguments passed as the command-line arguments list
+    /// * `output` - the broker the guest's console log goes to
+    pub fn new(
+        module_data: Vec<u8>,
+        env: HashMap<String, String>,
+        args: Vec<String>,
+        output: Option<OutputBroker>,
+    ) -> (Self, WapcGuest) {
+        let (sender, receiver) = unbounded();
+        let runtime = WapcRuntime {
+            module_data,
+            env,
+            args,
+            output,
+            engine_config: EngineConfig::default(),
+            invocations: Mutex::new(Some(receiver)),
+        };
+        (
+            runtime,
+            WapcGuest {
+                invocations: sender,
+            },
+        )
+    }
+
+    /// Enable the given wasm features for the guest.
+    pub fn with_engine_config(mut self, engine_config: EngineConfig) -> Self {
+        self.engine_config = engine_config;
+        self
+    }
+
+    /// Instantiate the guest with the host functions of the waPC protocol and WASI, and let it set itself up.
+    fn instantiate(&self, compiled: CompiledModule) -> super::Result<Guest> {
+        let CompiledModule { store, module } = compiled;
+        let global_exports = store.borrow().global_exports().clone();
+        let wasi_ctx = WasiCtxBuilder::new()
+            .args(&self.args)
+            .envs(&self.env)
+            .build()?;
+        let wasi_inst = Instance::from_handle(
+            &store,
+            instantiate_wasi_with_context(global_exports, wasi_ctx)?,
+        );
+        let host = Rc::new(Host {
+            state: RefCell::new(CallState::default()),
+            memory: Rc::new(RefCell::new(None)),
+            aborted: Rc::new(RefCell::new(None)),
+            output: self.output.clone(),
+        });
+        let imports = module
+            .borrow()
+            .imports()
+            .iter()
+            .map(|i| {
+                let module_name = i.module().as_str();
+                let field_name = i.name().as_str();
+                match (module_name, i.r#type()) {
+                    (WAPC_MODULE, ExternType::Func(ty)) => {
+                        let func = HostFunc::new(field_name, host.clone())?;
+                        Ok(Extern::Func(HostRef::new(Func::new(
+                            &store,
+                            ty.clone(),
+                            Rc::new(func),
+                        ))))
+                    }
+                    // guests written in AssemblyScript
+                    _ if (module_name, field_name) == ASSEMBLYSCRIPT_ABORT => {
+                        let abort = AssemblyScriptAbort {
+                            memory: host.memory.clone(),
+                            aborted: host.aborted.clone(),
+                        };
+                        Ok(Extern::Func(HostRef::new(Func::new(
+                            &store,
+                            AssemblyScriptAbort::func_type(),
+                            Rc::new(abort),
+                        ))))
+                    }
+                    _ => match wasi_inst.find_export_by_name(field_name) {
+                        Some(export) => Ok(export.clone()),
+                        None => failure::bail!(
+                            "Import {} was not found in module {}",
+                            field_name,
+                            module_name
+                        ),
+                    },
+                }
+            })
+            .collect::<super::Result<Vec<_>>>()?;
+
+        let instance = Instance::new(&store, &module, &imports)
+            .map_err(|e| format_err!("unable to instantiate the guest: {}", e))?;
+        let memory = instance
+            .find_export_by_name("memory")
+            .and_then(|export| export.memory())
+            .cloned()
+            .ok_or_else(|| format_err!("the guest exports no memory"))?;
+        *host.memory.borrow_mut() = Some(memory);
+        let guest_call = instance
+            .find_export_by_name("__guest_call")
+            .and_then(|export| export.func())
+            .cloned()
+            .ok_or_else(|| format_err!("the guest exports no __guest_call, it is no waPC guest"))?;
+        for name in INIT_FUNCTIONS {
+            if let Some(func) = instance
+                .find_export_by_name(name)
+                .and_then(|export| export.func())
+            {
+                func.borrow()
+                    .call(&[])
+                    .map_err(|trap| match host.aborted.borrow_mut().take() {
+                        Some(abort) => format_err!("{} aborted: {}", name, abort),
+                        None => format_err!("{} failed: {}", name, trap.borrow().message()),
+                    })?;
+            }
+        }
+        Ok(Guest {
+            host,
+            guest_call,
+            _instance: instance,
+        })
+    }
+}
+
+/// An instantiated guest, on the thread of its store.
+struct Guest {
+    host: Rc<Host>,
+    guest_call: HostRef<Func>,
+    /// the exports of the guest live as long as its instance
+    _instance: Instance,
+}
+
+impl Guest {
+    /// Invoke the operation with the given payload.
+    fn call(&self, operation: &str, payload: Vec<u8>) -> super::Result<Vec<u8>> {
+        let params = [
+            Val::I32(operation.len() as i32),
+            Val::I32(payload.len() as i32),
+        ];
+        *self.host.state.borrow_mut() = CallState {
+            operation: operation.to_owned(),
+            payload,
+            ..Default::default()
+        };
+        let results = self.guest_call.borrow().call(&params).map_err(|trap| {
+            match self.host.aborted.borrow_mut().take() {
+                Some(abort) => format_err!("{} aborted: {}", operation, abort),
+                None => format_err!("{} failed: {}", operation, trap.borrow().message()),
+            }
+        })?;
+        let mut state = self.host.state.borrow_mut();
+        match results.first().map(Val::unwrap_i32) {
+            Some(1) => Ok(state.response.take().unwrap_or_default()),
+            _ => Err(match state.error.take() {
+                Some(error) => format_err!("{} failed: {}", operation, error),
+                None => format_err!("{} failed without an error", operation),
+            }),
+        }
+    }
+}
+
+/// What the host and the guest exchange during an invocation.
+#[derive(Default)]
+struct CallState {
+    operation: String,
+    payload: Vec<u8>,
+    response: Option<Vec<u8>>,
+    error: Option<String>,
+    /// the error of the guest's last call to the host
+    host_error: Option<String>,
+}
+
+/// The host side of the waPC protocol, shared by the host functions of a guest.
+struct Host {
+    state: RefCell<CallState>,
+    /// the guest's memory, once it is instantiated
+    memory: Rc<RefCell<Option<HostRef<Memory>>>>,
+    /// why the guest aborted during the current invocation, if it is written in AssemblyScript
+    aborted: Rc<RefCell<Option<Abort>>>,
+    output: Option<OutputBroker>,
+}
+
+impl Host {
+    /// The guest's memory, as the bytes from `ptr` to `ptr + len`.
+    fn with_memory<T>(
+        &self,
+        ptr: &Val,
+        len: usize,
+        f: impl FnOnce(&mut [u8]) -> T,
+    ) -> std::result::Result<T, HostRef<Trap>> {
+        let memory = self.memory.borrow();
+        let memory = memory
+            .as_ref()
+            .ok_or_else(|| trap("the guest called the host before it was instantiated"))?
+            .borrow();
+        let start = ptr.unwrap_i32() as u32 as usize;
+        let end = start
+            .checked_add(len)
+            .filter(|end| *end <= memory.data_size())
+            .ok_or_else(|| trap("the guest passed memory out of bounds"))?;
+        // the guest doesn't run while the host function does, so nothing else touches its memory
+        let data = unsafe { std::slice::from_raw_parts_mut(memory.data_ptr(), memory.data_size()) };
+        Ok(f(&mut data[start..end]))
+    }
+
+    fn read(&self, ptr: &Val, len: &Val) -> std::result::Result<Vec<u8>, HostRef<Trap>> {
+        self.with_memory(ptr, len.unwrap_i32() as u32 as usize, |data| data.to_vec())
+    }
+
+    fn read_string(&self, ptr: &Val, len: &Val) -> std::result::Result<String, HostRef<Trap>> {
+        self.read(ptr, len)
+            .map(|data| String::from_utf8_lossy(&data).into_owned())
+    }
+
+    fn write(&self, ptr: &Val, data: &[u8]) -> std::result::Result<(), HostRef<Trap>> {
+        self.with_memory(ptr, data.len(), |memory| memory.copy_from_slice(data))
+    }
+}
+
+fn trap(message: &str) -> HostRef<Trap> {
+    HostRef::new(Trap::new(message))
+}
+
+/// The host functions of the waPC protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HostFuncKind {
+    GuestRequest,
+    GuestResponse,
+    GuestError,
+    HostCall,
+    HostResponse,
+    HostResponseLen,
+    HostError,
+    HostErrorLen,
+    ConsoleLog,
+}
+
+/// A host function imported by the guest.
+struct HostFunc {
+    kind: HostFuncKind,
+    host: Rc<Host>,
+}
+
+impl HostFunc {
+    fn new(name: &str, host: Rc<Host>) -> super::Result<Self> {
+        let kind = match name {
+            "__guest_request" => HostFuncKind::GuestRequest,
+            "__guest_response" => HostFuncKind::GuestResponse,
+            "__guest_error" => HostFuncKind::GuestError,
+            "__host_call" => HostFuncKind::HostCall,
+            "__host_response" => HostFuncKind::HostResponse,
+            "__host_response_len" => HostFuncKind::HostResponseLen,
+            "__host_error" => HostFuncKind::HostError,
+            "__host_error_len" => HostFuncKind::HostErrorLen,
+            "__console_log" => HostFuncKind::ConsoleLog,
+            _ => failure::bail!("Import {} was not found in module {}", name, WAPC_MODULE),
+        };
+        Ok(HostFunc { kind, host })
+    }
+}
+
+impl Callable for HostFunc {
+    fn call(&self, params: &[Val], results: &mut [Val]) -> std::result::Result<(), HostRef<Trap>> {
+        let host = &self.host;
+        match self.kind {
+            // the operation and the payload of the current invocation
+            HostFuncKind::GuestRequest => {
+                let (operation, payload) = {
+                    let state = host.state.borrow();
+                    (state.operation.clone(), state.payload.clone())
+                };
+                host.write(&params[0], operation.as_bytes())?;
+                host.write(&params[1], &payload)?;
+            }
+            HostFuncKind::GuestResponse => {
+                host.state.borrow_mut().response = Some(host.read(&params[0], &params[1])?);
+            }
+            HostFuncKind::GuestError => {
+                host.state.borrow_mut().error = Some(host.read_string(&params[0], &params[1])?);
+            }
+            // the namespace and the operation are the two pairs of arguments before the payload, whether or not
+            // the guest passes a binding before them
+            HostFuncKind::HostCall => {
+                let n = params.len();
+                if n < 6 {
+                    return Err(trap("__host_call takes at least 6 arguments"));
+                }
+                let namespace = host.read_string(&params[n - 6], &params[n - 5])?;
+                let operation = host.read_string(&params[n - 4], &params[n - 3])?;
+                host.state.borrow_mut().host_error = Some(format!(
+                    "no capability provider handles {} of {}",
+                    operation, namespace
+                ));
+                results[0] = Val::I32(0);
+            }
+            // calls to the host never succeed, so there is never a response
+            HostFuncKind::HostResponse => {}
+            HostFuncKind::HostResponseLen => results[0] = Val::I32(0),
+            HostFuncKind::HostError => {
+                let error = host.state.borrow().host_error.clone().unwrap_or_default();
+                host.write(&params[0], error.as_bytes())?;
+            }
+            HostFuncKind::HostErrorLen => {
+                let len = host
+                    .state
+                    .borrow()
+                    .host_error
+                    .as_ref()
+                    .map_or(0, String::len);
+                results[0] = Val::I32(len as i32);
+            }
+            HostFuncKind::ConsoleLog => {
+                let content = host.read(&params[0], &params[1])?;
+                match &host.output {
+                    Some(output) => output.publish(LogLine {
+                        timestamp: Utc::now(),
+                        stream: Stream::Stdout,
+                        partial: false,
+                        content,
+                    }),
+                    None => info!("{}", String::from_utf8_lossy(&content)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A guest echoing the payload of every invocation, placing the operation at 0 and the payload at 256.
+    fn echo_guest() -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // type section: (i32, i32) -> () and (i32, i32) -> i32
+        module.extend_from_slice(&[
+            1, 12, 2, 0x60, 2, 0x7f, 0x7f, 0, 0x60, 2, 0x7f, 0x7f, 1, 0x7f,
+        ]);
+        // import section: wapc.__guest_request and wapc.__guest_response, both of type 0
+        let mut imports = vec![2];
+        for field in &["__guest_request", "__guest_response"] {
+            imports.push(WAPC_MODULE.len() as u8);
+            imports.extend_from_slice(WAPC_MODULE.as_bytes());
+            imports.push(field.len() as u8);
+            imports.extend_from_slice(field.as_bytes());
+            imports.extend_from_slice(&[0, 0]);
+        }
+        module.push(2);
+        module.push(imports.len() as u8);
+        module.extend(imports);
+        // function section: __guest_call of type 1
+        module.extend_from_slice(&[3, 2, 1, 1]);
+        // memory section: one page
+        module.extend_from_slice(&[5, 3, 1, 0, 1]);
+        // export section: the memory and __guest_call, the function after the two imports
+        module.extend_from_slice(&[7, 25, 2, 6]);
+        module.extend_from_slice(b"memory");
+        module.extend_from_slice(&[2, 0, 12]);
+        module.extend_from_slice(b"__guest_call");
+        module.extend_from_slice(&[0, 2]);
+        // code section: __guest_request(0, 256); __guest_response(256, payload_len); return 1
+        module.extend_from_slice(&[
+            10, 20, 1, 18, 0, 0x41, 0, 0x41, 0x80, 2, 0x10, 0, 0x41, 0x80, 2, 0x20, 1, 0x10, 1,
+            0x41, 1, 0x0b,
+        ]);
+        module
+    }
+
+    #[test]
+    fn test_call() {
+        let (runtime, guest) = WapcRuntime::new(echo_guest(), HashMap::new(), vec![], None);
+        let running = std::thread::spawn(move || runtime.run());
+        let reply = futures::executor::block_on(guest.call("Echo", b"hello".to_vec()))
+            .expect("invocation of Echo");
+        assert_eq!(b"hello".to_vec(), reply);
+
+        guest.stop();
+        running.join().unwrap().expect("guest stopped");
+        futures::executor::block_on(guest.call("Echo", vec![])).expect_err("guest stopped");
+    }
+
+    #[test]
+    fn test_handle_http() {
+        let (runtime, guest) = WapcRuntime::new(echo_guest(), HashMap::new(), vec![], None);
+        let running = std::thread::spawn(move || runtime.run());
+        let request = |method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+        futures::executor::block_on(async {
+            let res = handle_http(&guest, request(Method::POST, "/Echo")).await;
+            assert_eq!(StatusCode::OK, res.status());
+            let body = res.into_body().try_concat().await.unwrap();
+            assert_eq!(b"hello", &body[..]);
+
+            let res = handle_http(&guest, request(Method::GET, "/Echo")).await;
+            assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+            let res = handle_http(&guest, request(Method::POST, "/")).await;
+            assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+            guest.stop();
+            let res = handle_http(&guest, request(Method::POST, "/Echo")).await;
+            assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        });
+        running.join().unwrap().expect("guest stopped");
+    }
+
+    #[test]
+    fn test_not_a_guest() {
+        // a module without __guest_call
+        let (runtime, guest) =
+            WapcRuntime::new(b"\0asm\x01\0\0\0".to_vec(), HashMap::new(), vec![], None);
+        runtime.run().expect_err("no waPC guest");
+        futures::executor::block_on(guest.call("Echo", vec![])).expect_err("guest failed");
+    }
+}
@@ -1,55 +1,129 @@
+//! Running waSCC actors.
+//!
+//! The actors run in the waSCC host, which is only built with the `wascc` feature. Without it, the functions
+//! running actors fail, so wok builds where waSCC's native capability providers aren't available, e.g. on
+//! Windows.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "wascc")]
 use std::collections::HashMap;
+#[cfg(feature = "wascc")]
+use std::path::Path;
 
-use log::info;
+#[cfg(feature = "wascc")]
+use tracing::info;
+#[cfg(feature = "wascc")]
 use wascc_host::{host, Actor, NativeCapability};
 
+#[cfg(feature = "wascc")]
+use super::wascc_logging::{LoggingProvider, LOGGING_CAPABILITY};
+
 /// The name of the HTTP capability.
+#[cfg(feature = "wascc")]
 const HTTP_CAPABILITY: &str = "wascc:http_server";
 
 /// Kubernetes' view of environment variables is an unordered map of string to string.
 pub type EnvVars = std::collections::HashMap<String, String>;
 
+/// The default location of the HTTP capability provider library.
 #[cfg(target_os = "linux")]
-const HTTP_LIB: &str = "./lib/libwascc_httpsrv.so";
+pub const HTTP_LIB: &str = "./lib/libwascc_httpsrv.so";
 #[cfg(target_os = "macos")]
-const HTTP_LIB: &str = "./lib/libwascc_httpsrv.dylib";
+pub const HTTP_LIB: &str = "./lib/libwascc_httpsrv.dylib";
+#[cfg(windows)]
+pub const HTTP_LIB: &str = "./lib/wascc_httpsrv.dll";
 
 /// This registers all of the native capabilities known to this host.
 ///
-/// In the future, we'll do this dynamically. For now, though, these are the
-/// caps that we know we need in order to wire up Kubernetes
-pub fn register_native_capabilities() -> Result<(), failure::Error> {
-    let data = NativeCapability::from_file(HTTP_LIB)
-        .map_err(|e| format_err!("Failed to read HTTP capability {}: {}", HTTP_LIB, e))?;
-    host::add_native_capability(data)
-        .map_err(|e| format_err!("Failed to load HTTP capability: {}", e))
+/// The built-in logging capability is always registered, the other capabilities are loaded from the
+/// given provider libraries. By default, that is the HTTP capability we need in order to wire up Kubernetes.
+#[cfg(feature = "wascc")]
+pub fn register_native_capabilities<P: AsRef<Path>>(libraries: &[P]) -> Result<(), failure::Error> {
+    let logging = NativeCapability::from_instance(LoggingProvider::new())
+        .map_err(|e| format_err!("Failed to create logging capability: {}", e))?;
+    host::add_native_capability(logging)
+        .map_err(|e| format_err!("Failed to load logging capability: {}", e))?;
+
+    libraries.iter().try_for_each(|lib| {
+        let lib = lib.as_ref();
+        let data = NativeCapability::from_file(lib)
+            .map_err(|e| format_err!("Failed to read capability {}: {}", lib.display(), e))?;
+        host::add_native_capability(data)
+            .map_err(|e| format_err!("Failed to load capability {}: {}", lib.display(), e))
+    })
 }
 
 /// Run a WasCC module inside of the host, configuring it to handle HTTP requests.
 ///
-/// This bootstraps an HTTP host, using the value of the env's `PORT` key to expose a port.
-pub fn wascc_run_http(data: Vec<u8>, env: EnvVars, key: &str) -> Result<(), failure::Error> {
+/// This bootstraps an HTTP host listening on the given port. If no port is given, the value of the
+/// env's `PORT` key is used, falling back to port 80.
+/// Any additional capabilities are configured for the actor as well. If one of them is the
+/// HTTP capability, its values are merged over the generated HTTP configuration.
+#[cfg(feature = "wascc")]
+pub fn wascc_run_http(
+    data: Vec<u8>,
+    env: EnvVars,
+    key: &str,
+    port: Option<u16>,
+    capabilities: Vec<Capability>,
+) -> Result<(), failure::Error> {
     let mut httpenv: HashMap<String, String> = HashMap::new();
     httpenv.insert(
         "PORT".into(),
-        env.get("PORT")
-            .map(|a| a.to_string())
+        port.map(|p| p.to_string())
+            .or_else(|| env.get("PORT").map(|a| a.to_string()))
             .unwrap_or_else(|| "80".to_string()),
     );
 
-    wascc_run(
-        data,
-        key,
-        vec![Capability {
-            name: HTTP_CAPABILITY,
-            env,
-        }],
-    )
+    let mut caps = vec![];
+    for cap in capabilities {
+        if cap.name == HTTP_CAPABILITY {
+            httpenv.extend(cap.env);
+        } else {
+            caps.push(cap);
+        }
+    }
+    caps.insert(
+        0,
+        Capability {
+            name: HTTP_CAPABILITY.to_owned(),
+            env: httpenv,
+        },
+    );
+
+    wascc_run(data, key, caps)
+}
+
+/// Determine the public key of the actor embedded in a signed waSCC module.
+///
+/// If `pinned` is given, the embedded key must match it, otherwise an error is returned. This allows callers
+/// to make sure the module that was retrieved is the one they expected.
+#[cfg(feature = "wascc")]
+pub fn actor_key(data: &[u8], pinned: Option<&str>) -> Result<String, failure::Error> {
+    let actor =
+        Actor::from_bytes(data.to_vec()).map_err(|e| format_err!("Error loading WASM: {}", e))?;
+    let key = actor.public_key();
+    match pinned {
+        Some(expected) if expected != key => Err(format_err!(
+            "Actor key mismatch: expected {}, but the module is signed with {}",
+            expected,
+            key
+        )),
+        _ => Ok(key),
+    }
+}
+
+/// Invoke an operation of a running waSCC actor with the given payload, returning the actor's reply.
+#[cfg(feature = "wascc")]
+pub fn wascc_call(key: &str, operation: &str, payload: &[u8]) -> Result<Vec<u8>, failure::Error> {
+    host::call_actor(key, operation, payload)
+        .map_err(|e| format_err!("Error invoking {} on actor {}: {}", operation, key, e))
 }
 
 /// Stop a running waSCC actor.
-pub fn wascc_stop(key: &str) -> Result<(), wascc_host::errors::Error> {
-    host::remove_actor(key)
+#[cfg(feature = "wascc")]
+pub fn wascc_stop(key: &str) -> Result<(), failure::Error> {
+    host::remove_actor(key).map_err(|e| format_err!("Error removing actor {}: {}", key, e))
 }
 
 /// Capability describes a waSCC capability.
@@ -57,15 +131,37 @@ pub fn wascc_stop(key: &str) -> Result<(), wascc_host::errors::Error> {
 /// Capabilities are made available to actors through a two-part processthread:
 /// - They must be registered
 /// - For each actor, the capability must be configured
+#[derive(Clone, Debug, PartialEq)]
 pub struct Capability {
-    name: &'static str,
-    env: EnvVars,
+    /// the capability ID, e.g. `wascc:messaging`
+    pub name: String,
+    /// the configuration values passed to the capability provider for the actor
+    pub env: EnvVars,
+}
+
+/// Parse a set of capability configurations from their JSON representation.
+///
+/// The input is a JSON object mapping a capability ID to the configuration values for that
+/// capability, for example:
+///
+/// ```json
+/// {"wascc:messaging": {"SUBSCRIPTION": "orders"}, "wascc:keyvalue": {"URL": "redis://127.0.0.1:6379"}}
+/// ```
+pub fn parse_capabilities(raw: &str) -> Result<Vec<Capability>, failure::Error> {
+    let caps: BTreeMap<String, EnvVars> = serde_json::from_str(raw)
+        .map_err(|e| format_err!("Invalid capability configuration: {}", e))?;
+    Ok(caps
+        .into_iter()
+        .map(|(name, env)| Capability { name, env })
+        .collect())
 }
 
 /// Run the given WASM data as a waSCC actor with the given public key.
 ///
 /// The provided capabilities will be configured for this actor, but the capabilities
 /// must first be loaded into the host by some other process, such as register_native_capabilities().
+/// The logging capability is only configured if the actor is signed with a claim for it.
+#[cfg(feature = "wascc")]
 pub fn wascc_run(
     data: Vec<u8>,
     key: &str,
@@ -73,34 +169,94 @@ pub fn wascc_run(
 ) -> Result<(), failure::Error> {
     info!("wascc run");
     let load = Actor::from_bytes(data).map_err(|e| format_err!("Error loading WASM: {}", e))?;
+    let claims = load.capabilities();
     host::add_actor(load).map_err(|e| format_err!("Error adding actor: {}", e))?;
 
-    capabilities.iter().try_for_each(|cap| {
-        info!("configuring capability {}", cap.name);
-        host::configure(key, cap.name, cap.env.clone())
-            .map_err(|e| format_err!("Error configuring capabilities for module: {}", e))
-    })?;
+    capabilities
+        .iter()
+        .filter(|cap| cap.name != LOGGING_CAPABILITY || claims.contains(&cap.name))
+        .try_for_each(|cap| {
+            info!("configuring capability {}", cap.name);
+            host::configure(key, &cap.name, cap.env.clone())
+                .map_err(|e| format_err!("Error configuring capabilities for module: {}", e))
+        })?;
     info!("Instance executing");
     Ok(())
 }
 
+#[cfg(not(feature = "wascc"))]
+pub use unavailable::*;
+
+/// The functions running actors in a build without the waSCC host.
+#[cfg(not(feature = "wascc"))]
+mod unavailable {
+    use std::path::Path;
+
+    use super::{Capability, EnvVars};
+
+    const UNAVAILABLE: &str =
+        "waSCC actors are not available, wok was built without the wascc feature";
+
+    pub fn register_native_capabilities<P: AsRef<Path>>(
+        _libraries: &[P],
+    ) -> Result<(), failure::Error> {
+        failure::bail!(UNAVAILABLE)
+    }
+
+    pub fn wascc_run_http(
+        _data: Vec<u8>,
+        _env: EnvVars,
+        _key: &str,
+        _port: Option<u16>,
+        _capabilities: Vec<Capability>,
+    ) -> Result<(), failure::Error> {
+        failure::bail!(UNAVAILABLE)
+    }
+
+    pub fn actor_key(_data: &[u8], _pinned: Option<&str>) -> Result<String, failure::Error> {
+        failure::bail!(UNAVAILABLE)
+    }
+
+    pub fn wascc_call(
+        _key: &str,
+        _operation: &str,
+        _payload: &[u8],
+    ) -> Result<Vec<u8>, failure::Error> {
+        failure::bail!(UNAVAILABLE)
+    }
+
+    pub fn wascc_stop(_key: &str) -> Result<(), failure::Error> {
+        failure::bail!(UNAVAILABLE)
+    }
+
+    pub fn wascc_run(
+        _data: Vec<u8>,
+        _key: &str,
+        _capabilities: Vec<Capability>,
+    ) -> Result<(), failure::Error> {
+        failure::bail!(UNAVAILABLE)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    #[cfg(target_os = "linux")]
+    #[cfg(all(feature = "wascc", target_os = "linux"))]
     const ECHO_LIB: &str = "./lib/libecho_provider.so";
-    #[cfg(target_os = "macos")]
+    #[cfg(all(feature = "wascc", target_os = "macos"))]
     const ECHO_LIB: &str = "./lib/libecho_provider.dylib";
 
+    #[cfg(feature = "wascc")]
     #[test]
     fn test_register_native_capabilities() {
-        register_native_capabilities().expect("HTTP capability is registered");
+        register_native_capabilities(&[HTTP_LIB]).expect("HTTP capability is registered");
     }
 
+    #[cfg(feature = "wascc")]
     #[test]
     fn test_wascc_run() {
-        //register_native_capabilities().expect("HTTP capability is registered");
+        //register_native_capabilities(&[HTTP_LIB]).expect("HTTP capability is registered");
         // Open file
         let data = std::fs::read("./testdata/greet_actor_signed.wasm").expect("read the wasm file");
         // Send into wascc_run
@@ -108,6 +264,8 @@ mod test {
             data,
             EnvVars::new(),
             "MADK3R3H47FGXN5F4HWPSJH4WCKDWKXQBBIOVI7YEPEYEMGJ2GDFIFE5",
+            None,
+            vec![],
         )
         .expect("successfully executed a WASM");
 
@@ -117,6 +275,7 @@ mod test {
             .expect("Removed the actor");
     }
 
+    #[cfg(feature = "wascc")]
     #[test]
     fn test_wascc_echo() {
         let data = NativeCapability::from_file(ECHO_LIB).expect("loaded echo library");
@@ -130,10 +289,45 @@ mod test {
             wasm,
             key,
             vec![Capability {
-                name: "wok:echoProvider",
+                name: "wok:echoProvider".to_owned(),
                 env: EnvVars::new(),
             }],
         )
         .expect("completed echo run")
     }
+
+    #[cfg(feature = "wascc")]
+    #[test]
+    fn test_actor_key() {
+        let data = std::fs::read("./testdata/greet_actor_signed.wasm").expect("read the wasm file");
+        let key = "MADK3R3H47FGXN5F4HWPSJH4WCKDWKXQBBIOVI7YEPEYEMGJ2GDFIFE5";
+
+        assert_eq!(key, actor_key(&data, None).expect("key from module"));
+        assert_eq!(key, actor_key(&data, Some(key)).expect("key matches pin"));
+        actor_key(
+            &data,
+            Some("MDAYLDTOZEHQFPB3CL5PAFY5UTNCW32P54XGWYX3FOM2UBRYNCP3I3BF"),
+        )
+        .expect_err("mismatched pin is rejected");
+    }
+
+    #[test]
+    fn test_parse_capabilities() {
+        let caps = parse_capabilities(
+            r#"{"wascc:messaging": {"SUBSCRIPTION": "orders"}, "wascc:keyvalue": {"URL": "redis://127.0.0.1:6379"}}"#,
+        )
+        .expect("parsed capabilities");
+        assert_eq!(2, caps.len());
+        assert_eq!("wascc:keyvalue", caps[0].name);
+        assert_eq!(
+            Some(&"redis://127.0.0.1:6379".to_owned()),
+            caps[0].env.get("URL")
+        );
+        assert_eq!("wascc:messaging", caps[1].name);
+        assert_eq!(Some(&"orders".to_owned()), caps[1].env.get("SUBSCRIPTION"));
+
+        parse_capabilities("not json").expect_err("invalid JSON is rejected");
+        parse_capabilities(r#"{"wascc:messaging": "orders"}"#)
+            .expect_err("capability values must be a map");
+    }
 }
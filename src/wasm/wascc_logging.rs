@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::RwLock;
+
+use chrono::{SecondsFormat, Utc};
+use prost::Message;
+use tracing::debug;
+use wascc_codec::capabilities::{CapabilityProvider, Dispatcher};
+use wascc_codec::core::{CapabilityConfiguration, OP_CONFIGURE, OP_REMOVE_ACTOR};
+
+/// The ID of the logging capability provided by wok.
+pub const LOGGING_CAPABILITY: &str = "wok:logging";
+
+/// The operation an actor invokes to write a log line.
+pub const OP_WRITE_LOG: &str = "WriteLog";
+
+/// The configuration key holding the path of the CRI log file for an actor.
+pub const LOG_PATH_KEY: &str = "LOG_PATH";
+
+/// The actor name the waSCC host uses when it sends configuration to a provider.
+const SYSTEM_ACTOR: &str = "system";
+
+/// WriteLogRequest is the payload of an OP_WRITE_LOG invocation.
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteLogRequest {
+    /// the log level, following the numbering of the `log` crate (1 = error ... 5 = trace)
+    #[prost(uint32, tag = "1")]
+    pub level: u32,
+    /// the log message
+    #[prost(string, tag = "2")]
+    pub body: String,
+}
+
+/// LoggingProvider is a native waSCC capability provider that writes actor log lines into the
+/// container's CRI log file, so actor logs show up in `kubectl logs` just like WASI stdout does.
+#[derive(Default)]
+pub struct LoggingProvider {
+    /// the open log files, keyed by the public key of the actor writing to them
+    files: RwLock<HashMap<String, File>>,
+}
+
+impl LoggingProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn configure(&self, config: CapabilityConfiguration) -> Result<Vec<u8>, Box<dyn Error>> {
+        let path = config
+            .values
+            .get(LOG_PATH_KEY)
+            .ok_or_else(|| format!("missing {} configuration value", LOG_PATH_KEY))?;
+        debug!("actor {} logs to {}", config.module, path);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.files.write().unwrap().insert(config.module, file);
+        Ok(vec![])
+    }
+
+    fn remove_actor(&self, config: CapabilityConfiguration) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.files.write().unwrap().remove(&config.module);
+        Ok(vec![])
+    }
+
+    fn write_log(&self, actor: &str, req: WriteLogRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut files = self.files.write().unwrap();
+        let file = files
+            .get_mut(actor)
+            .ok_or_else(|| format!("actor {} has not been configured for logging", actor))?;
+        file.write_all(cri_log_line(&req).as_bytes())?;
+        Ok(vec![])
+    }
+}
+
+impl CapabilityProvider for LoggingProvider {
+    fn capability_id(&self) -> &'static str {
+        LOGGING_CAPABILITY
+    }
+
+    fn configure_dispatch(&self, _dispatcher: Box<dyn Dispatcher>) -> Result<(), Box<dyn Error>> {
+        // The logging provider never calls back into actors.
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "wok logging provider"
+    }
+
+    fn handle_call(&self, actor: &str, op: &str, msg: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match op {
+            OP_CONFIGURE if actor == SYSTEM_ACTOR => {
+                self.configure(CapabilityConfiguration::decode(msg)?)
+            }
+            OP_REMOVE_ACTOR if actor == SYSTEM_ACTOR => {
+                self.remove_actor(CapabilityConfiguration::decode(msg)?)
+            }
+            OP_WRITE_LOG => self.write_log(actor, WriteLogRequest::decode(msg)?),
+            _ => Err(format!("unsupported operation {}", op).into()),
+        }
+    }
+}
+
+/// Format a log request as a line in the CRI logging format:
+///
+/// `<RFC3339Nano timestamp> <stream> <tag> <message>`
+///
+/// Errors and warnings go to the stderr stream, everything else to stdout. The message itself is a
+/// structured `level=<level> msg=<quoted message>` pair.
+fn cri_log_line(req: &WriteLogRequest) -> String {
+    let (stream, level) = match req.level {
+        1 => ("stderr", "error"),
+        2 => ("stderr", "warn"),
+        3 => ("stdout", "info"),
+        4 => ("stdout", "debug"),
+        _ => ("stdout", "trace"),
+    };
+    format!(
+        "{} {} F level={} msg={}\n",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+        stream,
+        level,
+        serde_json::Value::from(req.body.as_str())
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode<M: Message>(msg: M) -> Vec<u8> {
+        let mut buf = vec![];
+        msg.encode(&mut buf).expect("encoded message");
+        buf
+    }
+
+    #[test]
+    fn test_write_log() {
+        let dir = tempfile::tempdir().expect("created temp dir");
+        let log_path = dir.path().join("0.log");
+        let provider = LoggingProvider::new();
+
+        // writing before the actor is configured fails
+        provider
+            .handle_call(
+                "actor",
+                OP_WRITE_LOG,
+                &encode(WriteLogRequest {
+                    level: 3,
+                    body: "hello".to_owned(),
+                }),
+            )
+            .expect_err("actor is not configured");
+
+        let mut values = HashMap::new();
+        values.insert(
+            LOG_PATH_KEY.to_owned(),
+            log_path.to_str().unwrap().to_owned(),
+        );
+        provider
+            .handle_call(
+                SYSTEM_ACTOR,
+                OP_CONFIGURE,
+                &encode(CapabilityConfiguration {
+                    module: "actor".to_owned(),
+                    values,
+                }),
+            )
+            .expect("configured actor");
+
+        provider
+            .handle_call(
+                "actor",
+                OP_WRITE_LOG,
+                &encode(WriteLogRequest {
+                    level: 1,
+                    body: "something \"bad\" happened".to_owned(),
+                }),
+            )
+            .expect("wrote log line");
+
+        let logs = std::fs::read_to_string(&log_path).expect("read log file");
+        assert!(logs.ends_with(" stderr F level=error msg=\"something \\\"bad\\\" happened\"\n"));
+    }
+}
@@ -1,14 +1,19 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
 
-use log::info;
 use tempfile::NamedTempFile;
+use tracing::{debug, info, info_span};
 use wasi_common::*;
 use wasmtime::*;
 use wasmtime_wasi::*;
 
+use super::diagnostics::{rust_panic_in, Abort, AssemblyScriptAbort, ASSEMBLYSCRIPT_ABORT};
+use super::engine::EngineConfig;
+use super::handles::OpenHandles;
+use super::output::{OutputBroker, Stream};
 use super::Runtime;
 
 /// WasiRuntime provides a WASI compatible runtime. A runtime should be used for
@@ -24,30 +29,190 @@ pub struct WasiRuntime {
     /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     /// the same path will be allowed in the runtime
     dirs: HashMap<String, Option<String>>,
-    /// Handle to stdout
+    /// the broker the module's output goes to
+    output: Option<OutputBroker>,
+    /// the files the module writes its stdout and stderr to, captured by the broker
     stdout: Option<NamedTempFile>,
-    /// handle to stderr
     stderr: Option<NamedTempFile>,
+    /// whether the module uses the host's stdin, stdout and stderr when its output isn't captured
+    inherit_stdio: bool,
+    /// the wasm features the module may use
+    engine_config: EngineConfig,
+    /// the exported function called once the module is instantiated. When it isn't given, only the
+    /// module's start function runs.
+    entrypoint: Option<String>,
+    /// the host directory preopened as the module's working directory, and its path in the runtime
+    working_dir: Option<(PathBuf, String)>,
+    /// the size of the stack the module runs on, if not the default of its thread
+    stack_size: Option<usize>,
+    /// how many files and directories the module may have open at once, besides stdio and the preopened ones
+    max_open_handles: Option<usize>,
+}
+
+/// A module compiled into a store of its own, ready to be instantiated once.
+///
+/// Compiling is the slow part of starting a module, so it can be done ahead of time, e.g. by the
+/// warm pool. Stores can't be moved between threads, so the module has to be run on the thread that
+/// compiled it.
+///
+/// TODO: share compiled modules between the containers of the same image, keyed by the module's digest and
+/// the engine's configuration. This needs a wasmtime whose `Module` is `Send + Sync` and can be instantiated
+/// in any store of its engine. In the version we use, a module belongs to the store it was compiled into,
+/// and stores are neither `Send` nor ever free the instances they hold, so every container compiles its
+/// own copy. Until then, the warm pool moves the compilation out of `start_container`.
+pub struct CompiledModule {
+    pub(super) store: HostRef<Store>,
+    pub(super) module: HostRef<Module>,
+}
+
+impl CompiledModule {
+    /// Compile the module into a new store of an engine with the given configuration.
+    pub fn compile(module_data: &[u8], engine_config: &EngineConfig) -> super::Result<Self> {
+        let span = info_span!("compile", features = %engine_config);
+        let _enter = span.enter();
+        let started = Instant::now();
+        let engine = HostRef::new(engine_config.engine());
+        let store = HostRef::new(Store::new(&engine));
+        let module = Module::new(&store, module_data)
+            .map_err(|e| format_err!("unable to load module data {}", e))?;
+        debug!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "compiled module"
+        );
+        Ok(CompiledModule {
+            store,
+            module: HostRef::new(module),
+        })
+    }
 }
 
 impl Runtime for WasiRuntime {
     fn run(&self) -> super::Result<()> {
-        let engine = HostRef::new(Engine::default());
-        let store = Store::new(&engine);
+        self.run_compiled(CompiledModule::compile(
+            &self.module_data,
+            &self.engine_config,
+        )?)
+    }
+
+    fn output(&self) -> Option<&OutputBroker> {
+        self.output.as_ref()
+    }
+
+    fn stack_size(&self) -> Option<usize> {
+        self.stack_size
+    }
+}
 
+/// Subscribers of the module's output are done once the runtime is, whether the module ran or not.
+impl Drop for WasiRuntime {
+    fn drop(&mut self) {
+        if let Some(output) = &self.output {
+            output.close();
+        }
+    }
+}
+
+impl WasiRuntime {
+    /// Creates a new WasiRuntime
+    ///
+    /// # Arguments
+    ///
+    /// * `module_path` - the path to the WebAssembly binary
+    /// * `env` - a collection of key/value pairs containing the environment variables
+    /// * `args` - the arguments passed as the command-line arguments list
+    /// * `dirs` - a map of local file system paths to optional path names in the runtime
+    ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
+    ///     the same path will be allowed in the runtime
+    /// * `output` - the broker the module's stdout and stderr go to
+    pub fn new<M: AsRef<Path>>(
+        module_path: M,
+        env: HashMap<String, String>,
+        args: Vec<String>,
+        dirs: HashMap<String, Option<String>>,
+        output: Option<OutputBroker>,
+    ) -> super::Result<Self> {
+        let module_data = std::fs::read(module_path)?;
+        Self::from_module_data(module_data, env, args, dirs, output)
+    }
+
+    /// Creates a new WasiRuntime from the contents of a WebAssembly binary, e.g. one read from the
+    /// module store. The other arguments are the same as for `new`.
+    pub fn from_module_data(
+        module_data: Vec<u8>,
+        env: HashMap<String, String>,
+        args: Vec<String>,
+        dirs: HashMap<String, Option<String>>,
+        output: Option<OutputBroker>,
+    ) -> super::Result<Self> {
+        // We need to use named temp file because we need multiple file handles
+        // and if we are running in the temp dir, we run the possibility of the
+        // temp file getting cleaned out from underneath us while running. They are
+        // kept next to the CRI log file when there is one. These will get deleted
+        // when the reference is dropped
+        let temp_file = || -> std::io::Result<Option<NamedTempFile>> {
+            let output = match &output {
+                Some(output) => output,
+                None => return Ok(None),
+            };
+            match output.log_path().as_ref().and_then(|p| p.parent()) {
+                Some(dir) => NamedTempFile::new_in(dir).map(Some),
+                None => NamedTempFile::new().map(Some),
+            }
+        };
+        let stdout = temp_file()?;
+        let stderr = temp_file()?;
+
+        Ok(WasiRuntime {
+            module_data,
+            env,
+            args,
+            dirs,
+            output,
+            stdout,
+            stderr,
+            inherit_stdio: false,
+            engine_config: EngineConfig::default(),
+            entrypoint: None,
+            working_dir: None,
+            stack_size: None,
+            max_open_handles: None,
+        })
+    }
+
+    /// Run a module compiled ahead of time. It must be the module this runtime was created with.
+    ///
+    /// TODO: checkpoint and restore instances, i.e. snapshot the linear memory and globals to disk and instantiate
+    /// from a snapshot. With the wasmtime version we use, the module runs inside `Instance::new` or its entrypoint
+    /// call on this thread, and nothing else can reach its store while it runs, so there is no point to take a
+    /// consistent snapshot at. Restoring would also need to skip the start function, which instantiating always
+    /// runs. This needs interruptible execution, like the epoch interruption of newer wasmtime versions.
+    pub fn run_compiled(&self, compiled: CompiledModule) -> super::Result<()> {
+        let CompiledModule { store, module } = compiled;
         // Build the WASI instance and then generate a list of WASI modules
-        let global_exports = store.global_exports().clone();
-        let store = HostRef::new(store);
+        let global_exports = store.borrow().global_exports().clone();
 
         let ctx_builder = WasiCtxBuilder::new().args(&self.args).envs(&self.env);
-        let ctx_builder = match &self.stdout {
-            Some(f) => ctx_builder.stdout(f.reopen()?),
-            None => ctx_builder,
+        let ctx_builder = if self.inherit_stdio {
+            ctx_builder.inherit_stdio()
+        } else {
+            ctx_builder
+        };
+        // the captures publish what is left once they are dropped, after the module returned
+        let mut captures = vec![];
+        let ctx_builder = match (&self.output, &self.stdout) {
+            (Some(output), Some(f)) => {
+                captures.push(output.capture(Stream::Stdout, f.reopen()?));
+                ctx_builder.stdout(f.reopen()?)
+            }
+            _ => ctx_builder,
         };
 
-        let mut ctx_builder = match &self.stderr {
-            Some(f) => ctx_builder.stderr(f.reopen()?),
-            None => ctx_builder,
+        let mut ctx_builder = match (&self.output, &self.stderr) {
+            (Some(output), Some(f)) => {
+                captures.push(output.capture(Stream::Stderr, f.reopen()?));
+                ctx_builder.stderr(f.reopen()?)
+            }
+            _ => ctx_builder,
         };
 
         for (key, value) in self.dirs.iter() {
@@ -55,15 +220,24 @@ impl Runtime for WasiRuntime {
             // Try and preopen the directory and then try to clone it. This step adds the directory to the context
             ctx_builder = ctx_builder.preopened_dir(preopen_dir(key)?, guest_dir);
         }
+        // WASI has no current directory. The libc of the module resolves relative paths against a directory
+        // preopened as `.`, so the working directory is preopened under both names.
+        if let Some((host_dir, guest_dir)) = &self.working_dir {
+            ctx_builder = ctx_builder
+                .preopened_dir(preopen_dir(host_dir)?, guest_dir)
+                .preopened_dir(preopen_dir(host_dir)?, ".");
+        }
         let wasi_ctx = ctx_builder.build()?;
         let wasi_inst = wasmtime::Instance::from_handle(
             &store,
             instantiate_wasi_with_context(global_exports, wasi_ctx)?,
         );
-        let module = Module::new(&store, &self.module_data)
-            .map_err(|e| format_err!("unable to load module data {}", e))?;
-        let module = HostRef::new(module);
         // Iterate through the module includes and resolve imports
+        let memory = Rc::new(RefCell::new(None));
+        let aborted = Rc::new(RefCell::new(None));
+        let handles = self
+            .max_open_handles
+            .map(|max| OpenHandles::new(max, memory.clone()));
         let imports = module
             .borrow()
             .imports()
@@ -71,8 +245,25 @@ impl Runtime for WasiRuntime {
             .map(|i| {
                 let module_name = i.module().as_str();
                 let field_name = i.name().as_str();
-                if let Some(export) = wasi_inst.find_export_by_name(field_name) {
-                    Ok(export.clone())
+                if (module_name, field_name) == ASSEMBLYSCRIPT_ABORT {
+                    let abort = AssemblyScriptAbort {
+                        memory: memory.clone(),
+                        aborted: aborted.clone(),
+                    };
+                    Ok(Extern::Func(HostRef::new(Func::new(
+                        &store,
+                        AssemblyScriptAbort::func_type(),
+                        Rc::new(abort),
+                    ))))
+                } else if let Some(export) = wasi_inst.find_export_by_name(field_name) {
+                    let counted = match (&handles, export.func()) {
+                        (Some(handles), Some(func)) => handles.wrap(&store, field_name, func),
+                        _ => None,
+                    };
+                    Ok(match counted {
+                        Some(func) => Extern::Func(HostRef::new(func)),
+                        None => export.clone(),
+                    })
                 } else {
                     failure::bail!(
                         "Import {} was not found in module {}",
@@ -84,80 +275,93 @@ impl Runtime for WasiRuntime {
             .collect::<super::Result<Vec<_>>>()?;
 
         info!("starting run of module");
-        let _instance = Instance::new(&store, &module, &imports)
-            .map_err(|e| format_err!("unable to run module: {}", e))?;
+        let span = info_span!("instantiate");
+        let enter = span.enter();
+        let started = Instant::now();
+        let instance = Instance::new(&store, &module, &imports)
+            .map_err(|e| self.diagnose(format_err!("unable to run module: {}", e), &aborted))?;
+        *memory.borrow_mut() = instance
+            .find_export_by_name("memory")
+            .and_then(|export| export.memory())
+            .cloned();
+        debug!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "instantiated module"
+        );
+        drop(enter);
+        if let Some(name) = &self.entrypoint {
+            let func = instance
+                .find_export_by_name(name)
+                .and_then(|export| export.func())
+                .ok_or_else(|| format_err!("module exports no function {}", name))?;
+            func.borrow().call(&[]).map_err(|trap| {
+                let error = format_err!("{} failed: {}", name, trap.borrow().message());
+                self.diagnose(error, &aborted)
+            })?;
+        }
 
         info!("module run complete");
         Ok(())
     }
 
-    /// output returns a tuple of BufReaders containing stdout and stderr
-    /// respectively. It will error if it can't open a stream
-    // TODO(taylor): I can't completely tell from documentation, but we may
-    // need to switch this out from a BufReader if it can't handle streaming
-    // logs
-    fn output(&self) -> super::Result<(BufReader<File>, BufReader<File>)> {
-        // As warned in the BufReader docs, creating multiple BufReaders on the
-        // same stream can cause data loss. So reopen a new file object each
-        // time this function as called so as to not drop any data
-        let stdout = match &self.stdout {
-            Some(s) => s,
-            None => return Err(format_err!("logging is not enabled for this runtime")),
+    /// The error the module failed with: why it aborted, when its language tells, see `diagnostics`, or else the
+    /// trap.
+    fn diagnose(&self, error: failure::Error, aborted: &RefCell<Option<Abort>>) -> failure::Error {
+        let abort = aborted.borrow_mut().take().or_else(|| {
+            self.stderr
+                .as_ref()
+                .and_then(|stderr| rust_panic_in(stderr.path()).ok().flatten())
+        });
+        match abort {
+            Some(abort) => {
+                debug!("module aborted, it failed with: {}", error);
+                format_err!("module aborted: {}", abort)
+            }
+            None => error,
         }
-        .reopen()?;
-        let stderr = match &self.stderr {
-            Some(s) => s,
-            None => return Err(format_err!("logging is not enabled for this runtime")),
-        }
-        .reopen()?;
+    }
 
-        Ok((BufReader::new(stdout), BufReader::new(stderr)))
+    /// Enable the given wasm features for the module.
+    pub fn with_engine_config(mut self, engine_config: EngineConfig) -> Self {
+        self.engine_config = engine_config;
+        self
     }
-}
 
-impl WasiRuntime {
-    /// Creates a new WasiRuntime
-    ///
-    /// # Arguments
-    ///
-    /// * `module_path` - the path to the WebAssembly binary
-    /// * `env` - a collection of key/value pairs containing the environment variables
-    /// * `args` - the arguments passed as the command-line arguments list
-    /// * `dirs` - a map of local file system paths to optional path names in the runtime
-    ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
-    ///     the same path will be allowed in the runtime
-    /// * `log_file_location` - location for storing logs
-    pub fn new<M: AsRef<Path>, L: AsRef<Path> + Copy>(
-        module_path: M,
-        env: HashMap<String, String>,
-        args: Vec<String>,
-        dirs: HashMap<String, Option<String>>,
-        log_file_location: Option<L>,
-    ) -> super::Result<Self> {
-        let module_data = std::fs::read(module_path)?;
+    /// Call the exported function with the given name once the module is instantiated, e.g. `_start`.
+    pub fn with_entrypoint(mut self, entrypoint: String) -> Self {
+        self.entrypoint = Some(entrypoint);
+        self
+    }
 
-        // We need to use named temp file because we need multiple file handles
-        // and if we are running in the temp dir, we run the possibility of the
-        // temp file getting cleaned out from underneath us while running. If we
-        // think it necessary, we can make these permanent files with a cleanup
-        // loop that runs elsewhere. These will get deleted when the reference
-        // is dropped
-        let stdout = match log_file_location {
-            Some(l) => Some(NamedTempFile::new_in(l)?),
-            None => None,
-        };
-        let stderr = match log_file_location {
-            Some(l) => Some(NamedTempFile::new_in(l)?),
-            None => None,
-        };
+    /// Preopen the host directory as the module's working directory, at the given path in the runtime.
+    pub fn with_working_dir(mut self, host_dir: PathBuf, guest_dir: String) -> Self {
+        self.working_dir = Some((host_dir, guest_dir));
+        self
+    }
 
-        Ok(WasiRuntime {
-            module_data,
-            env,
-            args,
-            dirs,
-            stdout,
-            stderr,
-        })
+    /// Run the module on a stack of the given size in bytes. wasm frames live on the native stack of the thread
+    /// running the module, next to wasmtime's own, so the stack bounds how deep the module may recurse before it
+    /// traps with a stack overflow. Runners must honour `Runtime::stack_size`.
+    pub fn with_stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Let the module have at most the given number of files and directories open at once, see `wasm::handles`.
+    pub fn with_max_open_handles(mut self, max_open_handles: usize) -> Self {
+        self.max_open_handles = Some(max_open_handles);
+        self
+    }
+
+    /// The wasm features enabled for the module.
+    pub fn engine_config(&self) -> &EngineConfig {
+        &self.engine_config
+    }
+
+    /// Let the module use the host's stdin, stdout and stderr. Streams that are redirected into a
+    /// log file location are not affected.
+    pub fn inherit_stdio(mut self) -> Self {
+        self.inherit_stdio = true;
+        self
     }
 }